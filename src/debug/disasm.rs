@@ -0,0 +1,289 @@
+use crate::system::r5900::{COP0_REGNAMES, MIPS_FPR_NAMES, MIPS_GPR_NAMES};
+use crate::system::symbols::SymbolTable;
+
+/*
+    A standalone R5900 disassembler for the debuggers. The instruction handlers in r5900.rs trace as they
+    execute, but a debugger needs to show code that hasn't run yet.
+*/
+
+struct Fields {
+    rs: &'static str,
+    rt: &'static str,
+    rd: &'static str,
+    sa: u32,
+    imm: i16,
+    uimm: u32,
+    funct: u32,
+}
+
+fn fields(instruction: u32) -> Fields {
+    Fields {
+        rs: MIPS_GPR_NAMES[((instruction >> 21) & 0x1f) as usize],
+        rt: MIPS_GPR_NAMES[((instruction >> 16) & 0x1f) as usize],
+        rd: MIPS_GPR_NAMES[((instruction >> 11) & 0x1f) as usize],
+        sa: (instruction >> 6) & 0x1f,
+        imm: (instruction & 0xFFFF) as i16,
+        uimm: instruction & 0xFFFF,
+        funct: instruction & 0x3f,
+    }
+}
+
+/// Formats a code address along with its symbol if we know one.
+fn target(addr: u32, symbols: &SymbolTable) -> String {
+    match symbols.format_addr(addr) {
+        Some(name) => format!("{:#010X} <{}>", addr, name),
+        None => format!("{:#010X}", addr),
+    }
+}
+
+/// Disassembles the instruction at the given address.
+pub fn disassemble(instruction: u32, pc: u32, symbols: &SymbolTable) -> String {
+    let f = fields(instruction);
+    let branch_target = pc.wrapping_add(4).wrapping_add(((f.imm as i32) * 4) as u32);
+    let jump_target = ((instruction & 0x03FF_FFFF) * 4) | (pc.wrapping_add(4) & 0xF000_0000);
+
+    let branch2 = |name: &str| format!("{} {}, {}, {}", name, f.rs, f.rt, target(branch_target, symbols));
+    let branch1 = |name: &str| format!("{} {}, {}", name, f.rs, target(branch_target, symbols));
+    let arith_imm = |name: &str| format!("{} {}, {}, {:#06X}", name, f.rt, f.rs, f.imm);
+    let logic_imm = |name: &str| format!("{} {}, {}, {:#06X}", name, f.rt, f.rs, f.uimm);
+    let mem = |name: &str| format!("{} {}, {:#06X}({})", name, f.rt, f.imm, f.rs);
+    let fmem = |name: &str| format!("{} {}, {:#06X}({})", name, MIPS_FPR_NAMES[((instruction >> 16) & 0x1f) as usize], f.imm, f.rs);
+    let vmem = |name: &str| format!("{} VF{}, {:#06X}({})", name, (instruction >> 16) & 0x1f, f.imm, f.rs);
+
+    match instruction >> 26 {
+        0x00 => disassemble_special(instruction, &f),
+        0x01 => disassemble_regimm(instruction, &f, branch_target, symbols),
+        0x02 => format!("J {}", target(jump_target, symbols)),
+        0x03 => format!("JAL {}", target(jump_target, symbols)),
+        0x04 => branch2("BEQ"),
+        0x05 => branch2("BNE"),
+        0x06 => branch1("BLEZ"),
+        0x07 => branch1("BGTZ"),
+        0x08 => arith_imm("ADDI"),
+        0x09 => arith_imm("ADDIU"),
+        0x0A => arith_imm("SLTI"),
+        0x0B => arith_imm("SLTIU"),
+        0x0C => logic_imm("ANDI"),
+        0x0D => logic_imm("ORI"),
+        0x0E => logic_imm("XORI"),
+        0x0F => format!("LUI {}, {:#06X}", f.rt, f.uimm),
+        0x10 => disassemble_cop0(instruction, &f, branch_target, symbols),
+        0x11 => disassemble_cop1(instruction, &f, branch_target, symbols),
+        0x12 => format!("COP2 {:#09X}", instruction & 0x03FF_FFFF),
+        0x14 => branch2("BEQL"),
+        0x15 => branch2("BNEL"),
+        0x16 => branch1("BLEZL"),
+        0x17 => branch1("BGTZL"),
+        0x18 => arith_imm("DADDI"),
+        0x19 => arith_imm("DADDIU"),
+        0x1A => mem("LDL"),
+        0x1B => mem("LDR"),
+        0x1C => format!("MMI {:#09X}", instruction & 0x03FF_FFFF),
+        0x1E => mem("LQ"),
+        0x1F => mem("SQ"),
+        0x20 => mem("LB"),
+        0x21 => mem("LH"),
+        0x22 => mem("LWL"),
+        0x23 => mem("LW"),
+        0x24 => mem("LBU"),
+        0x25 => mem("LHU"),
+        0x26 => mem("LWR"),
+        0x27 => mem("LWU"),
+        0x28 => mem("SB"),
+        0x29 => mem("SH"),
+        0x2A => mem("SWL"),
+        0x2B => mem("SW"),
+        0x2C => mem("SDL"),
+        0x2D => mem("SDR"),
+        0x2E => mem("SWR"),
+        0x2F => format!("CACHE {:#04X}, {:#06X}({})", (instruction >> 16) & 0x1f, f.imm, f.rs),
+        0x31 => fmem("LWC1"),
+        0x33 => format!("PREF {}, {:#06X}({})", (instruction >> 16) & 0x1f, f.imm, f.rs),
+        0x36 => vmem("LQC2"),
+        0x37 => mem("LD"),
+        0x39 => fmem("SWC1"),
+        0x3E => vmem("SQC2"),
+        0x3F => mem("SD"),
+        _ => format!("ILLEGAL {:#010X}", instruction),
+    }
+}
+
+fn disassemble_special(instruction: u32, f: &Fields) -> String {
+    let three = |name: &str| format!("{} {}, {}, {}", name, f.rd, f.rs, f.rt);
+    let shift = |name: &str| format!("{} {}, {}, {}", name, f.rd, f.rt, f.sa);
+    let shiftv = |name: &str| format!("{} {}, {}, {}", name, f.rd, f.rt, f.rs);
+    let two = |name: &str| format!("{} {}, {}", name, f.rs, f.rt);
+    match f.funct {
+        0x00 if instruction == 0 => String::from("NOP"),
+        0x00 => shift("SLL"),
+        0x02 => shift("SRL"),
+        0x03 => shift("SRA"),
+        0x04 => shiftv("SLLV"),
+        0x06 => shiftv("SRLV"),
+        0x07 => shiftv("SRAV"),
+        0x08 => format!("JR {}", f.rs),
+        0x09 => format!("JALR {}, {}", f.rd, f.rs),
+        0x0A => three("MOVZ"),
+        0x0B => three("MOVN"),
+        0x0C => format!("SYSCALL {:#X}", (instruction >> 6) & 0xFFFFF),
+        0x0D => format!("BREAK {:#X}", (instruction >> 6) & 0xFFFFF),
+        0x0F => String::from("SYNC"),
+        0x10 => format!("MFHI {}", f.rd),
+        0x11 => format!("MTHI {}", f.rs),
+        0x12 => format!("MFLO {}", f.rd),
+        0x13 => format!("MTLO {}", f.rs),
+        0x14 => shiftv("DSLLV"),
+        0x16 => shiftv("DSRLV"),
+        0x17 => shiftv("DSRAV"),
+        0x18 => two("MULT"),
+        0x19 => two("MULTU"),
+        0x1A => two("DIV"),
+        0x1B => two("DIVU"),
+        0x20 => three("ADD"),
+        0x21 => three("ADDU"),
+        0x22 => three("SUB"),
+        0x23 => three("SUBU"),
+        0x24 => three("AND"),
+        0x25 => three("OR"),
+        0x26 => three("XOR"),
+        0x27 => three("NOR"),
+        0x28 => format!("MFSA {}", f.rd),
+        0x29 => format!("MTSA {}", f.rs),
+        0x2A => three("SLT"),
+        0x2B => three("SLTU"),
+        0x2C => three("DADD"),
+        0x2D => three("DADDU"),
+        0x2E => three("DSUB"),
+        0x2F => three("DSUBU"),
+        0x30 => two("TGE"),
+        0x31 => two("TGEU"),
+        0x32 => two("TLT"),
+        0x33 => two("TLTU"),
+        0x34 => two("TEQ"),
+        0x36 => two("TNE"),
+        0x38 => shift("DSLL"),
+        0x3A => shift("DSRL"),
+        0x3B => shift("DSRA"),
+        0x3C => shift("DSLL32"),
+        0x3E => shift("DSRL32"),
+        0x3F => shift("DSRA32"),
+        _ => format!("ILLEGAL {:#010X}", instruction),
+    }
+}
+
+fn disassemble_regimm(instruction: u32, f: &Fields, branch_target: u32, symbols: &SymbolTable) -> String {
+    let branch = |name: &str| format!("{} {}, {}", name, f.rs, target(branch_target, symbols));
+    let trap = |name: &str| format!("{} {}, {:#06X}", name, f.rs, f.imm);
+    match (instruction >> 16) & 0x1f {
+        0x00 => branch("BLTZ"),
+        0x01 => branch("BGEZ"),
+        0x02 => branch("BLTZL"),
+        0x03 => branch("BGEZL"),
+        0x08 => trap("TGEI"),
+        0x09 => trap("TGEIU"),
+        0x0A => trap("TLTI"),
+        0x0B => trap("TLTIU"),
+        0x0C => trap("TEQI"),
+        0x0E => trap("TNEI"),
+        0x10 => branch("BLTZAL"),
+        0x11 => branch("BGEZAL"),
+        0x12 => branch("BLTZALL"),
+        0x13 => branch("BGEZALL"),
+        0x18 => format!("MTSAB {}, {:#06X}", f.rs, f.imm),
+        0x19 => format!("MTSAH {}, {:#06X}", f.rs, f.imm),
+        _ => format!("ILLEGAL {:#010X}", instruction),
+    }
+}
+
+fn disassemble_cop0(instruction: u32, f: &Fields, branch_target: u32, symbols: &SymbolTable) -> String {
+    let cop0_reg = COP0_REGNAMES[((instruction >> 11) & 0x1f) as usize];
+    match (instruction >> 21) & 0x1f {
+        0x00 => format!("MFC0 {}, {}", f.rt, cop0_reg),
+        0x04 => format!("MTC0 {}, {}", f.rt, cop0_reg),
+        0x08 => {
+            let names = ["BC0F", "BC0T", "BC0FL", "BC0TL"];
+            match names.get(((instruction >> 16) & 0x1f) as usize) {
+                Some(name) => format!("{} {}", name, target(branch_target, symbols)),
+                None => format!("ILLEGAL {:#010X}", instruction),
+            }
+        }
+        0x10 => match f.funct {
+            0x01 => String::from("TLBR"),
+            0x02 => String::from("TLBWI"),
+            0x06 => String::from("TLBWR"),
+            0x08 => String::from("TLBP"),
+            0x18 => String::from("ERET"),
+            0x38 => String::from("EI"),
+            0x39 => String::from("DI"),
+            _ => format!("ILLEGAL {:#010X}", instruction),
+        },
+        _ => format!("ILLEGAL {:#010X}", instruction),
+    }
+}
+
+fn disassemble_cop1(instruction: u32, f: &Fields, branch_target: u32, symbols: &SymbolTable) -> String {
+    let ft = MIPS_FPR_NAMES[((instruction >> 16) & 0x1f) as usize];
+    let fs = MIPS_FPR_NAMES[((instruction >> 11) & 0x1f) as usize];
+    let fd = MIPS_FPR_NAMES[((instruction >> 6) & 0x1f) as usize];
+    let three = |name: &str| format!("{} {}, {}, {}", name, fd, fs, ft);
+    let two = |name: &str| format!("{} {}, {}", name, fd, fs);
+    let acc = |name: &str| format!("{} {}, {}", name, fs, ft);
+    match (instruction >> 21) & 0x1f {
+        0x00 => format!("MFC1 {}, {}", f.rt, fs),
+        0x02 => format!("CFC1 {}, FCR{}", f.rt, (instruction >> 11) & 0x1f),
+        0x04 => format!("MTC1 {}, {}", f.rt, fs),
+        0x06 => format!("CTC1 {}, FCR{}", f.rt, (instruction >> 11) & 0x1f),
+        0x08 => {
+            let names = ["BC1F", "BC1T", "BC1FL", "BC1TL"];
+            match names.get(((instruction >> 16) & 0x1f) as usize) {
+                Some(name) => format!("{} {}", name, target(branch_target, symbols)),
+                None => format!("ILLEGAL {:#010X}", instruction),
+            }
+        }
+        0x10 => match f.funct {
+            0x00 => three("ADD.S"),
+            0x01 => three("SUB.S"),
+            0x02 => three("MUL.S"),
+            0x03 => three("DIV.S"),
+            0x04 => format!("SQRT.S {}, {}", fd, ft),
+            0x05 => two("ABS.S"),
+            0x06 => two("MOV.S"),
+            0x07 => two("NEG.S"),
+            0x16 => three("RSQRT.S"),
+            0x18 => acc("ADDA.S"),
+            0x19 => acc("SUBA.S"),
+            0x1A => acc("MULA.S"),
+            0x1C => three("MADD.S"),
+            0x1D => three("MSUB.S"),
+            0x1E => acc("MADDA.S"),
+            0x1F => acc("MSUBA.S"),
+            0x24 => two("CVT.W.S"),
+            0x28 => three("MAX.S"),
+            0x29 => three("MIN.S"),
+            0x30 => acc("C.F.S"),
+            0x32 => acc("C.EQ.S"),
+            0x34 => acc("C.LT.S"),
+            0x36 => acc("C.LE.S"),
+            _ => format!("ILLEGAL {:#010X}", instruction),
+        },
+        0x14 if f.funct == 0x20 => two("CVT.S.W"),
+        _ => format!("ILLEGAL {:#010X}", instruction),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let mut symbols = SymbolTable::new();
+        symbols.add(0x0010_0000, "main", 0x100);
+        assert_eq!("NOP", disassemble(0, 0, &symbols));
+        assert_eq!("ADDIU SP, SP, 0xFFF0", disassemble(0x27BD_FFF0, 0, &symbols));
+        assert_eq!("JAL 0x00100000 <main>", disassemble(0x0C04_0000, 0x0010_0100, &symbols));
+        assert_eq!("BNE V0, Zero, 0x00100010 <main+0x10>", disassemble(0x1440_0003, 0x0010_0000, &symbols));
+        assert_eq!("MFC0 K0, Status", disassemble(0x401A_6000, 0, &symbols));
+        assert_eq!("SW RA, 0x0004(SP)", disassemble(0xAFBF_0004, 0, &symbols));
+    }
+}
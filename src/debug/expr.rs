@@ -0,0 +1,211 @@
+use crate::system::ps2::Ps2;
+use crate::system::r5900::MIPS_GPR_NAMES;
+
+/*
+    A small expression evaluator for the debugger. Values are 32 bit and arithmetic wraps.
+
+        number      1234, 0x1F00
+        register    $sp, $a0, $pc, $hi, $lo, $r4, $f2 (raw bits)
+        symbol      main, _gp
+        memory      [expr] reads a word through the bus
+        operators   unary - ~, then * / %, + -, << >>, &, ^, | from tightest to loosest, and parentheses
+*/
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Number(u32),
+    Register(String),
+    Symbol(String),
+    Op(&'static str),
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+}
+
+const OPERATORS: [&str; 12] = ["<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "!"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let word_end = |start: usize| {
+            let mut end = start;
+            while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_' || chars[end] == '.') {
+                end += 1;
+            }
+            end
+        };
+        match c {
+            '(' => { tokens.push(Token::Open); i += 1; }
+            ')' => { tokens.push(Token::Close); i += 1; }
+            '[' => { tokens.push(Token::OpenBracket); i += 1; }
+            ']' => { tokens.push(Token::CloseBracket); i += 1; }
+            '$' => {
+                let end = word_end(i + 1);
+                tokens.push(Token::Register(chars[i + 1..end].iter().collect::<String>().to_lowercase()));
+                i = end;
+            }
+            '0'..='9' => {
+                let end = word_end(i);
+                let word: String = chars[i..end].iter().collect();
+                let value = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => word.parse::<u32>(),
+                };
+                tokens.push(Token::Number(value.map_err(|_| format!("bad number {}", word))?));
+                i = end;
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let end = word_end(i);
+                tokens.push(Token::Symbol(chars[i..end].iter().collect()));
+                i = end;
+            }
+            _ => {
+                let rest: String = chars[i..].iter().collect();
+                let op = OPERATORS.iter().find(|op| rest.starts_with(*op)).ok_or_else(|| format!("unexpected '{}'", c))?;
+                tokens.push(Token::Op(op));
+                i += op.len();
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn register_value(ps2: &Ps2, name: &str) -> Result<u32, String> {
+    let cpu = &ps2.r5900;
+    match name {
+        "pc" => return Ok(cpu.pc),
+        "hi" => return Ok(cpu.hi),
+        "lo" => return Ok(cpu.lo),
+        _ => (),
+    }
+    if let Some(index) = MIPS_GPR_NAMES.iter().position(|n| n.to_lowercase() == name) {
+        return Ok(cpu.gpr_regs[index][0]);
+    }
+    let numbered = |prefix: &str| name.strip_prefix(prefix).and_then(|n| n.parse::<usize>().ok()).filter(|n| *n < 32);
+    if let Some(index) = numbered("r") {
+        return Ok(cpu.gpr_regs[index][0]);
+    }
+    if let Some(index) = numbered("f") {
+        return Ok(cpu.fpr_regs[index].to_bits());
+    }
+    Err(format!("unknown register ${}", name))
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    ps2: &'a Ps2,
+}
+
+/// Binary operators from loosest to tightest binding.
+const PRECEDENCE: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn binary(&mut self, level: usize) -> Result<u32, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1)?;
+        while let Some(Token::Op(op)) = self.peek() {
+            let op = *op;
+            if !PRECEDENCE[level].contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            value = match op {
+                "|" => value | rhs,
+                "^" => value ^ rhs,
+                "&" => value & rhs,
+                "<<" => value.wrapping_shl(rhs),
+                ">>" => value.wrapping_shr(rhs),
+                "+" => value.wrapping_add(rhs),
+                "-" => value.wrapping_sub(rhs),
+                "*" => value.wrapping_mul(rhs),
+                "/" => value.checked_div(rhs).ok_or("divide by zero")?,
+                _ => value.checked_rem(rhs).ok_or("divide by zero")?,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<u32, String> {
+        match self.next() {
+            Some(Token::Op("-")) => Ok(self.unary()?.wrapping_neg()),
+            Some(Token::Op("~")) => Ok(!self.unary()?),
+            Some(Token::Op("!")) => Ok((self.unary()? == 0) as u32),
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::Register(name)) => register_value(self.ps2, &name),
+            Some(Token::Symbol(name)) => self.ps2.symbols.find_by_name(&name).ok_or(format!("unknown symbol {}", name)),
+            Some(Token::Open) => {
+                let value = self.binary(0)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(value),
+                    _ => Err(String::from("missing )")),
+                }
+            }
+            Some(Token::OpenBracket) => {
+                let addr = self.binary(0)?;
+                match self.next() {
+                    Some(Token::CloseBracket) => Ok(self.ps2.peek_ee_u32(addr)),
+                    _ => Err(String::from("missing ]")),
+                }
+            }
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+}
+
+/// Evaluates an expression against the current machine state.
+pub fn evaluate(ps2: &Ps2, text: &str) -> Result<u32, String> {
+    let mut parser = Parser { tokens: tokenize(text)?, pos: 0, ps2 };
+    let value = parser.binary(0)?;
+    match parser.peek() {
+        None => Ok(value),
+        Some(token) => Err(format!("unexpected {:?}", token)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.r5900.gpr_regs[29][0] = 0x0100_0000;
+        ps2.r5900.gpr_regs[4][0] = 3;
+        ps2.write_ee_u32(0x0100_0010, 0xCAFE);
+        ps2.symbols.add(0x0010_0000, "main", 0);
+
+        assert_eq!(Ok(14), evaluate(&ps2, "2 + 3 * 4"));
+        assert_eq!(Ok(20), evaluate(&ps2, "(2 + 3) * 4"));
+        assert_eq!(Ok(0x0100_0010), evaluate(&ps2, "$sp + 0x10"));
+        assert_eq!(Ok(0xCAFE), evaluate(&ps2, "[$sp + 0x10]"));
+        assert_eq!(Ok(24), evaluate(&ps2, "$a0 << 3"));
+        assert_eq!(Ok(0x0010_0008), evaluate(&ps2, "main + 8"));
+        assert_eq!(Ok(0xFFFF_FFFF), evaluate(&ps2, "-1"));
+        assert!(evaluate(&ps2, "$nope").is_err());
+        assert!(evaluate(&ps2, "1 / 0").is_err());
+        assert!(evaluate(&ps2, "(1").is_err());
+    }
+}
@@ -0,0 +1,506 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::system::breakpoints::WatchKind;
+use crate::system::ps2::Ps2;
+use crate::system::r5900::COP0_REGNAMES;
+
+/*
+    A GDB remote serial protocol stub for the EE.
+
+    The register numbering follows GDB's standard MIPS layout so that gdb-multiarch is happy with a 32 bit target:
+        0-31    r0-r31 (lower 32 bits)
+        32-37   sr, lo, hi, badvaddr, cause, pc
+        38-69   f0-f31
+        70-71   fcsr, fir
+    and then two extra features we describe in the target XML:
+        72-103  the full 128 bit GPRs (r0q-r31q)
+        104-135 every COP0 register
+*/
+
+const REG_SR: usize = 32;
+const REG_LO: usize = 33;
+const REG_HI: usize = 34;
+const REG_BADVADDR: usize = 35;
+const REG_CAUSE: usize = 36;
+const REG_PC: usize = 37;
+const REG_FPR_BASE: usize = 38;
+const REG_FCSR: usize = 70;
+const REG_FIR: usize = 71;
+const REG_GPR128_BASE: usize = 72;
+const REG_COP0_BASE: usize = 104;
+const NUM_REGS: usize = 136;
+
+const COP0_BADVADDR: usize = 8;
+const COP0_STATUS: usize = 12;
+const COP0_CAUSE: usize = 13;
+
+/* FCR0 implementation and revision of the R5900 FPU */
+const FPU_FIR: u32 = 0x2E30;
+
+/* How many instructions to run between checks for an interrupt from GDB */
+const INTERRUPT_CHECK_INTERVAL: u32 = 4096;
+
+const MAX_MEMORY_READ: usize = 0x4000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target>\n<architecture>mips</architecture>\n");
+
+    xml += "<feature name=\"org.gnu.gdb.mips.cpu\">\n";
+    for i in 0..32 {
+        xml += &format!("<reg name=\"r{}\" bitsize=\"32\" regnum=\"{}\"/>\n", i, i);
+    }
+    xml += &format!("<reg name=\"lo\" bitsize=\"32\" regnum=\"{}\"/>\n", REG_LO);
+    xml += &format!("<reg name=\"hi\" bitsize=\"32\" regnum=\"{}\"/>\n", REG_HI);
+    xml += &format!("<reg name=\"pc\" bitsize=\"32\" regnum=\"{}\" type=\"code_ptr\"/>\n", REG_PC);
+    xml += "</feature>\n";
+
+    xml += "<feature name=\"org.gnu.gdb.mips.cp0\">\n";
+    xml += &format!("<reg name=\"status\" bitsize=\"32\" regnum=\"{}\"/>\n", REG_SR);
+    xml += &format!("<reg name=\"badvaddr\" bitsize=\"32\" regnum=\"{}\"/>\n", REG_BADVADDR);
+    xml += &format!("<reg name=\"cause\" bitsize=\"32\" regnum=\"{}\"/>\n", REG_CAUSE);
+    xml += "</feature>\n";
+
+    xml += "<feature name=\"org.gnu.gdb.mips.fpu\">\n";
+    for i in 0..32 {
+        xml += &format!("<reg name=\"f{}\" bitsize=\"32\" type=\"ieee_single\" regnum=\"{}\"/>\n", i, REG_FPR_BASE + i);
+    }
+    xml += &format!("<reg name=\"fcsr\" bitsize=\"32\" group=\"float\" regnum=\"{}\"/>\n", REG_FCSR);
+    xml += &format!("<reg name=\"fir\" bitsize=\"32\" group=\"float\" regnum=\"{}\"/>\n", REG_FIR);
+    xml += "</feature>\n";
+
+    xml += "<feature name=\"org.rustsx2.ee.gpr128\">\n";
+    for i in 0..32 {
+        xml += &format!("<reg name=\"r{}q\" bitsize=\"128\" type=\"uint128\" regnum=\"{}\"/>\n", i, REG_GPR128_BASE + i);
+    }
+    xml += "</feature>\n";
+
+    xml += "<feature name=\"org.rustsx2.ee.cop0\">\n";
+    for (i, name) in COP0_REGNAMES.iter().enumerate() {
+        let name = if *name == "RESERVED" { format!("cop0_r{}", i) } else { format!("cop0_{}", name.to_lowercase()) };
+        xml += &format!("<reg name=\"{}\" bitsize=\"32\" group=\"system\" regnum=\"{}\"/>\n", name, REG_COP0_BASE + i);
+    }
+    xml += "</feature>\n</target>\n";
+    xml
+}
+
+/// Returns the little endian bytes of a register.
+fn read_register(ps2: &Ps2, reg: usize) -> Vec<u8> {
+    let cpu = &ps2.r5900;
+    let value = match reg {
+        0..=31 => cpu.gpr_regs[reg][0],
+        REG_SR => cpu.cop0_regs[COP0_STATUS],
+        REG_LO => cpu.lo,
+        REG_HI => cpu.hi,
+        REG_BADVADDR => cpu.cop0_regs[COP0_BADVADDR],
+        REG_CAUSE => cpu.cop0_regs[COP0_CAUSE],
+        REG_PC => cpu.pc,
+        REG_FCSR => 0,
+        REG_FIR => FPU_FIR,
+        _ if (REG_FPR_BASE..REG_FPR_BASE + 32).contains(&reg) => cpu.fpr_regs[reg - REG_FPR_BASE].to_bits(),
+        _ if (REG_GPR128_BASE..REG_GPR128_BASE + 32).contains(&reg) => {
+            return cpu.gpr_regs[reg - REG_GPR128_BASE].iter().flat_map(|w| w.to_le_bytes()).collect();
+        }
+        _ if (REG_COP0_BASE..REG_COP0_BASE + 32).contains(&reg) => cpu.cop0_regs[reg - REG_COP0_BASE],
+        _ => 0,
+    };
+    value.to_le_bytes().to_vec()
+}
+
+fn register_size(reg: usize) -> usize {
+    if (REG_GPR128_BASE..REG_GPR128_BASE + 32).contains(&reg) { 16 } else { 4 }
+}
+
+fn write_register(ps2: &mut Ps2, reg: usize, bytes: &[u8]) {
+    let words: Vec<u32> = bytes.chunks(4).map(|c| {
+        let mut word = [0u8; 4];
+        word[..c.len()].copy_from_slice(c);
+        u32::from_le_bytes(word)
+    }).collect();
+    let value = words.first().copied().unwrap_or(0);
+    let cpu = &mut ps2.r5900;
+    match reg {
+        0 => (),
+        1..=31 => {
+            cpu.gpr_regs[reg][0] = value;
+            cpu.gpr_regs[reg][1] = if value & 0x8000_0000 != 0 { 0xFFFF_FFFF } else { 0 };
+        }
+        REG_SR => cpu.cop0_regs[COP0_STATUS] = value,
+        REG_LO => cpu.lo = value,
+        REG_HI => cpu.hi = value,
+        REG_BADVADDR => cpu.cop0_regs[COP0_BADVADDR] = value,
+        REG_CAUSE => cpu.cop0_regs[COP0_CAUSE] = value,
+        REG_PC => {
+            cpu.pc = value;
+            cpu.delay_slot_addr = 0;
+        }
+        _ if (REG_FPR_BASE..REG_FPR_BASE + 32).contains(&reg) => cpu.fpr_regs[reg - REG_FPR_BASE] = f32::from_bits(value),
+        _ if (REG_GPR128_BASE + 1..REG_GPR128_BASE + 32).contains(&reg) => {
+            for (i, word) in words.iter().take(4).enumerate() {
+                cpu.gpr_regs[reg - REG_GPR128_BASE][i] = *word;
+            }
+        }
+        _ if (REG_COP0_BASE..REG_COP0_BASE + 32).contains(&reg) => cpu.cop0_regs[reg - REG_COP0_BASE] = value,
+        _ => (),
+    }
+}
+
+fn peek_byte(ps2: &Ps2, addr: u32) -> u8 {
+    (ps2.peek_ee_u32(addr & !3) >> ((addr & 3) * 8)) as u8
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex_u32(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+/// What the stub should do after handling a packet.
+enum Action {
+    Reply(String),
+    Resume { single_step: bool },
+    Detach,
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    no_ack: bool,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> GdbStub {
+        GdbStub { stream, no_ack: false }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Reads the next packet, returning its contents. A bare interrupt (Ctrl-C) is returned as "\x03".
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            match self.read_byte()? {
+                b'$' => (),
+                0x03 => return Ok(String::from("\x03")),
+                _ => continue, // acks and noise
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b'}' => {
+                        let escaped = self.read_byte()?;
+                        data.push(escaped ^ 0x20);
+                    }
+                    byte => data.push(byte),
+                }
+            }
+            let checksum_text = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum_text).ok().and_then(|t| u8::from_str_radix(t, 16).ok());
+            let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            if self.no_ack {
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+            if expected == Some(actual) {
+                self.stream.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+            // ask for a retransmit
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            self.stream.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                b'+' => return Ok(()),
+                b'-' => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Non blocking check for GDB sending an interrupt while the target runs.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8; 1];
+        let result = match self.stream.read(&mut byte) {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    /// Runs the EE until something interesting happens and returns the stop reply.
+    fn resume(&mut self, ps2: &mut Ps2, single_step: bool) -> io::Result<String> {
+        let mut count: u32 = 0;
+        loop {
+            ps2.step();
+            if let Some(hit) = ps2.breakpoints.take_hit() {
+                let kind = match hit.watchpoint.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                return Ok(format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.addr));
+            }
+            if single_step || ps2.breakpoints.pc.contains(&ps2.r5900.pc) {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+            count += 1;
+            if count.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && self.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, ps2: &mut Ps2, packet: &str) -> Action {
+        let reply = |s: &str| Action::Reply(s.to_string());
+        // the packet can hold anything the client sent, so split after a whole character
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        match command {
+            "?" => Action::Reply(format!("S{:02x}", SIGTRAP)),
+            "g" => Action::Reply((0..NUM_REGS).map(|r| to_hex(&read_register(ps2, r))).collect()),
+            "G" => {
+                let bytes = match from_hex(args) {
+                    Some(bytes) => bytes,
+                    None => return reply("E01"),
+                };
+                let mut offset = 0;
+                for reg in 0..NUM_REGS {
+                    let size = register_size(reg);
+                    if offset + size > bytes.len() {
+                        break;
+                    }
+                    write_register(ps2, reg, &bytes[offset..offset + size]);
+                    offset += size;
+                }
+                reply("OK")
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(reg) if reg < NUM_REGS => Action::Reply(to_hex(&read_register(ps2, reg))),
+                _ => reply("E01"),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(reg, value)| Some((usize::from_str_radix(reg, 16).ok()?, from_hex(value)?)));
+                match parsed {
+                    Some((reg, bytes)) if reg < NUM_REGS => {
+                        write_register(ps2, reg, &bytes);
+                        reply("OK")
+                    }
+                    _ => reply("E01"),
+                }
+            }
+            "m" => {
+                let parsed = args.split_once(',').and_then(|(addr, len)| Some((parse_hex_u32(addr)?, usize::from_str_radix(len, 16).ok()?)));
+                match parsed {
+                    Some((addr, len)) => {
+                        let bytes: Vec<u8> = (0..len.min(MAX_MEMORY_READ) as u32).map(|i| peek_byte(ps2, addr.wrapping_add(i))).collect();
+                        Action::Reply(to_hex(&bytes))
+                    }
+                    None => reply("E01"),
+                }
+            }
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(header, data)| {
+                    let (addr, _) = header.split_once(',')?;
+                    Some((parse_hex_u32(addr)?, from_hex(data)?))
+                });
+                match parsed {
+                    Some((addr, bytes)) => {
+                        for (i, byte) in bytes.iter().enumerate() {
+                            ps2.poke_ee_u8(addr.wrapping_add(i as u32), *byte);
+                        }
+                        reply("OK")
+                    }
+                    None => reply("E01"),
+                }
+            }
+            "c" | "s" => {
+                if let Some(addr) = parse_hex_u32(args) {
+                    write_register(ps2, REG_PC, &addr.to_le_bytes());
+                }
+                Action::Resume { single_step: command == "s" }
+            }
+            "Z" | "z" => self.handle_breakpoint(ps2, command == "Z", args),
+            "H" => reply("OK"),
+            "T" => reply("OK"),
+            "D" => Action::Detach,
+            "k" => Action::Detach,
+            "q" | "Q" => self.handle_query(packet),
+            _ => reply(""),
+        }
+    }
+
+    fn handle_breakpoint(&mut self, ps2: &mut Ps2, insert: bool, args: &str) -> Action {
+        let fields: Vec<&str> = args.split(',').collect();
+        let (kind, addr, len) = match fields.as_slice() {
+            [kind, addr, len, ..] => match (parse_hex_u32(addr), parse_hex_u32(len)) {
+                (Some(addr), Some(len)) => (*kind, addr, len),
+                _ => return Action::Reply(String::from("E01")),
+            },
+            _ => return Action::Reply(String::from("E01")),
+        };
+        let watch_kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    ps2.breakpoints.pc.insert(addr);
+                } else {
+                    ps2.breakpoints.pc.remove(&addr);
+                }
+                return Action::Reply(String::from("OK"));
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Action::Reply(String::new()),
+        };
+        if insert {
+            ps2.breakpoints.add_watchpoint(addr, len, watch_kind);
+        } else {
+            ps2.breakpoints.remove_watchpoint(addr, len, watch_kind);
+        }
+        Action::Reply(String::from("OK"))
+    }
+
+    fn handle_query(&mut self, packet: &str) -> Action {
+        if packet.starts_with("qSupported") {
+            return Action::Reply(String::from("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+"));
+        }
+        if packet == "QStartNoAckMode" {
+            // the ack for this packet has already gone, the reply is the last thing acknowledged
+            self.no_ack = true;
+            return Action::Reply(String::from("OK"));
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_xml();
+            let parsed = range.split_once(',').and_then(|(off, len)| Some((usize::from_str_radix(off, 16).ok()?, usize::from_str_radix(len, 16).ok()?)));
+            return match parsed {
+                Some((offset, _)) if offset >= xml.len() => Action::Reply(String::from("l")),
+                Some((offset, len)) => {
+                    let end = (offset + len).min(xml.len());
+                    let marker = if end == xml.len() { "l" } else { "m" };
+                    Action::Reply(format!("{}{}", marker, &xml[offset..end]))
+                }
+                None => Action::Reply(String::from("E01")),
+            };
+        }
+        match packet {
+            "qAttached" => Action::Reply(String::from("1")),
+            "qC" => Action::Reply(String::from("QC1")),
+            "qfThreadInfo" => Action::Reply(String::from("m1")),
+            "qsThreadInfo" => Action::Reply(String::from("l")),
+            _ => Action::Reply(String::new()),
+        }
+    }
+
+    /// Serves GDB requests until it detaches or the connection drops.
+    pub fn run(&mut self, ps2: &mut Ps2) -> io::Result<()> {
+        loop {
+            let packet = self.read_packet()?;
+            if packet == "\x03" {
+                // interrupt while already stopped
+                self.send_packet(&format!("S{:02x}", SIGINT))?;
+                continue;
+            }
+            match self.handle_packet(ps2, &packet) {
+                Action::Reply(reply) => self.send_packet(&reply)?,
+                Action::Resume { single_step } => {
+                    let stop_reply = self.resume(ps2, single_step)?;
+                    self.send_packet(&stop_reply)?;
+                }
+                Action::Detach => {
+                    self.send_packet("OK")?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Listens for GDB on the given local port.
+pub fn listen(port: u16) -> io::Result<TcpListener> {
+    TcpListener::bind(("127.0.0.1", port))
+}
+
+/// Hands control of the EE over to a connected GDB until it detaches.
+pub fn serve(ps2: &mut Ps2, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    GdbStub::new(stream).run(ps2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_round_trip() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        write_register(&mut ps2, 4, &0x8000_1234u32.to_le_bytes());
+        assert_eq!([0x8000_1234, 0xFFFF_FFFF, 0, 0], ps2.r5900.gpr_regs[4]);
+
+        let wide: Vec<u8> = (0..16).collect();
+        write_register(&mut ps2, REG_GPR128_BASE + 5, &wide);
+        assert_eq!(wide, read_register(&ps2, REG_GPR128_BASE + 5));
+
+        write_register(&mut ps2, REG_FPR_BASE + 2, &1.5f32.to_bits().to_le_bytes());
+        assert_eq!(1.5, ps2.r5900.fpr_regs[2]);
+
+        // r0 is hardwired to zero
+        write_register(&mut ps2, 0, &[1, 2, 3, 4]);
+        assert_eq!(vec![0, 0, 0, 0], read_register(&ps2, 0));
+    }
+
+    #[test]
+    fn test_memory_and_breakpoint_packets() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stub = GdbStub::new(client);
+
+        assert!(matches!(stub.handle_packet(&mut ps2, "M100,4:78563412"), Action::Reply(r) if r == "OK"));
+        assert_eq!(0x1234_5678, ps2.read_ee_u32(0x100));
+        assert!(matches!(stub.handle_packet(&mut ps2, "m101,2"), Action::Reply(r) if r == "5634"));
+
+        stub.handle_packet(&mut ps2, "Z0,bfc00010,4");
+        stub.handle_packet(&mut ps2, "Z2,200,4");
+        assert!(ps2.breakpoints.pc.contains(&0xBFC0_0010));
+        ps2.write_ee_u32(0x8000_0200, 1);
+        assert_eq!(Some(0x8000_0200), ps2.breakpoints.take_hit().map(|h| h.addr));
+        stub.handle_packet(&mut ps2, "z2,200,4");
+        assert!(ps2.breakpoints.watchpoints.is_empty());
+
+        // a stray byte comes through as a multi-byte replacement character
+        let garbled = String::from_utf8_lossy(&[0xFF, b'g']).into_owned();
+        assert!(matches!(stub.handle_packet(&mut ps2, &garbled), Action::Reply(r) if r.is_empty()));
+
+        // resuming somewhere else leaves any pending branch behind
+        ps2.r5900.delay_slot_addr = 0x0010_0040;
+        assert!(matches!(stub.handle_packet(&mut ps2, "c100200"), Action::Resume { single_step: false }));
+        assert_eq!((0x0010_0200, 0), (ps2.r5900.pc, ps2.r5900.delay_slot_addr));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod disasm;
+pub mod expr;
+pub mod gdb;
+pub mod repl;
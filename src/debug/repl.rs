@@ -0,0 +1,376 @@
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::Ordering;
+
+use super::disasm::disassemble;
+use super::expr::evaluate;
+use crate::system::breakpoints::WatchKind;
+use crate::system::ps2::Ps2;
+use crate::system::r5900::{COP0_REGNAMES, MIPS_FPR_NAMES, MIPS_GPR_NAMES, TRACE_ENABLED};
+use crate::system::rewind::{RewindBuffer, RewindConfig};
+
+/*
+    A line based debugger that works over plain stdin/stdout so it can be scripted or used on a build server.
+    An empty line repeats the previous command.
+*/
+
+const HELP: &str = "\
+step [n]             execute n instructions (default 1)
+back [n]             step backwards n instructions (default 1), needs rewind on
+continue [max]       run until a breakpoint or watchpoint (or max instructions)
+break <expr>         set a breakpoint on a PC
+delete <expr>        remove a PC breakpoint
+watch r|w|a <expr> [len]   stop on read, write or any access to memory
+unwatch r|w|a <expr> [len] remove a watchpoint
+info                 list breakpoints and watchpoints
+regs                 show the GPRs (128 bit), HI/LO and PC
+cop0                 show the COP0 registers
+fpr                  show the FPU registers
+x <expr> [len]       hexdump memory (default 64 bytes)
+dis [expr] [count]   disassemble (default at the PC, 10 instructions)
+print <expr>         evaluate an expression, e.g. print [$sp + 8] & 0xff
+trace on|off         switch the instruction trace
+save <file>          write a save state
+load <file>          restore a save state
+rewind on [frames] [MB]   snapshot every few frames (default 10) within a budget (default 256MB)
+rewind off|info      stop taking snapshots or show what is held
+quit                 leave the debugger";
+
+/// Why execution stopped.
+enum StopReason {
+    Breakpoint,
+    Watchpoint(String),
+    Completed,
+}
+
+pub struct Repl<W: Write> {
+    out: W,
+    last_command: String,
+    rewind: Option<RewindBuffer>,
+}
+
+impl<W: Write> Repl<W> {
+    pub fn new(out: W) -> Repl<W> {
+        Repl { out, last_command: String::new(), rewind: None }
+    }
+
+    /// Reads commands until quit or end of input.
+    pub fn run<R: BufRead>(&mut self, ps2: &mut Ps2, input: R) -> io::Result<()> {
+        self.show_location(ps2)?;
+        write!(self.out, "> ")?;
+        self.out.flush()?;
+        for line in input.lines() {
+            let mut line = line?.trim().to_string();
+            if line.is_empty() {
+                line = self.last_command.clone();
+            }
+            self.last_command = line.clone();
+            if matches!(line.as_str(), "q" | "quit") {
+                break;
+            }
+            if let Err(message) = self.execute(ps2, &line) {
+                writeln!(self.out, "error: {}", message)?;
+            }
+            write!(self.out, "> ")?;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Runs a single command.
+    pub fn execute(&mut self, ps2: &mut Ps2, line: &str) -> Result<(), String> {
+        let (command, args) = match line.split_once(char::is_whitespace) {
+            Some((command, args)) => (command, args.trim()),
+            None => (line, ""),
+        };
+        let result = match command {
+            "" => Ok(()),
+            "s" | "step" => {
+                let count = if args.is_empty() { 1 } else { evaluate(ps2, args)? };
+                self.resume(ps2, Some(count as u64))
+            }
+            "back" => {
+                let count = if args.is_empty() { 1 } else { evaluate(ps2, args)? };
+                let rewind = self.rewind.as_mut().ok_or("rewind is off")?;
+                rewind.step_back(ps2, count as u64).and_then(|_| self.show_location(ps2))
+            }
+            "c" | "continue" => {
+                let max = if args.is_empty() { None } else { Some(evaluate(ps2, args)? as u64) };
+                self.resume(ps2, max)
+            }
+            "b" | "break" => {
+                let addr = evaluate(ps2, args)?;
+                ps2.breakpoints.pc.insert(addr);
+                writeln!(self.out, "breakpoint at {}", self.describe(ps2, addr))
+            }
+            "d" | "delete" => {
+                let addr = evaluate(ps2, args)?;
+                if !ps2.breakpoints.pc.remove(&addr) {
+                    return Err(format!("no breakpoint at {:#010X}", addr));
+                }
+                Ok(())
+            }
+            "w" | "watch" | "unwatch" => {
+                let (kind, addr, len) = self.parse_watch(ps2, args)?;
+                if command == "unwatch" {
+                    if !ps2.breakpoints.remove_watchpoint(addr, len, kind) {
+                        return Err(String::from("no such watchpoint"));
+                    }
+                } else {
+                    ps2.breakpoints.add_watchpoint(addr, len, kind);
+                }
+                Ok(())
+            }
+            "i" | "info" => self.show_breakpoints(ps2),
+            "r" | "regs" => self.show_gprs(ps2),
+            "cop0" => self.show_cop0(ps2),
+            "fpr" => self.show_fprs(ps2),
+            "x" => {
+                let mut parts = args.split_whitespace();
+                let addr = evaluate(ps2, parts.next().unwrap_or(""))?;
+                let len = match parts.next() {
+                    Some(len) => evaluate(ps2, len)?,
+                    None => 64,
+                };
+                self.hexdump(ps2, addr, len)
+            }
+            "dis" => {
+                let mut parts = args.split_whitespace();
+                let addr = match parts.next() {
+                    Some(addr) => evaluate(ps2, addr)?,
+                    None => ps2.r5900.pc,
+                };
+                let count = match parts.next() {
+                    Some(count) => evaluate(ps2, count)?,
+                    None => 10,
+                };
+                self.show_disassembly(ps2, addr & !3, count)
+            }
+            "p" | "print" => {
+                let value = evaluate(ps2, args)?;
+                writeln!(self.out, "{:#010X} ({})", value, value as i32)
+            }
+            "trace" => {
+                match args {
+                    "on" => TRACE_ENABLED.store(true, Ordering::Relaxed),
+                    "off" => TRACE_ENABLED.store(false, Ordering::Relaxed),
+                    _ => return Err(String::from("trace on|off")),
+                }
+                Ok(())
+            }
+            "save" => ps2.save_state_file(args),
+            "load" => {
+                ps2.load_state_file(args).map_err(|e| e.to_string())?;
+                // the old history doesn't lead here any more
+                if let Some(rewind) = &mut self.rewind {
+                    *rewind = RewindBuffer::new(rewind.config);
+                    rewind.capture(ps2);
+                }
+                self.show_location(ps2)
+            }
+            "rewind" => self.rewind_command(ps2, args),
+            "h" | "help" => writeln!(self.out, "{}", HELP),
+            _ => return Err(format!("unknown command {}, try help", command)),
+        };
+        result.map_err(|e| e.to_string())
+    }
+
+    fn parse_watch(&self, ps2: &Ps2, args: &str) -> Result<(WatchKind, u32, u32), String> {
+        let mut parts = args.split_whitespace();
+        let kind = match parts.next() {
+            Some("r") => WatchKind::Read,
+            Some("w") => WatchKind::Write,
+            Some("a") => WatchKind::Access,
+            _ => return Err(String::from("watch kind must be r, w or a")),
+        };
+        let addr = evaluate(ps2, parts.next().ok_or("watch needs an address")?)?;
+        let len = match parts.next() {
+            Some(len) => evaluate(ps2, len)?,
+            None => 4,
+        };
+        Ok((kind, addr, len))
+    }
+
+    fn rewind_command(&mut self, ps2: &Ps2, args: &str) -> io::Result<()> {
+        let mut parts = args.split_whitespace();
+        match parts.next() {
+            Some("on") => {
+                let mut config = RewindConfig::default();
+                if let Some(interval) = parts.next().and_then(|n| n.parse().ok()) {
+                    config.interval = interval;
+                }
+                if let Some(megabytes) = parts.next().and_then(|n| n.parse::<usize>().ok()) {
+                    config.budget = megabytes << 20;
+                }
+                let mut rewind = RewindBuffer::new(config);
+                rewind.capture(ps2);
+                self.rewind = Some(rewind);
+                Ok(())
+            }
+            Some("off") => {
+                self.rewind = None;
+                Ok(())
+            }
+            _ => match &self.rewind {
+                Some(rewind) => writeln!(self.out, "{} snapshots using {}KB, back to cycle {}", rewind.len(),
+                                         rewind.total_size() >> 10, rewind.oldest_cycles().unwrap_or(0)),
+                None => writeln!(self.out, "rewind is off"),
+            },
+        }
+    }
+
+    /// Runs until a breakpoint, a watchpoint or the instruction budget runs out.
+    fn resume(&mut self, ps2: &mut Ps2, max: Option<u64>) -> io::Result<()> {
+        let mut count = 0u64;
+        let reason = loop {
+            if max.is_some_and(|max| count >= max) {
+                break StopReason::Completed;
+            }
+            ps2.step();
+            count += 1;
+            if let Some(rewind) = &mut self.rewind {
+                rewind.maybe_capture(ps2);
+            }
+            if let Some(hit) = ps2.breakpoints.take_hit() {
+                break StopReason::Watchpoint(format!("{:?} watchpoint hit accessing {:#010X}", hit.watchpoint.kind, hit.addr));
+            }
+            if ps2.breakpoints.pc.contains(&ps2.r5900.pc) {
+                break StopReason::Breakpoint;
+            }
+        };
+        match reason {
+            StopReason::Breakpoint => writeln!(self.out, "breakpoint hit after {} instructions", count)?,
+            StopReason::Watchpoint(message) => writeln!(self.out, "{}", message)?,
+            StopReason::Completed => (),
+        }
+        self.show_location(ps2)
+    }
+
+    fn describe(&self, ps2: &Ps2, addr: u32) -> String {
+        match ps2.symbols.format_addr(addr) {
+            Some(name) => format!("{:#010X} <{}>", addr, name),
+            None => format!("{:#010X}", addr),
+        }
+    }
+
+    fn show_location(&mut self, ps2: &Ps2) -> io::Result<()> {
+        let pc = ps2.r5900.pc;
+        let instruction = ps2.peek_ee_u32(pc);
+        let location = self.describe(ps2, pc);
+        writeln!(self.out, "{}:  {}", location, disassemble(instruction, pc, &ps2.symbols))
+    }
+
+    fn show_breakpoints(&mut self, ps2: &Ps2) -> io::Result<()> {
+        for addr in &ps2.breakpoints.pc {
+            writeln!(self.out, "break {}", self.describe(ps2, *addr))?;
+        }
+        for w in &ps2.breakpoints.watchpoints {
+            writeln!(self.out, "watch {:?} {:#010X} len {}", w.kind, w.addr, w.len)?;
+        }
+        Ok(())
+    }
+
+    fn show_gprs(&mut self, ps2: &Ps2) -> io::Result<()> {
+        let cpu = &ps2.r5900;
+        for (i, name) in MIPS_GPR_NAMES.iter().enumerate() {
+            let r = cpu.gpr_regs[i];
+            writeln!(self.out, "{:<5}{:08X}_{:08X}_{:08X}_{:08X}", name, r[3], r[2], r[1], r[0])?;
+        }
+        writeln!(self.out, "HI   {:08X}  LO   {:08X}  PC   {:08X}", cpu.hi, cpu.lo, cpu.pc)
+    }
+
+    fn show_cop0(&mut self, ps2: &Ps2) -> io::Result<()> {
+        for (i, name) in COP0_REGNAMES.iter().enumerate() {
+            write!(self.out, "{:<9}{:08X}", name, ps2.r5900.cop0_regs[i])?;
+            if i % 4 == 3 { writeln!(self.out)?; } else { write!(self.out, "  ")?; }
+        }
+        Ok(())
+    }
+
+    fn show_fprs(&mut self, ps2: &Ps2) -> io::Result<()> {
+        for (i, name) in MIPS_FPR_NAMES.iter().enumerate() {
+            let value = ps2.r5900.fpr_regs[i];
+            write!(self.out, "{:<4}{:08X} {:<14e}", name, value.to_bits(), value)?;
+            if i % 4 == 3 { writeln!(self.out)?; } else { write!(self.out, "  ")?; }
+        }
+        Ok(())
+    }
+
+    fn hexdump(&mut self, ps2: &Ps2, addr: u32, len: u32) -> io::Result<()> {
+        for line_start in (0..len).step_by(16) {
+            let line_addr = addr.wrapping_add(line_start);
+            let bytes: Vec<u8> = (0..16.min(len - line_start))
+                .map(|i| {
+                    let byte_addr = line_addr.wrapping_add(i);
+                    (ps2.peek_ee_u32(byte_addr & !3) >> ((byte_addr & 3) * 8)) as u8
+                })
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let ascii: String = bytes.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+            writeln!(self.out, "{:08X}  {:<48}  {}", line_addr, hex.join(" "), ascii)?;
+        }
+        Ok(())
+    }
+
+    fn show_disassembly(&mut self, ps2: &Ps2, addr: u32, count: u32) -> io::Result<()> {
+        for i in 0..count {
+            let pc = addr.wrapping_add(i * 4);
+            let instruction = ps2.peek_ee_u32(pc);
+            if let Some((name, 0)) = ps2.symbols.lookup(pc) {
+                writeln!(self.out, "{}:", name)?;
+            }
+            let marker = if pc == ps2.r5900.pc { "=>" } else if ps2.breakpoints.pc.contains(&pc) { "* " } else { "  " };
+            writeln!(self.out, "{} {:08X}:  {:08X}  {}", marker, pc, instruction, disassemble(instruction, pc, &ps2.symbols))?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the debugger on stdin/stdout. The instruction trace is switched off to keep the output readable.
+pub fn run_stdio(ps2: &mut Ps2) -> io::Result<()> {
+    TRACE_ENABLED.store(false, Ordering::Relaxed);
+    let stdin = io::stdin();
+    let mut repl = Repl::new(io::stdout());
+    repl.run(ps2, stdin.lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_script(ps2: &mut Ps2, script: &str) -> String {
+        let mut output = Vec::new();
+        Repl::new(&mut output).run(ps2, script.as_bytes()).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        TRACE_ENABLED.store(false, Ordering::Relaxed);
+        let mut ps2 = Ps2::new(&[0; 16]);
+        let output = run_script(&mut ps2, "break 0xBFC00010\ncontinue\nprint $pc - 0xBFC00000\n");
+        assert!(output.contains("breakpoint hit after 4 instructions"));
+        assert!(output.contains("0x00000010 (16)"));
+        assert_eq!(0xBFC0_0010, ps2.r5900.pc);
+    }
+
+    #[test]
+    fn test_step_repeat_and_errors() {
+        TRACE_ENABLED.store(false, Ordering::Relaxed);
+        let mut ps2 = Ps2::new(&[0; 16]);
+        let output = run_script(&mut ps2, "step 2\n\nbogus\nwatch z 0\n");
+        assert_eq!(0xBFC0_0010, ps2.r5900.pc);
+        assert!(output.contains("error: unknown command bogus"));
+        assert!(output.contains("error: watch kind must be r, w or a"));
+    }
+
+    #[test]
+    fn test_back() {
+        TRACE_ENABLED.store(false, Ordering::Relaxed);
+        let mut ps2 = Ps2::new(&[0; 16]);
+        let output = run_script(&mut ps2, "back\nrewind on\nstep 3\nback 2\nrewind info\n");
+        assert!(output.contains("error: rewind is off"));
+        assert!(output.contains("1 snapshots"));
+        assert_eq!(0xBFC0_0004, ps2.r5900.pc);
+        assert_eq!(1, ps2.cycles);
+    }
+}
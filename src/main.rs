@@ -1,32 +1,330 @@
+use rustsx2::debug::{gdb, repl};
+use rustsx2::system::elf::Elf;
+use rustsx2::system::framedump::{FrameDump, FrameDumpConfig};
+use rustsx2::system::gs::dump::{GsDump, GsDumpPlayer};
+use rustsx2::system::movie::{Movie, Player, Recorder};
+use rustsx2::system::ps2::Ps2;
+use rustsx2::system::romdir::RomDir;
 
-use crate::system::ps2::Ps2;
+use std::env;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use std::fs::File;
-use std::io::Read;
+/// Loads a ROM image from disk as little endian words.
+fn try_read_rom_file(path: &str) -> io::Result<Vec<u32>> {
+    let bytes = std::fs::read(path)?;
+    if bytes.len() % 4 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} bytes isn't a whole number of words", bytes.len())));
+    }
+    Ok(bytes.chunks_exact(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect())
+}
+
+fn read_rom_file(path: &str) -> Vec<u32> {
+    try_read_rom_file(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    })
+}
+
+/// Finds an optional companion ROM for the BIOS. An explicit path must exist, otherwise we look next to the
+/// BIOS for either <bios name>.<ext> or <ext>.bin.
+fn load_companion_rom(explicit: &Option<String>, bios_path: &str, ext: &str) -> Option<Vec<u32>> {
+    if let Some(path) = explicit {
+        return Some(read_rom_file(path));
+    }
+    let bios = Path::new(bios_path);
+    let candidates = [bios.with_extension(ext), bios.with_file_name(format!("{}.bin", ext))];
+    candidates.iter().find_map(|path| try_read_rom_file(path.to_str()?).ok())
+}
+
+/// rustsx2 romdir <bios> [list | extract <module> [out_file] | extract-all <out_dir>]
+fn romdir_command(args: &[String]) {
+    if args.is_empty() {
+        eprintln!("usage: romdir <bios> [list | extract <module> [out_file] | extract-all <out_dir>]");
+        return;
+    }
+    let rom = read_rom_file(&args[0]);
+    let romdir = match RomDir::parse(&rom) {
+        Ok(romdir) => romdir,
+        Err(e) => {
+            eprintln!("{}: {}", args[0], e);
+            return;
+        }
+    };
+
+    match args.get(1).map(|s| s.as_str()).unwrap_or("list") {
+        "list" => {
+            println!("{:<10} {:>10} {:>10} {:>8}", "Name", "Offset", "Size", "ExtInfo");
+            for entry in &romdir.entries {
+                println!("{:<10} {:#010X} {:#010X} {:>8}", entry.name, entry.offset, entry.size, entry.ext_info_size);
+            }
+        }
+        "extract" => {
+            let name = match args.get(2) {
+                Some(name) => name,
+                None => {
+                    eprintln!("extract needs a module name");
+                    return;
+                }
+            };
+            let out = args.get(3).cloned().unwrap_or_else(|| name.clone());
+            match romdir.extract(name) {
+                Some(data) => std::fs::write(&out, data).unwrap(),
+                None => eprintln!("no module named {}", name),
+            }
+        }
+        "extract-all" => {
+            let dir = args.get(2).cloned().unwrap_or_else(|| String::from("."));
+            std::fs::create_dir_all(&dir).unwrap();
+            for entry in &romdir.entries {
+                let data = romdir.extract(&entry.name).unwrap();
+                std::fs::write(format!("{}/{}", dir, entry.name), data).unwrap();
+            }
+        }
+        other => eprintln!("unknown romdir command {}", other),
+    }
+}
+
+/// Prints how --compare-frames went, exiting with an error if any frame didn't match.
+fn report_frame_comparison(dump: &FrameDump) {
+    if dump.compared == 0 {
+        return;
+    }
+    for (frame, mismatch) in &dump.mismatches {
+        eprintln!("frame {}: {}", frame, mismatch);
+    }
+    println!("{} of {} frames matched", dump.compared - dump.mismatches.len(), dump.compared);
+    if !dump.mismatches.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Handles the frame dumping options, returning false if the argument isn't one of them.
+fn parse_frame_dump_arg<'a>(arg: &str, arg_iter: &mut impl Iterator<Item = &'a String>, config: &mut FrameDumpConfig) -> bool {
+    match arg {
+        "--dump-frames" => config.dump_dir = arg_iter.next().cloned(),
+        "--compare-frames" => config.reference_dir = arg_iter.next().cloned(),
+        "--dump-every" => config.every = arg_iter.next().and_then(|n| n.parse::<u64>().ok()).expect("--dump-every needs a number"),
+        "--dump-at" => {
+            let list = arg_iter.next().expect("--dump-at needs a list of frames");
+            config.at = list.split(',').map(|n| n.trim().parse::<u64>().expect("--dump-at needs frame numbers")).collect();
+        }
+        "--tolerance" => config.tolerance = arg_iter.next().and_then(|n| n.parse::<u8>().ok()).expect("--tolerance needs a number up to 255"),
+        _ => return false,
+    }
+    true
+}
 
-pub mod system;
+/// An explicit list of frames replaces the default interval unless one was given too.
+fn finish_frame_dump_args(args: &[String], config: &mut FrameDumpConfig) {
+    if !config.at.is_empty() && !args.iter().any(|a| a == "--dump-every") {
+        config.every = 0;
+    }
+}
+
+/// rustsx2 gsdump <dump> [--frames n] [frame dumping options]
+fn gsdump_command(args: &[String]) {
+    if args.is_empty() {
+        eprintln!("usage: gsdump <dump> [--frames n] [--dump-frames dir] [--compare-frames dir] [--dump-every n] [--dump-at a,b,...] [--tolerance n]");
+        return;
+    }
+    let dump = GsDump::load_file(&args[0]).unwrap_or_else(|e| panic!("{}: {}", args[0], e));
+    let mut frames = None;
+    // a dump is usually a handful of frames, so take all of them unless told otherwise
+    let mut config = FrameDumpConfig { every: 1, ..Default::default() };
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--frames" => frames = arg_iter.next().and_then(|n| n.parse::<u64>().ok()),
+            _ if parse_frame_dump_arg(arg, &mut arg_iter, &mut config) => {}
+            _ => eprintln!("ignoring unknown argument {}", arg),
+        }
+    }
+    finish_frame_dump_args(args, &mut config);
+
+    let mut player = GsDumpPlayer::new(&dump).unwrap_or_else(|e| panic!("{}: {}", args[0], e));
+    let mut frame_dump = FrameDump::new(config);
+    while frames.is_none_or(|n| player.frame() < n) && player.run_frame() {
+        if frame_dump.wants(player.frame()) {
+            if let Err(e) = frame_dump.take(player.frame(), &player.gs.display_image()) {
+                eprintln!("frame {}: {}", player.frame(), e);
+            }
+        }
+    }
+    println!("{}: played {} of {} frames", args[0], player.frame(), dump.frame_count());
+    report_frame_comparison(&frame_dump);
+}
 
 fn main() {
-    
-    let mut bios_file = File::open("bios/bios.bin").unwrap();
-    let mut bios_data = Vec::new();
-    bios_file.read_to_end(&mut bios_data).unwrap();
-
-    // convert the bios data to words from bytes
-    let bios_u32_data = unsafe {
-        // Ensure the original vector is not dropped.
-        let mut v_clone = std::mem::ManuallyDrop::new(bios_data);
-        Vec::from_raw_parts(v_clone.as_mut_ptr() as *mut u32,
-                            v_clone.len() / 4,
-                            v_clone.capacity() / 4)
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "romdir" {
+        romdir_command(&args[2..]);
+        return;
+    }
+    if args.len() > 1 && args[1] == "gsdump" {
+        gsdump_command(&args[2..]);
+        return;
+    }
+
+    let mut bios_path = String::from("bios/bios.bin");
+    let mut rom1_path = None;
+    let mut erom_path = None;
+    let mut rom2_path = None;
+    let mut elf_path = None;
+    let mut fast_boot = false;
+    let mut map_path = None;
+    let mut gdb_port = None;
+    let mut debugger = false;
+    let mut load_state_path = None;
+    let mut save_state_path = None;
+    let mut record_path = None;
+    let mut replay_path = None;
+    let mut frames = None;
+    let mut hash_interval = 60;
+    let mut dump_config = FrameDumpConfig { every: 60, ..Default::default() };
+    let mut gs_dump_path = None;
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--bios" => bios_path = arg_iter.next().expect("--bios needs a path").clone(),
+            "--rom1" => rom1_path = arg_iter.next().cloned(),
+            "--erom" => erom_path = arg_iter.next().cloned(),
+            "--rom2" => rom2_path = arg_iter.next().cloned(),
+            "--elf" => elf_path = arg_iter.next().cloned(),
+            "--fast-boot" => fast_boot = true,
+            "--symbols" => map_path = arg_iter.next().cloned(),
+            "--gdb" => gdb_port = arg_iter.next().and_then(|p| p.parse::<u16>().ok()),
+            "--debug" => debugger = true,
+            "--load-state" => load_state_path = arg_iter.next().cloned(),
+            "--save-state" => save_state_path = arg_iter.next().cloned(),
+            "--record" => record_path = arg_iter.next().cloned(),
+            "--replay" => replay_path = arg_iter.next().cloned(),
+            "--frames" => frames = arg_iter.next().and_then(|n| n.parse::<u64>().ok()),
+            "--hash-interval" => hash_interval = arg_iter.next().and_then(|n| n.parse::<u32>().ok()).expect("--hash-interval needs a number"),
+            "--record-gs" => gs_dump_path = arg_iter.next().cloned(),
+            _ if parse_frame_dump_arg(arg, &mut arg_iter, &mut dump_config) => {}
+            _ => eprintln!("ignoring unknown argument {}", arg),
+        }
+    }
+    finish_frame_dump_args(&args, &mut dump_config);
+    // frames are only dumped when running whole frames, which the plain step loop doesn't
+    let dumping = dump_config.dump_dir.is_some() || dump_config.reference_dir.is_some();
+    if dumping && frames.is_none() && record_path.is_none() && replay_path.is_none() {
+        eprintln!("--dump-frames and --compare-frames need --frames, --record or --replay");
+        std::process::exit(1);
+    }
+
+    // a fast boot never touches the BIOS so we can do without one
+    let bios_u32_data = match try_read_rom_file(&bios_path) {
+        Ok(data) => data,
+        Err(_) if fast_boot && elf_path.is_some() => Vec::new(),
+        Err(e) => panic!("{}: {}", bios_path, e),
     };
 
     let mut ps2 = Ps2::new(&bios_u32_data);
+    if let Some(rom1) = load_companion_rom(&rom1_path, &bios_path, "rom1") {
+        ps2.set_rom1(&rom1);
+    }
+    if let Some(erom) = load_companion_rom(&erom_path, &bios_path, "erom") {
+        ps2.set_erom(&erom);
+    }
+    if let Some(rom2) = load_companion_rom(&rom2_path, &bios_path, "rom2") {
+        ps2.set_rom2(&rom2);
+    }
+    if let Some(path) = elf_path {
+        let elf = Elf::parse(std::fs::read(&path).unwrap()).unwrap_or_else(|e| panic!("{}: {}", path, e));
+        if let Err(e) = ps2.boot_elf(elf, fast_boot) {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = map_path {
+        let text = std::fs::read_to_string(&path).unwrap();
+        if let Err(e) = ps2.symbols.load_map(&text) {
+            eprintln!("{}: {}", path, e);
+        }
+    }
+
+    // a replay takes the clock from the movie instead
+    ps2.inputs.rtc_base = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
 
-    for _i in 0 .. 10000 {
-        ps2.step();
+    let started_from_state = load_state_path.is_some();
+    if let Some(path) = load_state_path {
+        if let Err(e) = ps2.load_state_file(&path) {
+            panic!("{}: {}", path, e);
+        }
     }
-    
+
+    if gs_dump_path.is_some() {
+        ps2.gs.start_dump();
+    }
+    let mut dump = FrameDump::new(dump_config);
+    let mut after_frame = |ps2: &Ps2| {
+        if let Err(e) = dump.frame_done(ps2) {
+            eprintln!("frame {}: {}", ps2.frame_number(), e);
+        }
+    };
+
+    if let Some(path) = replay_path {
+        let movie = Movie::load_file(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+        let mut player = Player::new(movie, &mut ps2).unwrap_or_else(|e| panic!("{}: {}", path, e));
+        let frame_count = frames.map_or(player.frame_count(), |n| (n as usize).min(player.frame_count()));
+        while player.frame() < frame_count {
+            if let Err(desync) = player.run_frame(&mut ps2) {
+                eprintln!("{}: {}", path, desync);
+                std::process::exit(1);
+            }
+            after_frame(&ps2);
+        }
+        println!("{}: replayed {} frames", path, frame_count);
+        report_frame_comparison(&dump);
+        return;
+    }
+
+    if let Some(port) = gdb_port {
+        let session = gdb::listen(port).and_then(|listener| {
+            println!("Waiting for GDB on port {}", port);
+            let (stream, peer) = listener.accept()?;
+            println!("GDB connected from {}", peer);
+            gdb::serve(&mut ps2, stream)
+        });
+        if let Err(e) = session {
+            eprintln!("GDB session ended: {}", e);
+        }
+        return;
+    }
+    if debugger {
+        repl::run_stdio(&mut ps2).unwrap();
+        return;
+    }
+
+    if let Some(path) = record_path {
+        let mut recorder = Recorder::new(&ps2, hash_interval, started_from_state);
+        for _ in 0..frames.unwrap_or(60) {
+            recorder.run_frame(&mut ps2);
+            after_frame(&ps2);
+        }
+        recorder.finish().save_file(&path).unwrap();
+    } else if let Some(frames) = frames {
+        for _ in 0..frames {
+            ps2.run_frame();
+            after_frame(&ps2);
+        }
+    } else {
+        for _i in 0 .. 10000 {
+            ps2.step();
+        }
+    }
+
+    if let Some(path) = save_state_path {
+        ps2.save_state_file(&path).unwrap();
+    }
+    if let (Some(path), Some(gs_dump)) = (gs_dump_path, ps2.gs.finish_dump()) {
+        gs_dump.save_file(&path).unwrap();
+    }
+    report_frame_comparison(&dump);
+
  //   let mut val = ps2.read_ee_u32(0xBFC0_0000);
  //   println!("0x{:X}", val);
 
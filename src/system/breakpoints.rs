@@ -0,0 +1,82 @@
+use std::cell::Cell;
+use std::collections::BTreeSet;
+
+/*
+    Breakpoints and watchpoints used by the debuggers. PC breakpoints are checked by whoever is driving
+    execution, while watchpoints are checked by the Ps2 bus on every data access. The bus only has shared
+    access when reading so a hit is latched in a Cell and collected once the instruction has finished.
+*/
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Watchpoint {
+    pub addr: u32,
+    pub len: u32,
+    pub kind: WatchKind
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WatchHit {
+    /* The address actually accessed */
+    pub addr: u32,
+    pub watchpoint: Watchpoint
+}
+
+pub struct Breakpoints {
+    pub pc: BTreeSet<u32>,
+    pub watchpoints: Vec<Watchpoint>,
+    hit: Cell<Option<WatchHit>>
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints { pc: BTreeSet::new(), watchpoints: Vec::new(), hit: Cell::new(None) }
+    }
+
+    pub fn add_watchpoint(&mut self, addr: u32, len: u32, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { addr, len: len.max(1), kind });
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u32, len: u32, kind: WatchKind) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| !(w.addr == addr && w.len == len.max(1) && w.kind == kind));
+        count != self.watchpoints.len()
+    }
+
+    /// Called by the bus for every data access.
+    pub fn check_access(&self, addr: u32, len: u32, is_write: bool) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+        let phys_addr = addr & 0x1FFF_FFFF;
+        for w in &self.watchpoints {
+            let kind_matches = match w.kind {
+                WatchKind::Read => !is_write,
+                WatchKind::Write => is_write,
+                WatchKind::Access => true,
+            };
+            let start = w.addr & 0x1FFF_FFFF;
+            if kind_matches && phys_addr < start.wrapping_add(w.len) && start < phys_addr.wrapping_add(len) {
+                self.hit.set(Some(WatchHit { addr, watchpoint: *w }));
+                return;
+            }
+        }
+    }
+
+    /// Returns and clears the last watchpoint hit.
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
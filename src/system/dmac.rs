@@ -0,0 +1,788 @@
+use std::io;
+
+use super::ee_hw::DMA_CHANNEL_BASES;
+use super::ps2::Ps2;
+use super::savestate::{Savestate, StateReader, StateWriter};
+use super::scheduler::Event;
+
+/*
+    The EE DMA controller. Ten channels move quadwords between memory (EE RAM, or the scratchpad when
+    bit 31 of an address is set) and a peripheral, each through its own registers:
+        CHCR  0-1 DIR, 2-3 MOD (normal, chain, interleave), 4-5 ASP, 6 TTE, 7 TIE, 8 STR, 16-31 TAG
+        MADR  memory address
+        QWC   quadwords left in the current block
+        TADR  address of the next tag in source chain mode
+        ASR0/1  return addresses pushed by call tags
+        SADR  scratchpad address, for the two SPR channels
+
+    In chain mode the transfer is driven by 128 bit tags:
+        0-15  QWC, 26-27 PCE, 28-30 ID, 31 IRQ, 32-62 ADDR, 63 SPR
+    A source chain reads its tags from memory at TADR, a destination chain (SIF0 and fromSPR) gets
+    them from the peripheral ahead of each block.
+
+    D_STAT has a status bit for each channel finishing and a mask bit alongside it. Any set pair
+    raises INT1, which is separate from the INTC and goes straight to the CPU.
+
+    MFIFO mode turns part of RAM (D_RBOR, D_RBSR) into a ring buffer filled by fromSPR and drained
+    by VIF1 or GIF. Stall control similarly lets a channel writing to memory (D_CTRL.STS) hold back a
+    channel reading the same data (D_CTRL.STD) with D_STADR.
+
+    A started channel runs from a scheduler event, in slices so that an endless chain doesn't hang
+    the emulator. A channel that can't go on, because its peripheral has nothing to give or can't
+    take more, or the ring buffer is empty, just stops where it is until something kicks it again.
+*/
+
+pub const CHANNEL_COUNT: usize = 10;
+
+pub const VIF0: usize = 0;
+pub const VIF1: usize = 1;
+pub const GIF: usize = 2;
+pub const FROM_IPU: usize = 3;
+pub const TO_IPU: usize = 4;
+pub const SIF0: usize = 5;
+pub const SIF1: usize = 6;
+pub const SIF2: usize = 7;
+pub const FROM_SPR: usize = 8;
+pub const TO_SPR: usize = 9;
+
+pub const CHANNEL_NAMES: [&str; CHANNEL_COUNT] = [
+    "VIF0", "VIF1", "GIF", "fromIPU", "toIPU", "SIF0", "SIF1", "SIF2", "fromSPR", "toSPR",
+];
+
+pub const D_CTRL: u32 = 0x1000_E000;
+pub const D_STAT: u32 = 0x1000_E010;
+pub const D_PCR: u32 = 0x1000_E020;
+pub const D_SQWC: u32 = 0x1000_E030;
+pub const D_RBSR: u32 = 0x1000_E040;
+pub const D_RBOR: u32 = 0x1000_E050;
+pub const D_STADR: u32 = 0x1000_E060;
+pub const D_ENABLER: u32 = 0x1000_F520;
+pub const D_ENABLEW: u32 = 0x1000_F590;
+
+const CHCR_DIR: u32 = 1;
+const CHCR_MOD_SHIFT: u32 = 2;
+const CHCR_ASP_SHIFT: u32 = 4;
+const CHCR_TTE: u32 = 1 << 6;
+const CHCR_TIE: u32 = 1 << 7;
+const CHCR_STR: u32 = 1 << 8;
+
+const MODE_CHAIN: u32 = 1;
+const MODE_INTERLEAVE: u32 = 2;
+
+const CTRL_DMAE: u32 = 1;
+const CTRL_MFD_SHIFT: u32 = 2;
+const CTRL_STS_SHIFT: u32 = 4;
+const CTRL_STD_SHIFT: u32 = 6;
+
+const STAT_SIS: u32 = 1 << 13;
+const STAT_MEIS: u32 = 1 << 14;
+const STAT_BEIS: u32 = 1 << 15;
+const STAT_CLEAR_BITS: u32 = 0x3FF | STAT_SIS | STAT_MEIS | STAT_BEIS;
+const STAT_TOGGLE_BITS: u32 = (0x3FF << 16) | (1 << 29) | (1 << 30);
+
+const PCR_PCE: u32 = 1 << 31;
+
+/// Set in D_ENABLEW to hold every channel.
+const ENABLE_CPND: u32 = 1 << 16;
+
+/* source chain tag IDs */
+const TAG_REFE: u32 = 0;
+const TAG_CNT: u32 = 1;
+const TAG_NEXT: u32 = 2;
+const TAG_REF: u32 = 3;
+const TAG_REFS: u32 = 4;
+const TAG_CALL: u32 = 5;
+const TAG_RET: u32 = 6;
+
+/* destination chain tag IDs, anything else is cnt */
+const DEST_TAG_END: u32 = 7;
+
+/// How many quadwords (or tags) a channel moves before letting the CPU run again.
+const SLICE_QWORDS: u32 = 256;
+
+/// Roughly one quadword per bus cycle.
+const SLICE_CYCLES: u64 = SLICE_QWORDS as u64 * 2;
+
+/// Which channels D_CTRL.STS and D_CTRL.STD pick, indexed by the field.
+const STALL_SOURCES: [Option<usize>; 4] = [None, Some(SIF0), Some(FROM_SPR), Some(FROM_IPU)];
+const STALL_DRAINS: [Option<usize>; 4] = [None, Some(VIF1), Some(GIF), Some(SIF1)];
+
+#[derive(Clone, Copy)]
+pub struct Channel {
+    pub chcr: u32,
+    pub madr: u32,
+    pub qwc: u32,
+    pub tadr: u32,
+    pub asr: [u32; 2],
+    pub sadr: u32,
+
+    /* the current block is the last of the chain */
+    tag_end: bool,
+
+    /* the current block follows its tag in the MFIFO ring, so MADR wraps with it */
+    ring_data: bool,
+
+    /* quadwords moved since the last skip in interleave mode */
+    interleave_count: u32
+}
+
+impl Channel {
+    pub fn new() -> Channel {
+        Channel { chcr: 0, madr: 0, qwc: 0, tadr: 0, asr: [0; 2], sadr: 0, tag_end: false, ring_data: false, interleave_count: 0 }
+    }
+
+    fn mode(&self) -> u32 {
+        (self.chcr >> CHCR_MOD_SHIFT) & 3
+    }
+
+    fn asp(&self) -> usize {
+        ((self.chcr >> CHCR_ASP_SHIFT) & 3) as usize
+    }
+
+    fn set_asp(&mut self, asp: usize) {
+        self.chcr = (self.chcr & !(3 << CHCR_ASP_SHIFT)) | ((asp as u32) << CHCR_ASP_SHIFT);
+    }
+
+    /// The ID field of the last tag, kept in the top half of CHCR.
+    fn tag_id(&self) -> u32 {
+        (self.chcr >> 28) & 7
+    }
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Dmac {
+    pub channels: [Channel; CHANNEL_COUNT],
+    pub ctrl: u32,
+    pub stat: u32,
+    pub pcr: u32,
+    pub sqwc: u32,
+    pub rbsr: u32,
+    pub rbor: u32,
+    pub stadr: u32,
+    pub enable: u32,
+
+    /* channels whose missing peripheral has already been reported */
+    logged: u32
+}
+
+impl Dmac {
+    pub fn new() -> Dmac {
+        Dmac {
+            channels: [Channel::new(); CHANNEL_COUNT],
+            ctrl: 0, stat: 0, pcr: 0, sqwc: 0, rbsr: 0, rbor: 0, stadr: 0,
+            enable: 0x1201,
+            logged: 0
+        }
+    }
+
+    /// Whether INT1 is asserted.
+    pub fn int1(&self) -> bool {
+        let masked = self.stat & (self.stat >> 16);
+        masked & (0x3FF | STAT_SIS | STAT_MEIS) != 0 || self.stat & STAT_BEIS != 0
+    }
+
+    /// The channel draining the MFIFO ring, if it's in use.
+    fn mfifo_drain(&self) -> Option<usize> {
+        match (self.ctrl >> CTRL_MFD_SHIFT) & 3 {
+            2 => Some(VIF1),
+            3 => Some(GIF),
+            _ => None,
+        }
+    }
+
+    fn ring_wrap(&self, addr: u32) -> u32 {
+        self.rbor + (addr & self.rbsr)
+    }
+
+    fn stall_source(&self) -> Option<usize> {
+        STALL_SOURCES[((self.ctrl >> CTRL_STS_SHIFT) & 3) as usize]
+    }
+
+    fn stall_drain(&self) -> Option<usize> {
+        STALL_DRAINS[((self.ctrl >> CTRL_STD_SHIFT) & 3) as usize]
+    }
+
+    /// The value a register reads as. Reading the DMAC has no side effects.
+    pub fn peek(&self, addr: u32) -> Option<u32> {
+        if let Some((n, reg)) = channel_register(addr) {
+            let channel = &self.channels[n];
+            return match reg {
+                0x00 => Some(channel.chcr),
+                0x10 => Some(channel.madr),
+                0x20 => Some(channel.qwc),
+                0x30 => Some(channel.tadr),
+                0x40 | 0x50 if n <= GIF => Some(channel.asr[(reg as usize - 0x40) / 0x10]),
+                0x80 if n >= FROM_SPR => Some(channel.sadr),
+                _ => None,
+            };
+        }
+        match addr {
+            D_CTRL => Some(self.ctrl),
+            D_STAT => Some(self.stat),
+            D_PCR => Some(self.pcr),
+            D_SQWC => Some(self.sqwc),
+            D_RBSR => Some(self.rbsr),
+            D_RBOR => Some(self.rbor),
+            D_STADR => Some(self.stadr),
+            D_ENABLER => Some(self.enable),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Dmac {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The channel and register offset of a channel register address.
+fn channel_register(addr: u32) -> Option<(usize, u32)> {
+    let n = DMA_CHANNEL_BASES.iter().position(|&base| base == addr & !0x3FF)?;
+    Some((n, addr & 0x3FF))
+}
+
+/// Whether a channel is moving data from its peripheral into memory.
+fn to_memory(n: usize, chcr: u32) -> bool {
+    match n {
+        VIF1 | SIF2 => chcr & CHCR_DIR == 0,
+        FROM_IPU | SIF0 | FROM_SPR => true,
+        _ => false,
+    }
+}
+
+fn source_chain(n: usize) -> bool {
+    !matches!(n, FROM_IPU | SIF0 | FROM_SPR)
+}
+
+/// Splits a tag into QWC, ID, IRQ and ADDR.
+fn parse_tag(tag: [u32; 4]) -> (u32, u32, bool, u32) {
+    // bit 31 of ADDR is the SPR flag, which is how the rest of the DMAC marks scratchpad addresses anyway
+    (tag[0] & 0xFFFF, (tag[0] >> 28) & 7, tag[0] & 0x8000_0000 != 0, tag[1] & 0xFFFF_FFF0)
+}
+
+impl Ps2 {
+    pub fn dmac_read(&mut self, addr: u32) -> u32 {
+        match self.dmac.peek(addr) {
+            Some(value) => value,
+            None => self.ee_hw.unimplemented_read(addr),
+        }
+    }
+
+    pub fn dmac_write(&mut self, addr: u32, value: u32) {
+        if let Some((n, reg)) = channel_register(addr) {
+            return self.dma_channel_write(n, reg, addr, value);
+        }
+        match addr {
+            D_CTRL => self.dmac.ctrl = value & 0xFF,
+            D_STAT => {
+                self.dmac.stat &= !(value & STAT_CLEAR_BITS);
+                self.dmac.stat ^= value & STAT_TOGGLE_BITS;
+                self.dmac_update_int1();
+                return;
+            }
+            D_PCR => self.dmac.pcr = value,
+            D_SQWC => self.dmac.sqwc = value & 0x00FF_00FF,
+            D_RBSR => self.dmac.rbsr = value & 0x7FFF_FFF0,
+            D_RBOR => self.dmac.rbor = value & 0x7FFF_FFF0,
+            D_STADR => self.dmac.stadr = value & 0x7FFF_FFF0,
+            D_ENABLEW => self.dmac.enable = value,
+            _ => return self.ee_hw.unimplemented_write(addr, value),
+        }
+        // any of these can let a held channel go
+        for n in 0..CHANNEL_COUNT {
+            self.dma_kick(n);
+        }
+    }
+
+    fn dma_channel_write(&mut self, n: usize, reg: u32, addr: u32, value: u32) {
+        let channel = &mut self.dmac.channels[n];
+        match reg {
+            0x00 => {
+                let start = value & CHCR_STR != 0 && channel.chcr & CHCR_STR == 0;
+                channel.chcr = (channel.chcr & 0xFFFF_0000) | (value & 0xFFFF);
+                if start {
+                    channel.tag_end = false;
+                    channel.ring_data = false;
+                    channel.interleave_count = 0;
+                    self.dma_kick(n);
+                } else if value & CHCR_STR == 0 {
+                    self.scheduler.cancel(Event::Dma(n));
+                }
+            }
+            0x10 => channel.madr = value & 0xFFFF_FFF0,
+            0x20 => channel.qwc = value & 0xFFFF,
+            0x30 => channel.tadr = value & 0xFFFF_FFF0,
+            0x40 | 0x50 if n <= GIF => channel.asr[(reg as usize - 0x40) / 0x10] = value & 0xFFFF_FFF0,
+            0x80 if n >= FROM_SPR => channel.sadr = value & 0x3FF0,
+            _ => self.ee_hw.unimplemented_write(addr, value),
+        }
+    }
+
+    fn dmac_update_int1(&mut self) {
+        let asserted = self.dmac.int1();
+        self.set_int1(asserted);
+    }
+
+    /// Lets a started channel carry on, for when whatever was holding it up may have changed.
+    pub fn dma_kick(&mut self, n: usize) {
+        if self.dmac.channels[n].chcr & CHCR_STR != 0 {
+            self.scheduler.schedule(self.cycles, Event::Dma(n));
+        }
+    }
+
+    fn dma_runnable(&self, n: usize) -> bool {
+        let dmac = &self.dmac;
+        dmac.channels[n].chcr & CHCR_STR != 0
+            && dmac.ctrl & CTRL_DMAE != 0
+            && dmac.enable & ENABLE_CPND == 0
+            && (dmac.pcr & PCR_PCE == 0 || dmac.pcr & (1 << (16 + n)) != 0)
+    }
+
+    /// Runs a channel for a slice, from the scheduler.
+    pub fn dma_event(&mut self, n: usize, cycle: u64) {
+        let mut budget = SLICE_QWORDS;
+        while self.dma_runnable(n) {
+            if budget == 0 {
+                self.scheduler.schedule(cycle + SLICE_CYCLES, Event::Dma(n));
+                return;
+            }
+            budget -= 1;
+            let progressed = if self.dmac.channels[n].qwc > 0 {
+                self.dma_transfer_qword(n)
+            } else {
+                self.dma_next_block(n)
+            };
+            if !progressed {
+                return;
+            }
+        }
+    }
+
+    /// Starts the next block once QWC runs out, or finishes the transfer.
+    fn dma_next_block(&mut self, n: usize) -> bool {
+        let channel = &self.dmac.channels[n];
+        if channel.mode() == MODE_CHAIN && !channel.tag_end {
+            return if source_chain(n) { self.dma_source_tag(n) } else { self.dma_dest_tag(n) };
+        }
+        self.dmac.channels[n].chcr &= !CHCR_STR;
+        self.dmac.stat |= 1 << n;
+        self.dmac_update_int1();
+        true
+    }
+
+    fn dma_transfer_qword(&mut self, n: usize) -> bool {
+        let mut channel = self.dmac.channels[n];
+        if to_memory(n, channel.chcr) {
+            let Some(data) = self.dma_from_peripheral(n) else { return false };
+            self.dma_write_qword(channel.madr, data);
+            channel.madr += 16;
+            if n == FROM_SPR {
+                if let Some(drain) = self.dmac.mfifo_drain() {
+                    channel.madr = self.dmac.ring_wrap(channel.madr);
+                    self.dmac.channels[n].madr = channel.madr;
+                    self.dma_kick(drain);
+                }
+            }
+        } else {
+            if channel.ring_data && self.dma_ring_empty(channel.madr) {
+                return false;
+            }
+            if channel.tag_id() == TAG_REFS && self.dmac.stall_drain() == Some(n) && channel.madr + 16 > self.dmac.stadr {
+                self.dmac.stat |= STAT_SIS;
+                self.dmac_update_int1();
+                return false;
+            }
+            let data = self.dma_read_qword(channel.madr);
+            if !self.dma_to_peripheral(n, data) {
+                return false;
+            }
+            channel = self.dmac.channels[n];
+            channel.madr += 16;
+            if channel.ring_data {
+                channel.madr = self.dmac.ring_wrap(channel.madr);
+            }
+        }
+        // the peripheral may have moved SADR
+        channel.sadr = self.dmac.channels[n].sadr;
+        channel.qwc -= 1;
+        if channel.mode() == MODE_INTERLEAVE && n >= FROM_SPR {
+            channel.interleave_count += 1;
+            if channel.interleave_count == (self.dmac.sqwc >> 16) & 0xFF {
+                channel.interleave_count = 0;
+                channel.madr += (self.dmac.sqwc & 0xFF) * 16;
+            }
+        }
+        self.dmac.channels[n] = channel;
+
+        if self.dmac.stall_source() == Some(n) {
+            self.dmac.stadr = channel.madr & 0x7FFF_FFF0;
+            if let Some(drain) = self.dmac.stall_drain() {
+                self.dma_kick(drain);
+            }
+        }
+        true
+    }
+
+    /// Whether the MFIFO drain has caught up with fromSPR at the given address, which raises MEIS.
+    fn dma_ring_empty(&mut self, addr: u32) -> bool {
+        if addr != self.dmac.channels[FROM_SPR].madr {
+            return false;
+        }
+        self.dmac.stat |= STAT_MEIS;
+        self.dmac_update_int1();
+        true
+    }
+
+    fn dma_source_tag(&mut self, n: usize) -> bool {
+        let drain = self.dmac.mfifo_drain() == Some(n);
+        let (rbor, rbsr) = (self.dmac.rbor, self.dmac.rbsr);
+        let wrap = |addr: u32| if drain { rbor + (addr & rbsr) } else { addr };
+
+        let mut channel = self.dmac.channels[n];
+        channel.tadr = wrap(channel.tadr);
+        if drain && self.dma_ring_empty(channel.tadr) {
+            self.dmac.channels[n].tadr = channel.tadr;
+            return false;
+        }
+        let tag = self.dma_read_qword(channel.tadr);
+        if channel.chcr & CHCR_TTE != 0 && !self.dma_to_peripheral(n, tag) {
+            return false;
+        }
+        let (qwc, id, irq, addr) = parse_tag(tag);
+        channel.chcr = (channel.chcr & 0xFFFF) | (tag[0] & 0xFFFF_0000);
+        channel.qwc = qwc;
+        channel.ring_data = drain;
+        let after = wrap(channel.tadr + 16);
+        match id {
+            TAG_REFE => {
+                channel.madr = addr;
+                channel.tadr = after;
+                channel.tag_end = true;
+                channel.ring_data = false;
+            }
+            TAG_CNT => {
+                channel.madr = after;
+                channel.tadr = wrap(after + qwc * 16);
+            }
+            TAG_NEXT => {
+                channel.madr = after;
+                channel.tadr = addr;
+            }
+            TAG_REF | TAG_REFS => {
+                channel.madr = addr;
+                channel.tadr = after;
+                channel.ring_data = false;
+            }
+            TAG_CALL => {
+                channel.madr = after;
+                let asp = channel.asp();
+                if asp < 2 {
+                    channel.asr[asp] = wrap(after + qwc * 16);
+                    channel.set_asp(asp + 1);
+                    channel.tadr = addr;
+                } else {
+                    // a third level has nowhere to go, so the chain ends
+                    channel.tag_end = true;
+                }
+            }
+            TAG_RET => {
+                channel.madr = after;
+                let asp = channel.asp();
+                if asp > 0 {
+                    channel.set_asp(asp - 1);
+                    channel.tadr = channel.asr[asp - 1];
+                } else {
+                    channel.tag_end = true;
+                }
+            }
+            _ => {
+                channel.madr = after;
+                channel.tag_end = true;
+            }
+        }
+        if irq && channel.chcr & CHCR_TIE != 0 {
+            channel.tag_end = true;
+        }
+        // the tag may have gone through TTE, which can move SADR
+        channel.sadr = self.dmac.channels[n].sadr;
+        self.dmac.channels[n] = channel;
+        true
+    }
+
+    fn dma_dest_tag(&mut self, n: usize) -> bool {
+        let Some(tag) = self.dma_from_peripheral(n) else { return false };
+        let (qwc, id, irq, addr) = parse_tag(tag);
+        let madr = if n == FROM_SPR && self.dmac.mfifo_drain().is_some() { self.dmac.ring_wrap(addr) } else { addr };
+        let channel = &mut self.dmac.channels[n];
+        channel.chcr = (channel.chcr & 0xFFFF) | (tag[0] & 0xFFFF_0000);
+        channel.qwc = qwc;
+        channel.madr = madr;
+        channel.tag_end = id == DEST_TAG_END || (irq && channel.chcr & CHCR_TIE != 0);
+        true
+    }
+
+    /// Hands a quadword to a channel's peripheral, false if it can't take it yet.
+    fn dma_to_peripheral(&mut self, n: usize, data: [u32; 4]) -> bool {
+        match n {
+            TO_SPR => {
+                let sadr = self.dmac.channels[n].sadr;
+                let index = sadr as usize / 4;
+                self.scratchpad[index..index + 4].copy_from_slice(&data);
+                self.dmac.channels[n].sadr = (sadr + 16) & 0x3FF0;
+            }
+            GIF => return self.gif_dma_write(data),
+            _ => self.dma_log_missing(n, "discarded"),
+        }
+        true
+    }
+
+    /// Takes a quadword from a channel's peripheral, None if it has nothing yet.
+    fn dma_from_peripheral(&mut self, n: usize) -> Option<[u32; 4]> {
+        match n {
+            FROM_SPR => {
+                let sadr = self.dmac.channels[n].sadr;
+                let index = sadr as usize / 4;
+                let mut data = [0; 4];
+                data.copy_from_slice(&self.scratchpad[index..index + 4]);
+                self.dmac.channels[n].sadr = (sadr + 16) & 0x3FF0;
+                Some(data)
+            }
+            _ => {
+                self.dma_log_missing(n, "waiting");
+                None
+            }
+        }
+    }
+
+    fn dma_log_missing(&mut self, n: usize, what: &str) {
+        if self.dmac.logged & (1 << n) == 0 {
+            self.dmac.logged |= 1 << n;
+            trace!("DMAC: no peripheral on channel {} ({}), {}\n", n, CHANNEL_NAMES[n], what);
+        }
+    }
+
+    fn dma_memory(&self, addr: u32) -> (&[u32], usize) {
+        if addr & 0x8000_0000 != 0 {
+            (&self.scratchpad, (addr & 0x3FF0) as usize / 4)
+        } else {
+            (&self.ee_ram, (addr as usize & (self.ee_ram.len() * 4 - 1)) / 4)
+        }
+    }
+
+    fn dma_read_qword(&self, addr: u32) -> [u32; 4] {
+        let (memory, index) = self.dma_memory(addr);
+        let mut data = [0; 4];
+        data.copy_from_slice(&memory[index..index + 4]);
+        data
+    }
+
+    fn dma_write_qword(&mut self, addr: u32, data: [u32; 4]) {
+        let (memory, index) = if addr & 0x8000_0000 != 0 {
+            (&mut self.scratchpad, (addr & 0x3FF0) as usize / 4)
+        } else {
+            let mask = self.ee_ram.len() * 4 - 1;
+            (&mut self.ee_ram, (addr as usize & mask) / 4)
+        };
+        memory[index..index + 4].copy_from_slice(&data);
+    }
+}
+
+impl Savestate for Channel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.chcr);
+        w.write_u32(self.madr);
+        w.write_u32(self.qwc);
+        w.write_u32(self.tadr);
+        w.write_u32(self.asr[0]);
+        w.write_u32(self.asr[1]);
+        w.write_u32(self.sadr);
+        w.write_bool(self.tag_end);
+        w.write_bool(self.ring_data);
+        w.write_u32(self.interleave_count);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.chcr = r.read_u32()?;
+        self.madr = r.read_u32()?;
+        self.qwc = r.read_u32()?;
+        self.tadr = r.read_u32()?;
+        self.asr[0] = r.read_u32()?;
+        self.asr[1] = r.read_u32()?;
+        self.sadr = r.read_u32()?;
+        self.tag_end = r.read_bool()?;
+        self.ring_data = r.read_bool()?;
+        self.interleave_count = r.read_u32()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Dmac {
+    fn save_state(&self, w: &mut StateWriter) {
+        for channel in &self.channels {
+            channel.save_state(w);
+        }
+        w.write_u32(self.ctrl);
+        w.write_u32(self.stat);
+        w.write_u32(self.pcr);
+        w.write_u32(self.sqwc);
+        w.write_u32(self.rbsr);
+        w.write_u32(self.rbor);
+        w.write_u32(self.stadr);
+        w.write_u32(self.enable);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        for channel in &mut self.channels {
+            channel.load_state(r)?;
+        }
+        self.ctrl = r.read_u32()?;
+        self.stat = r.read_u32()?;
+        self.pcr = r.read_u32()?;
+        self.sqwc = r.read_u32()?;
+        self.rbsr = r.read_u32()?;
+        self.rbor = r.read_u32()?;
+        self.stadr = r.read_u32()?;
+        self.enable = r.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::r5900::COP0_CAUSE;
+
+    const D9_CHCR: u32 = 0xB000_D400;
+    const D9_MADR: u32 = 0xB000_D410;
+    const D9_QWC: u32 = 0xB000_D420;
+    const D9_TADR: u32 = 0xB000_D430;
+    const D9_SADR: u32 = 0xB000_D480;
+
+    fn write_qword(ps2: &mut Ps2, addr: u32, data: [u32; 4]) {
+        for (i, word) in data.iter().enumerate() {
+            ps2.write_ee_u32(addr + i as u32 * 4, *word);
+        }
+    }
+
+    fn tag(qwc: u32, id: u32, addr: u32) -> [u32; 4] {
+        [qwc | (id << 28), addr, 0, 0]
+    }
+
+    fn start(ps2: &mut Ps2, chcr_addr: u32, chcr: u32) {
+        ps2.write_ee_u32(0xB000_E000, CTRL_DMAE);
+        ps2.write_ee_u32(chcr_addr, chcr | CHCR_STR);
+        ps2.run_events();
+    }
+
+    #[test]
+    fn test_normal_and_interrupt() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        for i in 0..8 {
+            ps2.write_ee_u32(0x1000 + i * 4, i);
+        }
+        ps2.write_ee_u32(D9_MADR, 0x1000);
+        ps2.write_ee_u32(D9_QWC, 2);
+        ps2.write_ee_u32(D9_SADR, 0x100);
+        ps2.write_ee_u32(0xB000_E010, 1 << (16 + TO_SPR));
+        start(&mut ps2, D9_CHCR, 0);
+
+        assert_eq!(7, ps2.read_ee_u32(0x7000_011C));
+        assert_eq!(0x1020, ps2.read_ee_u32(D9_MADR));
+        assert_eq!(0, ps2.read_ee_u32(D9_QWC));
+        assert_eq!(0x120, ps2.read_ee_u32(D9_SADR));
+        assert_eq!(0, ps2.read_ee_u32(D9_CHCR) & CHCR_STR);
+        assert_eq!(1 << TO_SPR, ps2.read_ee_u32(0xB000_E010) & 0x3FF);
+        assert_eq!(1 << 11, ps2.r5900.cop0_regs[COP0_CAUSE] & (1 << 11));
+
+        // clearing the status drops INT1
+        ps2.write_ee_u32(0xB000_E010, 1 << TO_SPR);
+        assert_eq!(0, ps2.r5900.cop0_regs[COP0_CAUSE] & (1 << 11));
+    }
+
+    #[test]
+    fn test_source_chain() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        // cnt with one qword, call a ref + ret subroutine, next, then end with one qword
+        write_qword(&mut ps2, 0x2000, tag(1, TAG_CNT, 0));
+        write_qword(&mut ps2, 0x2010, [1, 1, 1, 1]);
+        write_qword(&mut ps2, 0x2020, tag(0, TAG_CALL, 0x3000));
+        write_qword(&mut ps2, 0x2030, tag(0, TAG_NEXT, 0x4000));
+        write_qword(&mut ps2, 0x3000, tag(1, TAG_REF, 0x5000));
+        write_qword(&mut ps2, 0x3010, tag(0, TAG_RET, 0));
+        write_qword(&mut ps2, 0x4000, tag(1, 7, 0));
+        write_qword(&mut ps2, 0x4010, [3, 3, 3, 3]);
+        write_qword(&mut ps2, 0x5000, [2, 2, 2, 2]);
+
+        ps2.write_ee_u32(D9_TADR, 0x2000);
+        start(&mut ps2, D9_CHCR, MODE_CHAIN << CHCR_MOD_SHIFT);
+
+        assert_eq!(1, ps2.read_ee_u32(0x7000_0000));
+        assert_eq!(2, ps2.read_ee_u32(0x7000_0010));
+        assert_eq!(3, ps2.read_ee_u32(0x7000_0020));
+        assert_eq!(0x30, ps2.read_ee_u32(D9_SADR));
+        let chcr = ps2.read_ee_u32(D9_CHCR);
+        assert_eq!(0, chcr & CHCR_STR);
+        assert_eq!(0, (chcr >> CHCR_ASP_SHIFT) & 3);
+        assert_eq!(7, (chcr >> 28) & 7);
+        assert_eq!(0x4020, ps2.read_ee_u32(D9_MADR));
+    }
+
+    #[test]
+    fn test_interleave() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        for i in 0..8 {
+            write_qword(&mut ps2, 0x1000 + i * 16, [i; 4]);
+        }
+        // two qwords on, one skipped
+        ps2.write_ee_u32(0xB000_E030, 0x0002_0001);
+        ps2.write_ee_u32(D9_MADR, 0x1000);
+        ps2.write_ee_u32(D9_QWC, 4);
+        start(&mut ps2, D9_CHCR, MODE_INTERLEAVE << CHCR_MOD_SHIFT);
+
+        let moved: Vec<u32> = (0..4).map(|i| ps2.read_ee_u32(0x7000_0000 + i * 16)).collect();
+        assert_eq!(vec![0, 1, 3, 4], moved);
+        assert_eq!(0x1060, ps2.read_ee_u32(D9_MADR));
+    }
+
+    #[test]
+    fn test_mfifo() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.write_ee_u32(0xB000_E010, 1 << 30);
+        // a 64 byte ring at 0x8000 drained by GIF
+        ps2.write_ee_u32(0xB000_E050, 0x8000);
+        ps2.write_ee_u32(0xB000_E040, 0x30);
+        ps2.write_ee_u32(0xB000_D010, 0x8000);
+        ps2.write_ee_u32(0xB000_A030, 0x8000);
+        ps2.write_ee_u32(0xB000_E000, CTRL_DMAE | (3 << CTRL_MFD_SHIFT));
+
+        // the drain starts against an empty ring
+        ps2.write_ee_u32(0xB000_A000, (MODE_CHAIN << CHCR_MOD_SHIFT) | CHCR_STR);
+        ps2.run_events();
+        assert_eq!(STAT_MEIS, ps2.dmac.stat & STAT_MEIS);
+        assert_eq!(1 << 11, ps2.r5900.cop0_regs[COP0_CAUSE] & (1 << 11));
+        ps2.write_ee_u32(0xB000_E010, STAT_MEIS);
+
+        // fromSPR fills it with a cnt tag and its data, wrapping round the end of the ring
+        ps2.write_ee_u32(0xB000_A030, 0x8020);
+        ps2.write_ee_u32(0xB000_D010, 0x8020);
+        ps2.write_ee_u32(0x7000_0000, 1 | (TAG_CNT << 28));
+        ps2.write_ee_u32(0x7000_0010, 0xAA);
+        ps2.write_ee_u32(0x7000_0020, 7 << 28);
+        ps2.write_ee_u32(0xB000_D020, 3);
+        ps2.write_ee_u32(0xB000_D000, CHCR_STR);
+        ps2.run_events();
+
+        assert_eq!(0xAA, ps2.read_ee_u32(0x8030));
+        assert_eq!(7 << 28, ps2.read_ee_u32(0x8000));
+        assert_eq!(0x8010, ps2.read_ee_u32(0xB000_D010));
+        assert_eq!(0, ps2.read_ee_u32(0xB000_A000) & CHCR_STR);
+        assert_eq!(0x8010, ps2.read_ee_u32(0xB000_A010));
+        assert_eq!(0, ps2.dmac.stat & STAT_MEIS);
+    }
+}
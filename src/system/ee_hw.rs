@@ -0,0 +1,329 @@
+use std::collections::BTreeSet;
+use std::io;
+
+use super::intc::{I_MASK, I_STAT};
+use super::ps2::Ps2;
+use super::savestate::{Savestate, StateReader, StateWriter};
+
+/*
+    The EE hardware registers at 0x1000_0000 - 0x1000_FFFF.
+
+    Each access is routed by address to the block that owns it. Blocks without a model yet keep their
+    registers in a plain backing store so that values written read back, and the first access to each
+    such register is logged by name so it is obvious what the BIOS or a game is waiting on.
+*/
+
+pub const EE_HW_START: u32 = 0x1000_0000;
+pub const EE_HW_SIZE: usize = 0x1_0000;
+
+const MCH_RICM: u32 = 0x1000_F430;
+const MCH_DRD: u32 = 0x1000_F440;
+
+/// 32MB of RDRAM is two 128Mbit devices on the channel.
+const RDRAM_DEVICES: u32 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EeHwBlock {
+    Timers,
+    Ipu,
+    Gif,
+    Vif0,
+    Vif1,
+    Vif0Fifo,
+    Vif1Fifo,
+    GifFifo,
+    IpuFifo,
+    Dmac,
+    Intc,
+    Sio,
+    Sif,
+    Mch,
+    Unknown,
+}
+
+/// Which block owns a physical address in the EE hardware register space.
+pub fn ee_hw_block(addr: u32) -> EeHwBlock {
+    match addr {
+        0x1000_0000..=0x1000_1FFF => EeHwBlock::Timers,
+        0x1000_2000..=0x1000_2FFF => EeHwBlock::Ipu,
+        0x1000_3000..=0x1000_37FF => EeHwBlock::Gif,
+        0x1000_3800..=0x1000_3BFF => EeHwBlock::Vif0,
+        0x1000_3C00..=0x1000_3FFF => EeHwBlock::Vif1,
+        0x1000_4000..=0x1000_4FFF => EeHwBlock::Vif0Fifo,
+        0x1000_5000..=0x1000_5FFF => EeHwBlock::Vif1Fifo,
+        0x1000_6000..=0x1000_6FFF => EeHwBlock::GifFifo,
+        0x1000_7000..=0x1000_7FFF => EeHwBlock::IpuFifo,
+        0x1000_8000..=0x1000_EFFF => EeHwBlock::Dmac,
+        0x1000_F000..=0x1000_F0FF => EeHwBlock::Intc,
+        0x1000_F100..=0x1000_F1FF => EeHwBlock::Sio,
+        0x1000_F200..=0x1000_F2FF => EeHwBlock::Sif,
+        0x1000_F400..=0x1000_F4FF => EeHwBlock::Mch,
+        0x1000_F500..=0x1000_F5FF => EeHwBlock::Dmac,
+        _ => EeHwBlock::Unknown,
+    }
+}
+
+const REGISTER_NAMES: &[(u32, &str)] = &[
+    (0x1000_0000, "T0_COUNT"), (0x1000_0010, "T0_MODE"), (0x1000_0020, "T0_COMP"), (0x1000_0030, "T0_HOLD"),
+    (0x1000_0800, "T1_COUNT"), (0x1000_0810, "T1_MODE"), (0x1000_0820, "T1_COMP"), (0x1000_0830, "T1_HOLD"),
+    (0x1000_1000, "T2_COUNT"), (0x1000_1010, "T2_MODE"), (0x1000_1020, "T2_COMP"),
+    (0x1000_1800, "T3_COUNT"), (0x1000_1810, "T3_MODE"), (0x1000_1820, "T3_COMP"),
+
+    (0x1000_2000, "IPU_CMD"), (0x1000_2010, "IPU_CTRL"), (0x1000_2020, "IPU_BP"), (0x1000_2030, "IPU_TOP"),
+
+    (0x1000_3000, "GIF_CTRL"), (0x1000_3010, "GIF_MODE"), (0x1000_3020, "GIF_STAT"),
+    (0x1000_3040, "GIF_TAG0"), (0x1000_3050, "GIF_TAG1"), (0x1000_3060, "GIF_TAG2"), (0x1000_3070, "GIF_TAG3"),
+    (0x1000_3080, "GIF_CNT"), (0x1000_3090, "GIF_P3CNT"), (0x1000_30A0, "GIF_P3TAG"),
+
+    (0x1000_3800, "VIF0_STAT"), (0x1000_3810, "VIF0_FBRST"), (0x1000_3820, "VIF0_ERR"), (0x1000_3830, "VIF0_MARK"),
+    (0x1000_3840, "VIF0_CYCLE"), (0x1000_3850, "VIF0_MODE"), (0x1000_3860, "VIF0_NUM"), (0x1000_3870, "VIF0_MASK"),
+    (0x1000_3880, "VIF0_CODE"), (0x1000_3890, "VIF0_ITOPS"), (0x1000_38D0, "VIF0_ITOP"),
+    (0x1000_3900, "VIF0_R0"), (0x1000_3910, "VIF0_R1"), (0x1000_3920, "VIF0_R2"), (0x1000_3930, "VIF0_R3"),
+    (0x1000_3940, "VIF0_C0"), (0x1000_3950, "VIF0_C1"), (0x1000_3960, "VIF0_C2"), (0x1000_3970, "VIF0_C3"),
+
+    (0x1000_3C00, "VIF1_STAT"), (0x1000_3C10, "VIF1_FBRST"), (0x1000_3C20, "VIF1_ERR"), (0x1000_3C30, "VIF1_MARK"),
+    (0x1000_3C40, "VIF1_CYCLE"), (0x1000_3C50, "VIF1_MODE"), (0x1000_3C60, "VIF1_NUM"), (0x1000_3C70, "VIF1_MASK"),
+    (0x1000_3C80, "VIF1_CODE"), (0x1000_3C90, "VIF1_ITOPS"), (0x1000_3CA0, "VIF1_BASE"), (0x1000_3CB0, "VIF1_OFST"),
+    (0x1000_3CC0, "VIF1_TOPS"), (0x1000_3CD0, "VIF1_ITOP"), (0x1000_3CE0, "VIF1_TOP"),
+    (0x1000_3D00, "VIF1_R0"), (0x1000_3D10, "VIF1_R1"), (0x1000_3D20, "VIF1_R2"), (0x1000_3D30, "VIF1_R3"),
+    (0x1000_3D40, "VIF1_C0"), (0x1000_3D50, "VIF1_C1"), (0x1000_3D60, "VIF1_C2"), (0x1000_3D70, "VIF1_C3"),
+
+    (0x1000_4000, "VIF0_FIFO"), (0x1000_5000, "VIF1_FIFO"), (0x1000_6000, "GIF_FIFO"),
+    (0x1000_7000, "IPU_OUT_FIFO"), (0x1000_7010, "IPU_IN_FIFO"),
+
+    (0x1000_E000, "D_CTRL"), (0x1000_E010, "D_STAT"), (0x1000_E020, "D_PCR"), (0x1000_E030, "D_SQWC"),
+    (0x1000_E040, "D_RBSR"), (0x1000_E050, "D_RBOR"), (0x1000_E060, "D_STADR"),
+
+    (0x1000_F000, "I_STAT"), (0x1000_F010, "I_MASK"),
+
+    (0x1000_F100, "SIO_LCR"), (0x1000_F110, "SIO_LSR"), (0x1000_F120, "SIO_IER"), (0x1000_F130, "SIO_ISR"),
+    (0x1000_F140, "SIO_FCR"), (0x1000_F150, "SIO_BGR"), (0x1000_F180, "SIO_TXFIFO"), (0x1000_F1C0, "SIO_RXFIFO"),
+
+    (0x1000_F200, "SB_MSCOM"), (0x1000_F210, "SB_SMCOM"), (0x1000_F220, "SB_MSFLG"), (0x1000_F230, "SB_SMFLG"),
+    (0x1000_F240, "SB_CTRL"), (0x1000_F260, "SB_BD6"),
+
+    (MCH_RICM, "MCH_RICM"), (MCH_DRD, "MCH_DRD"),
+
+    (0x1000_F520, "D_ENABLER"), (0x1000_F590, "D_ENABLEW"),
+];
+
+/// DMA channel register blocks, in channel order.
+pub const DMA_CHANNEL_BASES: [u32; 10] = [
+    0x1000_8000, 0x1000_9000, 0x1000_A000, 0x1000_B000, 0x1000_B400,
+    0x1000_C000, 0x1000_C400, 0x1000_C800, 0x1000_D000, 0x1000_D400,
+];
+
+const DMA_CHANNEL_REGISTERS: [(u32, &str); 7] = [
+    (0x00, "CHCR"), (0x10, "MADR"), (0x20, "QWC"), (0x30, "TADR"), (0x40, "ASR0"), (0x50, "ASR1"), (0x80, "SADR"),
+];
+
+/// The name of an EE hardware register, if it's one we know about.
+pub fn ee_hw_register_name(addr: u32) -> Option<String> {
+    if let Some((_, name)) = REGISTER_NAMES.iter().find(|(reg, _)| *reg == addr) {
+        return Some(name.to_string());
+    }
+    for (channel, base) in DMA_CHANNEL_BASES.iter().enumerate() {
+        if let Some((_, name)) = DMA_CHANNEL_REGISTERS.iter().find(|(offset, _)| base + offset == addr) {
+            return Some(format!("D{}_{}", channel, name));
+        }
+    }
+    None
+}
+
+pub struct EeHw {
+    /* backing store for the registers of blocks that aren't modelled */
+    regs: Vec<u32>,
+
+    /* RDRAM controller, just enough for the BIOS to find its memory */
+    mch_ricm: u32,
+    mch_drd: u32,
+    rdram_sdevid: u32,
+
+    /* registers we've already complained about */
+    logged: BTreeSet<u32>
+}
+
+impl EeHw {
+    pub fn new() -> EeHw {
+        EeHw { regs: vec![0; EE_HW_SIZE / 4], mch_ricm: 0, mch_drd: 0, rdram_sdevid: 0, logged: BTreeSet::new() }
+    }
+
+    fn log_unimplemented(&mut self, addr: u32, access: &str) {
+        if self.logged.insert(addr) {
+            let name = ee_hw_register_name(addr).unwrap_or_else(|| String::from("unknown register"));
+            trace!("EE HW: unimplemented {:?} {} {} at {:#010X}\n", ee_hw_block(addr), name, access, addr);
+        }
+    }
+
+    fn backing(&self, addr: u32) -> u32 {
+        self.regs[((addr - EE_HW_START) / 4) as usize]
+    }
+
+    fn set_backing(&mut self, addr: u32, value: u32) {
+        self.regs[((addr - EE_HW_START) / 4) as usize] = value;
+    }
+
+    pub fn unimplemented_read(&mut self, addr: u32) -> u32 {
+        self.log_unimplemented(addr, "read");
+        self.backing(addr)
+    }
+
+    pub fn unimplemented_write(&mut self, addr: u32, value: u32) {
+        self.log_unimplemented(addr, "write");
+        self.set_backing(addr, value);
+    }
+
+    /*
+        The BIOS sizes RDRAM by sending commands through MCH_RICM and reading the replies from MCH_DRD.
+        Bits 16-27 of RICM are the command, 6-9 the sub command and 0-4 the device. Bit 31 is busy,
+        which we never are.
+    */
+    fn mch_read(&mut self, addr: u32) -> u32 {
+        match addr {
+            MCH_RICM => self.mch_ricm,
+            MCH_DRD => {
+                if (self.mch_ricm >> 6) & 0xF != 0 {
+                    return 0;
+                }
+                match (self.mch_ricm >> 16) & 0xFFF {
+                    // INIT, each read finds the next device until they run out
+                    0x21 if self.rdram_sdevid < RDRAM_DEVICES => {
+                        self.rdram_sdevid += 1;
+                        0x1F
+                    }
+                    0x23 => 0x0D0D,  // CNFGA
+                    0x24 => 0x0090,  // CNFGB, 128Mbit devices
+                    0x40 => self.mch_ricm & 0x1F,  // DEVID
+                    _ => 0,
+                }
+            }
+            _ => self.unimplemented_read(addr),
+        }
+    }
+
+    fn mch_write(&mut self, addr: u32, value: u32) {
+        match addr {
+            MCH_RICM => {
+                let command = (value >> 16) & 0xFFF;
+                let sub_command = (value >> 6) & 0xF;
+                // an INIT broadcast restarts the device enumeration
+                if command == 0x21 && sub_command == 1 && (self.mch_drd >> 7) & 1 == 0 {
+                    self.rdram_sdevid = 0;
+                }
+                self.mch_ricm = value & !0x8000_0000;
+            }
+            MCH_DRD => self.mch_drd = value,
+            _ => self.unimplemented_write(addr, value),
+        }
+    }
+
+    /// The value a register would read as, without any side effects. Used by the debuggers.
+    pub fn peek(&self, addr: u32) -> u32 {
+        match addr {
+            MCH_RICM => self.mch_ricm,
+            MCH_DRD => self.mch_drd,
+            _ => self.backing(addr),
+        }
+    }
+}
+
+impl Default for EeHw {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ps2 {
+    /// Reads a word from the EE hardware registers, which must be a physical address in their range.
+    pub fn read_ee_hw_u32(&mut self, addr: u32) -> u32 {
+        match ee_hw_block(addr) {
+            EeHwBlock::Timers => self.timer_read(addr),
+            EeHwBlock::Dmac => self.dmac_read(addr),
+            EeHwBlock::Gif => self.gif_read(addr),
+            EeHwBlock::GifFifo => self.gif_fifo_read(addr),
+            EeHwBlock::Intc => self.intc_read(addr),
+            EeHwBlock::Mch => self.ee_hw.mch_read(addr),
+            _ => self.ee_hw.unimplemented_read(addr),
+        }
+    }
+
+    /// Writes a word to the EE hardware registers, which must be a physical address in their range.
+    pub fn write_ee_hw_u32(&mut self, addr: u32, value: u32) {
+        match ee_hw_block(addr) {
+            EeHwBlock::Timers => self.timer_write(addr, value),
+            EeHwBlock::Dmac => self.dmac_write(addr, value),
+            EeHwBlock::Gif => self.gif_write(addr, value),
+            EeHwBlock::GifFifo => self.gif_fifo_write(addr, value),
+            EeHwBlock::Intc => self.intc_write(addr, value),
+            EeHwBlock::Mch => self.ee_hw.mch_write(addr, value),
+            _ => self.ee_hw.unimplemented_write(addr, value),
+        }
+    }
+
+    /// Reads a hardware register without side effects, for the debuggers.
+    pub fn peek_ee_hw_u32(&self, addr: u32) -> u32 {
+        match (ee_hw_block(addr), addr) {
+            (EeHwBlock::Timers, _) => self.timers.peek(addr),
+            (EeHwBlock::Dmac, _) => self.dmac.peek(addr).unwrap_or_else(|| self.ee_hw.peek(addr)),
+            (EeHwBlock::Gif, _) => self.gif.peek(addr, self.gs.busdir).unwrap_or_else(|| self.ee_hw.peek(addr)),
+            (EeHwBlock::GifFifo, _) => self.gif_fifo_peek(addr),
+            (EeHwBlock::Intc, I_STAT) => self.intc.stat,
+            (EeHwBlock::Intc, I_MASK) => self.intc.mask,
+            _ => self.ee_hw.peek(addr),
+        }
+    }
+}
+
+impl Savestate for EeHw {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32_slice(&self.regs);
+        w.write_u32(self.mch_ricm);
+        w.write_u32(self.mch_drd);
+        w.write_u32(self.rdram_sdevid);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_u32_slice_into(&mut self.regs)?;
+        self.mch_ricm = r.read_u32()?;
+        self.mch_drd = r.read_u32()?;
+        self.rdram_sdevid = r.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_names() {
+        assert_eq!(Some(String::from("GIF_STAT")), ee_hw_register_name(0x1000_3020));
+        assert_eq!(Some(String::from("D2_CHCR")), ee_hw_register_name(0x1000_A000));
+        assert_eq!(Some(String::from("D9_SADR")), ee_hw_register_name(0x1000_D480));
+        assert_eq!(None, ee_hw_register_name(0x1000_F800));
+        assert_eq!(EeHwBlock::Dmac, ee_hw_block(0x1000_F520));
+    }
+
+    #[test]
+    fn test_rdram_init() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+
+        // INIT broadcast, then count the devices as the BIOS does
+        ps2.write_ee_u32(0xB000_F440, 0);
+        ps2.write_ee_u32(0xB000_F430, 0x0021_0040);
+        ps2.write_ee_u32(0xB000_F430, 0x0021_0000);
+        assert_eq!(0, ps2.read_ee_u32(0xB000_F430) & 0x8000_0000);
+        let mut devices = 0;
+        while ps2.read_ee_u32(0xB000_F440) == 0x1F {
+            devices += 1;
+        }
+        assert_eq!(RDRAM_DEVICES, devices);
+
+        ps2.write_ee_u32(0xB000_F430, 0x0040_0001);
+        assert_eq!(1, ps2.read_ee_u32(0xB000_F440));
+
+        // unmodelled registers keep what was written
+        ps2.write_ee_u32(0xB000_F100, 0x1234);
+        assert_eq!(0x1234, ps2.read_ee_u32(0xB000_F100));
+    }
+}
@@ -0,0 +1,292 @@
+use std::io;
+
+use super::ps2::Ps2;
+
+/*
+    A minimal ELF32 little endian MIPS loader. We only care about the loadable segments, the entry point
+    and the MIPS register info (which gives us the value the linker chose for GP).
+*/
+
+const PT_LOAD: u32 = 1;
+const PT_MIPS_REGINFO: u32 = 0x7000_0000;
+
+const EM_MIPS: u16 = 8;
+
+/// Stack pointer handed to an ELF when we skip the BIOS, just below the top of EE RAM.
+const FAST_BOOT_STACK: u32 = 0x01FF_FF00;
+
+/// Entry point of EELOAD. The BIOS jumps here to start the shell, so a BIOS boot swaps in the ELF at this point.
+pub const EELOAD_ENTRY: u32 = 0x0008_2000;
+
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub offset: u32,
+    pub vaddr: u32,
+    pub filesz: u32,
+    pub memsz: u32,
+}
+
+pub struct SectionHeader {
+    pub name: String,
+    pub sh_type: u32,
+    pub addr: u32,
+    pub offset: u32,
+    pub size: u32,
+    pub link: u32,
+}
+
+pub struct Elf {
+    pub data: Vec<u8>,
+    pub entry: u32,
+    pub program_headers: Vec<ProgramHeader>,
+    pub section_headers: Vec<SectionHeader>,
+
+    /* GP value from the MIPS register info, if there is any */
+    pub gp: Option<u32>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> io::Result<u16> {
+    let bytes = data.get(offset..offset + 2).ok_or_else(|| invalid("ELF truncated"))?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = data.get(offset..offset + 4).ok_or_else(|| invalid("ELF truncated"))?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads a nul terminated string. Out of range offsets give an empty string.
+pub(crate) fn read_string(data: &[u8], offset: usize) -> String {
+    let bytes = data.get(offset..).unwrap_or(&[]);
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// The bytes of a segment that come from the file.
+fn segment_data<'a>(data: &'a [u8], header: &ProgramHeader) -> io::Result<&'a [u8]> {
+    let start = header.offset as usize;
+    start.checked_add(header.filesz as usize)
+        .and_then(|end| data.get(start..end))
+        .ok_or_else(|| invalid("ELF segment extends past the end of the file"))
+}
+
+/// The address just past a segment in memory.
+fn segment_end(header: &ProgramHeader) -> io::Result<u32> {
+    if header.filesz > header.memsz {
+        return Err(invalid("ELF segment is bigger in the file than in memory"));
+    }
+    header.vaddr.checked_add(header.memsz).ok_or_else(|| invalid("ELF segment extends past the end of the address space"))
+}
+
+impl Elf {
+    pub fn parse(data: Vec<u8>) -> io::Result<Elf> {
+        if data.len() < 0x34 || &data[0..4] != b"\x7FELF" {
+            return Err(invalid("not an ELF file"));
+        }
+        if data[4] != 1 || data[5] != 1 {
+            return Err(invalid("not a 32 bit little endian ELF"));
+        }
+        if read_u16(&data, 0x12)? != EM_MIPS {
+            return Err(invalid("not a MIPS ELF"));
+        }
+
+        let entry = read_u32(&data, 0x18)?;
+        let phoff = read_u32(&data, 0x1C)? as usize;
+        let phentsize = read_u16(&data, 0x2A)? as usize;
+        let phnum = read_u16(&data, 0x2C)? as usize;
+
+        let mut program_headers = Vec::new();
+        for i in 0..phnum {
+            let base = phoff + i * phentsize;
+            let header = ProgramHeader {
+                p_type: read_u32(&data, base)?,
+                offset: read_u32(&data, base + 4)?,
+                vaddr: read_u32(&data, base + 8)?,
+                filesz: read_u32(&data, base + 16)?,
+                memsz: read_u32(&data, base + 20)?,
+            };
+            if header.p_type == PT_LOAD {
+                segment_data(&data, &header)?;
+                segment_end(&header)?;
+            }
+            program_headers.push(header);
+        }
+
+        let section_headers = Self::parse_section_headers(&data)?;
+
+        // ri_gp_value follows the general and coprocessor register masks
+        let gp = match program_headers.iter().find(|h| h.p_type == PT_MIPS_REGINFO) {
+            Some(reginfo) => Some(read_u32(&data, reginfo.offset as usize + 20)?),
+            None => None,
+        };
+
+        Ok(Elf { data, entry, program_headers, section_headers, gp })
+    }
+
+    fn parse_section_headers(data: &[u8]) -> io::Result<Vec<SectionHeader>> {
+        let shoff = read_u32(data, 0x20)? as usize;
+        let shentsize = read_u16(data, 0x2E)? as usize;
+        let shnum = read_u16(data, 0x30)? as usize;
+        let shstrndx = read_u16(data, 0x32)? as usize;
+
+        let mut headers = Vec::new();
+        let mut name_offsets = Vec::new();
+        for i in 0..shnum {
+            let base = shoff + i * shentsize;
+            name_offsets.push(read_u32(data, base)? as usize);
+            headers.push(SectionHeader {
+                name: String::new(),
+                sh_type: read_u32(data, base + 4)?,
+                addr: read_u32(data, base + 12)?,
+                offset: read_u32(data, base + 16)?,
+                size: read_u32(data, base + 20)?,
+                link: read_u32(data, base + 24)?,
+            });
+        }
+
+        // names live in the section header string table
+        if let Some(strtab_offset) = headers.get(shstrndx).map(|h| h.offset as usize) {
+            for (header, name_offset) in headers.iter_mut().zip(name_offsets) {
+                header.name = read_string(data, strtab_offset + name_offset);
+            }
+        }
+        Ok(headers)
+    }
+
+    pub fn section(&self, name: &str) -> Option<&SectionHeader> {
+        self.section_headers.iter().find(|h| h.name == name)
+    }
+}
+
+impl Ps2 {
+    /// Copies the loadable segments of the ELF into EE RAM, zeroing any BSS, and points the EE at the entry point.
+    /// Nothing is written if any segment is out of range.
+    pub fn load_elf(&mut self, elf: &Elf) -> io::Result<()> {
+        let segments = elf.program_headers.iter()
+            .filter(|h| h.p_type == PT_LOAD)
+            .map(|h| Ok((h.vaddr, segment_data(&elf.data, h)?, segment_end(h)?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        for (vaddr, file_data, end) in segments {
+            for (addr, byte) in (vaddr..end).zip(file_data) {
+                self.poke_ee_u8(addr, *byte);
+            }
+            for addr in vaddr + file_data.len() as u32..end {
+                self.poke_ee_u8(addr, 0);
+            }
+        }
+
+        self.r5900.pc = elf.entry;
+        self.r5900.delay_slot_addr = 0;
+        if let Some(gp) = elf.gp.or_else(|| self.symbols.find_by_name("_gp")) {
+            self.r5900.gpr_regs[28] = [gp, 0, 0, 0];
+        }
+        Ok(())
+    }
+
+    /// Boots an ELF. A fast boot skips the BIOS entirely, otherwise the BIOS runs as normal and the ELF is loaded
+    /// in place of EELOAD.
+    pub fn boot_elf(&mut self, elf: Elf, fast_boot: bool) -> io::Result<()> {
+        self.symbols.load_elf(&elf)?;
+        if fast_boot {
+            self.load_elf(&elf)?;
+            self.r5900.gpr_regs[29] = [FAST_BOOT_STACK, 0, 0, 0];
+        } else {
+            self.pending_elf = Some(elf);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds an ELF with one segment of 8 bytes of code followed by 8 bytes of BSS, plus register info.
+    fn make_elf() -> Vec<u8> {
+        let mut data = vec![0u8; 0x34];
+        data[0..4].copy_from_slice(b"\x7FELF");
+        data[4] = 1;
+        data[5] = 1;
+        data[0x12..0x14].copy_from_slice(&EM_MIPS.to_le_bytes());
+        data[0x18..0x1C].copy_from_slice(&0x0010_0008u32.to_le_bytes());
+        data[0x1C..0x20].copy_from_slice(&0x34u32.to_le_bytes());
+        data[0x2A..0x2C].copy_from_slice(&32u16.to_le_bytes());
+        data[0x2C..0x2E].copy_from_slice(&2u16.to_le_bytes());
+
+        let segment_offset = 0x34 + 2 * 32;
+        for (p_type, offset, filesz, memsz) in [(PT_LOAD, segment_offset, 8, 16), (PT_MIPS_REGINFO, segment_offset + 8, 24, 24)] {
+            let mut header = vec![0u8; 32];
+            header[0..4].copy_from_slice(&p_type.to_le_bytes());
+            header[4..8].copy_from_slice(&(offset as u32).to_le_bytes());
+            header[8..12].copy_from_slice(&0x0010_0000u32.to_le_bytes());
+            header[16..20].copy_from_slice(&(filesz as u32).to_le_bytes());
+            header[20..24].copy_from_slice(&(memsz as u32).to_le_bytes());
+            data.extend(header);
+        }
+        data.extend([0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]);
+        let mut reginfo = vec![0u8; 24];
+        reginfo[20..24].copy_from_slice(&0x0010_8000u32.to_le_bytes());
+        data.extend(reginfo);
+        data
+    }
+
+    #[test]
+    fn test_load_elf() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.write_ee_u32(0x0010_000C, 0xFFFF_FFFF);
+
+        let elf = Elf::parse(make_elf()).unwrap();
+        assert_eq!(Some(0x0010_8000), elf.gp);
+        ps2.boot_elf(elf, true).unwrap();
+
+        assert_eq!(0x4433_2211, ps2.read_ee_u32(0x0010_0000));
+        assert_eq!(0x8877_6655, ps2.read_ee_u32(0x0010_0004));
+        assert_eq!(0, ps2.read_ee_u32(0x0010_000C));
+        assert_eq!(0x0010_0008, ps2.r5900.pc);
+        assert_eq!(0x0010_8000, ps2.r5900.gpr_regs[28][0]);
+        assert_eq!(FAST_BOOT_STACK, ps2.r5900.gpr_regs[29][0]);
+    }
+
+    #[test]
+    fn test_bios_boot_waits_for_eeload() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.boot_elf(Elf::parse(make_elf()).unwrap(), false).unwrap();
+        assert_eq!(0, ps2.read_ee_u32(0x0010_0000));
+        assert!(ps2.pending_elf.is_some());
+
+        ps2.r5900.pc = EELOAD_ENTRY;
+        ps2.step();
+        assert!(ps2.pending_elf.is_none());
+        assert_eq!(0x4433_2211, ps2.read_ee_u32(0x0010_0000));
+    }
+
+    #[test]
+    fn test_reject_non_elf() {
+        assert!(Elf::parse(vec![0; 64]).is_err());
+    }
+
+    #[test]
+    fn test_reject_bad_segments() {
+        // BSS running off the top of the address space
+        let mut data = make_elf();
+        data[0x3C..0x40].copy_from_slice(&0xFFFF_FFF8u32.to_le_bytes());
+        assert!(Elf::parse(data).is_err());
+
+        // file data past the end of the file
+        let mut data = make_elf();
+        data[0x44..0x48].copy_from_slice(&0x1000u32.to_le_bytes());
+        data[0x48..0x4C].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert!(Elf::parse(data).is_err());
+
+        // headers changed after parsing are still caught, and nothing is written
+        let mut ps2 = Ps2::new(&[0; 4]);
+        let mut elf = Elf::parse(make_elf()).unwrap();
+        elf.program_headers[0].offset = u32::MAX;
+        assert!(ps2.load_elf(&elf).is_err());
+        assert_eq!(0, ps2.read_ee_u32(0x0010_0000));
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use super::gs::crtc::Image;
+use super::png;
+use super::ps2::Ps2;
+
+/*
+    Frame dumping for headless runs, so rendering regressions can be caught without a display.
+
+    After each frame the CRTC output is taken if the frame is one we want: every `every` frames, or
+    one of the frame numbers listed in `at`. Frame numbers are Ps2::frame_number, so they line up
+    between a plain run, a replay and a run started from a save state.
+
+    A taken frame is written to `dump_dir` as frame_NNNNNN.png, compared against the file of the
+    same name in `reference_dir`, or both. Comparison allows each channel to be off by up to
+    `tolerance`, so small changes to blending maths don't fail every test. A missing reference counts
+    as a mismatch, so adding a test means running it once with --dump-frames to make the references.
+
+    Only the colour counts. The alpha the CRTC puts out never reaches the screen, so frames are written
+    opaque and compared on RGB alone, whatever alpha the reference was saved with.
+*/
+
+#[derive(Clone, Debug, Default)]
+pub struct FrameDumpConfig {
+    pub dump_dir: Option<String>,
+    pub reference_dir: Option<String>,
+
+    /* take every Nth frame, 0 for none */
+    pub every: u64,
+    pub at: Vec<u64>,
+
+    pub tolerance: u8
+}
+
+/// How far a frame is from its reference.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum FrameMismatch {
+    NoReference(String),
+    Size { frame: (u32, u32), reference: (u32, u32) },
+    Pixels { count: usize, first: (u32, u32), max_difference: u8 }
+}
+
+impl fmt::Display for FrameMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameMismatch::NoReference(e) => write!(f, "no reference image ({})", e),
+            FrameMismatch::Size { frame, reference } => {
+                write!(f, "frame is {}x{} but the reference is {}x{}", frame.0, frame.1, reference.0, reference.1)
+            }
+            FrameMismatch::Pixels { count, first, max_difference } => write!(
+                f,
+                "{} pixels differ, the first at ({}, {}), by up to {}",
+                count, first.0, first.1, max_difference
+            ),
+        }
+    }
+}
+
+/// Compares the colour of two images, ignoring channel differences up to the tolerance.
+pub fn compare_images(image: &Image, reference: &Image, tolerance: u8) -> Result<(), FrameMismatch> {
+    if (image.width, image.height) != (reference.width, reference.height) {
+        return Err(FrameMismatch::Size {
+            frame: (image.width, image.height),
+            reference: (reference.width, reference.height),
+        });
+    }
+    let (mut count, mut first, mut max_difference) = (0, (0, 0), 0);
+    for (i, (a, b)) in image.pixels.chunks(4).zip(reference.pixels.chunks(4)).enumerate() {
+        let difference = a.iter().zip(b).take(3).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0);
+        if difference > tolerance {
+            if count == 0 {
+                first = (i as u32 % image.width, i as u32 / image.width);
+            }
+            count += 1;
+            max_difference = max_difference.max(difference);
+        }
+    }
+    if count == 0 {
+        Ok(())
+    } else {
+        Err(FrameMismatch::Pixels { count, first, max_difference })
+    }
+}
+
+pub struct FrameDump {
+    config: FrameDumpConfig,
+
+    /* frames compared so far, and the ones that didn't match */
+    pub compared: usize,
+    pub mismatches: Vec<(u64, FrameMismatch)>
+}
+
+impl FrameDump {
+    pub fn new(config: FrameDumpConfig) -> FrameDump {
+        FrameDump { config, compared: 0, mismatches: Vec::new() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.dump_dir.is_some() || self.config.reference_dir.is_some()
+    }
+
+    pub fn wants(&self, frame: u64) -> bool {
+        (self.config.every != 0 && frame.is_multiple_of(self.config.every)) || self.config.at.contains(&frame)
+    }
+
+    pub fn file_name(frame: u64) -> String {
+        format!("frame_{:06}.png", frame)
+    }
+
+    /// Called after each frame has run, dumping or checking it if it's one we want.
+    pub fn frame_done(&mut self, ps2: &Ps2) -> io::Result<()> {
+        let frame = ps2.frame_number();
+        if !self.enabled() || !self.wants(frame) {
+            return Ok(());
+        }
+        self.take(frame, &ps2.gs.display_image())
+    }
+
+    /// Dumps and compares an image as the given frame.
+    pub fn take(&mut self, frame: u64, image: &Image) -> io::Result<()> {
+        let name = Self::file_name(frame);
+        let mut image = image.clone();
+        for pixel in image.pixels.chunks_mut(4) {
+            pixel[3] = 0xFF;
+        }
+        if let Some(dir) = &self.config.dump_dir {
+            std::fs::create_dir_all(dir)?;
+            png::save_file(&image, &Path::new(dir).join(&name).to_string_lossy())?;
+        }
+        if let Some(dir) = &self.config.reference_dir {
+            let path = Path::new(dir).join(&name);
+            let result = match png::load_file(&path.to_string_lossy()) {
+                Ok(reference) => compare_images(&image, &reference, self.config.tolerance),
+                Err(e) => Err(FrameMismatch::NoReference(format!("{}: {}", path.display(), e))),
+            };
+            self.compared += 1;
+            if let Err(mismatch) = result {
+                self.mismatches.push((frame, mismatch));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::gs::swizzle::PSMCT32;
+    use super::super::gs::Gs;
+
+    fn solid(width: u32, height: u32, rgba: [u8; 4]) -> Image {
+        Image { width, height, pixels: rgba.repeat((width * height) as usize) }
+    }
+
+    #[test]
+    fn test_compare_images() {
+        let reference = solid(4, 2, [10, 20, 30, 255]);
+        let mut image = reference.clone();
+        image.pixels[5 * 4 + 1] = 23;
+        assert_eq!(Ok(()), compare_images(&image, &reference, 3));
+        assert_eq!(
+            Err(FrameMismatch::Pixels { count: 1, first: (1, 1), max_difference: 3 }),
+            compare_images(&image, &reference, 2)
+        );
+        assert!(matches!(compare_images(&solid(2, 4, [0; 4]), &reference, 0), Err(FrameMismatch::Size { .. })));
+
+        // alpha isn't looked at
+        assert_eq!(Ok(()), compare_images(&solid(4, 2, [10, 20, 30, 0]), &reference, 0));
+    }
+
+    #[test]
+    fn test_dump_and_compare() {
+        let dir = std::env::temp_dir().join(format!("rustsx2_framedump_{}", std::process::id()));
+        let dir_name = dir.to_string_lossy().to_string();
+        let config = FrameDumpConfig { dump_dir: Some(dir_name.clone()), every: 10, at: vec![15], ..Default::default() };
+        let mut dump = FrameDump::new(config);
+        assert_eq!((true, true, false), (dump.wants(20), dump.wants(15), dump.wants(16)));
+        dump.take(20, &solid(3, 3, [1, 2, 3, 255])).unwrap();
+
+        let config = FrameDumpConfig { reference_dir: Some(dir_name), every: 10, tolerance: 1, ..Default::default() };
+        let mut check = FrameDump::new(config);
+        check.take(20, &solid(3, 3, [2, 2, 3, 255])).unwrap();
+        check.take(30, &solid(3, 3, [2, 2, 3, 255])).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(2, check.compared);
+        assert_eq!(1, check.mismatches.len());
+        assert!(matches!(check.mismatches[0], (30, FrameMismatch::NoReference(_))));
+    }
+
+    #[test]
+    fn test_dump_display_image() {
+        // the CRTC puts out GS alpha, half at (0, 0) and none at (1, 0), which mustn't make the dump see through
+        let mut gs = Gs::new();
+        gs.write_pixel(PSMCT32, 0, 1, 0, 0, 0x4011_2233);
+        gs.pmode = 1 | 1 << 5 | 0xFF << 8;  // EN1, MMOD, ALP 0xFF
+        gs.dispfb[0] = 1 << 9;  // CT32 at 0, 64 pixels wide
+        gs.display[0] = 1 << 32;  // 2x1
+        let image = gs.display_image();
+        assert_eq!([0x40, 0], [image.pixel(0, 0)[3], image.pixel(1, 0)[3]]);
+
+        let dir = std::env::temp_dir().join(format!("rustsx2_framedump_alpha_{}", std::process::id()));
+        let dir_name = dir.to_string_lossy().to_string();
+        let mut dump = FrameDump::new(FrameDumpConfig { dump_dir: Some(dir_name.clone()), ..Default::default() });
+        dump.take(1, &image).unwrap();
+        let written = png::load_file(&dir.join(FrameDump::file_name(1)).to_string_lossy()).unwrap();
+
+        let mut check = FrameDump::new(FrameDumpConfig { reference_dir: Some(dir_name), ..Default::default() });
+        check.take(1, &image).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!([0x33, 0x22, 0x11, 0xFF], written.pixel(0, 0));
+        assert_eq!([0, 0, 0, 0xFF], written.pixel(1, 0));
+        assert_eq!((1, 0), (check.compared, check.mismatches.len()));
+    }
+}
@@ -0,0 +1,680 @@
+use std::collections::VecDeque;
+use std::io;
+
+use super::dmac;
+use super::gs::{FOG, HWREG, PRIM, RGBAQ, ST, UV, XYZ2, XYZ3, XYZF2, XYZF3};
+use super::ps2::Ps2;
+use super::savestate::{Savestate, StateReader, StateWriter};
+
+/*
+    The GIF, which turns packets from three paths into GS register writes:
+        PATH1  VU1 XGKICK, from VU1 data memory
+        PATH2  VIF1 DIRECT and DIRECTHL
+        PATH3  the GIF DMA channel, or the EE writing the GIF FIFO directly
+
+    A packet is one or more GIFtags, each followed by its data, and ends after the data of a tag with
+    EOP set. Once a path starts a packet it has the GS until the packet ends, and a path that finds
+    the GS busy is queued (P1Q, P2Q, P3Q in GIF_STAT) until it's free. Between packets the queued
+    path with the highest priority goes next, PATH1 first.
+
+    GIFtag:
+        0-14  NLOOP, 15 EOP, 46 PRE, 47-57 PRIM, 58-59 FLG, 60-63 NREG (0 means 16), 64-127 REGS
+
+    FLG picks how the data is read:
+        PACKED   a qword per register descriptor, NLOOP times round the NREG descriptors, with the
+                 common registers unpacked from 32 bit fields and A+D giving the address in the data
+        REGLIST  two 64 bit values to each qword, written straight to the descriptors
+        IMAGE    NLOOP qwords of host data for HWREG
+
+    PATH3 can be masked, by GIF_MODE.M3R or VIF1's MSKPATH3. Either only takes effect between
+    packets, so a packet already under way finishes. With GIF_MODE.IMT set, a PATH3 IMAGE packet
+    lets a queued PATH1 or PATH2 in every 8 qwords rather than holding the GS for the whole image.
+
+    PATH3 data arrives through a 16 qword FIFO, so the DMA channel can run ahead of the GS, and is
+    held there while PATH3 is masked or waiting. The other paths call gif_path_write for each qword
+    and must try again later when it says the GS is busy.
+*/
+
+pub const GIF_CTRL: u32 = 0x1000_3000;
+pub const GIF_MODE: u32 = 0x1000_3010;
+pub const GIF_STAT: u32 = 0x1000_3020;
+pub const GIF_TAG0: u32 = 0x1000_3040;
+pub const GIF_TAG1: u32 = 0x1000_3050;
+pub const GIF_TAG2: u32 = 0x1000_3060;
+pub const GIF_TAG3: u32 = 0x1000_3070;
+pub const GIF_CNT: u32 = 0x1000_3080;
+pub const GIF_P3CNT: u32 = 0x1000_3090;
+pub const GIF_P3TAG: u32 = 0x1000_30A0;
+
+pub const PATH1: usize = 0;
+pub const PATH2: usize = 1;
+pub const PATH3: usize = 2;
+
+pub const FLG_PACKED: u32 = 0;
+pub const FLG_REGLIST: u32 = 1;
+pub const FLG_IMAGE: u32 = 2;
+
+/* register descriptors with their own meaning in PACKED mode */
+const DESC_FOG: u64 = 0x0A;
+const DESC_AD: u64 = 0x0E;
+const DESC_NOP: u64 = 0x0F;
+
+const CTRL_RST: u32 = 1;
+const CTRL_PSE: u32 = 1 << 3;
+
+const MODE_M3R: u32 = 1;
+const MODE_IMT: u32 = 1 << 2;
+
+const STAT_M3R: u32 = 1;
+const STAT_M3P: u32 = 1 << 1;
+const STAT_IMT: u32 = 1 << 2;
+const STAT_PSE: u32 = 1 << 3;
+const STAT_IP3: u32 = 1 << 5;
+const STAT_P3Q: u32 = 1 << 6;
+const STAT_P2Q: u32 = 1 << 7;
+const STAT_P1Q: u32 = 1 << 8;
+const STAT_OPH: u32 = 1 << 9;
+const STAT_APATH_SHIFT: u32 = 10;
+const STAT_DIR: u32 = 1 << 12;
+const STAT_FQC_SHIFT: u32 = 24;
+
+pub const FIFO_QWORDS: usize = 16;
+
+/// How many qwords of an IMAGE packet PATH3 sends before letting another path in, with IMT set.
+const IMT_QWORDS: u32 = 8;
+
+/// A GIFtag, split into its fields.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GifTag {
+    pub nloop: u32,
+    pub eop: bool,
+    pub pre: bool,
+    pub prim: u64,
+    pub flg: u32,
+    pub nreg: u32,
+    pub regs: u64
+}
+
+impl GifTag {
+    pub fn parse(data: [u32; 4]) -> GifTag {
+        let nreg = data[1] >> 28;
+        GifTag {
+            nloop: data[0] & 0x7FFF,
+            eop: data[0] & 0x8000 != 0,
+            pre: data[1] & (1 << 14) != 0,
+            prim: ((data[1] >> 15) & 0x7FF) as u64,
+            flg: (data[1] >> 26) & 3,
+            nreg: if nreg == 0 { 16 } else { nreg },
+            regs: data[2] as u64 | (data[3] as u64) << 32,
+        }
+    }
+
+    /// The register descriptor for a position in the list.
+    fn desc(&self, index: u32) -> u64 {
+        (self.regs >> (index * 4)) & 0xF
+    }
+}
+
+/// Where a path is in its packet.
+#[derive(Clone, Copy, Debug, Default)]
+struct Path {
+    /* the last tag, as it came in */
+    tag: [u32; 4],
+
+    /* loops left in the current tag, none meaning the next qword is a tag */
+    loops: u32,
+    reg: u32,
+
+    /* a packet has started and not yet reached the end of its EOP tag */
+    in_packet: bool,
+
+    /* qwords of IMAGE data since PATH3 last let anyone in */
+    image_qwords: u32
+}
+
+impl Path {
+    fn tag(&self) -> GifTag {
+        GifTag::parse(self.tag)
+    }
+}
+
+pub struct Gif {
+    pub ctrl: u32,
+    pub mode: u32,
+
+    /* PATH3 masked by VIF1 */
+    pub mskpath3: bool,
+
+    paths: [Path; 3],
+
+    /* the path with the GS, if any */
+    active: Option<usize>,
+
+    /* paths that found the GS busy, by path bit */
+    queued: u32,
+
+    /* Q from the last PACKED ST, sent with the next PACKED RGBAQ and reset to 1.0 by every tag */
+    q: u32,
+
+    fifo: VecDeque<[u32; 4]>,
+
+    /* words the EE has written to the FIFO towards the next qword */
+    fifo_input: [u32; 4]
+}
+
+impl Gif {
+    pub fn new() -> Gif {
+        Gif {
+            ctrl: 0, mode: 0, mskpath3: false, paths: [Path::default(); 3], active: None, queued: 0,
+            q: 0x3F80_0000, fifo: VecDeque::new(), fifo_input: [0; 4]
+        }
+    }
+
+    fn path3_masked(&self) -> bool {
+        self.mode & MODE_M3R != 0 || self.mskpath3
+    }
+
+    /// Whether a path can send a qword to the GS now.
+    fn can_send(&self, path: usize) -> bool {
+        if self.ctrl & CTRL_PSE != 0 {
+            return false;
+        }
+        match self.active {
+            Some(active) => active == path,
+            None => {
+                // a higher priority path waiting goes first, and a masked PATH3 can't start a packet
+                let ahead = self.queued & ((1 << path) - 1) != 0;
+                let masked = path == PATH3 && self.path3_masked() && !self.paths[PATH3].in_packet;
+                !ahead && !masked
+            }
+        }
+    }
+
+    /// GIF_STAT, which is put together from the rest of the state when it's read.
+    pub fn stat(&self, gs_busdir: u64) -> u32 {
+        let mut stat = (self.fifo.len() as u32) << STAT_FQC_SHIFT;
+        if self.mode & MODE_M3R != 0 {
+            stat |= STAT_M3R;
+        }
+        if self.mskpath3 {
+            stat |= STAT_M3P;
+        }
+        if self.mode & MODE_IMT != 0 {
+            stat |= STAT_IMT;
+        }
+        if self.ctrl & CTRL_PSE != 0 {
+            stat |= STAT_PSE;
+        }
+        if self.paths[PATH3].in_packet && self.active != Some(PATH3) {
+            stat |= STAT_IP3;
+        }
+        if !self.fifo.is_empty() && self.active != Some(PATH3) {
+            stat |= STAT_P3Q;
+        }
+        if self.queued & (1 << PATH2) != 0 {
+            stat |= STAT_P2Q;
+        }
+        if self.queued & (1 << PATH1) != 0 {
+            stat |= STAT_P1Q;
+        }
+        if let Some(path) = self.active {
+            stat |= STAT_OPH | ((path as u32 + 1) << STAT_APATH_SHIFT);
+        }
+        if gs_busdir & 1 != 0 {
+            stat |= STAT_DIR;
+        }
+        stat
+    }
+
+    /// The path GIF_TAGn and GIF_CNT describe: the active one, or PATH3 when the GS is idle.
+    fn shown_path(&self) -> &Path {
+        &self.paths[self.active.unwrap_or(PATH3)]
+    }
+
+    /// The value a register reads as. Reading the GIF has no side effects.
+    pub fn peek(&self, addr: u32, gs_busdir: u64) -> Option<u32> {
+        let path3 = &self.paths[PATH3];
+        match addr {
+            GIF_CTRL => Some(self.ctrl),
+            GIF_MODE => Some(self.mode),
+            GIF_STAT => Some(self.stat(gs_busdir)),
+            GIF_TAG0 | GIF_TAG1 | GIF_TAG2 | GIF_TAG3 => Some(self.shown_path().tag[((addr - GIF_TAG0) / 0x10) as usize]),
+            GIF_CNT => {
+                let path = self.shown_path();
+                Some(path.loops | path.reg << 16)
+            }
+            GIF_P3CNT => Some(if path3.in_packet && self.active != Some(PATH3) { path3.loops } else { 0 }),
+            GIF_P3TAG => Some(path3.loops | (path3.tag[0] & 0x8000)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Gif {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ps2 {
+    pub fn gif_read(&mut self, addr: u32) -> u32 {
+        match self.gif.peek(addr, self.gs.busdir) {
+            Some(value) => value,
+            None => self.ee_hw.unimplemented_read(addr),
+        }
+    }
+
+    pub fn gif_write(&mut self, addr: u32, value: u32) {
+        match addr {
+            GIF_CTRL => {
+                if value & CTRL_RST != 0 {
+                    // MSKPATH3 belongs to VIF1, which the reset doesn't touch
+                    let mskpath3 = self.gif.mskpath3;
+                    self.gif = Gif::new();
+                    self.gif.mskpath3 = mskpath3;
+                }
+                self.gif.ctrl = value & CTRL_PSE;
+            }
+            GIF_MODE => self.gif.mode = value & (MODE_M3R | MODE_IMT),
+            _ => return self.ee_hw.unimplemented_write(addr, value),
+        }
+        self.gif_resume_path3();
+    }
+
+    /// VIF1's MSKPATH3.
+    pub fn gif_set_mskpath3(&mut self, masked: bool) {
+        self.gif.mskpath3 = masked;
+        self.gif_resume_path3();
+    }
+
+    /// Sends a qword down PATH1 or PATH2, returning false if the GS is busy with another path.
+    pub fn gif_path_write(&mut self, path: usize, data: [u32; 4]) -> bool {
+        if !self.gif.can_send(path) {
+            self.gif.queued |= 1 << path;
+            return false;
+        }
+        self.gif.queued &= !(1 << path);
+        self.gif_qword(path, data);
+        if self.gif.active.is_none() {
+            self.gif_resume_path3();
+        }
+        true
+    }
+
+    /// Whether a path has started a packet and not yet finished it.
+    pub fn gif_path_in_packet(&self, path: usize) -> bool {
+        self.gif.paths[path].in_packet
+    }
+
+    /// Takes a qword from the GIF DMA channel, false if the FIFO is full.
+    pub(super) fn gif_dma_write(&mut self, data: [u32; 4]) -> bool {
+        self.gif_run_fifo();
+        if self.gif.fifo.len() >= FIFO_QWORDS {
+            return false;
+        }
+        self.gif.fifo.push_back(data);
+        self.gif_run_fifo();
+        true
+    }
+
+    /// The EE writes the GIF FIFO a word at a time, and the qword goes in with its last word.
+    pub fn gif_fifo_write(&mut self, addr: u32, value: u32) {
+        let index = (addr as usize & 0xF) / 4;
+        self.gif.fifo_input[index] = value;
+        if index == 3 {
+            // there's no way to hold the EE off, so a full FIFO just grows
+            self.gif.fifo.push_back(self.gif.fifo_input);
+            self.gif_run_fifo();
+        }
+    }
+
+    /// Sends what PATH3 has waiting, for when something that was holding it back may have changed.
+    fn gif_resume_path3(&mut self) {
+        self.gif_run_fifo();
+        self.dma_kick(dmac::GIF);
+    }
+
+    fn gif_run_fifo(&mut self) {
+        while !self.gif.fifo.is_empty() && self.gif.can_send(PATH3) {
+            let data = self.gif.fifo.pop_front().unwrap();
+            self.gif_qword(PATH3, data);
+        }
+    }
+
+    /// Runs a qword from a path that has the GS.
+    fn gif_qword(&mut self, path: usize, data: [u32; 4]) {
+        self.gif.active = Some(path);
+        let state = self.gif.paths[path];
+        if state.loops == 0 {
+            return self.gif_tag(path, data);
+        }
+        let tag = state.tag();
+        match tag.flg {
+            FLG_PACKED => {
+                self.gif_packed(tag.desc(state.reg), data);
+                self.gif_next_register(path, &tag);
+            }
+            FLG_REGLIST => {
+                let halves = [data[0] as u64 | (data[1] as u64) << 32, data[2] as u64 | (data[3] as u64) << 32];
+                for half in halves {
+                    let desc = tag.desc(self.gif.paths[path].reg);
+                    // A+D has no address to go with it here, so both it and NOP write nothing
+                    if desc != DESC_AD && desc != DESC_NOP {
+                        self.gs_write_register(desc as u8, half);
+                    }
+                    self.gif_next_register(path, &tag);
+                    if self.gif.paths[path].loops == 0 {
+                        // an odd number of registers leaves the top half of the last qword unused
+                        break;
+                    }
+                }
+            }
+            _ => {
+                self.gs_write_register(HWREG, data[0] as u64 | (data[1] as u64) << 32);
+                self.gs_write_register(HWREG, data[2] as u64 | (data[3] as u64) << 32);
+                let state = &mut self.gif.paths[path];
+                state.loops -= 1;
+                state.image_qwords += 1;
+                if path == PATH3 && self.gif.mode & MODE_IMT != 0 && state.image_qwords >= IMT_QWORDS && state.loops > 0 {
+                    state.image_qwords = 0;
+                    if self.gif.queued & ((1 << PATH1) | (1 << PATH2)) != 0 {
+                        self.gif.active = None;
+                        return;
+                    }
+                }
+            }
+        }
+        if self.gif.paths[path].loops == 0 {
+            self.gif_end_of_tag(path);
+        }
+    }
+
+    fn gif_tag(&mut self, path: usize, data: [u32; 4]) {
+        let tag = GifTag::parse(data);
+        self.gif.paths[path] = Path { tag: data, loops: tag.nloop, reg: 0, in_packet: true, image_qwords: 0 };
+        // reading a tag sets Q back to 1.0, so RGBAQ without an ST first isn't divided by a stale Q
+        self.gif.q = 0x3F80_0000;
+        if tag.pre && tag.flg == FLG_PACKED {
+            self.gs_write_register(PRIM, tag.prim);
+        }
+        if tag.nloop == 0 {
+            self.gif_end_of_tag(path);
+        }
+    }
+
+    fn gif_next_register(&mut self, path: usize, tag: &GifTag) {
+        let state = &mut self.gif.paths[path];
+        state.reg += 1;
+        if state.reg == tag.nreg {
+            state.reg = 0;
+            state.loops -= 1;
+        }
+    }
+
+    /// The data for a tag is done, which ends the packet if it was the last tag.
+    fn gif_end_of_tag(&mut self, path: usize) {
+        let state = &mut self.gif.paths[path];
+        if state.tag().eop {
+            state.in_packet = false;
+            self.gif.active = None;
+        }
+    }
+
+    /// Writes a PACKED mode qword to the GS.
+    fn gif_packed(&mut self, desc: u64, data: [u32; 4]) {
+        let low = data[0] as u64 | (data[1] as u64) << 32;
+        match desc as u8 {
+            PRIM => self.gs_write_register(PRIM, low & 0x7FF),
+            RGBAQ => {
+                let rgba = (data[0] & 0xFF) | (data[1] & 0xFF) << 8 | (data[2] & 0xFF) << 16 | (data[3] & 0xFF) << 24;
+                self.gs_write_register(RGBAQ, rgba as u64 | (self.gif.q as u64) << 32);
+            }
+            ST => {
+                self.gif.q = data[2];
+                self.gs_write_register(ST, low);
+            }
+            UV => self.gs_write_register(UV, (data[0] & 0x3FFF) as u64 | ((data[1] & 0x3FFF) as u64) << 16),
+            // the XYZF3 and XYZ3 descriptors never kick, the others don't with ADC set
+            reg @ (XYZF2 | XYZF3) => {
+                let reg = if data[3] & 0x8000 != 0 { XYZF3 } else { reg };
+                let xy = (data[0] & 0xFFFF) as u64 | ((data[1] & 0xFFFF) as u64) << 16;
+                let z = ((data[2] >> 4) & 0xFF_FFFF) as u64;
+                let f = ((data[3] >> 4) & 0xFF) as u64;
+                self.gs_write_register(reg, xy | z << 32 | f << 56);
+            }
+            reg @ (XYZ2 | XYZ3) => {
+                let reg = if data[3] & 0x8000 != 0 { XYZ3 } else { reg };
+                let xy = (data[0] & 0xFFFF) as u64 | ((data[1] & 0xFFFF) as u64) << 16;
+                self.gs_write_register(reg, xy | (data[2] as u64) << 32);
+            }
+            _ if desc == DESC_FOG => self.gs_write_register(FOG, (((data[3] >> 4) & 0xFF) as u64) << 56),
+            _ if desc == DESC_AD => self.gs_write_register(data[2] as u8, low),
+            _ if desc == DESC_NOP => {}
+            reg => self.gs_write_register(reg, low),
+        }
+    }
+}
+
+impl Savestate for Path {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32_slice(&self.tag);
+        w.write_u32(self.loops);
+        w.write_u32(self.reg);
+        w.write_bool(self.in_packet);
+        w.write_u32(self.image_qwords);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_u32_slice_into(&mut self.tag)?;
+        self.loops = r.read_u32()?;
+        self.reg = r.read_u32()?;
+        self.in_packet = r.read_bool()?;
+        self.image_qwords = r.read_u32()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Gif {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.ctrl);
+        w.write_u32(self.mode);
+        w.write_bool(self.mskpath3);
+        for path in &self.paths {
+            path.save_state(w);
+        }
+        w.write_u8(self.active.map_or(0xFF, |path| path as u8));
+        w.write_u32(self.queued);
+        w.write_u32(self.q);
+        w.write_u32(self.fifo.len() as u32);
+        for data in &self.fifo {
+            w.write_u32_slice(data);
+        }
+        w.write_u32_slice(&self.fifo_input);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.ctrl = r.read_u32()?;
+        self.mode = r.read_u32()?;
+        self.mskpath3 = r.read_bool()?;
+        for path in &mut self.paths {
+            path.load_state(r)?;
+        }
+        self.active = match r.read_u8()? {
+            path @ 0..=2 => Some(path as usize),
+            _ => None,
+        };
+        self.queued = r.read_u32()?;
+        self.q = r.read_u32()?;
+        self.fifo.clear();
+        for _ in 0..r.read_u32()? {
+            let mut data = [0; 4];
+            r.read_u32_slice_into(&mut data)?;
+            self.fifo.push_back(data);
+        }
+        r.read_u32_slice_into(&mut self.fifo_input)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::gs::swizzle::PSMCT32;
+    use crate::system::gs::{BITBLTBUF, TEX0_1, TRXDIR, TRXREG};
+
+    fn tag(nloop: u32, eop: bool, flg: u32, nreg: u32, regs: u64) -> [u32; 4] {
+        [nloop | (eop as u32) << 15, flg << 26 | nreg << 28, regs as u32, (regs >> 32) as u32]
+    }
+
+    fn ad(addr: u8, value: u64) -> [u32; 4] {
+        [value as u32, (value >> 32) as u32, addr as u32, 0]
+    }
+
+    #[test]
+    fn test_packed() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        // PRE sets PRIM, then ST, RGBAQ and XYZF2 with ADC, then an A+D
+        let mut packet = tag(1, false, FLG_PACKED, 3, 0x412);
+        packet[1] |= 1 << 14 | 6 << 15;
+        assert!(ps2.gif_path_write(PATH2, packet));
+        assert_eq!(Some(PATH2), ps2.gif.active);
+        for data in [[0x3F00_0000, 0x3E80_0000, 0x4000_0000, 0], [0x1FF, 0x22, 0x33, 0x44], [0x10, 0x20, 0x12340, 0x8AB0]] {
+            assert!(ps2.gif_path_write(PATH2, data));
+        }
+        assert_eq!(6, ps2.gs.regs[PRIM as usize]);
+        assert_eq!(0x3E80_0000_3F00_0000, ps2.gs.regs[ST as usize]);
+        assert_eq!(0x4000_0000_4433_22FF, ps2.gs.regs[RGBAQ as usize]);
+        assert_eq!(0xAB00_1234_0020_0010, ps2.gs.regs[XYZF3 as usize]);
+
+        // still in the packet, so PATH3 can't get in
+        for (i, word) in tag(1, true, FLG_PACKED, 1, DESC_AD).iter().enumerate() {
+            ps2.gif_fifo_write(0x1000_6000 + i as u32 * 4, *word);
+        }
+        assert_eq!(1, ps2.gif.fifo.len());
+        assert_eq!(2 << STAT_APATH_SHIFT | STAT_OPH | STAT_P3Q | 1 << STAT_FQC_SHIFT, ps2.read_ee_u32(0xB000_3020) & !STAT_DIR);
+
+        assert!(ps2.gif_path_write(PATH2, tag(0, true, FLG_PACKED, 1, 0)));
+        // the GS went straight to PATH3, which has its tag in and now waits on the data
+        assert_eq!((Some(PATH3), 0), (ps2.gif.active, ps2.gif.fifo.len()));
+        for (i, word) in ad(TEX0_1, 0x1234).iter().enumerate() {
+            ps2.gif_fifo_write(0x1000_6000 + i as u32 * 4, *word);
+        }
+        assert_eq!(0x1234, ps2.gs.regs[TEX0_1 as usize]);
+        assert_eq!(0, ps2.read_ee_u32(0xB000_3020));
+    }
+
+    #[test]
+    fn test_packed_xyz3() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        // XYZF3 then XYZ3 descriptors, unpacked like XYZF2 and XYZ2 but never kicking
+        let data = [tag(1, true, FLG_PACKED, 2, (XYZ3 as u64) << 4 | XYZF3 as u64), [0x10, 0x20, 0x12340, 0xAB0], [0x30, 0x40, 0x5678, 0]];
+        for qword in data {
+            assert!(ps2.gif_path_write(PATH2, qword));
+        }
+        assert_eq!(0xAB00_1234_0020_0010, ps2.gs.regs[XYZF3 as usize]);
+        assert_eq!(0x0000_5678_0040_0030, ps2.gs.regs[XYZ3 as usize]);
+        assert_eq!((0, 0), (ps2.gs.regs[XYZF2 as usize], ps2.gs.regs[XYZ2 as usize]));
+    }
+
+    #[test]
+    fn test_packed_q_reset() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        // ST then RGBAQ takes the Q from the ST
+        let data = [tag(1, false, FLG_PACKED, 2, (RGBAQ as u64) << 4 | ST as u64), [0, 0, 2.0f32.to_bits(), 0], [1, 2, 3, 4]];
+        for qword in data {
+            assert!(ps2.gif_path_write(PATH2, qword));
+        }
+        assert_eq!(2.0f32.to_bits(), (ps2.gs.regs[RGBAQ as usize] >> 32) as u32);
+
+        // the next tag starts again from 1.0
+        for qword in [tag(1, true, FLG_PACKED, 1, RGBAQ as u64), [1, 2, 3, 4]] {
+            assert!(ps2.gif_path_write(PATH2, qword));
+        }
+        assert_eq!(1.0f32.to_bits(), (ps2.gs.regs[RGBAQ as usize] >> 32) as u32);
+    }
+
+    #[test]
+    fn test_reglist_and_image() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        // three registers leave the last half qword unused
+        for data in [tag(1, false, FLG_REGLIST, 3, 0x561), [1, 0, 2, 0], [3, 0, 0xFFFF, 0xFFFF]] {
+            assert!(ps2.gif_path_write(PATH1, data));
+        }
+        assert_eq!((1, 2, 3), (ps2.gs.regs[RGBAQ as usize], ps2.gs.regs[TEX0_1 as usize], ps2.gs.regs[XYZ2 as usize]));
+
+        // a 4x1 image into local memory
+        let setup = [
+            tag(3, false, FLG_PACKED, 1, DESC_AD),
+            ad(BITBLTBUF, (PSMCT32 as u64) << 56 | 1 << 48),
+            ad(TRXREG, 4 | 1 << 32),
+            ad(TRXDIR, 0),
+        ];
+        for data in setup.into_iter().chain([tag(1, true, FLG_IMAGE, 0, 0), [1, 2, 3, 4]]) {
+            assert!(ps2.gif_path_write(PATH1, data));
+        }
+        let pixels: Vec<u32> = (0..4).map(|x| ps2.gs.read_pixel(PSMCT32, 0, 1, x, 0)).collect();
+        assert_eq!(vec![1, 2, 3, 4], pixels);
+        assert_eq!(None, ps2.gif.active);
+    }
+
+    #[test]
+    fn test_path3_masking() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        for i in 0..4 {
+            ps2.write_ee_u32(0x1000 + i * 4, tag(1, true, FLG_PACKED, 1, DESC_AD)[i as usize]);
+            ps2.write_ee_u32(0x1010 + i * 4, ad(TEX0_1, 0x55)[i as usize]);
+        }
+        ps2.write_ee_u32(0xB000_3010, MODE_M3R);
+        ps2.write_ee_u32(0xB000_A010, 0x1000);
+        ps2.write_ee_u32(0xB000_A020, 2);
+        ps2.write_ee_u32(0xB000_E000, 1);
+        ps2.write_ee_u32(0xB000_A000, 1 << 8);
+        ps2.run_events();
+
+        // the DMA filled the FIFO but nothing reached the GS
+        assert_eq!(0, ps2.read_ee_u32(0xB000_A000) & (1 << 8));
+        assert_eq!(0, ps2.gs.regs[TEX0_1 as usize]);
+        assert_eq!(STAT_M3R | STAT_P3Q | 2 << STAT_FQC_SHIFT, ps2.read_ee_u32(0xB000_3020) & !STAT_DIR);
+
+        ps2.write_ee_u32(0xB000_3010, 0);
+        assert_eq!(0x55, ps2.gs.regs[TEX0_1 as usize]);
+        assert_eq!(0, ps2.read_ee_u32(0xB000_3020) & !STAT_DIR);
+
+        // MSKPATH3 does the same, but not in the middle of a packet
+        ps2.gif_dma_write(tag(1, true, FLG_PACKED, 1, DESC_AD));
+        ps2.gif_set_mskpath3(true);
+        ps2.gif_dma_write(ad(TEX0_1, 0x66));
+        assert_eq!(0x66, ps2.gs.regs[TEX0_1 as usize]);
+        ps2.gif_dma_write(tag(0, true, FLG_PACKED, 1, 0));
+        assert!(!ps2.gif.paths[PATH3].in_packet);
+        assert_eq!(STAT_M3P | STAT_P3Q | 1 << STAT_FQC_SHIFT, ps2.read_ee_u32(0xB000_3020) & !STAT_DIR);
+
+        // resetting the GIF empties the FIFO but leaves VIF1's mask alone
+        ps2.write_ee_u32(0xB000_3000, CTRL_RST);
+        assert_eq!(STAT_M3P, ps2.read_ee_u32(0xB000_3020) & !STAT_DIR);
+    }
+
+    #[test]
+    fn test_intermittent_mode() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.write_ee_u32(0xB000_3010, MODE_IMT);
+        ps2.gif_dma_write(tag(20, true, FLG_IMAGE, 0, 0));
+        for _ in 0..4 {
+            ps2.gif_dma_write([0; 4]);
+        }
+        // PATH1 has to wait for the first 8 qwords, then gets in
+        assert!(!ps2.gif_path_write(PATH1, tag(0, true, FLG_PACKED, 1, 0)));
+        assert_eq!(STAT_P1Q, ps2.read_ee_u32(0xB000_3020) & STAT_P1Q);
+        for _ in 0..4 {
+            ps2.gif_dma_write([0; 4]);
+        }
+        assert_eq!(None, ps2.gif.active);
+        assert_eq!(STAT_IMT | STAT_IP3 | STAT_P1Q, ps2.read_ee_u32(0xB000_3020) & !STAT_DIR);
+        assert_eq!(12, ps2.read_ee_u32(0xB000_3090));
+        ps2.gif_dma_write([0; 4]);
+        assert_eq!(1, ps2.gif.fifo.len());
+
+        assert!(ps2.gif_path_write(PATH1, tag(0, true, FLG_PACKED, 1, 0)));
+        assert_eq!((Some(PATH3), 0, 11), (ps2.gif.active, ps2.gif.fifo.len(), ps2.gif.paths[PATH3].loops));
+    }
+}
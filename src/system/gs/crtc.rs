@@ -0,0 +1,263 @@
+use super::raster::unpack_color;
+use super::swizzle::PSMCT24;
+use super::*;
+
+/*
+    The CRTC, which turns local memory into the picture on screen.
+
+    There are two read circuits, each taking a rectangle of a frame buffer (DISPFB: where it is, its
+    format and the top left pixel to start at) and placing it somewhere on the display (DISPLAY).
+    DISPLAY is in video clock units horizontally and lines vertically, with each frame buffer pixel
+    lasting MAGH + 1 clocks and MAGV + 1 lines.
+
+    PMODE merges the two. Circuit 1 goes on top of either circuit 2 or BGCOLOR (SLBG), blended by
+    ALP (MMOD set) or by circuit 1's own pixel alpha, where 0x80 is opaque like everywhere else.
+    The alpha that comes out with the colour is the pixel alpha of circuit 1, or of circuit 2 with
+    AMOD set, and 0 where that circuit isn't showing.
+
+    Interlaced output (SMODE2.INT) comes in two flavours. In field mode (FFMD clear) the buffer holds
+    the whole frame and each field reads every other line of it, so the two fields woven together are
+    just the buffer. In frame mode each field reads every line of a buffer half the height of the
+    display, so the image is half as tall.
+
+    The image is sized to cover both circuits, in units of the smaller magnification, so a circuit with
+    MAGH 3 next to one with MAGH 1 gets each of its pixels doubled.
+*/
+
+/* PMODE */
+const PMODE_EN1: u64 = 1;
+const PMODE_EN2: u64 = 1 << 1;
+const PMODE_MMOD: u64 = 1 << 5;
+const PMODE_AMOD: u64 = 1 << 6;
+const PMODE_SLBG: u64 = 1 << 7;
+
+/* SMODE2 */
+const SMODE2_INT: u64 = 1;
+const SMODE2_FFMD: u64 = 1 << 1;
+
+/// A displayed picture, as RGBA bytes a row at a time.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>
+}
+
+impl Image {
+    /// The RGBA of pixel (x, y).
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = (y * self.width + x) as usize * 4;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+}
+
+/// One read circuit's DISPFB and DISPLAY, unpacked.
+#[derive(Clone, Copy, Debug)]
+struct Circuit {
+    bp: u32,
+    bw: u32,
+    psm: u32,
+    dbx: u32,
+    dby: u32,
+    dx: u32,
+    dy: u32,
+    magh: u32,
+    magv: u32,
+    dw: u32,
+    dh: u32
+}
+
+impl Circuit {
+    fn new(dispfb: u64, display: u64, frame_mode: bool) -> Circuit {
+        let dh = ((display >> 44) & 0x7FF) as u32 + 1;
+        Circuit {
+            bp: (dispfb & 0x1FF) as u32 * 32,
+            bw: ((dispfb >> 9) & 0x3F) as u32,
+            psm: ((dispfb >> 15) & 0x1F) as u32,
+            dbx: ((dispfb >> 32) & 0x7FF) as u32,
+            dby: ((dispfb >> 43) & 0x7FF) as u32,
+            dx: (display & 0xFFF) as u32,
+            dy: ((display >> 12) & 0x7FF) as u32,
+            magh: ((display >> 23) & 0xF) as u32 + 1,
+            magv: ((display >> 27) & 3) as u32 + 1,
+            dw: ((display >> 32) & 0xFFF) as u32 + 1,
+            dh: if frame_mode { (dh / 2).max(1) } else { dh },
+        }
+    }
+
+    /// The frame buffer pixel shown at a video clock and line, if the circuit covers it.
+    fn source(&self, clock: u32, line: u32) -> Option<(u32, u32)> {
+        if clock < self.dx || clock >= self.dx + self.dw || line < self.dy || line >= self.dy + self.dh {
+            return None;
+        }
+        Some((((clock - self.dx) / self.magh + self.dbx) % 2048, ((line - self.dy) / self.magv + self.dby) % 2048))
+    }
+}
+
+impl Gs {
+    fn circuit_color(&self, circuit: &Circuit, x: u32, y: u32) -> [u8; 4] {
+        let mut color = unpack_color(circuit.psm, self.read_pixel(circuit.psm, circuit.bp, circuit.bw, x, y));
+        if circuit.psm == PSMCT24 {
+            color[3] = 0x80;
+        }
+        color
+    }
+
+    /// Builds the picture the CRTC is currently putting out, merging both circuits as PMODE says.
+    pub fn display_image(&self) -> Image {
+        let frame_mode = self.smode2 & SMODE2_INT != 0 && self.smode2 & SMODE2_FFMD != 0;
+        let circuits = [(PMODE_EN1, 0), (PMODE_EN2, 1)]
+            .map(|(enable, n)| (self.pmode & enable != 0).then(|| Circuit::new(self.dispfb[n], self.display[n], frame_mode)));
+        let enabled: Vec<&Circuit> = circuits.iter().flatten().collect();
+        if enabled.is_empty() {
+            return Image { width: 0, height: 0, pixels: Vec::new() };
+        }
+
+        let clock_step = enabled.iter().map(|c| c.magh).min().unwrap_or(1);
+        let line_step = enabled.iter().map(|c| c.magv).min().unwrap_or(1);
+        let left = enabled.iter().map(|c| c.dx).min().unwrap_or(0);
+        let top = enabled.iter().map(|c| c.dy).min().unwrap_or(0);
+        let right = enabled.iter().map(|c| c.dx + c.dw).max().unwrap_or(0);
+        let bottom = enabled.iter().map(|c| c.dy + c.dh).max().unwrap_or(0);
+        let width = (right - left) / clock_step;
+        let height = (bottom - top) / line_step;
+
+        let bgcolor = (self.bgcolor as u32).to_le_bytes();
+        let alp = ((self.pmode >> 8) & 0xFF) as u32;
+        let mut pixels = Vec::with_capacity((width * height) as usize * 4);
+        for y in 0..height {
+            let line = top + y * line_step;
+            for x in 0..width {
+                let clock = left + x * clock_step;
+                let sample = |n: usize| {
+                    circuits[n].as_ref().and_then(|c| c.source(clock, line).map(|(fx, fy)| self.circuit_color(c, fx, fy)))
+                };
+                let (top, bottom) = (sample(0), sample(1));
+                let alpha_source = if self.pmode & PMODE_AMOD != 0 { bottom } else { top };
+                let alpha = alpha_source.map_or(0, |c| c[3]);
+                let background = match bottom {
+                    Some(color) if self.pmode & PMODE_SLBG == 0 => color,
+                    _ => bgcolor,
+                };
+                let color = match top {
+                    Some(top) => {
+                        let weight = if self.pmode & PMODE_MMOD != 0 { alp } else { (top[3] as u32 * 2).min(255) };
+                        [0, 1, 2].map(|c| ((top[c] as u32 * weight + background[c] as u32 * (255 - weight) + 127) / 255) as u8)
+                    }
+                    None => [background[0], background[1], background[2]],
+                };
+                pixels.extend_from_slice(&[color[0], color[1], color[2], alpha]);
+            }
+        }
+        Image { width, height, pixels }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::swizzle::*;
+
+    fn dispfb(fbp: u64, fbw: u64, psm: u32, dbx: u64, dby: u64) -> u64 {
+        fbp | fbw << 9 | (psm as u64) << 15 | dbx << 32 | dby << 43
+    }
+
+    fn display(dx: u64, dy: u64, magh: u64, magv: u64, dw: u64, dh: u64) -> u64 {
+        dx | dy << 12 | magh << 23 | magv << 27 | (dw - 1) << 32 | (dh - 1) << 44
+    }
+
+    #[test]
+    fn test_single_circuit() {
+        let mut gs = Gs::new();
+        gs.write_pixel(PSMCT32, 32, 1, 5, 3, 0x0033_2211);
+        gs.write_pixel(PSMCT16, 64, 1, 0, 0, 0x7C00);
+        gs.pmode = PMODE_EN1 | PMODE_MMOD | 0xFF << 8;
+        gs.dispfb[0] = dispfb(1, 1, PSMCT32, 5, 3);
+        gs.display[0] = display(600, 50, 3, 1, 64 * 4, 32 * 2);
+        let image = gs.display_image();
+        assert_eq!((64, 32), (image.width, image.height));
+        assert_eq!([0x11, 0x22, 0x33, 0], image.pixel(0, 0));
+        assert_eq!([0, 0, 0, 0], image.pixel(1, 0));
+
+        // 16 bit buffers are expanded
+        gs.dispfb[0] = dispfb(2, 1, PSMCT16, 0, 0);
+        assert_eq!([0, 0, 0xF8, 0], gs.display_image().pixel(0, 0));
+
+        gs.pmode = 0;
+        assert_eq!(0, gs.display_image().width);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut gs = Gs::new();
+        gs.write_pixel(PSMCT32, 0, 1, 0, 0, 0x8000_00FF);
+        gs.write_pixel(PSMCT32, 0, 1, 1, 0, 0x4000_00FF);
+        gs.write_pixel(PSMCT32, 32, 1, 0, 0, 0x80FF_0000);
+        gs.write_pixel(PSMCT32, 32, 1, 1, 0, 0x80FF_0000);
+        gs.dispfb[0] = dispfb(0, 1, PSMCT32, 0, 0);
+        gs.dispfb[1] = dispfb(1, 1, PSMCT32, 0, 0);
+        gs.display[0] = display(0, 0, 0, 0, 2, 1);
+        // circuit 2 is wider, and shows through where circuit 1 doesn't reach
+        gs.display[1] = display(0, 0, 0, 0, 3, 1);
+        gs.bgcolor = 0x00_FF00;
+
+        // pixel alpha, 0x80 is opaque and 0x40 half
+        gs.pmode = PMODE_EN1 | PMODE_EN2;
+        let image = gs.display_image();
+        assert_eq!(3, image.width);
+        assert_eq!([0xFF, 0, 0, 0x80], image.pixel(0, 0));
+        assert_eq!([0x80, 0, 0x7F, 0x40], image.pixel(1, 0));
+        assert_eq!([0, 0, 0, 0], image.pixel(2, 0));
+
+        // a fixed alpha over the background colour
+        gs.pmode = PMODE_EN1 | PMODE_EN2 | PMODE_MMOD | PMODE_SLBG | 0x33 << 8;
+        let image = gs.display_image();
+        assert_eq!([0x33, 0xCC, 0, 0x80], image.pixel(0, 0));
+        assert_eq!([0, 0xFF, 0, 0], image.pixel(2, 0));
+    }
+
+    #[test]
+    fn test_output_alpha() {
+        let mut gs = Gs::new();
+        gs.write_pixel(PSMCT32, 0, 1, 0, 0, 0x4000_00FF);
+        gs.write_pixel(PSMCT32, 32, 1, 0, 0, 0x20FF_0000);
+        gs.write_pixel(PSMCT32, 32, 1, 1, 0, 0x10FF_0000);
+        gs.dispfb[0] = dispfb(0, 1, PSMCT32, 0, 0);
+        gs.dispfb[1] = dispfb(1, 1, PSMCT32, 0, 0);
+        gs.display[0] = display(0, 0, 0, 0, 1, 1);
+        gs.display[1] = display(0, 0, 0, 0, 2, 1);
+
+        // circuit 1's alpha, none where it doesn't reach
+        gs.pmode = PMODE_EN1 | PMODE_EN2;
+        let image = gs.display_image();
+        assert_eq!([0x40, 0], [image.pixel(0, 0)[3], image.pixel(1, 0)[3]]);
+
+        // AMOD takes it from circuit 2, even when circuit 1 covers it
+        gs.pmode = PMODE_EN1 | PMODE_EN2 | PMODE_AMOD;
+        let image = gs.display_image();
+        assert_eq!([0x20, 0x10], [image.pixel(0, 0)[3], image.pixel(1, 0)[3]]);
+    }
+
+    #[test]
+    fn test_interlace_modes() {
+        let mut gs = Gs::new();
+        for y in 0..4 {
+            gs.write_pixel(PSMCT32, 0, 1, 0, y, y + 1);
+        }
+        gs.pmode = PMODE_EN1 | PMODE_MMOD | 0xFF << 8;
+        gs.dispfb[0] = dispfb(0, 1, PSMCT32, 0, 0);
+        gs.display[0] = display(0, 0, 0, 0, 1, 4);
+
+        // field mode weaves the whole buffer
+        gs.smode2 = SMODE2_INT;
+        let image = gs.display_image();
+        assert_eq!(4, image.height);
+        assert_eq!([1, 2, 3, 4], [0, 1, 2, 3].map(|y| image.pixel(0, y)[0]));
+
+        // frame mode shows half as many lines
+        gs.smode2 = SMODE2_INT | SMODE2_FFMD;
+        let image = gs.display_image();
+        assert_eq!(2, image.height);
+        assert_eq!([1, 2], [0, 1].map(|y| image.pixel(0, y)[0]));
+    }
+}
@@ -0,0 +1,252 @@
+use std::io;
+
+use super::*;
+use crate::system::savestate::{compress, decompress, STATE_VERSION};
+
+/*
+    GS dumps, for looking at a graphics bug without the rest of the machine.
+
+    A dump is a snapshot of the whole GS (local memory, registers, CLUT and any transfer in flight)
+    followed by everything written to it: general register writes, which is what GIF packets come out
+    as, HWREG image data included, privileged register writes, and vsyncs marking the frames. Playing
+    one back loads the snapshot into a GS of its own and feeds it the writes a frame at a time.
+
+    Reads don't go in the dump, so a local to host transfer plays back without the EE taking the data.
+
+    File layout:
+        0x00  magic "RSX2GSDP"
+        0x08  dump version (u32)
+        0x0C  save state version the snapshot was written with (u32)
+        0x10  uncompressed payload length (u32)
+        0x14  compressed payload
+*/
+
+const MAGIC: &[u8; 8] = b"RSX2GSDP";
+const HEADER_SIZE: usize = 0x14;
+const DUMP_VERSION: u32 = 1;
+
+/* event tags in the payload */
+const EVENT_REGISTER: u8 = 0;
+const EVENT_PRIVILEGED: u8 = 1;
+const EVENT_VSYNC: u8 = 2;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DumpEvent {
+    Register(u8, u64),
+    Privileged(u32, u64),
+    VSync
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct GsDump {
+    pub state_version: u32,
+    pub state: Vec<u8>,
+    pub events: Vec<DumpEvent>
+}
+
+impl GsDump {
+    pub fn frame_count(&self) -> usize {
+        self.events.iter().filter(|e| **e == DumpEvent::VSync).count()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.write_bytes(&self.state);
+        w.write_u32(self.events.len() as u32);
+        for event in &self.events {
+            match *event {
+                DumpEvent::Register(addr, value) => {
+                    w.write_u8(EVENT_REGISTER);
+                    w.write_u8(addr);
+                    w.write_u64(value);
+                }
+                DumpEvent::Privileged(addr, value) => {
+                    w.write_u8(EVENT_PRIVILEGED);
+                    w.write_u32(addr);
+                    w.write_u64(value);
+                }
+                DumpEvent::VSync => w.write_u8(EVENT_VSYNC),
+            }
+        }
+        let payload = w.into_bytes();
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&DUMP_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.state_version.to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&compress(&payload));
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<GsDump> {
+        if data.len() < HEADER_SIZE || &data[0..8] != MAGIC {
+            return Err(invalid("not a GS dump"));
+        }
+        let header_word = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let version = header_word(0x08);
+        if version != DUMP_VERSION {
+            return Err(invalid(&format!("GS dump version {} is not supported", version)));
+        }
+        let state_version = header_word(0x0C);
+        if state_version > STATE_VERSION {
+            return Err(invalid(&format!("GS dump state version {} is newer than this build supports ({})", state_version, STATE_VERSION)));
+        }
+        // decompress refuses lengths no real dump could have, so a bad header can't ask for gigabytes
+        let payload_len = header_word(0x10) as usize;
+        let payload = decompress(&data[HEADER_SIZE..], payload_len)?;
+        let mut r = StateReader::new(&payload, version);
+
+        let state = r.read_bytes()?;
+        let count = r.read_u32()?;
+        let mut events = Vec::new();
+        for _ in 0..count {
+            events.push(match r.read_u8()? {
+                EVENT_REGISTER => DumpEvent::Register(r.read_u8()?, r.read_u64()?),
+                EVENT_PRIVILEGED => DumpEvent::Privileged(r.read_u32()?, r.read_u64()?),
+                EVENT_VSYNC => DumpEvent::VSync,
+                tag => return Err(invalid(&format!("unknown GS dump event {}", tag))),
+            });
+        }
+        Ok(GsDump { state_version, state, events })
+    }
+
+    pub fn save_file(&self, path: &str) -> io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn load_file(path: &str) -> io::Result<GsDump> {
+        GsDump::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+impl Gs {
+    /// Snapshots the GS and starts logging what's written to it.
+    pub fn start_dump(&mut self) {
+        self.dump = Some(Vec::new());
+        let mut w = StateWriter::new();
+        self.save_state(&mut w);
+        self.dump_state = w.into_bytes();
+    }
+
+    /// Stops logging, returning the dump if one was being made.
+    pub fn finish_dump(&mut self) -> Option<GsDump> {
+        let events = self.dump.take()?;
+        let state = std::mem::take(&mut self.dump_state);
+        Some(GsDump { state_version: STATE_VERSION, state, events })
+    }
+
+    pub(super) fn record(&mut self, event: DumpEvent) {
+        if let Some(events) = &mut self.dump {
+            events.push(event);
+        }
+    }
+}
+
+/// Plays a dump back into a GS of its own.
+pub struct GsDumpPlayer {
+    pub gs: Gs,
+    events: Vec<DumpEvent>,
+    next: usize,
+    frame: u64
+}
+
+impl GsDumpPlayer {
+    pub fn new(dump: &GsDump) -> io::Result<GsDumpPlayer> {
+        let mut gs = Gs::new();
+        let mut r = StateReader::new(&dump.state, dump.state_version);
+        gs.load_state(&mut r)?;
+        Ok(GsDumpPlayer { gs, events: dump.events.clone(), next: 0, frame: 0 })
+    }
+
+    /// Frames played so far.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.events.len()
+    }
+
+    /// Plays up to and including the next vsync, returning false if the dump ran out first.
+    pub fn run_frame(&mut self) -> bool {
+        while let Some(&event) = self.events.get(self.next) {
+            self.next += 1;
+            match event {
+                DumpEvent::Register(addr, value) => {
+                    self.gs.write_register(addr, value);
+                }
+                DumpEvent::Privileged(addr, value) => {
+                    self.gs.write_privileged(addr, value);
+                }
+                DumpEvent::VSync => {
+                    self.gs.vsync();
+                    self.frame += 1;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::raster::PRIM_SPRITE;
+    use super::super::swizzle::PSMCT32;
+
+    #[test]
+    fn test_record_and_play() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.gs.write_pixel(PSMCT32, 0, 1, 20, 20, 0x1234_5678);
+        ps2.gs.start_dump();
+
+        // a sprite in one frame, then a background colour change in the next
+        ps2.gs_write_register(FRAME_1, 1 << 16);
+        ps2.gs_write_register(SCISSOR_1, 63 << 16 | 63 << 48);
+        ps2.gs_write_register(PRIM, PRIM_SPRITE);
+        ps2.gs_write_register(RGBAQ, 0x8000_00FF);
+        ps2.gs_write_register(XYZ2, 0);
+        ps2.gs_write_register(XYZ2, (4 << 4) | (4 << 4) << 16);
+        ps2.gs_vsync();
+        ps2.gs_write_u32(BGCOLOR, 0x00FF_0000);
+        ps2.gs_vsync();
+        // not a whole frame, so it's left over at the end
+        ps2.gs_write_u32(PMODE, 1);
+
+        let dump = ps2.gs.finish_dump().unwrap();
+        assert!(ps2.gs.finish_dump().is_none());
+        let dump = GsDump::from_bytes(&dump.to_bytes()).unwrap();
+        assert_eq!(2, dump.frame_count());
+
+        let mut player = GsDumpPlayer::new(&dump).unwrap();
+        assert_eq!(0x1234_5678, player.gs.read_pixel(PSMCT32, 0, 1, 20, 20));
+        assert!(player.run_frame());
+        assert_eq!(0x8000_00FF, player.gs.read_pixel(PSMCT32, 0, 1, 3, 3));
+        assert_eq!(0, player.gs.bgcolor);
+        assert!(player.run_frame());
+        assert_eq!(0x00FF_0000, player.gs.bgcolor);
+        assert_eq!(ps2.gs.csr & CSR_FIELD, player.gs.csr & CSR_FIELD);
+        assert!(!player.run_frame());
+        assert_eq!((2, true, 1), (player.frame(), player.is_finished(), player.gs.pmode));
+        assert_eq!(ps2.gs.vram, player.gs.vram);
+    }
+
+    #[test]
+    fn test_bad_files() {
+        assert!(GsDump::from_bytes(b"RSX2MOVI").is_err());
+        let dump = GsDump { state_version: STATE_VERSION + 1, state: Vec::new(), events: Vec::new() };
+        assert!(GsDump::from_bytes(&dump.to_bytes()).is_err());
+
+        let dump = GsDump { state_version: STATE_VERSION, state: Vec::new(), events: Vec::new() };
+        let mut data = dump.to_bytes();
+        assert!(GsDump::from_bytes(&data[..HEADER_SIZE - 1]).is_err());
+        data[0x10..0x14].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(GsDump::from_bytes(&data).is_err());
+    }
+}
@@ -0,0 +1,586 @@
+use std::io;
+
+use super::intc::Interrupt;
+use super::ps2::Ps2;
+use super::savestate::{Savestate, StateReader, StateWriter};
+
+pub mod crtc;
+pub mod dump;
+pub mod pipeline;
+pub mod raster;
+pub mod swizzle;
+pub mod texture;
+pub mod transfer;
+
+use dump::DumpEvent;
+use raster::Vertex;
+use transfer::Transfer;
+
+/*
+    The Graphics Synthesizer. It has 4MB of local memory holding the frame buffers, Z buffers,
+    textures and CLUTs, and two sets of registers.
+
+    The privileged registers are mapped into EE space at 0x1200_0000 and set up the video output
+    (PMODE, SMODE, DISPFB, DISPLAY...) along with CSR and IMR, which the GS uses to interrupt the EE.
+    They are 64 bits wide, so the 32 bit bus sees them as two halves.
+
+    The general registers can only be written through the GIF, and are addressed by the 8 bit
+    numbers below. Most of them hold drawing state; writing XYZ2/XYZ3 adds a vertex, and SIGNAL,
+    FINISH and LABEL report progress back through CSR and SIGLBLID.
+
+    CSR:
+        0   SIGNAL  a SIGNAL was written         8   FLUSH
+        1   FINISH  a FINISH was written         9   RESET
+        2   HSINT   horizontal sync              12  NFIELD
+        3   VSINT   vertical sync                13  FIELD, odd or even field being shown
+        4   EDWINT  rectangular area write end   14-15  FIFO status
+                                                 16-23  REV, 24-31 ID
+    The first five are cleared by writing 1 to them, and raise the GS interrupt through the INTC
+    unless the matching bit of IMR (8-12) masks it.
+*/
+
+pub const GS_VRAM_SIZE: usize = 0x40_0000;
+
+pub const GS_PRIV_START: u32 = 0x1200_0000;
+pub const GS_PRIV_SIZE: usize = 0x2000;
+
+pub const PMODE: u32 = 0x1200_0000;
+pub const SMODE1: u32 = 0x1200_0010;
+pub const SMODE2: u32 = 0x1200_0020;
+pub const SRFSH: u32 = 0x1200_0030;
+pub const SYNCH1: u32 = 0x1200_0040;
+pub const SYNCH2: u32 = 0x1200_0050;
+pub const SYNCV: u32 = 0x1200_0060;
+pub const DISPFB1: u32 = 0x1200_0070;
+pub const DISPLAY1: u32 = 0x1200_0080;
+pub const DISPFB2: u32 = 0x1200_0090;
+pub const DISPLAY2: u32 = 0x1200_00A0;
+pub const EXTBUF: u32 = 0x1200_00B0;
+pub const EXTDATA: u32 = 0x1200_00C0;
+pub const EXTWRITE: u32 = 0x1200_00D0;
+pub const BGCOLOR: u32 = 0x1200_00E0;
+pub const CSR: u32 = 0x1200_1000;
+pub const IMR: u32 = 0x1200_1010;
+pub const BUSDIR: u32 = 0x1200_1040;
+pub const SIGLBLID: u32 = 0x1200_1080;
+
+/* general register addresses */
+pub const PRIM: u8 = 0x00;
+pub const RGBAQ: u8 = 0x01;
+pub const ST: u8 = 0x02;
+pub const UV: u8 = 0x03;
+pub const XYZF2: u8 = 0x04;
+pub const XYZ2: u8 = 0x05;
+pub const TEX0_1: u8 = 0x06;
+pub const TEX0_2: u8 = 0x07;
+pub const CLAMP_1: u8 = 0x08;
+pub const CLAMP_2: u8 = 0x09;
+pub const FOG: u8 = 0x0A;
+pub const XYZF3: u8 = 0x0C;
+pub const XYZ3: u8 = 0x0D;
+pub const TEX1_1: u8 = 0x14;
+pub const TEX1_2: u8 = 0x15;
+pub const TEX2_1: u8 = 0x16;
+pub const TEX2_2: u8 = 0x17;
+pub const XYOFFSET_1: u8 = 0x18;
+pub const XYOFFSET_2: u8 = 0x19;
+pub const PRMODECONT: u8 = 0x1A;
+pub const PRMODE: u8 = 0x1B;
+pub const TEXCLUT: u8 = 0x1C;
+pub const SCANMSK: u8 = 0x22;
+pub const MIPTBP1_1: u8 = 0x34;
+pub const MIPTBP1_2: u8 = 0x35;
+pub const MIPTBP2_1: u8 = 0x36;
+pub const MIPTBP2_2: u8 = 0x37;
+pub const TEXA: u8 = 0x3B;
+pub const FOGCOL: u8 = 0x3D;
+pub const TEXFLUSH: u8 = 0x3F;
+pub const SCISSOR_1: u8 = 0x40;
+pub const SCISSOR_2: u8 = 0x41;
+pub const ALPHA_1: u8 = 0x42;
+pub const ALPHA_2: u8 = 0x43;
+pub const DIMX: u8 = 0x44;
+pub const DTHE: u8 = 0x45;
+pub const COLCLAMP: u8 = 0x46;
+pub const TEST_1: u8 = 0x47;
+pub const TEST_2: u8 = 0x48;
+pub const PABE: u8 = 0x49;
+pub const FBA_1: u8 = 0x4A;
+pub const FBA_2: u8 = 0x4B;
+pub const FRAME_1: u8 = 0x4C;
+pub const FRAME_2: u8 = 0x4D;
+pub const ZBUF_1: u8 = 0x4E;
+pub const ZBUF_2: u8 = 0x4F;
+pub const BITBLTBUF: u8 = 0x50;
+pub const TRXPOS: u8 = 0x51;
+pub const TRXREG: u8 = 0x52;
+pub const TRXDIR: u8 = 0x53;
+pub const HWREG: u8 = 0x54;
+pub const SIGNAL: u8 = 0x60;
+pub const FINISH: u8 = 0x61;
+pub const LABEL: u8 = 0x62;
+
+pub const GENERAL_REGISTER_NAMES: &[(u8, &str)] = &[
+    (PRIM, "PRIM"), (RGBAQ, "RGBAQ"), (ST, "ST"), (UV, "UV"), (XYZF2, "XYZF2"), (XYZ2, "XYZ2"),
+    (TEX0_1, "TEX0_1"), (TEX0_2, "TEX0_2"), (CLAMP_1, "CLAMP_1"), (CLAMP_2, "CLAMP_2"), (FOG, "FOG"),
+    (XYZF3, "XYZF3"), (XYZ3, "XYZ3"), (TEX1_1, "TEX1_1"), (TEX1_2, "TEX1_2"), (TEX2_1, "TEX2_1"),
+    (TEX2_2, "TEX2_2"), (XYOFFSET_1, "XYOFFSET_1"), (XYOFFSET_2, "XYOFFSET_2"), (PRMODECONT, "PRMODECONT"),
+    (PRMODE, "PRMODE"), (TEXCLUT, "TEXCLUT"), (SCANMSK, "SCANMSK"), (MIPTBP1_1, "MIPTBP1_1"),
+    (MIPTBP1_2, "MIPTBP1_2"), (MIPTBP2_1, "MIPTBP2_1"), (MIPTBP2_2, "MIPTBP2_2"), (TEXA, "TEXA"),
+    (FOGCOL, "FOGCOL"), (TEXFLUSH, "TEXFLUSH"), (SCISSOR_1, "SCISSOR_1"), (SCISSOR_2, "SCISSOR_2"),
+    (ALPHA_1, "ALPHA_1"), (ALPHA_2, "ALPHA_2"), (DIMX, "DIMX"), (DTHE, "DTHE"), (COLCLAMP, "COLCLAMP"),
+    (TEST_1, "TEST_1"), (TEST_2, "TEST_2"), (PABE, "PABE"), (FBA_1, "FBA_1"), (FBA_2, "FBA_2"),
+    (FRAME_1, "FRAME_1"), (FRAME_2, "FRAME_2"), (ZBUF_1, "ZBUF_1"), (ZBUF_2, "ZBUF_2"),
+    (BITBLTBUF, "BITBLTBUF"), (TRXPOS, "TRXPOS"), (TRXREG, "TRXREG"), (TRXDIR, "TRXDIR"),
+    (HWREG, "HWREG"), (SIGNAL, "SIGNAL"), (FINISH, "FINISH"), (LABEL, "LABEL"),
+];
+
+const GENERAL_REGISTER_COUNT: usize = LABEL as usize + 1;
+
+pub const CSR_SIGNAL: u64 = 1 << 0;
+pub const CSR_FINISH: u64 = 1 << 1;
+pub const CSR_HSINT: u64 = 1 << 2;
+pub const CSR_VSINT: u64 = 1 << 3;
+pub const CSR_EDWINT: u64 = 1 << 4;
+const CSR_INTERRUPTS: u64 = 0x1F;
+const CSR_RESET: u64 = 1 << 9;
+const CSR_FIELD: u64 = 1 << 13;
+
+/// FIFO empty, revision 0x1B and ID 0x55, as the BIOS expects.
+const CSR_FIXED: u64 = 0x551B_4000;
+
+/// The IMR bits line up with the CSR interrupt bits, 8 places up.
+const IMR_SHIFT: u32 = 8;
+const IMR_BITS: u64 = 0x7F00;
+
+/// The name of a general register, if it exists.
+pub fn general_register_name(addr: u8) -> Option<&'static str> {
+    GENERAL_REGISTER_NAMES.iter().find(|(reg, _)| *reg == addr).map(|(_, name)| *name)
+}
+
+pub struct Gs {
+    /* 4MB local memory, as 32 bit words */
+    pub vram: Vec<u32>,
+
+    /* general registers, by address */
+    pub regs: [u64; GENERAL_REGISTER_COUNT],
+
+    /* vertices waiting for the rest of their primitive */
+    vertex_queue: Vec<Vertex>,
+
+    /* the CLUT buffer, in 16 bit halves, and the CBP it was last loaded from for CLD 2 to 5 */
+    clut: [u16; texture::CLUT_ENTRIES],
+    cbp0: u32,
+    cbp1: u32,
+
+    pub transfer: Transfer,
+
+    /* what's been written since start_dump, and the snapshot taken then */
+    dump: Option<Vec<DumpEvent>>,
+    dump_state: Vec<u8>,
+
+    pub pmode: u64,
+    pub smode1: u64,
+    pub smode2: u64,
+    pub srfsh: u64,
+    pub synch1: u64,
+    pub synch2: u64,
+    pub syncv: u64,
+    pub dispfb: [u64; 2],
+    pub display: [u64; 2],
+    pub extbuf: u64,
+    pub extdata: u64,
+    pub extwrite: u64,
+    pub bgcolor: u64,
+    pub csr: u64,
+    pub imr: u64,
+    pub busdir: u64,
+    pub siglblid: u64
+}
+
+impl Gs {
+    pub fn new() -> Gs {
+        Gs {
+            vram: vec![0; GS_VRAM_SIZE / 4],
+            regs: [0; GENERAL_REGISTER_COUNT],
+            vertex_queue: Vec::new(),
+            clut: [0; texture::CLUT_ENTRIES], cbp0: 0, cbp1: 0,
+            transfer: Transfer::new(),
+            dump: None, dump_state: Vec::new(),
+            pmode: 0, smode1: 0, smode2: 0, srfsh: 0, synch1: 0, synch2: 0, syncv: 0,
+            dispfb: [0; 2], display: [0; 2],
+            extbuf: 0, extdata: 0, extwrite: 0, bgcolor: 0,
+            csr: 0, imr: IMR_BITS, busdir: 0, siglblid: 0
+        }
+    }
+
+    /// The value of a general register, for the debuggers. The EE can't read them back.
+    pub fn register(&self, addr: u8) -> u64 {
+        self.regs.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    /// Sets CSR interrupt bits, returning whether that should interrupt the EE.
+    fn signal(&mut self, bits: u64) -> bool {
+        let new = bits & !self.csr;
+        self.csr |= bits;
+        new & !(self.imr >> IMR_SHIFT) != 0
+    }
+
+    /// The full 64 bit value of a privileged register.
+    pub fn read_privileged(&self, addr: u32) -> Option<u64> {
+        let value = match addr & !7 {
+            PMODE => self.pmode,
+            SMODE1 => self.smode1,
+            SMODE2 => self.smode2,
+            SRFSH => self.srfsh,
+            SYNCH1 => self.synch1,
+            SYNCH2 => self.synch2,
+            SYNCV => self.syncv,
+            DISPFB1 => self.dispfb[0],
+            DISPLAY1 => self.display[0],
+            DISPFB2 => self.dispfb[1],
+            DISPLAY2 => self.display[1],
+            EXTBUF => self.extbuf,
+            EXTDATA => self.extdata,
+            EXTWRITE => self.extwrite,
+            BGCOLOR => self.bgcolor,
+            CSR => self.csr | CSR_FIXED,
+            IMR => self.imr,
+            BUSDIR => self.busdir,
+            SIGLBLID => self.siglblid,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Writes a privileged register, returning whether the EE should be interrupted.
+    fn write_privileged(&mut self, addr: u32, value: u64) -> bool {
+        self.record(DumpEvent::Privileged(addr, value));
+        let reg = match addr & !7 {
+            PMODE => &mut self.pmode,
+            SMODE1 => &mut self.smode1,
+            SMODE2 => &mut self.smode2,
+            SRFSH => &mut self.srfsh,
+            SYNCH1 => &mut self.synch1,
+            SYNCH2 => &mut self.synch2,
+            SYNCV => &mut self.syncv,
+            DISPFB1 => &mut self.dispfb[0],
+            DISPLAY1 => &mut self.display[0],
+            DISPFB2 => &mut self.dispfb[1],
+            DISPLAY2 => &mut self.display[1],
+            EXTBUF => &mut self.extbuf,
+            EXTDATA => &mut self.extdata,
+            EXTWRITE => &mut self.extwrite,
+            BGCOLOR => &mut self.bgcolor,
+            BUSDIR => &mut self.busdir,
+            SIGLBLID => &mut self.siglblid,
+            CSR => {
+                if value & CSR_RESET != 0 {
+                    self.reset();
+                }
+                self.csr &= !(value & CSR_INTERRUPTS);
+                return false;
+            }
+            IMR => {
+                self.imr = value & IMR_BITS;
+                // unmasking a pending interrupt lets it through
+                return self.csr & CSR_INTERRUPTS & !(self.imr >> IMR_SHIFT) != 0;
+            }
+            _ => return false,
+        };
+        *reg = value;
+        false
+    }
+
+    /// What CSR.RESET does. The local memory and display setup are left alone.
+    fn reset(&mut self) {
+        self.regs = [0; GENERAL_REGISTER_COUNT];
+        self.vertex_queue.clear();
+        self.transfer = Transfer::new();
+        self.csr = 0;
+        self.siglblid = 0;
+    }
+
+    /// Moves on to the next field, returning whether the EE should be interrupted.
+    pub fn vsync(&mut self) -> bool {
+        self.record(DumpEvent::VSync);
+        self.csr ^= CSR_FIELD;
+        self.signal(CSR_VSINT)
+    }
+
+    /// Writes a general register, returning whether the EE should be interrupted.
+    pub fn write_register(&mut self, addr: u8, value: u64) -> bool {
+        self.record(DumpEvent::Register(addr, value));
+        let Some(reg) = self.regs.get_mut(addr as usize) else { return false };
+        *reg = value;
+        match addr {
+            PRIM => {
+                self.reset_vertex_queue();
+                false
+            }
+            TEX0_1 | TEX0_2 => {
+                self.load_clut(value);
+                false
+            }
+            TEX2_1 | TEX2_2 => {
+                // TEX2 only carries the format and CLUT fields of TEX0
+                let tex0 = addr as usize - TEX2_1 as usize + TEX0_1 as usize;
+                let mask = 0x3F << 20 | !0 << 37;
+                self.regs[tex0] = (self.regs[tex0] & !mask) | (value & mask);
+                self.load_clut(self.regs[tex0]);
+                false
+            }
+            TRXDIR => {
+                self.start_transfer(value);
+                false
+            }
+            HWREG => {
+                self.write_hwreg(value);
+                false
+            }
+            XYZ2 | XYZF2 | XYZ3 | XYZF3 => {
+                self.vertex_kick(value, matches!(addr, XYZF2 | XYZF3), matches!(addr, XYZ2 | XYZF2));
+                false
+            }
+            SIGNAL => {
+                let (id, mask) = (value & 0xFFFF_FFFF, value >> 32);
+                self.siglblid = (self.siglblid & !mask) | (id & mask);
+                self.signal(CSR_SIGNAL)
+            }
+            FINISH => self.signal(CSR_FINISH),
+            LABEL => {
+                let (id, mask) = ((value & 0xFFFF_FFFF) << 32, value & !0xFFFF_FFFF);
+                self.siglblid = (self.siglblid & !mask) | (id & mask);
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Default for Gs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ps2 {
+    /// Reads half of a privileged GS register.
+    pub fn gs_read_u32(&self, addr: u32) -> u32 {
+        let value = self.gs.read_privileged(addr).unwrap_or(0);
+        (value >> ((addr & 4) * 8)) as u32
+    }
+
+    /// Writes half of a privileged GS register.
+    pub fn gs_write_u32(&mut self, addr: u32, value: u32) {
+        let Some(old) = self.gs.read_privileged(addr) else { return };
+        let new = if addr & !7 == CSR {
+            // the bits that do anything are all in the low half, and writing back the old value would clear them
+            if addr & 4 != 0 {
+                return;
+            }
+            value as u64
+        } else if addr & 4 != 0 {
+            (old & 0xFFFF_FFFF) | ((value as u64) << 32)
+        } else {
+            (old & !0xFFFF_FFFF) | value as u64
+        };
+        if self.gs.write_privileged(addr, new) {
+            self.raise_interrupt(Interrupt::Gs);
+        }
+    }
+
+    /// Writes a GS general register, as the GIF does.
+    pub fn gs_write_register(&mut self, addr: u8, value: u64) {
+        if self.gs.write_register(addr, value) {
+            self.raise_interrupt(Interrupt::Gs);
+        }
+    }
+
+    pub fn gs_hsync(&mut self) {
+        if self.gs.signal(CSR_HSINT) {
+            self.raise_interrupt(Interrupt::Gs);
+        }
+    }
+
+    pub fn gs_vsync(&mut self) {
+        if self.gs.vsync() {
+            self.raise_interrupt(Interrupt::Gs);
+        }
+    }
+}
+
+impl Savestate for Gs {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32_slice(&self.vram);
+        for reg in &self.regs {
+            w.write_u64(*reg);
+        }
+        w.write_u64(self.pmode);
+        w.write_u64(self.smode1);
+        w.write_u64(self.smode2);
+        w.write_u64(self.srfsh);
+        w.write_u64(self.synch1);
+        w.write_u64(self.synch2);
+        w.write_u64(self.syncv);
+        w.write_u64(self.dispfb[0]);
+        w.write_u64(self.dispfb[1]);
+        w.write_u64(self.display[0]);
+        w.write_u64(self.display[1]);
+        w.write_u64(self.extbuf);
+        w.write_u64(self.extdata);
+        w.write_u64(self.extwrite);
+        w.write_u64(self.bgcolor);
+        w.write_u64(self.csr);
+        w.write_u64(self.imr);
+        w.write_u64(self.busdir);
+        w.write_u64(self.siglblid);
+        w.write_u32(self.vertex_queue.len() as u32);
+        for vertex in &self.vertex_queue {
+            vertex.save_state(w);
+        }
+        for entry in &self.clut {
+            w.write_u16(*entry);
+        }
+        w.write_u32(self.cbp0);
+        w.write_u32(self.cbp1);
+        self.transfer.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_u32_slice_into(&mut self.vram)?;
+        for reg in &mut self.regs {
+            *reg = r.read_u64()?;
+        }
+        self.pmode = r.read_u64()?;
+        self.smode1 = r.read_u64()?;
+        self.smode2 = r.read_u64()?;
+        self.srfsh = r.read_u64()?;
+        self.synch1 = r.read_u64()?;
+        self.synch2 = r.read_u64()?;
+        self.syncv = r.read_u64()?;
+        self.dispfb[0] = r.read_u64()?;
+        self.dispfb[1] = r.read_u64()?;
+        self.display[0] = r.read_u64()?;
+        self.display[1] = r.read_u64()?;
+        self.extbuf = r.read_u64()?;
+        self.extdata = r.read_u64()?;
+        self.extwrite = r.read_u64()?;
+        self.bgcolor = r.read_u64()?;
+        self.csr = r.read_u64()?;
+        self.imr = r.read_u64()?;
+        self.busdir = r.read_u64()?;
+        self.siglblid = r.read_u64()?;
+        self.vertex_queue.clear();
+        if r.version() >= 8 {
+            for _ in 0..r.read_u32()? {
+                let mut vertex = Vertex::default();
+                vertex.load_state(r)?;
+                self.vertex_queue.push(vertex);
+            }
+        }
+        if r.version() >= 9 {
+            for entry in &mut self.clut {
+                *entry = r.read_u16()?;
+            }
+            self.cbp0 = r.read_u32()?;
+            self.cbp1 = r.read_u32()?;
+        } else {
+            self.clut = [0; texture::CLUT_ENTRIES];
+            self.cbp0 = 0;
+            self.cbp1 = 0;
+        }
+        if r.version() >= 10 {
+            self.transfer.load_state(r)?;
+        } else {
+            self.transfer = Transfer::new();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An XYZ2 value for a pixel position, with no subpixel part and Z 0.
+    pub fn xyz(x: u16, y: u16) -> u64 {
+        ((y as u64) << 20) | ((x as u64) << 4)
+    }
+
+    pub fn rgbaq(color: u32, q: f32) -> u64 {
+        color as u64 | (q.to_bits() as u64) << 32
+    }
+
+    pub fn rgba(color: u32) -> u64 {
+        rgbaq(color, 1.0)
+    }
+
+    /// A 64 pixel wide frame of the given format at 0, with a 1024x1024 scissor and no offset.
+    pub fn setup(psm: u32) -> Gs {
+        let mut gs = Gs::new();
+        gs.write_register(FRAME_1, 1 << 16 | (psm as u64) << 24);
+        gs.write_register(SCISSOR_1, 1023 << 16 | 1023 << 48);
+        gs.write_register(PRMODECONT, 1);
+        gs
+    }
+
+    #[test]
+    fn test_vertex_kick() {
+        // XYZ2 kicks a vertex with the colour RGBAQ left, and a point draws straight away
+        let mut gs = setup(swizzle::PSMCT32);
+        gs.write_register(PRIM, raster::PRIM_POINT);
+        gs.write_register(RGBAQ, rgba(0x8011_2233));
+        gs.write_register(XYZ2, xyz(3, 2));
+        assert_eq!(0x8011_2233, gs.read_pixel(swizzle::PSMCT32, 0, 1, 3, 2));
+    }
+
+    #[test]
+    fn test_privileged_halves() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.write_ee_u32(0xB200_0070, 0x1234_5678);
+        ps2.write_ee_u32(0xB200_0074, 0x9ABC_DEF0);
+        assert_eq!(0x9ABC_DEF0_1234_5678, ps2.gs.dispfb[0]);
+        assert_eq!(0x1234_5678, ps2.read_ee_u32(0xB200_0070));
+        assert_eq!(0x9ABC_DEF0, ps2.read_ee_u32(0xB200_0074));
+        assert_eq!(0x551B_4000, ps2.read_ee_u32(0xB200_1000));
+    }
+
+    #[test]
+    fn test_csr_interrupts() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.write_ee_u32(0xB000_F010, 1 << Interrupt::Gs as u32);
+
+        // masked by IMR from reset, but still latched in CSR
+        ps2.gs_write_register(FINISH, 0);
+        assert_eq!(CSR_FINISH, ps2.gs.csr);
+        assert_eq!(0, ps2.intc.stat);
+
+        // unmasking a pending interrupt delivers it
+        ps2.write_ee_u32(0xB200_1010, 0x7D00);
+        assert_eq!(1 << Interrupt::Gs as u32, ps2.intc.stat);
+        ps2.write_ee_u32(0xB000_F000, 1 << Interrupt::Gs as u32);
+        ps2.write_ee_u32(0xB200_1000, CSR_FINISH as u32);
+        assert_eq!(0, ps2.gs.csr);
+
+        // SIGNAL updates only the masked bits of the ID
+        ps2.gs.siglblid = 0xFFFF_FFFF;
+        ps2.write_ee_u32(0xB200_1010, 0x7E00);
+        ps2.gs_write_register(SIGNAL, 0x0000_FF00_0000_1234);
+        assert_eq!(0xFFFF_12FF, ps2.gs.siglblid);
+        assert_eq!(1 << Interrupt::Gs as u32, ps2.intc.stat);
+
+        ps2.gs_write_register(LABEL, 0x0000_FFFF_0000_ABCD);
+        assert_eq!(0x0000_ABCD_FFFF_12FF, ps2.gs.siglblid);
+    }
+
+    #[test]
+    fn test_vsync() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.gs_vsync();
+        assert_eq!(CSR_VSINT | CSR_FIELD, ps2.gs.csr);
+        ps2.write_ee_u32(0xB200_1000, CSR_VSINT as u32);
+        ps2.gs_vsync();
+        assert_eq!(CSR_VSINT, ps2.gs.csr);
+    }
+}
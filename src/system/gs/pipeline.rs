@@ -0,0 +1,342 @@
+use super::raster::{pack_color, unpack_color, DrawContext, Fragment, PRIM_TME};
+use super::swizzle::*;
+use super::texture::texture_function;
+use super::*;
+
+/*
+    The per pixel pipeline, which everything a primitive draws goes through.
+
+    In order: the texture function, fog, the alpha test, the destination alpha test, the depth test,
+    alpha blending, dithering, colour clamping, FBA, and finally the writes to the frame and Z
+    buffers through their masks.
+
+    The alpha test doesn't have to throw the pixel away, AFAIL can keep the frame or Z write, or
+    just the RGB part of the frame write. Blending works on 9 bit signed differences shifted right
+    by 7, so 0x80 is 1.0 and alpha above that brightens. With COLCLAMP off the result wraps to 8 bits
+    instead of saturating.
+
+    Leaving TEST.ZTE off isn't allowed by the manual. It's treated as the depth test always passing
+    and the Z buffer never being written, which is what games that do it expect.
+*/
+
+/* PRIM */
+const PRIM_FGE: u64 = 1 << 5;
+const PRIM_ABE: u64 = 1 << 6;
+
+/* TEST */
+const TEST_ATE: u64 = 1;
+const TEST_DATE: u64 = 1 << 14;
+const TEST_DATM: u64 = 1 << 15;
+const TEST_ZTE: u64 = 1 << 16;
+
+/* alpha test methods */
+const ATST_NEVER: u64 = 0;
+const ATST_ALWAYS: u64 = 1;
+const ATST_LESS: u64 = 2;
+const ATST_LEQUAL: u64 = 3;
+const ATST_EQUAL: u64 = 4;
+const ATST_GEQUAL: u64 = 5;
+const ATST_GREATER: u64 = 6;
+
+/* what happens when the alpha test fails */
+const AFAIL_KEEP: u64 = 0;
+const AFAIL_FB_ONLY: u64 = 1;
+const AFAIL_ZB_ONLY: u64 = 2;
+
+/* depth test methods */
+const ZTST_NEVER: u64 = 0;
+const ZTST_ALWAYS: u64 = 1;
+const ZTST_GEQUAL: u64 = 2;
+
+/// ZBUF.ZMSK, set to leave the Z buffer alone.
+const ZBUF_ZMSK: u64 = 1 << 32;
+
+fn is_16bit(psm: u32) -> bool {
+    matches!(psm, PSMCT16 | PSMCT16S | PSMZ16 | PSMZ16S)
+}
+
+/// The largest Z a Z buffer format can hold.
+fn z_max(psm: u32) -> u32 {
+    match psm {
+        PSMZ24 => 0xFF_FFFF,
+        PSMZ16 | PSMZ16S => 0xFFFF,
+        _ => 0xFFFF_FFFF,
+    }
+}
+
+/// Turns an FBMSK in 32 bit layout into one for a 16 bit frame, keeping the top bits of each channel.
+pub fn frame_mask_16(mask: u32) -> u32 {
+    ((mask >> 3) & 0x1F) | ((mask >> 6) & 0x3E0) | ((mask >> 9) & 0x7C00) | ((mask >> 16) & 0x8000)
+}
+
+fn alpha_test(test: u64, alpha: u8) -> bool {
+    let aref = ((test >> 4) & 0xFF) as u8;
+    match (test >> 1) & 7 {
+        ATST_NEVER => false,
+        ATST_ALWAYS => true,
+        ATST_LESS => alpha < aref,
+        ATST_LEQUAL => alpha <= aref,
+        ATST_EQUAL => alpha == aref,
+        ATST_GEQUAL => alpha >= aref,
+        ATST_GREATER => alpha > aref,
+        _ => alpha != aref,
+    }
+}
+
+/// ALPHA's ((A - B) * C >> 7) + D for one channel, before clamping.
+pub fn blend(alpha: u64, source: [u8; 4], dest: [u8; 4], channel: usize) -> i32 {
+    let color = |select: u64| match select & 3 {
+        0 => source[channel] as i32,
+        1 => dest[channel] as i32,
+        _ => 0,
+    };
+    let c = match (alpha >> 4) & 3 {
+        0 => source[3] as i32,
+        1 => dest[3] as i32,
+        _ => ((alpha >> 32) & 0xFF) as i32,
+    };
+    (((color(alpha) - color(alpha >> 2)) * c) >> 7) + color(alpha >> 6)
+}
+
+impl Gs {
+    /// Runs a pixel through the pipeline and writes whatever survives to the frame and Z buffers.
+    pub fn draw_pixel(&mut self, ctx: &DrawContext, fragment: &Fragment) {
+        let (x, y) = (fragment.x as u32, fragment.y as u32);
+        let mut rgba = if ctx.attributes & PRIM_TME != 0 {
+            texture_function(ctx.tex0, fragment.rgba, self.texture_color(ctx, fragment))
+        } else {
+            fragment.rgba
+        };
+        if ctx.attributes & PRIM_FGE != 0 {
+            let f = fragment.fog as u32;
+            let fogcol = (ctx.fogcol as u32).to_le_bytes();
+            for c in 0..3 {
+                rgba[c] = ((f * rgba[c] as u32 + (255 - f) * fogcol[c] as u32) >> 8) as u8;
+            }
+        }
+
+        let mut frame_mask = (ctx.frame >> 32) as u32;
+        let mut z_write = ctx.test & TEST_ZTE != 0 && ctx.zbuf & ZBUF_ZMSK == 0;
+        if ctx.test & TEST_ATE != 0 && !alpha_test(ctx.test, rgba[3]) {
+            match (ctx.test >> 12) & 3 {
+                AFAIL_KEEP => return,
+                AFAIL_FB_ONLY => z_write = false,
+                AFAIL_ZB_ONLY => frame_mask = 0xFFFF_FFFF,
+                _ => {
+                    frame_mask |= 0xFF00_0000;
+                    z_write = false;
+                }
+            }
+        }
+
+        let psm = ctx.frame_psm;
+        let old = self.read_pixel(psm, ctx.frame_bp, ctx.frame_bw, x, y);
+        if ctx.test & TEST_DATE != 0 && psm != PSMCT24 {
+            let alpha_bit = if is_16bit(psm) { old & 0x8000 != 0 } else { old & 0x8000_0000 != 0 };
+            if alpha_bit != (ctx.test & TEST_DATM != 0) {
+                return;
+            }
+        }
+
+        // the Z buffer shares the frame's width
+        let z_psm = ctx.zbuf_psm;
+        let z = fragment.z.min(z_max(z_psm));
+        if ctx.test & TEST_ZTE != 0 {
+            let old_z = self.read_pixel(z_psm, ctx.zbuf_bp, ctx.frame_bw, x, y);
+            let pass = match (ctx.test >> 17) & 3 {
+                ZTST_NEVER => false,
+                ZTST_ALWAYS => true,
+                ZTST_GEQUAL => z >= old_z,
+                _ => z > old_z,
+            };
+            if !pass {
+                return;
+            }
+        }
+
+        let mut dest = unpack_color(psm, old);
+        if psm == PSMCT24 {
+            dest[3] = 0x80;
+        }
+        let blending = ctx.attributes & PRIM_ABE != 0 && !(ctx.pabe && rgba[3] < 0x80);
+        let dither = ctx.dthe && is_16bit(psm);
+        let mut out = rgba;
+        for c in 0..3 {
+            let mut value = if blending { blend(ctx.alpha, rgba, dest, c) } else { rgba[c] as i32 };
+            if dither {
+                let shift = (y & 3) * 16 + (x & 3) * 4;
+                value += (((ctx.dimx >> shift) & 7) as i32) << 29 >> 29;
+            }
+            out[c] = if ctx.colclamp { value.clamp(0, 255) as u8 } else { value as u8 };
+        }
+        if ctx.fba {
+            out[3] |= 0x80;
+        }
+
+        let mask = if is_16bit(psm) { frame_mask_16(frame_mask) } else { frame_mask };
+        let bits = field_mask(bits_per_pixel(psm));
+        if mask & bits != bits {
+            let color = (pack_color(psm, out) & !mask) | (old & mask);
+            self.write_pixel(psm, ctx.frame_bp, ctx.frame_bw, x, y, color);
+        }
+        if z_write {
+            self.write_pixel(z_psm, ctx.zbuf_bp, ctx.frame_bw, x, y, z);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::{rgba, setup, xyz};
+
+    /// A 64 pixel wide frame at 0 with a Z32 buffer after it.
+    fn setup_depth(psm: u32) -> Gs {
+        let mut gs = setup(psm);
+        gs.write_register(ZBUF_1, 0x100);
+        gs
+    }
+
+    fn point(gs: &mut Gs, prim: u64, color: u32, z: u32) {
+        gs.write_register(PRIM, prim);
+        gs.write_register(RGBAQ, rgba(color));
+        gs.write_register(XYZ2, xyz(0, 0) | (z as u64) << 32);
+    }
+
+    fn frame(gs: &Gs, psm: u32) -> u32 {
+        gs.read_pixel(psm, 0, 1, 0, 0)
+    }
+
+    fn depth(gs: &Gs) -> u32 {
+        gs.read_pixel(PSMZ32, 0x100 * 32, 1, 0, 0)
+    }
+
+    #[test]
+    fn test_alpha_test_fail_modes() {
+        let mut gs = setup_depth(PSMCT32);
+        let test = |afail: u64| TEST_ATE | ATST_GEQUAL << 1 | 0x40 << 4 | afail << 12 | TEST_ZTE | ZTST_ALWAYS << 17;
+        gs.write_pixel(PSMCT32, 0, 1, 0, 0, 0x1122_3344);
+
+        gs.write_register(TEST_1, test(AFAIL_KEEP));
+        point(&mut gs, 0, 0x3FAA_BBCC, 5);
+        assert_eq!((0x1122_3344, 0), (frame(&gs, PSMCT32), depth(&gs)));
+
+        gs.write_register(TEST_1, test(AFAIL_FB_ONLY));
+        point(&mut gs, 0, 0x3FAA_BBCC, 5);
+        assert_eq!((0x3FAA_BBCC, 0), (frame(&gs, PSMCT32), depth(&gs)));
+
+        gs.write_register(TEST_1, test(AFAIL_ZB_ONLY));
+        point(&mut gs, 0, 0x3F00_0000, 6);
+        assert_eq!((0x3FAA_BBCC, 6), (frame(&gs, PSMCT32), depth(&gs)));
+
+        // RGB only leaves the alpha and Z alone
+        gs.write_register(TEST_1, test(3));
+        point(&mut gs, 0, 0x0001_0203, 7);
+        assert_eq!((0x3F01_0203, 6), (frame(&gs, PSMCT32), depth(&gs)));
+
+        // passing writes everything
+        point(&mut gs, 0, 0x4001_0203, 8);
+        assert_eq!((0x4001_0203, 8), (frame(&gs, PSMCT32), depth(&gs)));
+    }
+
+    #[test]
+    fn test_depth_and_destination_alpha() {
+        let mut gs = setup_depth(PSMCT32);
+        gs.write_register(TEST_1, TEST_ZTE | ZTST_GEQUAL << 17);
+        point(&mut gs, 0, 0x11, 100);
+        point(&mut gs, 0, 0x22, 99);
+        assert_eq!((0x11, 100), (frame(&gs, PSMCT32), depth(&gs)));
+        point(&mut gs, 0, 0x33, 100);
+        assert_eq!(0x33, frame(&gs, PSMCT32));
+
+        gs.write_register(TEST_1, TEST_ZTE | 3 << 17);
+        point(&mut gs, 0, 0x44, 100);
+        assert_eq!(0x33, frame(&gs, PSMCT32));
+
+        // ZMSK keeps the Z buffer as it was
+        gs.write_register(ZBUF_1, 0x100 | ZBUF_ZMSK);
+        point(&mut gs, 0, 0x55, 200);
+        assert_eq!((0x55, 100), (frame(&gs, PSMCT32), depth(&gs)));
+
+        // Z16 clamps rather than wrapping
+        gs.write_register(ZBUF_1, 0x100 | (PSMZ16 as u64 & 0xF) << 24);
+        gs.write_register(TEST_1, TEST_ZTE | ZTST_ALWAYS << 17);
+        point(&mut gs, 0, 0x55, 0x12_3456);
+        assert_eq!(0xFFFF, gs.read_pixel(PSMZ16, 0x100 * 32, 1, 0, 0));
+
+        // the destination alpha test only draws over pixels with the alpha bit clear when DATM is 0
+        gs.write_register(TEST_1, TEST_DATE | TEST_ZTE | ZTST_ALWAYS << 17);
+        gs.write_pixel(PSMCT32, 0, 1, 0, 0, 0x8000_0000);
+        point(&mut gs, 0, 0x66, 0);
+        assert_eq!(0x8000_0000, frame(&gs, PSMCT32));
+        gs.write_register(TEST_1, TEST_DATE | TEST_DATM | TEST_ZTE | ZTST_ALWAYS << 17);
+        point(&mut gs, 0, 0x66, 0);
+        assert_eq!(0x66, frame(&gs, PSMCT32));
+    }
+
+    #[test]
+    fn test_blending() {
+        let source = [0x40, 0x80, 0xFF, 0x40];
+        let dest = [0x80, 0x00, 0x10, 0x80];
+        // (Cs - Cd) * As >> 7 + Cd, the usual transparency
+        let alpha = 1 << 2 | 1 << 6;
+        assert_eq!([0x60, 0x40, 0x87], [0, 1, 2].map(|c| blend(alpha, source, dest, c)));
+        // (Cs - 0) * FIX + Cd can go past 255
+        let alpha = 2 << 2 | 2 << 4 | 1 << 6 | 0x80 << 32;
+        assert_eq!([0xC0, 0x80, 0x10F], [0, 1, 2].map(|c| blend(alpha, source, dest, c)));
+        // (0 - Cs) * Ad + Cd goes negative, rounding down
+        let alpha = 2 | 1 << 4 | 1 << 6;
+        assert_eq!([0x40, -0x80, -0xEF], [0, 1, 2].map(|c| blend(alpha, source, dest, c)));
+
+        let mut gs = setup_depth(PSMCT32);
+        gs.write_register(TEST_1, TEST_ZTE | ZTST_ALWAYS << 17);
+        gs.write_pixel(PSMCT32, 0, 1, 0, 0, 0x8010_0080);
+        gs.write_register(ALPHA_1, 2 << 2 | 2 << 4 | 1 << 6 | 0x80 << 32);
+        point(&mut gs, PRIM_ABE, 0x40FF_8040, 0);
+        // clamping off wraps the blue channel, and the alpha is the source's
+        assert_eq!(0x400F_80C0, frame(&gs, PSMCT32));
+
+        gs.write_pixel(PSMCT32, 0, 1, 0, 0, 0x8010_0080);
+        gs.write_register(COLCLAMP, 1);
+        gs.write_register(FBA_1, 1);
+        point(&mut gs, PRIM_ABE, 0x40FF_8040, 0);
+        assert_eq!(0xC0FF_80C0, frame(&gs, PSMCT32));
+
+        // PABE skips blending for pixels with the top alpha bit clear
+        gs.write_pixel(PSMCT32, 0, 1, 0, 0, 0x8010_0080);
+        gs.write_register(PABE, 1);
+        point(&mut gs, PRIM_ABE, 0x40FF_8040, 0);
+        assert_eq!(0xC0FF_8040, frame(&gs, PSMCT32));
+    }
+
+    #[test]
+    fn test_fog_dither_and_masks() {
+        let mut gs = setup_depth(PSMCT32);
+        gs.write_register(TEST_1, TEST_ZTE | ZTST_ALWAYS << 17);
+        gs.write_register(FOGCOL, 0x00_FF00);
+        gs.write_register(FOG, 0x80 << 56);
+        point(&mut gs, PRIM_FGE, 0x8000_00FF, 0);
+        assert_eq!(0x8000_7E7F, frame(&gs, PSMCT32));
+
+        // FBMSK keeps the bits that are set
+        gs.write_register(FRAME_1, 1 << 16 | 0xFF00_00F0 << 32);
+        point(&mut gs, 0, 0x1122_3344, 0);
+        assert_eq!(0x8022_3374, frame(&gs, PSMCT32));
+
+        // in a 16 bit frame only the top bits of each channel in the mask count
+        let mut gs = setup_depth(PSMCT16);
+        gs.write_register(TEST_1, TEST_ZTE | ZTST_ALWAYS << 17);
+        gs.write_pixel(PSMCT16, 0, 1, 0, 0, 0xFFFF);
+        gs.write_register(FRAME_1, 1 << 16 | (PSMCT16 as u64) << 24 | 0x8000_F800 << 32);
+        point(&mut gs, 0, 0x0000_0000, 0);
+        assert_eq!(0x83E0, frame(&gs, PSMCT16));
+
+        // dithering adds DIMX for the pixel's position in 16 bit frames, -4 at (0, 0) here
+        gs.write_register(FRAME_1, 1 << 16 | (PSMCT16 as u64) << 24);
+        gs.write_register(DTHE, 1);
+        gs.write_register(DIMX, 4);
+        gs.write_register(COLCLAMP, 1);
+        point(&mut gs, 0, 0x0000_1010, 0);
+        assert_eq!(0x0021, frame(&gs, PSMCT16));
+    }
+}
@@ -0,0 +1,516 @@
+use std::io;
+
+use super::swizzle::{PSMCT16, PSMCT16S, PSMCT24, PSMZ16, PSMZ16S, PSMZ24};
+use super::*;
+use crate::system::savestate::{Savestate, StateReader, StateWriter};
+
+/*
+    Drawing, done on the CPU.
+
+    Writing XYZ2 or XYZF2 adds a vertex to the queue, capturing the current RGBAQ, ST, UV and FOG, and
+    draws a primitive once there are enough of them for the type set by PRIM. XYZ3 and XYZF3 add the
+    vertex without drawing, which is how strips are started part way through.
+
+    Vertex coordinates are 12.4 fixed point in a 4096x4096 space, which XYOFFSET moves the frame
+    buffer around in. A pixel is drawn when its top left corner is inside the primitive, with ties
+    going to the top and left edges so that triangles sharing an edge don't both draw it. Points and
+    lines round to the nearest pixel instead.
+
+    The result of each pixel goes through Gs::draw_pixel in pipeline.rs, with the registers of
+    whichever context PRIM picks.
+*/
+
+pub const PRIM_POINT: u64 = 0;
+pub const PRIM_LINE: u64 = 1;
+pub const PRIM_LINE_STRIP: u64 = 2;
+pub const PRIM_TRIANGLE: u64 = 3;
+pub const PRIM_TRIANGLE_STRIP: u64 = 4;
+pub const PRIM_TRIANGLE_FAN: u64 = 5;
+pub const PRIM_SPRITE: u64 = 6;
+
+/* PRIM and PRMODE attributes */
+const PRIM_IIP: u64 = 1 << 3;
+pub const PRIM_TME: u64 = 1 << 4;
+pub const PRIM_FST: u64 = 1 << 8;
+const PRIM_CTXT: u64 = 1 << 9;
+
+/// PRMODECONT.AC, whether the attributes come from PRIM rather than PRMODE.
+const PRMODECONT_AC: u64 = 1;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Vertex {
+    /* 12.4 fixed point, before XYOFFSET */
+    pub x: u16,
+    pub y: u16,
+    pub z: u32,
+    pub fog: u8,
+    pub rgba: [u8; 4],
+    pub s: f32,
+    pub t: f32,
+    pub q: f32,
+
+    /* 10.4 fixed point texel coordinates */
+    pub u: u16,
+    pub v: u16
+}
+
+/// What a primitive produces for one pixel.
+#[derive(Clone, Copy, Debug)]
+pub struct Fragment {
+    pub x: i32,
+    pub y: i32,
+    pub z: u32,
+    pub fog: u8,
+    pub rgba: [u8; 4],
+    pub s: f32,
+    pub t: f32,
+    pub q: f32,
+
+    /* in texels */
+    pub u: f32,
+    pub v: f32
+}
+
+/// The registers a primitive is drawn with, picked out of the context PRIM selects.
+#[derive(Clone, Copy, Debug)]
+pub struct DrawContext {
+    pub attributes: u64,
+    pub context: usize,
+    pub frame: u64,
+    pub frame_bp: u32,
+    pub frame_bw: u32,
+    pub frame_psm: u32,
+    pub offset_x: i32,
+    pub offset_y: i32,
+    pub scissor: (i32, i32, i32, i32),
+
+    /* texturing */
+    pub tex0: u64,
+    pub tex1: u64,
+    pub clamp: u64,
+    pub miptbp1: u64,
+    pub miptbp2: u64,
+    pub texa: u64,
+
+    /* the pixel pipeline */
+    pub test: u64,
+    pub alpha: u64,
+    pub zbuf_bp: u32,
+    pub zbuf_psm: u32,
+    pub zbuf: u64,
+    pub fba: bool,
+    pub pabe: bool,
+    pub fogcol: u64,
+    pub dthe: bool,
+    pub dimx: u64,
+    pub colclamp: bool
+}
+
+impl DrawContext {
+    fn gouraud(&self) -> bool {
+        self.attributes & PRIM_IIP != 0
+    }
+}
+
+/// Packs a colour for a frame buffer format.
+pub fn pack_color(psm: u32, rgba: [u8; 4]) -> u32 {
+    let [r, g, b, a] = rgba.map(|c| c as u32);
+    match psm {
+        PSMCT16 | PSMCT16S | PSMZ16 | PSMZ16S => (r >> 3) | ((g >> 3) << 5) | ((b >> 3) << 10) | ((a >> 7) << 15),
+        PSMCT24 | PSMZ24 => r | (g << 8) | (b << 16),
+        _ => r | (g << 8) | (b << 16) | (a << 24),
+    }
+}
+
+/// Unpacks a frame buffer colour, the reverse of pack_color. 16 bit alpha comes back as 0 or 0x80.
+pub fn unpack_color(psm: u32, value: u32) -> [u8; 4] {
+    match psm {
+        PSMCT16 | PSMCT16S | PSMZ16 | PSMZ16S => [
+            ((value & 0x1F) << 3) as u8,
+            (((value >> 5) & 0x1F) << 3) as u8,
+            (((value >> 10) & 0x1F) << 3) as u8,
+            if value & 0x8000 != 0 { 0x80 } else { 0 },
+        ],
+        _ => value.to_le_bytes(),
+    }
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+impl Gs {
+    /// Which PRIM attributes are in force, going by PRMODECONT.
+    pub fn prim_attributes(&self) -> u64 {
+        if self.regs[PRMODECONT as usize] & PRMODECONT_AC != 0 {
+            self.regs[PRIM as usize]
+        } else {
+            self.regs[PRMODE as usize]
+        }
+    }
+
+    pub fn draw_context(&self) -> DrawContext {
+        let attributes = self.prim_attributes();
+        let context = ((attributes & PRIM_CTXT) >> 9) as usize;
+        let frame = self.regs[FRAME_1 as usize + context];
+        let offset = self.regs[XYOFFSET_1 as usize + context];
+        let scissor = self.regs[SCISSOR_1 as usize + context];
+        let zbuf = self.regs[ZBUF_1 as usize + context];
+        DrawContext {
+            attributes,
+            context,
+            frame,
+            frame_bp: (frame & 0x1FF) as u32 * 32,
+            frame_bw: ((frame >> 16) & 0x3F) as u32,
+            frame_psm: ((frame >> 24) & 0x3F) as u32,
+            offset_x: (offset & 0xFFFF) as i32,
+            offset_y: ((offset >> 32) & 0xFFFF) as i32,
+            scissor: (
+                (scissor & 0x7FF) as i32,
+                ((scissor >> 16) & 0x7FF) as i32,
+                ((scissor >> 32) & 0x7FF) as i32,
+                ((scissor >> 48) & 0x7FF) as i32,
+            ),
+            tex0: self.regs[TEX0_1 as usize + context],
+            tex1: self.regs[TEX1_1 as usize + context],
+            clamp: self.regs[CLAMP_1 as usize + context],
+            miptbp1: self.regs[MIPTBP1_1 as usize + context],
+            miptbp2: self.regs[MIPTBP2_1 as usize + context],
+            texa: self.regs[TEXA as usize],
+            test: self.regs[TEST_1 as usize + context],
+            alpha: self.regs[ALPHA_1 as usize + context],
+            zbuf_bp: (zbuf & 0x1FF) as u32 * 32,
+            zbuf_psm: ((zbuf >> 24) & 0xF) as u32 | 0x30,
+            zbuf,
+            fba: self.regs[FBA_1 as usize + context] & 1 != 0,
+            pabe: self.regs[PABE as usize] & 1 != 0,
+            fogcol: self.regs[FOGCOL as usize],
+            dthe: self.regs[DTHE as usize] & 1 != 0,
+            dimx: self.regs[DIMX as usize],
+            colclamp: self.regs[COLCLAMP as usize] & 1 != 0,
+        }
+    }
+
+    /// PRIM was written, which starts a new primitive.
+    pub(super) fn reset_vertex_queue(&mut self) {
+        self.vertex_queue.clear();
+    }
+
+    /// Adds a vertex from an XYZ register, drawing if it completes a primitive.
+    pub(super) fn vertex_kick(&mut self, value: u64, with_fog: bool, draw: bool) {
+        let rgbaq = self.regs[RGBAQ as usize];
+        let st = self.regs[ST as usize];
+        let uv = self.regs[UV as usize];
+        let (z, fog) = if with_fog {
+            (((value >> 32) & 0xFF_FFFF) as u32, (value >> 56) as u8)
+        } else {
+            ((value >> 32) as u32, (self.regs[FOG as usize] >> 56) as u8)
+        };
+        self.vertex_queue.push(Vertex {
+            x: value as u16,
+            y: (value >> 16) as u16,
+            z,
+            fog,
+            rgba: (rgbaq as u32).to_le_bytes(),
+            q: f32::from_bits((rgbaq >> 32) as u32),
+            s: f32::from_bits(st as u32),
+            t: f32::from_bits((st >> 32) as u32),
+            u: (uv & 0x3FFF) as u16,
+            v: ((uv >> 16) & 0x3FFF) as u16,
+        });
+
+        let prim = self.regs[PRIM as usize] & 7;
+        let needed = match prim {
+            PRIM_POINT => 1,
+            PRIM_LINE | PRIM_LINE_STRIP | PRIM_SPRITE => 2,
+            PRIM_TRIANGLE | PRIM_TRIANGLE_STRIP | PRIM_TRIANGLE_FAN => 3,
+            _ => {
+                self.vertex_queue.clear();
+                return;
+            }
+        };
+        if self.vertex_queue.len() < needed {
+            return;
+        }
+        if draw {
+            let queue = std::mem::take(&mut self.vertex_queue);
+            let ctx = self.draw_context();
+            match prim {
+                PRIM_POINT => self.draw_point(&ctx, &queue[0]),
+                PRIM_LINE | PRIM_LINE_STRIP => self.draw_line(&ctx, &queue[0], &queue[1]),
+                PRIM_SPRITE => self.draw_sprite(&ctx, &queue[0], &queue[1]),
+                _ => self.draw_triangle(&ctx, &queue[0], &queue[1], &queue[2]),
+            }
+            self.vertex_queue = queue;
+        }
+        // strips and fans keep the vertices the next one is drawn with
+        match prim {
+            PRIM_LINE_STRIP | PRIM_TRIANGLE_STRIP => { self.vertex_queue.remove(0); }
+            PRIM_TRIANGLE_FAN => { self.vertex_queue.remove(1); }
+            _ => self.vertex_queue.clear(),
+        }
+    }
+
+    fn in_scissor(ctx: &DrawContext, x: i32, y: i32) -> bool {
+        let (x0, x1, y0, y1) = ctx.scissor;
+        x >= x0 && x <= x1 && y >= y0 && y <= y1
+    }
+
+    /// Window coordinates of a vertex, still 12.4.
+    fn window_position(ctx: &DrawContext, v: &Vertex) -> (i64, i64) {
+        ((v.x as i32 - ctx.offset_x) as i64, (v.y as i32 - ctx.offset_y) as i64)
+    }
+
+    fn fragment(x: i32, y: i32, v: &Vertex) -> Fragment {
+        Fragment {
+            x, y, z: v.z, fog: v.fog, rgba: v.rgba, s: v.s, t: v.t, q: v.q,
+            u: v.u as f32 / 16.0, v: v.v as f32 / 16.0,
+        }
+    }
+
+    fn draw_point(&mut self, ctx: &DrawContext, v: &Vertex) {
+        let (x, y) = Self::window_position(ctx, v);
+        let fragment = Self::fragment(((x + 8) >> 4) as i32, ((y + 8) >> 4) as i32, v);
+        if Self::in_scissor(ctx, fragment.x, fragment.y) {
+            self.draw_pixel(ctx, &fragment);
+        }
+    }
+
+    /// Steps along the longer axis a pixel at a time, leaving off the last pixel so strips don't
+    /// draw their joins twice.
+    fn draw_line(&mut self, ctx: &DrawContext, v0: &Vertex, v1: &Vertex) {
+        let (x0, y0) = Self::window_position(ctx, v0);
+        let (x1, y1) = Self::window_position(ctx, v1);
+        let (px0, py0) = ((x0 + 8) >> 4, (y0 + 8) >> 4);
+        let (px1, py1) = ((x1 + 8) >> 4, (y1 + 8) >> 4);
+        let steps = (px1 - px0).abs().max((py1 - py0).abs());
+        for i in 0..steps {
+            let t = i as f64 / steps as f64;
+            let x = px0 + ((px1 - px0) * i + steps / 2).div_euclid(steps);
+            let y = py0 + ((py1 - py0) * i + steps / 2).div_euclid(steps);
+            if !Self::in_scissor(ctx, x as i32, y as i32) {
+                continue;
+            }
+            let fragment = Self::interpolate(ctx, x as i32, y as i32, &[(v0, 1.0 - t), (v1, t)], v1);
+            self.draw_pixel(ctx, &fragment);
+        }
+    }
+
+    /// Sprites are flat, taking everything but their texture coordinates from the second vertex.
+    fn draw_sprite(&mut self, ctx: &DrawContext, v0: &Vertex, v1: &Vertex) {
+        let (x0, y0) = Self::window_position(ctx, v0);
+        let (x1, y1) = Self::window_position(ctx, v1);
+        let (left, right) = (x0.min(x1), x0.max(x1));
+        let (top, bottom) = (y0.min(y1), y0.max(y1));
+        let (sx0, sx1, sy0, sy1) = ctx.scissor;
+        let px_start = ((left + 15) >> 4).max(sx0 as i64);
+        let px_end = ((right + 15) >> 4).min(sx1 as i64 + 1);
+        let py_start = ((top + 15) >> 4).max(sy0 as i64);
+        let py_end = ((bottom + 15) >> 4).min(sy1 as i64 + 1);
+        for py in py_start..py_end {
+            let ty = if y1 != y0 { (py * 16 - y0) as f64 / (y1 - y0) as f64 } else { 0.0 };
+            for px in px_start..px_end {
+                let tx = if x1 != x0 { (px * 16 - x0) as f64 / (x1 - x0) as f64 } else { 0.0 };
+                let mut fragment = Self::fragment(px as i32, py as i32, v1);
+                fragment.s = lerp(v0.s as f64, v1.s as f64, tx) as f32;
+                fragment.t = lerp(v0.t as f64, v1.t as f64, ty) as f32;
+                fragment.u = lerp(v0.u as f64, v1.u as f64, tx) as f32 / 16.0;
+                fragment.v = lerp(v0.v as f64, v1.v as f64, ty) as f32 / 16.0;
+                self.draw_pixel(ctx, &fragment);
+            }
+        }
+    }
+
+    fn draw_triangle(&mut self, ctx: &DrawContext, v0: &Vertex, v1: &Vertex, v2: &Vertex) {
+        let p0 = Self::window_position(ctx, v0);
+        let mut p1 = Self::window_position(ctx, v1);
+        let mut p2 = Self::window_position(ctx, v2);
+        let (mut v1, mut v2) = (v1, v2);
+        let flat = v2;
+        let edge = |a: (i64, i64), b: (i64, i64), c: (i64, i64)| (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0);
+        let mut area = edge(p0, p1, p2);
+        if area == 0 {
+            return;
+        }
+        if area < 0 {
+            std::mem::swap(&mut p1, &mut p2);
+            std::mem::swap(&mut v1, &mut v2);
+            area = -area;
+        }
+        // top edges (flat, going right) and left edges (going up) own the pixels on them
+        let top_left = |a: (i64, i64), b: (i64, i64)| b.1 < a.1 || (b.1 == a.1 && b.0 > a.0);
+        let bias = [top_left(p1, p2), top_left(p2, p0), top_left(p0, p1)].map(|owned| if owned { 0 } else { -1 });
+
+        let (sx0, sx1, sy0, sy1) = ctx.scissor;
+        let px_start = ((p0.0.min(p1.0).min(p2.0) + 15) >> 4).max(sx0 as i64);
+        let px_end = (p0.0.max(p1.0).max(p2.0) >> 4).min(sx1 as i64);
+        let py_start = ((p0.1.min(p1.1).min(p2.1) + 15) >> 4).max(sy0 as i64);
+        let py_end = (p0.1.max(p1.1).max(p2.1) >> 4).min(sy1 as i64);
+        for py in py_start..=py_end {
+            for px in px_start..=px_end {
+                let p = (px * 16, py * 16);
+                let w = [edge(p1, p2, p), edge(p2, p0, p), edge(p0, p1, p)];
+                if (0..3).any(|i| w[i] + bias[i] < 0) {
+                    continue;
+                }
+                let weights = w.map(|w| w as f64 / area as f64);
+                let fragment = Self::interpolate(ctx, px as i32, py as i32,
+                                                 &[(v0, weights[0]), (v1, weights[1]), (v2, weights[2])], flat);
+                self.draw_pixel(ctx, &fragment);
+            }
+        }
+    }
+
+    /// Blends the vertex attributes with the given weights, taking the colour from `flat` unless
+    /// the primitive is Gouraud shaded.
+    fn interpolate(ctx: &DrawContext, x: i32, y: i32, weighted: &[(&Vertex, f64)], flat: &Vertex) -> Fragment {
+        let sum = |f: &dyn Fn(&Vertex) -> f64| weighted.iter().map(|(v, w)| f(v) * w).sum::<f64>();
+        let rgba = if ctx.gouraud() {
+            [0, 1, 2, 3].map(|i| sum(&|v| v.rgba[i] as f64).round().clamp(0.0, 255.0) as u8)
+        } else {
+            flat.rgba
+        };
+        Fragment {
+            x, y,
+            z: sum(&|v| v.z as f64).round().clamp(0.0, u32::MAX as f64) as u32,
+            fog: sum(&|v| v.fog as f64).round().clamp(0.0, 255.0) as u8,
+            rgba,
+            s: sum(&|v| v.s as f64) as f32,
+            t: sum(&|v| v.t as f64) as f32,
+            q: sum(&|v| v.q as f64) as f32,
+            u: sum(&|v| v.u as f64) as f32 / 16.0,
+            v: sum(&|v| v.v as f64) as f32 / 16.0,
+        }
+    }
+}
+
+impl Savestate for Vertex {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.x);
+        w.write_u16(self.y);
+        w.write_u32(self.z);
+        w.write_u8(self.fog);
+        w.write_u32(u32::from_le_bytes(self.rgba));
+        w.write_f32(self.s);
+        w.write_f32(self.t);
+        w.write_f32(self.q);
+        w.write_u16(self.u);
+        w.write_u16(self.v);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.x = r.read_u16()?;
+        self.y = r.read_u16()?;
+        self.z = r.read_u32()?;
+        self.fog = r.read_u8()?;
+        self.rgba = r.read_u32()?.to_le_bytes();
+        self.s = r.read_f32()?;
+        self.t = r.read_f32()?;
+        self.q = r.read_f32()?;
+        self.u = r.read_u16()?;
+        self.v = r.read_u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::swizzle::PSMCT32;
+    use super::super::tests::{rgba, setup, xyz};
+
+    fn covered(gs: &Gs, w: u32, h: u32) -> Vec<(u32, u32)> {
+        (0..h).flat_map(|y| (0..w).map(move |x| (x, y)))
+            .filter(|&(x, y)| gs.read_pixel(PSMCT32, 0, 1, x, y) != 0)
+            .collect()
+    }
+
+    #[test]
+    fn test_sprite_scissor_and_offset() {
+        let mut gs = setup(PSMCT32);
+        gs.write_register(XYOFFSET_1, (100 << 4) | (200 << 4) << 32);
+        gs.write_register(SCISSOR_1, 2 | 63 << 16 | 1 << 32 | 63 << 48);
+        gs.write_register(PRIM, PRIM_SPRITE);
+        gs.write_register(RGBAQ, rgba(0x8040_2010));
+        gs.write_register(XYZ2, xyz(100, 200));
+        gs.write_register(XYZ2, xyz(104, 203));
+
+        let pixels = covered(&gs, 8, 8);
+        assert_eq!(4, pixels.len());
+        assert_eq!((2, 1), pixels[0]);
+        assert_eq!((3, 2), pixels[3]);
+        assert_eq!(0x8040_2010, gs.read_pixel(PSMCT32, 0, 1, 2, 1));
+    }
+
+    #[test]
+    fn test_shared_edges_draw_once() {
+        // two triangles making a square, each drawn on its own
+        let mut a = setup(PSMCT32);
+        let mut b = setup(PSMCT32);
+        for (gs, third) in [(&mut a, (0, 10)), (&mut b, (10, 0))] {
+            gs.write_register(PRIM, PRIM_TRIANGLE);
+            gs.write_register(RGBAQ, rgba(0xFFFF_FFFF));
+            gs.write_register(XYZ2, xyz(0, 0));
+            gs.write_register(XYZ2, xyz(third.0, third.1));
+            gs.write_register(XYZ2, xyz(10, 10));
+        }
+        let (a, b) = (covered(&a, 16, 16), covered(&b, 16, 16));
+        assert!(a.iter().all(|p| !b.contains(p)));
+        assert_eq!(100, a.len() + b.len());
+        assert!(a.iter().chain(&b).all(|&(x, y)| x < 10 && y < 10));
+    }
+
+    #[test]
+    fn test_shading() {
+        let mut gs = setup(PSMCT32);
+        gs.write_register(PRIM, PRIM_TRIANGLE | PRIM_IIP);
+        gs.write_register(RGBAQ, rgba(0));
+        gs.write_register(XYZ2, xyz(0, 0));
+        gs.write_register(XYZ2, xyz(32, 0));
+        gs.write_register(RGBAQ, rgba(0xFF));
+        gs.write_register(XYZ2, xyz(0, 32));
+        assert_eq!(0x80, gs.read_pixel(PSMCT32, 0, 1, 0, 16) & 0xFF);
+        assert_eq!(0, gs.read_pixel(PSMCT32, 0, 1, 16, 0));
+
+        // flat shading takes the last vertex
+        gs.write_register(PRIM, PRIM_TRIANGLE);
+        gs.write_register(XYZ2, xyz(40, 0));
+        gs.write_register(XYZ2, xyz(60, 0));
+        gs.write_register(RGBAQ, rgba(0x11));
+        gs.write_register(XYZ2, xyz(40, 20));
+        assert_eq!(0x11, gs.read_pixel(PSMCT32, 0, 1, 41, 1));
+    }
+
+    #[test]
+    fn test_strips_and_fans() {
+        let mut gs = setup(PSMCT32);
+        gs.write_register(RGBAQ, rgba(1));
+        gs.write_register(PRIM, PRIM_LINE_STRIP);
+        gs.write_register(XYZ2, xyz(0, 0));
+        gs.write_register(XYZ2, xyz(4, 0));
+        gs.write_register(XYZ2, xyz(4, 4));
+        // the end of the strip isn't drawn
+        assert_eq!(8, covered(&gs, 8, 8).len());
+        assert_eq!(0, gs.read_pixel(PSMCT32, 0, 1, 4, 4));
+
+        // XYZ3 moves a strip along without drawing
+        let mut gs = setup(PSMCT32);
+        gs.write_register(RGBAQ, rgba(1));
+        gs.write_register(PRIM, PRIM_TRIANGLE_FAN);
+        gs.write_register(XYZ2, xyz(0, 0));
+        gs.write_register(XYZ2, xyz(8, 0));
+        gs.write_register(XYZ3, xyz(8, 8));
+        assert_eq!(0, covered(&gs, 16, 16).len());
+        gs.write_register(XYZ2, xyz(0, 8));
+        assert_eq!(28, covered(&gs, 16, 16).len());
+    }
+
+    #[test]
+    fn test_frame_formats() {
+        let mut gs = setup(PSMCT16);
+        gs.write_register(PRIM, PRIM_POINT);
+        gs.write_register(RGBAQ, rgba(0x80F8_0800));
+        gs.write_register(XYZ2, xyz(3, 5));
+        assert_eq!(0x8000 | (1 << 5) | (0x1F << 10), gs.read_pixel(PSMCT16, 0, 1, 3, 5));
+    }
+}
@@ -0,0 +1,331 @@
+use super::Gs;
+
+/*
+    GS local memory isn't a linear frame buffer. It is split into 8KB pages, each page into 32
+    blocks of 256 bytes, and each block into 4 columns of 64 bytes. How a rectangle of pixels is
+    laid out across those depends on the pixel storage mode (PSM):
+
+        format                   page      block    blocks in a page
+        CT32, CT24, Z32, Z24     64x32     8x8      8 wide, 4 high
+        CT16, CT16S, Z16, Z16S   64x64     16x8     4 wide, 8 high
+        T8                       128x64    16x16    8 wide, 4 high
+        T4                       128x128   32x16    4 wide, 8 high
+
+    A buffer starts at a block (BP) and is some multiple of 64 pixels wide (BW), with pages filling
+    each row of the buffer left to right. The Z formats number their blocks differently from the
+    colour formats so that colour and depth buffers at the same address use different DRAM banks.
+
+    CT24 and Z24 leave the top byte of each word alone, which is where T8H keeps its pixels, and T4HL
+    and T4HH keep theirs in the two nibbles of it. So those three use the CT32 layout.
+*/
+
+pub const PSMCT32: u32 = 0x00;
+pub const PSMCT24: u32 = 0x01;
+pub const PSMCT16: u32 = 0x02;
+pub const PSMCT16S: u32 = 0x0A;
+pub const PSMT8: u32 = 0x13;
+pub const PSMT4: u32 = 0x14;
+pub const PSMT8H: u32 = 0x1B;
+pub const PSMT4HL: u32 = 0x24;
+pub const PSMT4HH: u32 = 0x2C;
+pub const PSMZ32: u32 = 0x30;
+pub const PSMZ24: u32 = 0x31;
+pub const PSMZ16: u32 = 0x32;
+pub const PSMZ16S: u32 = 0x3A;
+
+const VRAM_WORDS: usize = super::GS_VRAM_SIZE / 4;
+
+/// Block numbers within a page, by block row and column.
+pub const BLOCK_32: [[u8; 8]; 4] = [
+    [ 0,  1,  4,  5, 16, 17, 20, 21],
+    [ 2,  3,  6,  7, 18, 19, 22, 23],
+    [ 8,  9, 12, 13, 24, 25, 28, 29],
+    [10, 11, 14, 15, 26, 27, 30, 31],
+];
+
+pub const BLOCK_16: [[u8; 4]; 8] = [
+    [ 0,  2,  8, 10],
+    [ 1,  3,  9, 11],
+    [ 4,  6, 12, 14],
+    [ 5,  7, 13, 15],
+    [16, 18, 24, 26],
+    [17, 19, 25, 27],
+    [20, 22, 28, 30],
+    [21, 23, 29, 31],
+];
+
+pub const BLOCK_16S: [[u8; 4]; 8] = [
+    [ 0,  2, 16, 18],
+    [ 1,  3, 17, 19],
+    [ 8, 10, 24, 26],
+    [ 9, 11, 25, 27],
+    [ 4,  6, 20, 22],
+    [ 5,  7, 21, 23],
+    [12, 14, 28, 30],
+    [13, 15, 29, 31],
+];
+
+/// T8 and T4 share their block orders with CT32 and CT16.
+pub const BLOCK_8: [[u8; 8]; 4] = BLOCK_32;
+pub const BLOCK_4: [[u8; 4]; 8] = BLOCK_16;
+
+/// The Z formats' block numbers are the colour ones with the top two bits flipped.
+const Z_BLOCK_FLIP: u32 = 24;
+
+/*
+    Where each pixel of a block goes, in units of the pixel size. They are the same few bits of x and
+    y shuffled around, so they're built rather than typed out. In T8 and T4 every other column is
+    mirrored, which is the (y >> 2) term.
+*/
+
+const fn column_32() -> [[u8; 8]; 8] {
+    let mut table = [[0; 8]; 8];
+    let mut y = 0;
+    while y < 8 {
+        let mut x = 0;
+        while x < 8 {
+            table[y][x] = ((y >> 1) * 16 + (x >> 1) * 4 + (y & 1) * 2 + (x & 1)) as u8;
+            x += 1;
+        }
+        y += 1;
+    }
+    table
+}
+
+const fn column_16() -> [[u8; 16]; 8] {
+    let mut table = [[0; 16]; 8];
+    let mut y = 0;
+    while y < 8 {
+        let mut x = 0;
+        while x < 16 {
+            table[y][x] = ((y >> 1) * 32 + ((x >> 1) & 3) * 8 + (y & 1) * 4 + (x & 1) * 2 + (x >> 3)) as u8;
+            x += 1;
+        }
+        y += 1;
+    }
+    table
+}
+
+const fn column_8() -> [[u8; 16]; 16] {
+    let mut table = [[0; 16]; 16];
+    let mut y = 0;
+    while y < 16 {
+        let mut x = 0;
+        while x < 16 {
+            let swap = ((x >> 2) ^ (y >> 1) ^ (y >> 2)) & 1;
+            table[y][x] = ((y >> 2) * 64 + swap * 32 + ((x >> 1) & 1) * 16 + (y & 1) * 8 + (x & 1) * 4
+                + (x >> 3) * 2 + ((y >> 1) & 1)) as u8;
+            x += 1;
+        }
+        y += 1;
+    }
+    table
+}
+
+const fn column_4() -> [[u16; 32]; 16] {
+    let mut table = [[0; 32]; 16];
+    let mut y = 0;
+    while y < 16 {
+        let mut x = 0;
+        while x < 32 {
+            let swap = ((x >> 2) ^ (y >> 1) ^ (y >> 2)) & 1;
+            table[y][x] = ((y >> 2) * 128 + swap * 64 + ((x >> 1) & 1) * 32 + (y & 1) * 16 + (x & 1) * 8
+                + ((x >> 3) & 3) * 2 + ((y >> 1) & 1)) as u16;
+            x += 1;
+        }
+        y += 1;
+    }
+    table
+}
+
+pub const COLUMN_32: [[u8; 8]; 8] = column_32();
+pub const COLUMN_16: [[u8; 16]; 8] = column_16();
+pub const COLUMN_8: [[u8; 16]; 16] = column_8();
+pub const COLUMN_4: [[u16; 32]; 16] = column_4();
+
+/// Where a pixel lives: the word in local memory, the bit it starts at and how many bits it has.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PixelAddress {
+    pub word: usize,
+    pub shift: u32,
+    pub bits: u32
+}
+
+/// Bits per pixel of a format, as far as the frame buffer and transfers are concerned.
+pub fn bits_per_pixel(psm: u32) -> u32 {
+    match psm {
+        PSMCT32 | PSMZ32 => 32,
+        PSMCT24 | PSMZ24 => 24,
+        PSMCT16 | PSMCT16S | PSMZ16 | PSMZ16S => 16,
+        PSMT8 | PSMT8H => 8,
+        _ => 4,
+    }
+}
+
+/// Works out where pixel (x, y) of a buffer at block `bp`, `bw` * 64 pixels wide, is stored.
+pub fn pixel_address(psm: u32, bp: u32, bw: u32, x: u32, y: u32) -> PixelAddress {
+    let z_flip = if psm & 0x30 == 0x30 { Z_BLOCK_FLIP } else { 0 };
+    let (word, shift, bits) = match psm {
+        PSMCT16 | PSMCT16S | PSMZ16 | PSMZ16S => {
+            let page = (y / 64) * bw + x / 64;
+            let blocks = if psm & 0x08 != 0 { &BLOCK_16S } else { &BLOCK_16 };
+            let block = blocks[(y as usize / 8) % 8][(x as usize / 16) % 4] as u32 ^ z_flip;
+            let halfword = bp * 128 + page * 4096 + block * 128 + COLUMN_16[y as usize % 8][x as usize % 16] as u32;
+            (halfword / 2, (halfword & 1) * 16, 16)
+        }
+        PSMT8 => {
+            let page = (y / 64) * (bw / 2) + x / 128;
+            let block = BLOCK_8[(y as usize / 16) % 4][(x as usize / 16) % 8] as u32;
+            let byte = bp * 256 + page * 8192 + block * 256 + COLUMN_8[y as usize % 16][x as usize % 16] as u32;
+            (byte / 4, (byte & 3) * 8, 8)
+        }
+        PSMT4 => {
+            let page = (y / 128) * (bw / 2) + x / 128;
+            let block = BLOCK_4[(y as usize / 16) % 8][(x as usize / 32) % 4] as u32;
+            let nibble = bp * 512 + page * 16384 + block * 512 + COLUMN_4[y as usize % 16][x as usize % 32] as u32;
+            (nibble / 8, (nibble & 7) * 4, 4)
+        }
+        _ => {
+            let page = (y / 32) * bw + x / 64;
+            let block = BLOCK_32[(y as usize / 8) % 4][(x as usize / 8) % 8] as u32 ^ z_flip;
+            let word = bp * 64 + page * 2048 + block * 64 + COLUMN_32[y as usize % 8][x as usize % 8] as u32;
+            match psm {
+                PSMCT24 | PSMZ24 => (word, 0, 24),
+                PSMT8H => (word, 24, 8),
+                PSMT4HL => (word, 24, 4),
+                PSMT4HH => (word, 28, 4),
+                _ => (word, 0, 32),
+            }
+        }
+    };
+    PixelAddress { word: word as usize % VRAM_WORDS, shift, bits }
+}
+
+/// A mask of the low `bits` bits.
+pub fn field_mask(bits: u32) -> u32 {
+    if bits == 32 { u32::MAX } else { (1 << bits) - 1 }
+}
+
+impl Gs {
+    /// Reads a pixel from local memory, as the low bits of the result.
+    pub fn read_pixel(&self, psm: u32, bp: u32, bw: u32, x: u32, y: u32) -> u32 {
+        let addr = pixel_address(psm, bp, bw, x, y);
+        (self.vram[addr.word] >> addr.shift) & field_mask(addr.bits)
+    }
+
+    /// Writes a pixel to local memory, leaving any other bits of the word alone.
+    pub fn write_pixel(&mut self, psm: u32, bp: u32, bw: u32, x: u32, y: u32, value: u32) {
+        let addr = pixel_address(psm, bp, bw, x, y);
+        let mask = field_mask(addr.bits) << addr.shift;
+        let word = &mut self.vram[addr.word];
+        *word = (*word & !mask) | ((value << addr.shift) & mask);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_FORMATS: [u32; 13] = [
+        PSMCT32, PSMCT24, PSMCT16, PSMCT16S, PSMT8, PSMT4, PSMT8H, PSMT4HL, PSMT4HH, PSMZ32, PSMZ24, PSMZ16, PSMZ16S,
+    ];
+
+    /// The bits of each word a format uses, and how many pixels of it fit in local memory.
+    fn coverage(psm: u32) -> (u32, u32) {
+        let words = VRAM_WORDS as u32;
+        match psm {
+            PSMCT24 | PSMZ24 => (0x00FF_FFFF, words),
+            PSMT8H => (0xFF00_0000, words),
+            PSMT4HL => (0x0F00_0000, words),
+            PSMT4HH => (0xF000_0000, words),
+            _ => (u32::MAX, words * 32 / bits_per_pixel(psm)),
+        }
+    }
+
+    fn assert_permutation(values: impl Iterator<Item = u32>, count: usize) {
+        let mut seen = vec![false; count];
+        for value in values {
+            assert!(!seen[value as usize], "{} appears twice", value);
+            seen[value as usize] = true;
+        }
+        assert!(seen.iter().all(|&s| s));
+    }
+
+    #[test]
+    fn test_tables_are_permutations() {
+        for table in [BLOCK_32, BLOCK_8] {
+            assert_permutation(table.iter().flatten().map(|&b| b as u32), 32);
+        }
+        for table in [BLOCK_16, BLOCK_16S, BLOCK_4] {
+            assert_permutation(table.iter().flatten().map(|&b| b as u32), 32);
+        }
+        assert_permutation(COLUMN_32.iter().flatten().map(|&c| c as u32), 64);
+        assert_permutation(COLUMN_16.iter().flatten().map(|&c| c as u32), 128);
+        assert_permutation(COLUMN_8.iter().flatten().map(|&c| c as u32), 256);
+        assert_permutation(COLUMN_4.iter().flatten().map(|&c| c as u32), 512);
+    }
+
+    #[test]
+    fn test_known_addresses() {
+        // rows of the documented tables
+        assert_eq!([0, 1, 4, 5, 8, 9, 12, 13], COLUMN_32[0]);
+        assert_eq!([0, 2, 8, 10, 16, 18, 24, 26, 1, 3, 9, 11, 17, 19, 25, 27], COLUMN_16[0]);
+        assert_eq!([33, 37, 49, 53, 1, 5, 17, 21, 35, 39, 51, 55, 3, 7, 19, 23], COLUMN_8[2]);
+        assert_eq!([96, 100, 112, 116, 64, 68, 80, 84, 98, 102, 114, 118, 66, 70, 82, 86], COLUMN_8[4]);
+        assert_eq!(&[129, 137, 161, 169, 193, 201, 225, 233], &COLUMN_4[6][..8]);
+
+        assert_eq!(PixelAddress { word: 64, shift: 0, bits: 32 }, pixel_address(PSMCT32, 0, 1, 8, 0));
+        assert_eq!(PixelAddress { word: 24 * 64 + 2048, shift: 0, bits: 32 }, pixel_address(PSMZ32, 0, 1, 64, 0));
+        assert_eq!(PixelAddress { word: 64 + 4, shift: 0, bits: 16 }, pixel_address(PSMCT16, 0, 1, 2, 8));
+        assert_eq!(PixelAddress { word: 8 * 64, shift: 16, bits: 16 }, pixel_address(PSMCT16S, 0, 1, 8, 16));
+        assert_eq!(PixelAddress { word: 8, shift: 8, bits: 8 }, pixel_address(PSMT8, 0, 2, 0, 2));
+        assert_eq!(PixelAddress { word: 0, shift: 28, bits: 4 }, pixel_address(PSMT4HH, 0, 1, 0, 0));
+        assert_eq!(PixelAddress { word: 64 * 3, shift: 8, bits: 4 }, pixel_address(PSMT4, 3, 2, 8, 0));
+    }
+
+    /// Every pixel of every format lands on its own bits, and the full width formats use all of memory.
+    #[test]
+    fn test_addresses_cover_memory() {
+        for psm in ALL_FORMATS {
+            let (expected, pixels) = coverage(psm);
+            let (width, height) = (512, pixels / 512);
+            let mut used = vec![0u32; VRAM_WORDS];
+            for y in 0..height {
+                for x in 0..width {
+                    let addr = pixel_address(psm, 0, width / 64, x, y);
+                    let mask = field_mask(addr.bits) << addr.shift;
+                    assert_eq!(0, used[addr.word] & mask, "PSM {:#04X} ({}, {}) overlaps", psm, x, y);
+                    used[addr.word] |= mask;
+                }
+            }
+            assert!(used.iter().all(|&w| w == expected), "PSM {:#04X} leaves gaps", psm);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut gs = Gs::new();
+        for psm in ALL_FORMATS {
+            let (width, height) = (256, 256);
+            let mask = field_mask(bits_per_pixel(psm));
+            let value = |x: u32, y: u32| (x.wrapping_mul(0x9E37_79B9) ^ y.wrapping_mul(0x85EB_CA6B)) & mask;
+            for y in 0..height {
+                for x in 0..width {
+                    gs.write_pixel(psm, 0x100, width / 64, x, y, value(x, y));
+                }
+            }
+            for y in 0..height {
+                for x in 0..width {
+                    assert_eq!(value(x, y), gs.read_pixel(psm, 0x100, width / 64, x, y), "PSM {:#04X} ({}, {})", psm, x, y);
+                }
+            }
+        }
+
+        // the formats sharing a word don't disturb each other
+        gs.write_pixel(PSMCT24, 0, 1, 3, 3, 0x00AB_CDEF);
+        gs.write_pixel(PSMT4HL, 0, 1, 3, 3, 0x5);
+        gs.write_pixel(PSMT4HH, 0, 1, 3, 3, 0xA);
+        assert_eq!(0xA5AB_CDEF, gs.read_pixel(PSMCT32, 0, 1, 3, 3));
+        assert_eq!(0xA5, gs.read_pixel(PSMT8H, 0, 1, 3, 3));
+    }
+}
@@ -0,0 +1,420 @@
+use super::raster::{DrawContext, Fragment, PRIM_FST};
+use super::swizzle::*;
+use super::*;
+
+/*
+    Texture sampling.
+
+    TEX0 says where the texture is and what format it's in, TEX1 how it's filtered and mipmapped,
+    and CLAMP what happens outside it. A texel's colour comes either straight from the texture or,
+    for the 4 and 8 bit formats, from the CLUT: a 1KB buffer inside the GS that writing TEX0 (or
+    TEX2) can reload from local memory, depending on CLD. 32 bit CLUT entries are split into two
+    16 bit halves 256 entries apart, which is why CSA only reaches half as far for them.
+
+    Coordinates come from UV in texels when PRIM.FST is set, otherwise from S/Q and T/Q scaled by the
+    texture size, which is what makes STQ perspective correct. The level of detail is worked out per
+    pixel from Q as the GS does, rather than from screen space derivatives.
+
+    The texel is then combined with the vertex colour by the texture function in TEX0.TFX.
+*/
+
+/* TEX0 */
+const TEX0_TCC: u64 = 1 << 34;
+const TEX0_CSM: u64 = 1 << 55;
+
+/* TEX1 */
+const TEX1_LCM: u64 = 1;
+const TEX1_MMAG: u64 = 1 << 5;
+const TEX1_MTBA: u64 = 1 << 9;
+
+/* texture functions */
+const TFX_MODULATE: u64 = 0;
+const TFX_DECAL: u64 = 1;
+const TFX_HIGHLIGHT: u64 = 2;
+
+/* wrap modes */
+const WRAP_REPEAT: u64 = 0;
+const WRAP_CLAMP: u64 = 1;
+const WRAP_REGION_CLAMP: u64 = 2;
+
+/* MMIN filters past the first two */
+const MIN_NEAREST_MIPMAP_LINEAR: u64 = 3;
+const MIN_LINEAR_MIPMAP_NEAREST: u64 = 4;
+const MIN_LINEAR_MIPMAP_LINEAR: u64 = 5;
+
+/// TEXA, how 24 and 16 bit colours get their alpha.
+const TEXA_AEM: u64 = 1 << 15;
+
+pub const CLUT_ENTRIES: usize = 512;
+
+/// Where a mipmap level lives in local memory and how big it is.
+#[derive(Clone, Copy, Debug)]
+pub struct TextureLevel {
+    pub bp: u32,
+    pub bw: u32,
+    pub width: i32,
+    pub height: i32
+}
+
+fn is_indexed(psm: u32) -> bool {
+    matches!(psm, PSMT8 | PSMT8H | PSMT4 | PSMT4HL | PSMT4HH)
+}
+
+/// Bits each pixel takes up in local memory, counting the parts of words the format leaves alone.
+fn layout_bits(psm: u32) -> u32 {
+    match psm {
+        PSMT8 => 8,
+        PSMT4 => 4,
+        PSMCT16 | PSMCT16S | PSMZ16 | PSMZ16S => 16,
+        _ => 32,
+    }
+}
+
+/// Turns a 32, 24 or 16 bit colour into RGBA, filling in alpha from TEXA.
+pub fn expand_color(psm: u32, raw: u32, texa: u64) -> [u8; 4] {
+    let ta0 = texa as u8;
+    let ta1 = (texa >> 32) as u8;
+    let aem = texa & TEXA_AEM != 0;
+    match psm {
+        PSMCT32 | PSMZ32 => raw.to_le_bytes(),
+        PSMCT24 | PSMZ24 => {
+            let [r, g, b, _] = raw.to_le_bytes();
+            let a = if aem && raw & 0xFF_FFFF == 0 { 0 } else { ta0 };
+            [r, g, b, a]
+        }
+        _ => {
+            let rgb = [raw & 0x1F, (raw >> 5) & 0x1F, (raw >> 10) & 0x1F].map(|c| (c << 3) as u8);
+            let a = if raw & 0x8000 != 0 {
+                ta1
+            } else if aem && raw & 0x7FFF == 0 {
+                0
+            } else {
+                ta0
+            };
+            [rgb[0], rgb[1], rgb[2], a]
+        }
+    }
+}
+
+fn wrap(coord: i32, mode: u64, min: i32, max: i32, size: i32) -> i32 {
+    match mode {
+        WRAP_REPEAT => coord.rem_euclid(size),
+        WRAP_CLAMP => coord.clamp(0, size - 1),
+        WRAP_REGION_CLAMP => coord.clamp(min, max.max(min)),
+        // region repeat, where MIN is a mask and MAX is ORed in
+        _ => (coord & min) | max,
+    }
+}
+
+/// Combines the vertex colour with a texel according to TEX0.TFX and TCC.
+pub fn texture_function(tex0: u64, vertex: [u8; 4], texel: [u8; 4]) -> [u8; 4] {
+    let tcc = tex0 & TEX0_TCC != 0;
+    let (cv, ct) = (vertex.map(|c| c as u32), texel.map(|c| c as u32));
+    let modulate = |i: usize| ((cv[i] * ct[i]) >> 7).min(255);
+    let (rgb, alpha) = match (tex0 >> 35) & 3 {
+        TFX_MODULATE => ([0, 1, 2].map(modulate), if tcc { modulate(3) } else { cv[3] }),
+        TFX_DECAL => ([ct[0], ct[1], ct[2]], if tcc { ct[3] } else { cv[3] }),
+        TFX_HIGHLIGHT => ([0, 1, 2].map(|i| (modulate(i) + cv[3]).min(255)), if tcc { (ct[3] + cv[3]).min(255) } else { cv[3] }),
+        _ => ([0, 1, 2].map(|i| (modulate(i) + cv[3]).min(255)), if tcc { ct[3] } else { cv[3] }),
+    };
+    [rgb[0] as u8, rgb[1] as u8, rgb[2] as u8, alpha as u8]
+}
+
+impl Gs {
+    /// Reloads the CLUT if TEX0.CLD says to, after TEX0 or TEX2 is written.
+    pub(super) fn load_clut(&mut self, tex0: u64) {
+        let psm = ((tex0 >> 20) & 0x3F) as u32;
+        if !is_indexed(psm) {
+            return;
+        }
+        let cbp = ((tex0 >> 37) & 0x3FFF) as u32;
+        let load = match (tex0 >> 61) & 7 {
+            1 => true,
+            2 => {
+                self.cbp0 = cbp;
+                true
+            }
+            3 => {
+                self.cbp1 = cbp;
+                true
+            }
+            4 => std::mem::replace(&mut self.cbp0, cbp) != cbp,
+            5 => std::mem::replace(&mut self.cbp1, cbp) != cbp,
+            _ => false,
+        };
+        if !load {
+            return;
+        }
+
+        let cpsm = ((tex0 >> 51) & 0xF) as u32;
+        let csa = ((tex0 >> 56) & 0x1F) as usize;
+        let entries = if matches!(psm, PSMT8 | PSMT8H) { 256 } else { 16 };
+        let texclut = self.regs[TEXCLUT as usize];
+        for i in 0..entries {
+            let (bw, x, y) = if tex0 & TEX0_CSM == 0 {
+                // CSM1 stores 8 bit CLUTs with bits 3 and 4 of the index swapped
+                if entries == 256 {
+                    (1, (i & 7) | ((i & 0x10) >> 1), (i >> 5) * 2 + ((i & 8) >> 3))
+                } else {
+                    (1, i & 7, i >> 3)
+                }
+            } else {
+                let (cbw, cou, cov) = (texclut & 0x3F, (texclut >> 6) & 0x3F, (texclut >> 12) & 0x3FF);
+                (cbw as u32, cou as u32 * 16 + i, cov as u32)
+            };
+            let raw = self.read_pixel(cpsm, cbp, bw, x, y);
+            if cpsm == PSMCT32 || cpsm == PSMCT24 {
+                let index = ((csa & 0xF) * 16 + i as usize) & 0xFF;
+                self.clut[index] = raw as u16;
+                self.clut[index + 256] = (raw >> 16) as u16;
+            } else {
+                self.clut[(csa * 16 + i as usize) % CLUT_ENTRIES] = raw as u16;
+            }
+        }
+    }
+
+    /// A CLUT entry as RGBA.
+    fn clut_color(&self, tex0: u64, texa: u64, index: u32) -> [u8; 4] {
+        let cpsm = ((tex0 >> 51) & 0xF) as u32;
+        let csa = ((tex0 >> 56) & 0x1F) as usize;
+        if cpsm == PSMCT32 || cpsm == PSMCT24 {
+            let entry = ((csa & 0xF) * 16 + index as usize) & 0xFF;
+            (self.clut[entry] as u32 | (self.clut[entry + 256] as u32) << 16).to_le_bytes()
+        } else {
+            expand_color(PSMCT16, self.clut[(csa * 16 + index as usize) % CLUT_ENTRIES] as u32, texa)
+        }
+    }
+
+    /// Where a mipmap level is. Level 0 is TEX0, the rest come from MIPTBP1/2, or are packed after
+    /// each other when TEX1.MTBA is set.
+    pub fn texture_level(&self, ctx: &DrawContext, level: u32) -> TextureLevel {
+        let tex0 = ctx.tex0;
+        let psm = ((tex0 >> 20) & 0x3F) as u32;
+        let width = 1i32 << ((tex0 >> 26) & 0xF).min(10);
+        let height = 1i32 << ((tex0 >> 30) & 0xF).min(10);
+        let mut bp = (tex0 & 0x3FFF) as u32;
+        let mut bw = ((tex0 >> 14) & 0x3F) as u32;
+        if level > 0 && ctx.tex1 & TEX1_MTBA != 0 && level <= 3 {
+            for n in 1..=level {
+                let (w, h) = ((width >> (n - 1)).max(1), (height >> (n - 1)).max(1));
+                bp += (w * h) as u32 * layout_bits(psm) / 8 / 256;
+                bw = (bw / 2).max(1);
+            }
+        } else if level > 0 {
+            let reg = if level <= 3 { ctx.miptbp1 } else { ctx.miptbp2 };
+            let shift = 20 * ((level - 1) % 3);
+            bp = ((reg >> shift) & 0x3FFF) as u32;
+            bw = ((reg >> (shift + 14)) & 0x3F) as u32;
+        }
+        TextureLevel { bp, bw, width: (width >> level).max(1), height: (height >> level).max(1) }
+    }
+
+    /// The colour of one texel, after wrapping and the CLUT.
+    fn texel(&self, ctx: &DrawContext, level: &TextureLevel, shift: u32, u: i32, v: i32) -> [u8; 4] {
+        let clamp = ctx.clamp;
+        let field = |bit: u32| (((clamp >> bit) & 0x3FF) as i32) >> shift;
+        let u = wrap(u, clamp & 3, field(4), field(14), level.width);
+        let v = wrap(v, (clamp >> 2) & 3, field(24), field(34), level.height);
+        let psm = ((ctx.tex0 >> 20) & 0x3F) as u32;
+        let raw = self.read_pixel(psm, level.bp, level.bw, u as u32, v as u32);
+        if is_indexed(psm) {
+            self.clut_color(ctx.tex0, ctx.texa, raw)
+        } else {
+            expand_color(psm, raw, ctx.texa)
+        }
+    }
+
+    fn sample_level(&self, ctx: &DrawContext, level: u32, u: f32, v: f32, linear: bool) -> [u8; 4] {
+        let tex = self.texture_level(ctx, level);
+        let scale = 1.0 / (1 << level) as f32;
+        let (u, v) = (u * scale, v * scale);
+        if !linear {
+            return self.texel(ctx, &tex, level, u.floor() as i32, v.floor() as i32);
+        }
+        let (u, v) = (u - 0.5, v - 0.5);
+        let (u0, v0) = (u.floor() as i32, v.floor() as i32);
+        let (fu, fv) = (u - u0 as f32, v - v0 as f32);
+        let texels = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(du, dv)| self.texel(ctx, &tex, level, u0 + du, v0 + dv));
+        let weights = [(1.0 - fu) * (1.0 - fv), fu * (1.0 - fv), (1.0 - fu) * fv, fu * fv];
+        [0, 1, 2, 3].map(|c| {
+            let sum: f32 = (0..4).map(|i| texels[i][c] as f32 * weights[i]).sum();
+            sum.round().clamp(0.0, 255.0) as u8
+        })
+    }
+
+    /// Samples the texture for a pixel, with whatever filtering and mipmapping TEX1 asks for.
+    pub fn texture_color(&self, ctx: &DrawContext, fragment: &Fragment) -> [u8; 4] {
+        let tex0 = ctx.tex0;
+        let (u, v) = if ctx.attributes & PRIM_FST != 0 {
+            (fragment.u, fragment.v)
+        } else {
+            let width = (1u32 << ((tex0 >> 26) & 0xF).min(10)) as f32;
+            let height = (1u32 << ((tex0 >> 30) & 0xF).min(10)) as f32;
+            (fragment.s / fragment.q * width, fragment.t / fragment.q * height)
+        };
+
+        let tex1 = ctx.tex1;
+        let k = ((((tex1 >> 32) & 0xFFF) as i32) << 20 >> 20) as f32 / 16.0;
+        let lod = if tex1 & TEX1_LCM != 0 {
+            k
+        } else {
+            (1.0 / fragment.q.abs()).log2() * (1 << ((tex1 >> 19) & 3)) as f32 + k
+        };
+        let max_level = ((tex1 >> 2) & 7).min(6) as f32;
+        if lod <= 0.0 || max_level == 0.0 && (tex1 >> 6) & 7 <= 1 {
+            return self.sample_level(ctx, 0, u, v, tex1 & TEX1_MMAG != 0);
+        }
+        let lod = lod.min(max_level);
+        match (tex1 >> 6) & 7 {
+            0 => self.sample_level(ctx, 0, u, v, false),
+            1 => self.sample_level(ctx, 0, u, v, true),
+            filter @ (MIN_NEAREST_MIPMAP_LINEAR | MIN_LINEAR_MIPMAP_LINEAR) => {
+                let linear = filter == MIN_LINEAR_MIPMAP_LINEAR;
+                let level = lod.floor() as u32;
+                let near = self.sample_level(ctx, level, u, v, linear);
+                let far = self.sample_level(ctx, (level + 1).min(max_level as u32), u, v, linear);
+                let t = lod.fract();
+                [0, 1, 2, 3].map(|c| (near[c] as f32 + (far[c] as f32 - near[c] as f32) * t).round() as u8)
+            }
+            filter => self.sample_level(ctx, lod.round() as u32, u, v, filter == MIN_LINEAR_MIPMAP_NEAREST),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::raster::{PRIM_SPRITE, PRIM_TME, PRIM_TRIANGLE};
+    use super::super::tests::{rgbaq, setup, xyz};
+
+    fn uv(u: u16, v: u16) -> u64 {
+        ((v as u64) << 20) | ((u as u64) << 4)
+    }
+
+    /// A CT32 frame 64 pixels wide at block 0, textures go from block 0x1000 up.
+    fn setup_ct32() -> Gs {
+        let mut gs = setup(PSMCT32);
+        gs.write_register(RGBAQ, rgbaq(0x8080_8080, 1.0));
+        gs
+    }
+
+    fn tex0(psm: u32, log_width: u64, log_height: u64, tfx: u64) -> u64 {
+        0x1000 | 1 << 14 | (psm as u64) << 20 | log_width << 26 | log_height << 30 | TEX0_TCC | tfx << 35
+    }
+
+    fn sprite_uv(gs: &mut Gs, size: u16, u1: u16, v1: u16) {
+        gs.write_register(PRIM, PRIM_SPRITE | PRIM_TME | PRIM_FST);
+        gs.write_register(UV, uv(0, 0));
+        gs.write_register(XYZ2, xyz(0, 0));
+        gs.write_register(UV, uv(u1, v1));
+        gs.write_register(XYZ2, xyz(size, size));
+    }
+
+    fn frame(gs: &Gs, x: u32, y: u32) -> u32 {
+        gs.read_pixel(PSMCT32, 0, 1, x, y)
+    }
+
+    #[test]
+    fn test_decal_and_wrap() {
+        let mut gs = setup_ct32();
+        for y in 0..4 {
+            for x in 0..4 {
+                gs.write_pixel(PSMCT32, 0x1000, 1, x, y, 0x1000_0000 | y << 8 | x);
+            }
+        }
+        gs.write_register(TEX0_1, tex0(PSMCT32, 2, 2, TFX_DECAL));
+        sprite_uv(&mut gs, 8, 8, 8);
+        assert_eq!(0x1000_0302, frame(&gs, 2, 3));
+        assert_eq!(0x1000_0302, frame(&gs, 6, 7));
+
+        // clamping repeats the edge instead
+        gs.write_register(CLAMP_1, WRAP_CLAMP | WRAP_CLAMP << 2);
+        sprite_uv(&mut gs, 8, 8, 8);
+        assert_eq!(0x1000_0303, frame(&gs, 6, 7));
+    }
+
+    #[test]
+    fn test_clut() {
+        let mut gs = setup_ct32();
+        // CSM1 puts entry 16 of an 8 bit CLUT at (8, 0)
+        gs.write_pixel(PSMCT32, 0x2000, 1, 8, 0, 0x4433_2211);
+        gs.write_pixel(PSMT8, 0x1000, 2, 1, 1, 16);
+        let t8 = tex0(PSMT8, 2, 2, TFX_DECAL) & !(0x3F << 14) | 2 << 14 | 0x2000 << 37 | 1 << 61;
+        gs.write_register(TEX0_1, t8);
+        sprite_uv(&mut gs, 4, 4, 4);
+        assert_eq!(0x4433_2211, frame(&gs, 1, 1));
+
+        // a 16 bit CLUT for a 4 bit texture, at CSA 2 with alpha from TEXA
+        gs.write_pixel(PSMCT16, 0x3000, 1, 5, 0, 0x801F);
+        gs.write_pixel(PSMT4, 0x1000, 2, 2, 0, 5);
+        gs.write_register(TEXA, 0x7F << 32);
+        let t4 = tex0(PSMT4, 2, 2, TFX_DECAL) & !(0x3F << 14) | 2 << 14
+            | 0x3000 << 37 | (PSMCT16 as u64) << 51 | 2 << 56 | 2 << 61;
+        gs.write_register(TEX0_1, t4);
+        assert_eq!(0x3000, gs.cbp0);
+        sprite_uv(&mut gs, 4, 4, 4);
+        assert_eq!(0x7F00_00F8, frame(&gs, 2, 0));
+
+        // CLD 4 only loads when CBP changes
+        gs.write_pixel(PSMCT16, 0x3000, 1, 5, 0, 0x03E0);
+        gs.write_register(TEX0_1, t4 & !(7 << 61) | 4 << 61);
+        sprite_uv(&mut gs, 4, 4, 4);
+        assert_eq!(0x7F00_00F8, frame(&gs, 2, 0));
+    }
+
+    #[test]
+    fn test_texture_functions() {
+        let vertex = [0x40, 0x80, 0xFF, 0x20];
+        let texel = [0x80, 0x80, 0x80, 0x40];
+        let tfx = |tfx: u64, tcc: bool| if tcc { TEX0_TCC | tfx << 35 } else { tfx << 35 };
+        assert_eq!([0x40, 0x80, 0xFF, 0x10], texture_function(tfx(TFX_MODULATE, true), vertex, texel));
+        assert_eq!([0x40, 0x80, 0xFF, 0x20], texture_function(tfx(TFX_MODULATE, false), vertex, texel));
+        assert_eq!([0x80, 0x80, 0x80, 0x40], texture_function(tfx(TFX_DECAL, true), vertex, texel));
+        assert_eq!([0x60, 0xA0, 0xFF, 0x60], texture_function(tfx(TFX_HIGHLIGHT, true), vertex, texel));
+        assert_eq!([0x60, 0xA0, 0xFF, 0x40], texture_function(tfx(3, true), vertex, texel));
+    }
+
+    #[test]
+    fn test_filtering_and_stq() {
+        let mut gs = setup_ct32();
+        gs.write_pixel(PSMCT32, 0x1000, 1, 0, 0, 0);
+        gs.write_pixel(PSMCT32, 0x1000, 1, 1, 0, 0xFF);
+        gs.write_register(TEX0_1, tex0(PSMCT32, 1, 0, TFX_DECAL));
+        gs.write_register(CLAMP_1, WRAP_CLAMP | WRAP_CLAMP << 2);
+
+        // halfway between the texel centres
+        gs.write_register(TEX1_1, TEX1_MMAG);
+        gs.write_register(PRIM, PRIM_SPRITE | PRIM_TME | PRIM_FST);
+        gs.write_register(UV, uv(1, 0));
+        gs.write_register(XYZ2, xyz(0, 0));
+        gs.write_register(XYZ2, xyz(1, 1));
+        assert_eq!(0x80, frame(&gs, 0, 0) & 0xFF);
+
+        // S/Q picks the second texel, with nearest filtering
+        gs.write_register(TEX1_1, 0);
+        gs.write_register(PRIM, PRIM_TRIANGLE | PRIM_TME);
+        gs.write_register(ST, (0.375f32.to_bits() as u64) | (0.0f32.to_bits() as u64) << 32);
+        gs.write_register(RGBAQ, rgbaq(0x8080_8080, 0.5));
+        gs.write_register(XYZ2, xyz(10, 10));
+        gs.write_register(XYZ2, xyz(20, 10));
+        gs.write_register(XYZ2, xyz(10, 20));
+        assert_eq!(0xFF, frame(&gs, 11, 11) & 0xFF);
+    }
+
+    #[test]
+    fn test_mipmap_levels() {
+        let mut gs = setup_ct32();
+        gs.write_pixel(PSMCT32, 0x1000, 1, 0, 0, 0x11);
+        gs.write_pixel(PSMCT32, 0x1800, 1, 0, 0, 0x22);
+        gs.write_register(TEX0_1, tex0(PSMCT32, 2, 2, TFX_DECAL));
+        gs.write_register(MIPTBP1_1, 0x1800 | 1 << 14);
+        // LOD fixed at 1.0, nearest mipmap nearest
+        gs.write_register(TEX1_1, TEX1_LCM | 1 << 2 | 2 << 6 | 16 << 32);
+        sprite_uv(&mut gs, 1, 1, 1);
+        assert_eq!(0x22, frame(&gs, 0, 0) & 0xFF);
+
+        // MTBA puts level 1 straight after level 0, which at 64 bytes doesn't fill a block
+        let level = gs.texture_level(&DrawContext { tex1: TEX1_MTBA, ..gs.draw_context() }, 1);
+        assert_eq!((0x1000, 1, 2, 2), (level.bp, level.bw, level.width, level.height));
+    }
+}
@@ -0,0 +1,328 @@
+use std::io;
+
+use super::swizzle::bits_per_pixel;
+use super::*;
+
+/*
+    Transfers between the EE and local memory, and within local memory.
+
+    BITBLTBUF gives the source and destination buffers, TRXPOS where in them the rectangle starts,
+    TRXREG its size, and writing TRXDIR starts the transfer:
+
+        0   host to local, the pixels then arrive 64 bits at a time through HWREG (or IMAGE mode
+            GIF packets)
+        1   local to host, the EE reads them back through the GIF FIFO once BUSDIR says so
+        2   local to local, done there and then
+        3   off
+
+    Pixels are packed tightly in the host data, so 24 bit pixels straddle the 64 bit words and 4 bit
+    ones come low nibble first. Coordinates wrap at 2048.
+
+    Local to local copies go in the order TRXPOS.DIR asks for, which is what makes overlapping
+    copies come out right. Bit 59 runs the rows bottom to top and bit 60 the pixels right to left.
+
+    TRXPOS: SSAX 0-10, SSAY 16-26, DSAX 32-42, DSAY 48-58, DIR 59-60
+    TRXREG: RRW 0-11, RRH 32-43
+*/
+
+pub const TRXDIR_HOST_TO_LOCAL: u8 = 0;
+pub const TRXDIR_LOCAL_TO_HOST: u8 = 1;
+pub const TRXDIR_LOCAL_TO_LOCAL: u8 = 2;
+pub const TRXDIR_OFF: u8 = 3;
+
+/// BUSDIR.DIR, set while the EE reads local memory back.
+const BUSDIR_LOCAL_TO_HOST: u64 = 1;
+
+/// One side of a transfer, out of BITBLTBUF and TRXPOS.
+#[derive(Clone, Copy, Debug)]
+struct Buffer {
+    bp: u32,
+    bw: u32,
+    psm: u32,
+    x: u32,
+    y: u32
+}
+
+impl Buffer {
+    fn pixel(&self, x: u32, y: u32) -> (u32, u32) {
+        ((self.x + x) % 2048, (self.y + y) % 2048)
+    }
+}
+
+/// A host to local or local to host transfer in progress.
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    pub dir: u8,
+
+    /* the next pixel, relative to the start of the rectangle */
+    x: u32,
+    y: u32,
+
+    /* host data that doesn't make a whole pixel yet, or pixels that don't make a whole word yet */
+    buffer: u128,
+    buffered_bits: u32,
+
+    /* the last qword read from the GIF FIFO, which the EE reads a word at a time */
+    fifo: [u32; 4]
+}
+
+impl Transfer {
+    pub fn new() -> Transfer {
+        Transfer { dir: TRXDIR_OFF, x: 0, y: 0, buffer: 0, buffered_bits: 0, fifo: [0; 4] }
+    }
+
+    /// Moves on a pixel, returning false once the rectangle is done.
+    fn advance(&mut self, width: u32, height: u32) -> bool {
+        self.x += 1;
+        if self.x >= width {
+            self.x = 0;
+            self.y += 1;
+        }
+        self.y < height
+    }
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gs {
+    fn source_buffer(&self) -> Buffer {
+        let (bitbltbuf, trxpos) = (self.regs[BITBLTBUF as usize], self.regs[TRXPOS as usize]);
+        Buffer {
+            bp: (bitbltbuf & 0x3FFF) as u32,
+            bw: ((bitbltbuf >> 16) & 0x3F) as u32,
+            psm: ((bitbltbuf >> 24) & 0x3F) as u32,
+            x: (trxpos & 0x7FF) as u32,
+            y: ((trxpos >> 16) & 0x7FF) as u32,
+        }
+    }
+
+    fn dest_buffer(&self) -> Buffer {
+        let (bitbltbuf, trxpos) = (self.regs[BITBLTBUF as usize], self.regs[TRXPOS as usize]);
+        Buffer {
+            bp: ((bitbltbuf >> 32) & 0x3FFF) as u32,
+            bw: ((bitbltbuf >> 48) & 0x3F) as u32,
+            psm: ((bitbltbuf >> 56) & 0x3F) as u32,
+            x: ((trxpos >> 32) & 0x7FF) as u32,
+            y: ((trxpos >> 48) & 0x7FF) as u32,
+        }
+    }
+
+    /// The size of the rectangle in TRXREG.
+    fn transfer_size(&self) -> (u32, u32) {
+        let trxreg = self.regs[TRXREG as usize];
+        ((trxreg & 0xFFF) as u32, ((trxreg >> 32) & 0xFFF) as u32)
+    }
+
+    /// TRXDIR was written.
+    pub(super) fn start_transfer(&mut self, dir: u64) {
+        self.transfer = Transfer { fifo: self.transfer.fifo, ..Transfer::new() };
+        let (width, height) = self.transfer_size();
+        if width == 0 || height == 0 {
+            return;
+        }
+        match (dir & 3) as u8 {
+            TRXDIR_LOCAL_TO_LOCAL => self.local_copy(width, height),
+            dir => self.transfer.dir = dir,
+        }
+    }
+
+    /// Takes 64 bits of host data for a host to local transfer.
+    pub fn write_hwreg(&mut self, data: u64) {
+        if self.transfer.dir != TRXDIR_HOST_TO_LOCAL {
+            return;
+        }
+        let dest = self.dest_buffer();
+        let (width, height) = self.transfer_size();
+        let bits = bits_per_pixel(dest.psm);
+        let transfer = &mut self.transfer;
+        transfer.buffer |= (data as u128) << transfer.buffered_bits;
+        transfer.buffered_bits += 64;
+        while self.transfer.buffered_bits >= bits {
+            let transfer = &mut self.transfer;
+            let value = (transfer.buffer & ((1 << bits) - 1)) as u32;
+            transfer.buffer >>= bits;
+            transfer.buffered_bits -= bits;
+            let (x, y) = dest.pixel(transfer.x, transfer.y);
+            self.write_pixel(dest.psm, dest.bp, dest.bw, x, y, value);
+            if !self.transfer.advance(width, height) {
+                // anything left over in the last word is thrown away
+                self.transfer = Transfer { fifo: self.transfer.fifo, ..Transfer::new() };
+                return;
+            }
+        }
+    }
+
+    /// Gives the next 64 bits of a local to host transfer, or 0 if there isn't one.
+    pub fn read_hwreg(&mut self) -> u64 {
+        if self.transfer.dir != TRXDIR_LOCAL_TO_HOST {
+            return 0;
+        }
+        let source = self.source_buffer();
+        let (width, height) = self.transfer_size();
+        let bits = bits_per_pixel(source.psm);
+        while self.transfer.buffered_bits < 64 && self.transfer.y < height {
+            let (x, y) = source.pixel(self.transfer.x, self.transfer.y);
+            let value = self.read_pixel(source.psm, source.bp, source.bw, x, y);
+            let transfer = &mut self.transfer;
+            transfer.buffer |= (value as u128) << transfer.buffered_bits;
+            transfer.buffered_bits += bits;
+            transfer.advance(width, height);
+        }
+        let transfer = &mut self.transfer;
+        let data = transfer.buffer as u64;
+        transfer.buffer >>= 64;
+        transfer.buffered_bits = transfer.buffered_bits.saturating_sub(64);
+        if transfer.y >= height && transfer.buffered_bits == 0 {
+            transfer.dir = TRXDIR_OFF;
+        }
+        data
+    }
+
+    fn local_copy(&mut self, width: u32, height: u32) {
+        let (source, dest) = (self.source_buffer(), self.dest_buffer());
+        let dir = self.regs[TRXPOS as usize] >> 59;
+        for row in 0..height {
+            let y = if dir & 1 != 0 { height - 1 - row } else { row };
+            for column in 0..width {
+                let x = if dir & 2 != 0 { width - 1 - column } else { column };
+                let (sx, sy) = source.pixel(x, y);
+                let value = self.read_pixel(source.psm, source.bp, source.bw, sx, sy);
+                let (dx, dy) = dest.pixel(x, y);
+                self.write_pixel(dest.psm, dest.bp, dest.bw, dx, dy, value);
+            }
+        }
+    }
+}
+
+impl Ps2 {
+    /// Reads the GIF FIFO, which gives local memory back while BUSDIR is set. Reading the first word
+    /// of the qword pulls the next 128 bits through.
+    pub fn gif_fifo_read(&mut self, addr: u32) -> u32 {
+        let index = (addr as usize & 0xF) / 4;
+        if index == 0 && self.gs.busdir & BUSDIR_LOCAL_TO_HOST != 0 {
+            let (low, high) = (self.gs.read_hwreg(), self.gs.read_hwreg());
+            self.gs.transfer.fifo = [low as u32, (low >> 32) as u32, high as u32, (high >> 32) as u32];
+        }
+        self.gs.transfer.fifo[index]
+    }
+
+    /// The GIF FIFO qword the EE is part way through reading, for the debuggers.
+    pub fn gif_fifo_peek(&self, addr: u32) -> u32 {
+        self.gs.transfer.fifo[(addr as usize & 0xF) / 4]
+    }
+}
+
+impl Savestate for Transfer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.dir);
+        w.write_u32(self.x);
+        w.write_u32(self.y);
+        w.write_u64(self.buffer as u64);
+        w.write_u64((self.buffer >> 64) as u64);
+        w.write_u32(self.buffered_bits);
+        w.write_u32_slice(&self.fifo);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.dir = r.read_u8()?;
+        self.x = r.read_u32()?;
+        self.y = r.read_u32()?;
+        self.buffer = r.read_u64()? as u128 | (r.read_u64()? as u128) << 64;
+        self.buffered_bits = r.read_u32()?;
+        r.read_u32_slice_into(&mut self.fifo)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::swizzle::*;
+
+    fn bitbltbuf(source: (u32, u32, u32), dest: (u32, u32, u32)) -> u64 {
+        let side = |(bp, bw, psm): (u32, u32, u32)| bp as u64 | (bw as u64) << 16 | (psm as u64) << 24;
+        side(source) | side(dest) << 32
+    }
+
+    fn start(gs: &mut Gs, buffers: u64, trxpos: u64, width: u64, height: u64, dir: u64) {
+        gs.write_register(BITBLTBUF, buffers);
+        gs.write_register(TRXPOS, trxpos);
+        gs.write_register(TRXREG, width | height << 32);
+        gs.write_register(TRXDIR, dir);
+    }
+
+    #[test]
+    fn test_host_to_local() {
+        let mut gs = Gs::new();
+        start(&mut gs, bitbltbuf((0, 0, 0), (0x100, 1, PSMCT32)), 3 << 32 | 5 << 48, 2, 2, 0);
+        gs.write_register(HWREG, 0x2222_2222_1111_1111);
+        gs.write_register(HWREG, 0x4444_4444_3333_3333);
+        assert_eq!(0x1111_1111, gs.read_pixel(PSMCT32, 0x100, 1, 3, 5));
+        assert_eq!(0x2222_2222, gs.read_pixel(PSMCT32, 0x100, 1, 4, 5));
+        assert_eq!(0x4444_4444, gs.read_pixel(PSMCT32, 0x100, 1, 4, 6));
+        assert_eq!(TRXDIR_OFF, gs.transfer.dir);
+
+        // 24 bit pixels run across the words, and leave the top byte alone
+        gs.write_pixel(PSMCT32, 0, 1, 2, 0, 0xAA00_0000);
+        start(&mut gs, bitbltbuf((0, 0, 0), (0, 1, PSMCT24)), 0, 3, 1, 0);
+        gs.write_register(HWREG, 0x9988_7766_5544_3322);
+        gs.write_register(HWREG, 0x11);
+        assert_eq!(0x44_3322, gs.read_pixel(PSMCT24, 0, 1, 0, 0));
+        assert_eq!(0x77_6655, gs.read_pixel(PSMCT24, 0, 1, 1, 0));
+        assert_eq!(0xAA11_9988, gs.read_pixel(PSMCT32, 0, 1, 2, 0));
+
+        // 4 bit pixels come low nibble first
+        start(&mut gs, bitbltbuf((0, 0, 0), (0x200, 2, PSMT4)), 0, 16, 1, 0);
+        gs.write_register(HWREG, 0xFEDC_BA98_7654_3210);
+        assert_eq!([0, 1, 0xF], [0, 1, 15].map(|x| gs.read_pixel(PSMT4, 0x200, 2, x, 0)));
+    }
+
+    #[test]
+    fn test_local_to_host_through_gif_fifo() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        for x in 0..8 {
+            ps2.gs.write_pixel(PSMCT16, 0x40, 1, x, 1, 0x100 + x);
+        }
+        start(&mut ps2.gs, bitbltbuf((0x40, 1, PSMCT16), (0, 0, 0)), 1 << 16, 8, 1, 1);
+
+        // nothing comes through until BUSDIR is set
+        assert_eq!(0, ps2.gif_fifo_read(0x1000_6000));
+        ps2.gs.busdir = 1;
+        let words: Vec<u32> = (0..4).map(|i| ps2.read_ee_u32(0x1000_6000 + i * 4)).collect();
+        assert_eq!(vec![0x0101_0100, 0x0103_0102, 0x0105_0104, 0x0107_0106], words);
+        assert_eq!(0x0103_0102, ps2.peek_ee_u32(0x1000_6004));
+        assert_eq!(TRXDIR_OFF, ps2.gs.transfer.dir);
+    }
+
+    #[test]
+    fn test_local_to_local_directions() {
+        let mut gs = Gs::new();
+        for x in 0..4 {
+            gs.write_pixel(PSMCT32, 0, 1, x, 0, x + 1);
+        }
+        // shifting a row right by one over itself only works going right to left
+        start(&mut gs, bitbltbuf((0, 1, PSMCT32), (0, 1, PSMCT32)), 1 << 32 | 1 << 60, 3, 1, 2);
+        assert_eq!([1, 1, 2, 3], [0, 1, 2, 3].map(|x| gs.read_pixel(PSMCT32, 0, 1, x, 0)));
+
+        // and shifting a column down by one only works going bottom to top
+        for y in 0..4 {
+            gs.write_pixel(PSMCT32, 0, 1, 8, y, y + 1);
+        }
+        start(&mut gs, bitbltbuf((0, 1, PSMCT32), (0, 1, PSMCT32)), 8 | 8 << 32 | 1 << 48 | 1 << 59, 1, 3, 2);
+        assert_eq!([1, 1, 2, 3], [0, 1, 2, 3].map(|y| gs.read_pixel(PSMCT32, 0, 1, 8, y)));
+
+        // going left to right smears the first pixel along instead
+        start(&mut gs, bitbltbuf((0, 1, PSMCT32), (0, 1, PSMCT32)), 1 << 32, 3, 1, 2);
+        assert_eq!([1, 1, 1, 1], [0, 1, 2, 3].map(|x| gs.read_pixel(PSMCT32, 0, 1, x, 0)));
+
+        // the copy swizzles between formats as it goes
+        gs.write_pixel(PSMCT16, 0x80, 1, 17, 9, 0x7FFF);
+        start(&mut gs, bitbltbuf((0x80, 1, PSMCT16), (0xC0, 1, PSMCT16S)), 17 | 9 << 16 | 30 << 32 | 40 << 48, 1, 1, 2);
+        assert_eq!(0x7FFF, gs.read_pixel(PSMCT16S, 0xC0, 1, 30, 40));
+        assert_eq!(TRXDIR_OFF, gs.transfer.dir);
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+
+use super::savestate::{Savestate, StateReader, StateWriter};
+
+/*
+    Everything that comes into the machine from outside and isn't determined by the machine state itself.
+    Keeping it in one place means a movie only has to capture this struct to reproduce a session.
+*/
+
+/// Buttons are active low as the pad reports them, so 0xFFFF is nothing pressed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PadState {
+    pub buttons: u16,
+
+    /* right x, right y, left x, left y with 0x80 centred */
+    pub analog: [u8; 4]
+}
+
+impl PadState {
+    pub fn new() -> PadState {
+        PadState { buttons: 0xFFFF, analog: [0x80; 4] }
+    }
+}
+
+impl Default for PadState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Timing of a disc read, so the CDVD model can complete it at the same point on replay.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DiscRead {
+    pub lsn: u32,
+    pub sectors: u32,
+
+    /* cycles from the command to the data being ready */
+    pub latency: u32
+}
+
+pub struct Inputs {
+    pub pads: [PadState; 2],
+
+    /* Seconds since the Unix epoch when the machine was powered on. The RTC counts from here. */
+    pub rtc_base: u64,
+
+    /* Disc reads completed during the current frame */
+    pub disc_reads: Vec<DiscRead>
+}
+
+impl Inputs {
+    pub fn new() -> Inputs {
+        Inputs { pads: [PadState::new(); 2], rtc_base: 0, disc_reads: Vec::new() }
+    }
+}
+
+impl Default for Inputs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for PadState {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.buttons);
+        for axis in &self.analog {
+            w.write_u8(*axis);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.buttons = r.read_u16()?;
+        for axis in self.analog.iter_mut() {
+            *axis = r.read_u8()?;
+        }
+        Ok(())
+    }
+}
+
+impl Savestate for DiscRead {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.lsn);
+        w.write_u32(self.sectors);
+        w.write_u32(self.latency);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.lsn = r.read_u32()?;
+        self.sectors = r.read_u32()?;
+        self.latency = r.read_u32()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Inputs {
+    fn save_state(&self, w: &mut StateWriter) {
+        for pad in &self.pads {
+            pad.save_state(w);
+        }
+        w.write_u64(self.rtc_base);
+        w.write_u32(self.disc_reads.len() as u32);
+        for read in &self.disc_reads {
+            read.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        for pad in self.pads.iter_mut() {
+            pad.load_state(r)?;
+        }
+        self.rtc_base = r.read_u64()?;
+        let count = r.read_u32()?;
+        self.disc_reads.clear();
+        for _ in 0..count {
+            let mut read = DiscRead { lsn: 0, sectors: 0, latency: 0 };
+            read.load_state(r)?;
+            self.disc_reads.push(read);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use std::io;
+
+use super::ps2::Ps2;
+use super::r5900::COP0_CAUSE;
+use super::savestate::{Savestate, StateReader, StateWriter};
+
+/*
+    The EE interrupt controller. Devices set their bit in I_STAT and if any set bit is also enabled in
+    I_MASK the INT0 line into the R5900 is raised, which shows up as Cause.IP2. The DMAC has its own
+    line, INT1, wired to Cause.IP3. Whether the CPU takes the interrupt is down to Status, see
+    R5900::interrupt_pending.
+
+    I_STAT bits are cleared by writing 1 to them and I_MASK bits are flipped by writing 1 to them,
+    so neither can be set directly by software.
+*/
+
+pub const I_STAT: u32 = 0x1000_F000;
+pub const I_MASK: u32 = 0x1000_F010;
+
+/// The INTC interrupt sources, by their bit in I_STAT.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Interrupt {
+    Gs = 0,
+    Sbus = 1,
+    VBlankStart = 2,
+    VBlankEnd = 3,
+    Vif0 = 4,
+    Vif1 = 5,
+    Vu0 = 6,
+    Vu1 = 7,
+    Ipu = 8,
+    Timer0 = 9,
+    Timer1 = 10,
+    Timer2 = 11,
+    Timer3 = 12,
+    Sfifo = 13,
+    Vu0Watchdog = 14,
+}
+
+const INTC_BITS: u32 = 0x7FFF;
+
+/// Cause.IP2, driven by INT0 from the INTC.
+const CAUSE_INT0: u32 = 1 << 10;
+
+/// Cause.IP3, driven by INT1 from the DMAC.
+const CAUSE_INT1: u32 = 1 << 11;
+
+pub struct Intc {
+    pub stat: u32,
+    pub mask: u32
+}
+
+impl Intc {
+    pub fn new() -> Intc {
+        Intc { stat: 0, mask: 0 }
+    }
+
+    /// Whether INT0 is asserted.
+    pub fn int0(&self) -> bool {
+        self.stat & self.mask != 0
+    }
+}
+
+impl Default for Intc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ps2 {
+    /// Latches an interrupt from a device into I_STAT.
+    pub fn raise_interrupt(&mut self, interrupt: Interrupt) {
+        self.intc.stat |= 1 << interrupt as u32;
+        self.update_int0();
+    }
+
+    /// Drives the DMAC's INT1 line.
+    pub fn set_int1(&mut self, asserted: bool) {
+        self.set_cause_bit(CAUSE_INT1, asserted);
+    }
+
+    fn update_int0(&mut self) {
+        let asserted = self.intc.int0();
+        self.set_cause_bit(CAUSE_INT0, asserted);
+    }
+
+    fn set_cause_bit(&mut self, bit: u32, asserted: bool) {
+        if asserted {
+            self.r5900.cop0_regs[COP0_CAUSE] |= bit;
+        } else {
+            self.r5900.cop0_regs[COP0_CAUSE] &= !bit;
+        }
+    }
+
+    pub fn intc_read(&mut self, addr: u32) -> u32 {
+        match addr {
+            I_STAT => self.intc.stat,
+            I_MASK => self.intc.mask,
+            _ => self.ee_hw.unimplemented_read(addr),
+        }
+    }
+
+    pub fn intc_write(&mut self, addr: u32, value: u32) {
+        match addr {
+            I_STAT => self.intc.stat &= !(value & INTC_BITS),
+            I_MASK => self.intc.mask ^= value & INTC_BITS,
+            _ => return self.ee_hw.unimplemented_write(addr, value),
+        }
+        self.update_int0();
+    }
+}
+
+impl Savestate for Intc {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.stat);
+        w.write_u32(self.mask);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.stat = r.read_u32()?;
+        self.mask = r.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::r5900::{COP0_EPC, COP0_STATUS, TRACE_ENABLED};
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_stat_and_mask() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.raise_interrupt(Interrupt::VBlankStart);
+        ps2.raise_interrupt(Interrupt::Timer0);
+        assert_eq!(0x204, ps2.read_ee_u32(0xB000_F000));
+        assert_eq!(0, ps2.r5900.cop0_regs[COP0_CAUSE] & CAUSE_INT0);
+
+        // writes toggle the mask rather than set it
+        ps2.write_ee_u32(0xB000_F010, 0x4);
+        assert_eq!(CAUSE_INT0, ps2.r5900.cop0_regs[COP0_CAUSE] & CAUSE_INT0);
+        ps2.write_ee_u32(0xB000_F010, 0x204);
+        assert_eq!(0x200, ps2.read_ee_u32(0xB000_F010));
+
+        // and clear the status bits written as 1
+        ps2.write_ee_u32(0xB000_F000, 0x200);
+        assert_eq!(0x4, ps2.read_ee_u32(0xB000_F000));
+        assert_eq!(0, ps2.r5900.cop0_regs[COP0_CAUSE] & CAUSE_INT0);
+
+        ps2.set_int1(true);
+        assert_eq!(CAUSE_INT1, ps2.r5900.cop0_regs[COP0_CAUSE] & CAUSE_INT1);
+    }
+
+    #[test]
+    fn test_delivery() {
+        TRACE_ENABLED.store(false, Ordering::Relaxed);
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.r5900.pc = 0x0010_0000;
+        ps2.write_ee_u32(0xB000_F010, 1 << Interrupt::VBlankStart as u32);
+        ps2.raise_interrupt(Interrupt::VBlankStart);
+
+        // still held off by ERL from reset
+        ps2.step();
+        assert_eq!(0x0010_0004, ps2.r5900.pc);
+
+        // IE, EIE and IM2
+        ps2.r5900.cop0_regs[COP0_STATUS] = 0x0001_0401;
+        ps2.step();
+        assert_eq!(0x8000_0200, ps2.r5900.pc);
+        assert_eq!(0x0010_0004, ps2.r5900.cop0_regs[COP0_EPC]);
+        assert_eq!(0, ps2.r5900.cop0_regs[COP0_CAUSE] & 0x7C);
+        assert_eq!(2, ps2.r5900.cop0_regs[COP0_STATUS] & 2);
+
+        // EXL stops it being taken again until ERET
+        ps2.poke_ee_u32(0x8000_0204, 0x4200_0018);
+        ps2.step();
+        ps2.step();
+        assert_eq!(0x0010_0004, ps2.r5900.pc);
+        assert_eq!(0, ps2.r5900.cop0_regs[COP0_STATUS] & 2);
+    }
+}
@@ -1,2 +1,37 @@
+// allows us to quickly turn off tracing
+macro_rules! trace {
+    ($fmt:expr) => (if $crate::system::r5900::TRACE_ENABLED.load(std::sync::atomic::Ordering::Relaxed) { print!($fmt) });
+    ($fmt:expr, $($arg:tt)*) => (if $crate::system::r5900::TRACE_ENABLED.load(std::sync::atomic::Ordering::Relaxed) { print!($fmt, $($arg)*) });
+}
+
+// trace to a minimum width. Always expects parameters.
+macro_rules! tracew {
+    ($width:expr, $fmt:expr, $($arg:tt)*) => {
+        if $crate::system::r5900::TRACE_ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
+            let str = format!($fmt, $($arg)*);
+            let width = $width;
+            print!("{:width$}", str);
+        }
+    };
+}
+
+pub mod breakpoints;
+pub mod dmac;
+pub mod ee_hw;
+pub mod elf;
+pub mod framedump;
+pub mod gif;
+pub mod gs;
+pub mod input;
+pub mod intc;
+pub mod movie;
+pub mod png;
 pub mod ps2;
 pub mod r5900;
+pub mod rewind;
+pub mod romdir;
+pub mod savestate;
+pub mod scheduler;
+pub mod symbols;
+pub mod timers;
+pub mod vu;
@@ -0,0 +1,299 @@
+use std::fmt;
+use std::io;
+
+use super::input::{DiscRead, PadState};
+use super::ps2::Ps2;
+use super::savestate::{compress, decompress, Savestate, StateReader, StateWriter};
+
+/*
+    Movies record everything non-deterministic fed into the machine so a session can be replayed exactly.
+
+    Each frame stores the pad state applied at the start of the frame and the disc reads that completed
+    during it. The RTC base and optionally a save state to start from are stored once. Every hash_interval
+    frames the state hash is stored as well, so a replay that drifts is caught at the first checkpoint
+    after it goes wrong rather than when something visibly breaks.
+
+    File layout:
+        0x00  magic "RSX2MOVI"
+        0x08  version (u32)
+        0x0C  uncompressed payload length (u32)
+        0x10  compressed payload
+*/
+
+const MAGIC: &[u8; 8] = b"RSX2MOVI";
+const HEADER_SIZE: usize = 0x10;
+const MOVIE_VERSION: u32 = 1;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FrameInput {
+    pub pads: [PadState; 2],
+    pub disc_reads: Vec<DiscRead>
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Movie {
+    pub bios_hash: u32,
+    pub rtc_base: u64,
+    pub start_state: Option<Vec<u8>>,
+    pub hash_interval: u32,
+    pub frames: Vec<FrameInput>,
+
+    /* (frame, state hash at the end of that frame) */
+    pub hashes: Vec<(u32, u64)>
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.write_u32(self.bios_hash);
+        w.write_u64(self.rtc_base);
+        w.write_bool(self.start_state.is_some());
+        if let Some(state) = &self.start_state {
+            w.write_bytes(state);
+        }
+        w.write_u32(self.hash_interval);
+        w.write_u32(self.frames.len() as u32);
+        for frame in &self.frames {
+            for pad in &frame.pads {
+                pad.save_state(&mut w);
+            }
+            w.write_u32(frame.disc_reads.len() as u32);
+            for read in &frame.disc_reads {
+                read.save_state(&mut w);
+            }
+        }
+        w.write_u32(self.hashes.len() as u32);
+        for (frame, hash) in &self.hashes {
+            w.write_u32(*frame);
+            w.write_u64(*hash);
+        }
+        let payload = w.into_bytes();
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&MOVIE_VERSION.to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&compress(&payload));
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Movie> {
+        if data.len() < HEADER_SIZE || &data[0..8] != MAGIC {
+            return Err(invalid("not a movie file"));
+        }
+        let version = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+        if version != MOVIE_VERSION {
+            return Err(invalid(&format!("movie version {} is not supported", version)));
+        }
+        let payload_len = u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize;
+        let payload = decompress(&data[HEADER_SIZE..], payload_len)?;
+        let mut r = StateReader::new(&payload, version);
+
+        let bios_hash = r.read_u32()?;
+        let rtc_base = r.read_u64()?;
+        let start_state = if r.read_bool()? { Some(r.read_bytes()?) } else { None };
+        let hash_interval = r.read_u32()?;
+        let frame_count = r.read_u32()?;
+        let mut frames = Vec::new();
+        for _ in 0..frame_count {
+            let mut frame = FrameInput { pads: [PadState::new(); 2], disc_reads: Vec::new() };
+            for pad in frame.pads.iter_mut() {
+                pad.load_state(&mut r)?;
+            }
+            for _ in 0..r.read_u32()? {
+                let mut read = DiscRead { lsn: 0, sectors: 0, latency: 0 };
+                read.load_state(&mut r)?;
+                frame.disc_reads.push(read);
+            }
+            frames.push(frame);
+        }
+        let mut hashes = Vec::new();
+        for _ in 0..r.read_u32()? {
+            hashes.push((r.read_u32()?, r.read_u64()?));
+        }
+        if !r.is_at_end() {
+            return Err(invalid("movie has unexpected trailing data"));
+        }
+        Ok(Movie { bios_hash, rtc_base, start_state, hash_interval, frames, hashes })
+    }
+
+    pub fn save_file(&self, path: &str) -> io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn load_file(path: &str) -> io::Result<Movie> {
+        Movie::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+/// Runs the machine a frame at a time, capturing its inputs into a movie.
+pub struct Recorder {
+    movie: Movie
+}
+
+impl Recorder {
+    /// Starts recording from the machine as it is now. Unless it has just been powered on, pass
+    /// from_state so the current state goes into the movie as the starting point.
+    pub fn new(ps2: &Ps2, hash_interval: u32, from_state: bool) -> Recorder {
+        let movie = Movie {
+            bios_hash: ps2.bios_hash(),
+            rtc_base: ps2.inputs.rtc_base,
+            start_state: if from_state { Some(ps2.save_state_bytes()) } else { None },
+            hash_interval: hash_interval.max(1),
+            frames: Vec::new(),
+            hashes: Vec::new()
+        };
+        Recorder { movie }
+    }
+
+    pub fn run_frame(&mut self, ps2: &mut Ps2) {
+        let pads = ps2.inputs.pads;
+        ps2.run_frame();
+        self.movie.frames.push(FrameInput { pads, disc_reads: std::mem::take(&mut ps2.inputs.disc_reads) });
+
+        let frame = self.movie.frames.len() as u32;
+        if frame.is_multiple_of(self.movie.hash_interval) {
+            self.movie.hashes.push((frame - 1, ps2.state_hash()));
+        }
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// The first checkpoint where a replay didn't match the recording.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Desync {
+    pub frame: u32,
+    pub expected: u64,
+    pub actual: u64,
+
+    /* the last checkpoint that did match, if any */
+    pub last_good: Option<u32>
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "desync at frame {}: state hash {:016X}, expected {:016X}", self.frame, self.actual, self.expected)?;
+        match self.last_good {
+            Some(frame) => write!(f, " (last matched at frame {})", frame),
+            None => write!(f, " (no checkpoint matched)"),
+        }
+    }
+}
+
+/// Feeds a movie back into the machine a frame at a time, checking the state hashes as it goes.
+pub struct Player {
+    movie: Movie,
+    frame: usize,
+    next_hash: usize,
+    last_good: Option<u32>
+}
+
+impl Player {
+    /// Puts the machine into the state the recording started from.
+    pub fn new(movie: Movie, ps2: &mut Ps2) -> io::Result<Player> {
+        if movie.bios_hash != ps2.bios_hash() {
+            return Err(invalid("movie was recorded with a different BIOS"));
+        }
+        if let Some(state) = &movie.start_state {
+            ps2.load_state_bytes(state)?;
+        }
+        ps2.inputs.rtc_base = movie.rtc_base;
+        Ok(Player { movie, frame: 0, next_hash: 0, last_good: None })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.movie.frames.len()
+    }
+
+    /// Runs the next frame of the movie. Returns false once there are no frames left.
+    pub fn run_frame(&mut self, ps2: &mut Ps2) -> Result<bool, Desync> {
+        let input = match self.movie.frames.get(self.frame) {
+            Some(input) => input,
+            None => return Ok(false),
+        };
+        ps2.inputs.pads = input.pads;
+        ps2.inputs.disc_reads = input.disc_reads.clone();
+        ps2.run_frame();
+        ps2.inputs.disc_reads.clear();
+
+        let frame = self.frame as u32;
+        self.frame += 1;
+        if let Some(&(hash_frame, expected)) = self.movie.hashes.get(self.next_hash) {
+            if hash_frame == frame {
+                self.next_hash += 1;
+                let actual = ps2.state_hash();
+                if actual != expected {
+                    return Err(Desync { frame, expected, actual, last_good: self.last_good });
+                }
+                self.last_good = Some(frame);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Plays the rest of the movie, stopping at the first desync.
+    pub fn run_to_end(&mut self, ps2: &mut Ps2) -> Result<(), Desync> {
+        while self.run_frame(ps2)? {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* a machine spinning in a loop in RAM, so any number of frames can run */
+    fn looping_ps2() -> Box<Ps2> {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.poke_ee_u32(0x0010_0000, 0x1000_FFFF);  // beq zero, zero, -1
+        ps2.poke_ee_u32(0x0010_0004, 0x0000_0000);  // nop
+        ps2.r5900.pc = 0x0010_0000;
+        ps2
+    }
+
+    #[test]
+    fn test_record_replay() {
+        crate::system::r5900::TRACE_ENABLED.store(false, std::sync::atomic::Ordering::Relaxed);
+
+        let mut ps2 = looping_ps2();
+        ps2.inputs.rtc_base = 1_000_000;
+        let mut recorder = Recorder::new(&ps2, 1, true);
+        ps2.inputs.pads[0].buttons = 0xBFFF;
+        recorder.run_frame(&mut ps2);
+        ps2.inputs.disc_reads.push(DiscRead { lsn: 16, sectors: 1, latency: 1000 });
+        recorder.run_frame(&mut ps2);
+        let movie = Movie::from_bytes(&recorder.finish().to_bytes()).unwrap();
+        assert_eq!(2, movie.frames.len());
+        assert_eq!(0xBFFF, movie.frames[0].pads[0].buttons);
+        assert_eq!(2, movie.hashes.len());
+
+        let mut replay = looping_ps2();
+        replay.r5900.pc = 0;
+        let mut player = Player::new(movie.clone(), &mut replay).unwrap();
+        assert_eq!(Ok(()), player.run_to_end(&mut replay));
+        assert_eq!(1_000_000, replay.inputs.rtc_base);
+        assert_eq!(ps2.state_hash(), replay.state_hash());
+
+        let mut tampered = movie;
+        tampered.hashes[1].1 ^= 1;
+        let mut replay = looping_ps2();
+        let mut player = Player::new(tampered, &mut replay).unwrap();
+        let desync = player.run_to_end(&mut replay).unwrap_err();
+        assert_eq!((1, Some(0)), (desync.frame, desync.last_good));
+    }
+}
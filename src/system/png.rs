@@ -0,0 +1,475 @@
+use std::io;
+
+use super::gs::crtc::Image;
+
+/*
+    Just enough PNG to dump frames and read reference images back.
+
+    Frames are written as 8 bit RGBA with no filtering, inside zlib streams made of stored deflate
+    blocks. They come out big, but a frame compresses well enough with anything else and this way
+    there's no compressor to get wrong.
+
+    Reading has to cope with whatever a reference image was saved with, so it has a full inflater
+    (stored, fixed and dynamic Huffman blocks) and undoes all five filters. 8 bit greyscale, RGB, grey
+    with alpha and RGBA images are accepted, without interlacing; everything comes back as RGBA.
+*/
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// The most a stored deflate block can hold.
+const STORED_BLOCK_MAX: usize = 0xFFFF;
+
+/// The biggest image we'll decode on each side, far more than the GS can put out.
+const MAX_DIMENSION: u32 = 8192;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+/// The CRC-32 PNG puts after each chunk.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| CRC_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+/// The Adler-32 checksum at the end of a zlib stream.
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps data in a zlib stream of stored blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(STORED_BLOCK_MAX).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Encodes an image as an RGBA PNG.
+pub fn encode(image: &Image) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&image.width.to_be_bytes());
+    header.extend_from_slice(&image.height.to_be_bytes());
+    // 8 bits per channel, RGBA, deflate, no filtering choice, no interlacing
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(&mut out, b"IHDR", &header);
+
+    let row = image.width as usize * 4;
+    let mut raw = Vec::with_capacity((row + 1) * image.height as usize);
+    for line in image.pixels.chunks(row.max(1)).take(image.height as usize) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// Reads bits from a deflate stream, least significant first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self.data.get(self.pos).ok_or_else(|| invalid("deflate stream ends early"))?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1..16 {
+            offsets[len] = offsets[len - 1] + counts[len - 1];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("bad Huffman code"))
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// The order code length code lengths come in, in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn inflate_block(reader: &mut BitReader, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> io::Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                if i >= LENGTH_BASE.len() {
+                    return Err(invalid("bad deflate length"));
+                }
+                let len = LENGTH_BASE[i] as usize + reader.bits(LENGTH_EXTRA[i] as u32)? as usize;
+                let d = distances.decode(reader)? as usize;
+                if d >= DISTANCE_BASE.len() {
+                    return Err(invalid("bad deflate distance"));
+                }
+                let distance = DISTANCE_BASE[d] as usize + reader.bits(DISTANCE_EXTRA[d] as u32)? as usize;
+                if distance > out.len() {
+                    return Err(invalid("deflate distance goes back too far"));
+                }
+                let start = out.len() - distance;
+                for n in 0..len {
+                    out.push(out[start + n]);
+                }
+            }
+        }
+    }
+}
+
+/// Decompresses a raw deflate stream.
+pub fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut reader = BitReader { data, pos: 0, bit: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? != 0;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = data.get(reader.pos..reader.pos + 4).ok_or_else(|| invalid("deflate stream ends early"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(invalid("stored block length doesn't match its complement"));
+                }
+                let start = reader.pos + 4;
+                let block = data.get(start..start + len as usize).ok_or_else(|| invalid("deflate stream ends early"))?;
+                out.extend_from_slice(block);
+                reader.pos = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                inflate_block(&mut reader, &mut out, &literals, &distances)?;
+            }
+            2 => {
+                let literal_count = reader.bits(5)? as usize + 257;
+                let distance_count = reader.bits(5)? as usize + 1;
+                let code_count = reader.bits(4)? as usize + 4;
+                let mut code_lengths = [0u8; 19];
+                for &i in &CODE_LENGTH_ORDER[..code_count] {
+                    code_lengths[i] = reader.bits(3)? as u8;
+                }
+                let code = Huffman::new(&code_lengths);
+                let mut lengths = Vec::with_capacity(literal_count + distance_count);
+                while lengths.len() < literal_count + distance_count {
+                    let (value, repeat) = match code.decode(&mut reader)? {
+                        len @ 0..=15 => (len as u8, 1),
+                        16 => (*lengths.last().ok_or_else(|| invalid("repeat with no previous length"))?, 3 + reader.bits(2)?),
+                        17 => (0, 3 + reader.bits(3)?),
+                        _ => (0, 11 + reader.bits(7)?),
+                    };
+                    lengths.extend(std::iter::repeat_n(value, repeat as usize));
+                }
+                if lengths.len() > literal_count + distance_count {
+                    return Err(invalid("code lengths overrun"));
+                }
+                let literals = Huffman::new(&lengths[..literal_count]);
+                let distances = Huffman::new(&lengths[literal_count..]);
+                inflate_block(&mut reader, &mut out, &literals, &distances)?;
+            }
+            _ => return Err(invalid("bad deflate block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Decodes a PNG into an RGBA image.
+pub fn decode(data: &[u8]) -> io::Result<Image> {
+    if data.get(..8) != Some(&SIGNATURE[..]) {
+        return Err(invalid("not a PNG file"));
+    }
+    let mut pos = 8;
+    let mut header = None;
+    let mut compressed = Vec::new();
+    while pos + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let body = data.get(pos + 8..pos + 8 + len).ok_or_else(|| invalid("PNG chunk runs past the end of the file"))?;
+        let crc = data.get(pos + 8 + len..pos + 12 + len).ok_or_else(|| invalid("PNG chunk runs past the end of the file"))?;
+        if crc32(&data[pos + 4..pos + 8 + len]).to_be_bytes() != crc {
+            return Err(invalid("PNG chunk CRC mismatch"));
+        }
+        match kind {
+            b"IHDR" if len == 13 => header = Some(body),
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += 12 + len;
+    }
+
+    let header = header.ok_or_else(|| invalid("PNG has no IHDR"))?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let (depth, color_type, interlace) = (header[8], header[9], header[12]);
+    let channels = match color_type {
+        0 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return Err(invalid("only greyscale, RGB and RGBA PNGs are supported")),
+    };
+    if depth != 8 || interlace != 0 {
+        return Err(invalid("only 8 bit, non-interlaced PNGs are supported"));
+    }
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(invalid("PNG is too big"));
+    }
+
+    if compressed.len() < 6 || compressed[0] & 0xF != 8 {
+        return Err(invalid("PNG data isn't a zlib stream"));
+    }
+    let raw = inflate(&compressed[2..])?;
+    let too_big = || invalid("PNG is too big");
+    let stride = (width as usize).checked_mul(channels).ok_or_else(too_big)?;
+    let raw_len = (stride + 1).checked_mul(height as usize).ok_or_else(too_big)?;
+    if raw.len() < raw_len {
+        return Err(invalid("PNG image data is too short"));
+    }
+
+    let pixel_bytes = (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(4)).ok_or_else(too_big)?;
+    let mut pixels = Vec::with_capacity(pixel_bytes);
+    let mut previous = vec![0u8; stride];
+    let mut line = vec![0u8; stride];
+    for row in raw.chunks(stride + 1).take(height as usize) {
+        let filter = row[0];
+        for i in 0..stride {
+            let a = if i >= channels { line[i - channels] } else { 0 };
+            let (b, c) = (previous[i], if i >= channels { previous[i - channels] } else { 0 });
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err(invalid("bad PNG filter type")),
+            };
+            line[i] = row[i + 1].wrapping_add(predicted);
+        }
+        for pixel in line.chunks(channels) {
+            let rgba = match channels {
+                1 => [pixel[0], pixel[0], pixel[0], 0xFF],
+                2 => [pixel[0], pixel[0], pixel[0], pixel[1]],
+                3 => [pixel[0], pixel[1], pixel[2], 0xFF],
+                _ => [pixel[0], pixel[1], pixel[2], pixel[3]],
+            };
+            pixels.extend_from_slice(&rgba);
+        }
+        std::mem::swap(&mut previous, &mut line);
+    }
+    Ok(Image { width, height, pixels })
+}
+
+pub fn save_file(image: &Image, path: &str) -> io::Result<()> {
+    std::fs::write(path, encode(image))
+}
+
+pub fn load_file(path: &str) -> io::Result<Image> {
+    decode(&std::fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image(width: u32, height: u32) -> Image {
+        let pixels = (0..width * height * 4).map(|i| (i * 7 + i / 13) as u8).collect();
+        Image { width, height, pixels }
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+        assert_eq!(0x11E6_0398, adler32(b"Wikipedia"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // big enough to need several stored blocks
+        let image = test_image(200, 100);
+        assert_eq!(image, decode(&encode(&image)).unwrap());
+        let empty = Image { width: 0, height: 0, pixels: Vec::new() };
+        assert_eq!(empty, decode(&encode(&empty)).unwrap());
+
+        let mut broken = encode(&image);
+        broken[40] ^= 1;
+        assert!(decode(&broken).is_err());
+
+        // a header claiming a huge image is refused before anything is allocated for it
+        let mut data = SIGNATURE.to_vec();
+        let mut header = Vec::new();
+        header.extend_from_slice(&u32::MAX.to_be_bytes());
+        header.extend_from_slice(&u32::MAX.to_be_bytes());
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+        write_chunk(&mut data, b"IHDR", &header);
+        write_chunk(&mut data, b"IDAT", &zlib_stored(&[0; 16]));
+        write_chunk(&mut data, b"IEND", &[]);
+        assert!(decode(&data).is_err());
+    }
+
+    #[test]
+    fn test_inflate_huffman_blocks() {
+        // "abcabcabcabc" as a fixed Huffman block with a back reference
+        assert_eq!(b"abcabcabcabc".to_vec(), inflate(&[0x4B, 0x4C, 0x4A, 0x4E, 0x84, 0x21, 0x00]).unwrap());
+
+        // a dynamic Huffman block, as zlib -9 writes 100 pseudorandom letters that are mostly 'a'
+        let stream = [
+            0x5D, 0x8B, 0xB1, 0x0D, 0x00, 0x20, 0x0C, 0xC3, 0x6E, 0xB5, 0xF3, 0xFF, 0x0F, 0xD0, 0x42, 0x07, 0x88, 0x32,
+            0x58, 0xB1, 0xA2, 0x80, 0x04, 0x52, 0xB4, 0x41, 0x6E, 0x27, 0x8D, 0xFE, 0x9B, 0x7D, 0xAA, 0x9B, 0xE1, 0x71,
+            0x1C, 0xC1, 0x78, 0x16,
+        ];
+        let mut x = 1u32;
+        let letters: Vec<u8> = (0..100).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345) & 0x7FFF_FFFF;
+            b"aaaaaaabbc"[(x >> 16) as usize % 10]
+        }).collect();
+        assert_eq!(letters, inflate(&stream).unwrap());
+    }
+
+    #[test]
+    fn test_filters_and_formats() {
+        // a 2x2 RGB image using the Sub and Up filters, built by hand
+        let raw = [1, 10, 20, 30, 5, 5, 5, 2, 1, 1, 1, 0xFF, 0xFF, 0xFF];
+        let mut data = SIGNATURE.to_vec();
+        let mut header = Vec::new();
+        header.extend_from_slice(&2u32.to_be_bytes());
+        header.extend_from_slice(&2u32.to_be_bytes());
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(&mut data, b"IHDR", &header);
+        write_chunk(&mut data, b"IDAT", &zlib_stored(&raw));
+        write_chunk(&mut data, b"IEND", &[]);
+        let image = decode(&data).unwrap();
+        assert_eq!([10, 20, 30, 0xFF], image.pixel(0, 0));
+        assert_eq!([15, 25, 35, 0xFF], image.pixel(1, 0));
+        assert_eq!([11, 21, 31, 0xFF], image.pixel(0, 1));
+        assert_eq!([14, 24, 34, 0xFF], image.pixel(1, 1));
+    }
+}
@@ -1,24 +1,91 @@
+use crate::system::breakpoints::Breakpoints;
+use crate::system::ee_hw::{self, EeHw};
+use crate::system::elf;
+use crate::system::input::Inputs;
+use crate::system::dmac::Dmac;
+use crate::system::gif::Gif;
+use crate::system::gs::{self, Gs};
+use crate::system::intc::Intc;
 use crate::system::r5900;
+use crate::system::scheduler::Scheduler;
+use crate::system::symbols::SymbolTable;
+use crate::system::timers::Timers;
+use crate::system::vu::Vu;
 
 pub struct Ps2
 {
     // 32Mb EE RAM
     pub ee_ram: Vec<u32>, 
 
+    // 16Kb scratchpad RAM inside the EE, mapped at 0x7000_0000
+    pub scratchpad: Vec<u32>,
+
     // 2Mb IOP RAM (also mapped to EE space)
     pub iop_ram: Vec<u32>, 
 
     // 4Mb ROM mapped to both EE and IOP
     pub rom: Vec<u32>,
 
+    // Optional DVD player ROM (ROM1), its encrypted companion (EROM) and the regional ROM2.
+    // These are empty when no image was supplied and read as zero.
+    pub rom1: Vec<u32>,
+    pub erom: Vec<u32>,
+    pub rom2: Vec<u32>,
+
+    // An ELF waiting for the BIOS to reach EELOAD
+    pub pending_elf: Option<elf::Elf>,
+
+    // Names for addresses, used by the traces and debuggers
+    pub symbols: SymbolTable,
+
+    pub breakpoints: Breakpoints,
+
+    // Everything non-deterministic coming in from the host
+    pub inputs: Inputs,
+
+    // EE cycles since power on
+    pub cycles: u64,
+
+    // Future events against the cycle counter
+    pub scheduler: Scheduler,
+
+    // Timers, DMAC, INTC and the rest of the registers at 0x1000_0000
+    pub ee_hw: EeHw,
+
+    pub intc: Intc,
+    pub dmac: Dmac,
+
+    pub timers: Timers,
+
+    // GIF, feeding the GS from VU1, VIF1 and its DMA channel
+    pub gif: Gif,
+
+    // Graphics Synthesizer, with its privileged registers at 0x1200_0000
+    pub gs: Gs,
+
+    // VU0, which the EE also drives directly through COP2, and VU1
+    pub vu0: Vu,
+    pub vu1: Vu,
+
     pub r5900: r5900::R5900State
 }
 
 const EE_RAM_SIZE:  usize = 0x200_0000;
 const IOP_RAM_SIZE: usize = 0x20_0000;
+pub const SCRATCHPAD_SIZE: usize = 0x4000;
 const ROM_SIZE:     usize = 0x40_0000;
+const ROM1_SIZE:    usize = 0x4_0000;
+const EROM_SIZE:    usize = 0x1C_0000;
+const ROM2_SIZE:    usize = 0x8_0000;
+
+/// The EE runs at 294.912MHz, which is this many cycles for each 60Hz frame.
+pub const EE_CYCLES_PER_FRAME: u64 = 294_912_000 / 60;
 
-const ROM_START_ADDR: u32 = 0x1FC0_0000;
+const SCRATCHPAD_START_ADDR: u32 = 0x7000_0000;
+const ROM_START_ADDR:  u32 = 0x1FC0_0000;
+const ROM1_START_ADDR: u32 = 0x1E00_0000;
+const EROM_START_ADDR: u32 = 0x1E04_0000;
+const ROM2_START_ADDR: u32 = 0x1E40_0000;
 
 
 impl Ps2
@@ -26,30 +93,143 @@ impl Ps2
     /// Creates a new Ps2 object
     pub fn new(bios_data: &[u32]) -> Box<Ps2>
     {
-        let sys = Box::new(Ps2 { ee_ram: vec!(0; EE_RAM_SIZE/4), iop_ram: vec!(0; IOP_RAM_SIZE/4), rom: bios_data.to_vec(), r5900: r5900::R5900State::new() });
+        let mut sys = Box::new(Ps2 { ee_ram: vec!(0; EE_RAM_SIZE/4), scratchpad: vec!(0; SCRATCHPAD_SIZE/4), iop_ram: vec!(0; IOP_RAM_SIZE/4), rom: bios_data.to_vec(),
+                                 rom1: Vec::new(), erom: Vec::new(), rom2: Vec::new(), pending_elf: None, symbols: SymbolTable::new(),
+                                 breakpoints: Breakpoints::new(), inputs: Inputs::new(), cycles: 0, scheduler: Scheduler::new(),
+                                 ee_hw: EeHw::new(), intc: Intc::new(), dmac: Dmac::new(), timers: Timers::new(), gif: Gif::new(), gs: Gs::new(),
+                                 vu0: Vu::vu0(), vu1: Vu::vu1(),
+                                 r5900: r5900::R5900State::new() });
+        sys.schedule_video_events();
         return sys;
     }
 
+    pub fn set_rom1(&mut self, data: &[u32])
+    {
+        self.rom1 = data[..data.len().min(ROM1_SIZE/4)].to_vec();
+    }
+
+    pub fn set_erom(&mut self, data: &[u32])
+    {
+        self.erom = data[..data.len().min(EROM_SIZE/4)].to_vec();
+    }
+
+    pub fn set_rom2(&mut self, data: &[u32])
+    {
+        self.rom2 = data[..data.len().min(ROM2_SIZE/4)].to_vec();
+    }
+
     pub fn step(&mut self)
     {
+        if self.r5900.pc == elf::EELOAD_ENTRY {
+            if let Some(elf) = self.pending_elf.take() {
+                self.load_elf(&elf).expect("ELF segments are checked when the file is parsed");
+            }
+        }
         r5900::R5900::step(self);
+        self.vu_step();
+        self.cycles += 1;
+        if self.cycles >= self.scheduler.next_cycle() {
+            self.run_events();
+        }
+    }
+
+    /// Runs until the start of the next frame.
+    pub fn run_frame(&mut self)
+    {
+        let frame_end = (self.cycles / EE_CYCLES_PER_FRAME + 1) * EE_CYCLES_PER_FRAME;
+        while self.cycles < frame_end {
+            self.step();
+        }
+    }
+
+    pub fn frame_number(&self) -> u64
+    {
+        self.cycles / EE_CYCLES_PER_FRAME
+    }
+
+    /// Reads from one of the ROMs, which appear at the same physical addresses on the EE and IOP buses.
+    /// Returns None if the address is not in a ROM region. Regions without an image (or past the end of
+    /// a short image) read as zero.
+    fn read_rom_u32(&self, phys_addr: u32) -> Option<u32>
+    {
+        let regions: [(u32, usize, &Vec<u32>); 4] = [
+            (ROM_START_ADDR, ROM_SIZE, &self.rom),
+            (ROM1_START_ADDR, ROM1_SIZE, &self.rom1),
+            (EROM_START_ADDR, EROM_SIZE, &self.erom),
+            (ROM2_START_ADDR, ROM2_SIZE, &self.rom2),
+        ];
+        for (start, size, data) in regions {
+            if phys_addr >= start && ((phys_addr - start) as usize) < size {
+                let index = ((phys_addr - start) / 4) as usize;
+                return Some(data.get(index).copied().unwrap_or(0));
+            }
+        }
+        None
+    }
+
+    /// The scratchpad is mapped by the TLB at a virtual address, so it's checked before translating.
+    fn is_scratchpad(addr: u32) -> bool
+    {
+        addr >= SCRATCHPAD_START_ADDR && ((addr - SCRATCHPAD_START_ADDR) as usize) < SCRATCHPAD_SIZE
+    }
+
+    fn is_gs_privileged(phys_addr: u32) -> bool
+    {
+        phys_addr >= gs::GS_PRIV_START && ((phys_addr - gs::GS_PRIV_START) as usize) < gs::GS_PRIV_SIZE
+    }
+
+    fn is_ee_hw(phys_addr: u32) -> bool
+    {
+        phys_addr >= ee_hw::EE_HW_START && ((phys_addr - ee_hw::EE_HW_START) as usize) < ee_hw::EE_HW_SIZE
     }
 
     /// Reads a 32 bit unsigned value from the EE memory. Slow but simple.
-    pub fn read_ee_u32(&self, addr: u32) -> u32
+    pub fn read_ee_u32(&mut self, addr: u32) -> u32
+    {
+        self.breakpoints.check_access(addr, 4, false);
+        let phys_addr = addr & 0x1FFFFFFF;
+        if !Self::is_scratchpad(addr) && Self::is_ee_hw(phys_addr) {
+            // hardware registers can change when read, so go through the devices
+            return self.read_ee_hw_u32(phys_addr & !3);
+        }
+        self.peek_ee_u32(addr)
+    }
+
+    /// Reads a 32 bit value from the EE memory without triggering watchpoints.
+    /// Used for instruction fetches and by the debuggers.
+    pub fn peek_ee_u32(&self, addr: u32) -> u32
     {
+        if Self::is_scratchpad(addr) {
+            return self.scratchpad[(addr - SCRATCHPAD_START_ADDR) as usize/4];
+        }
         let phys_addr = addr & 0x1FFFFFFF;
-        if phys_addr >= ROM_START_ADDR{
-            let rom_addr = (phys_addr - ROM_START_ADDR) as usize;
-            return self.rom[rom_addr/4];
+        if (phys_addr as usize) < EE_RAM_SIZE {
+            return self.ee_ram[phys_addr as usize/4];
+        }
+        if let Some(value) = self.read_rom_u32(phys_addr) {
+            return value;
+        }
+        if Self::is_ee_hw(phys_addr) {
+            return self.peek_ee_hw_u32(phys_addr & !3);
+        }
+        if Self::is_gs_privileged(phys_addr) {
+            return self.gs_read_u32(phys_addr & !3);
+        }
+        if Self::is_vu_mem(phys_addr) {
+            return self.vu_mem_read(phys_addr);
         }
         return 0xDEAD_BEEF;
     }
 
-    pub fn read_ee_i8(&self, addr: u32) -> i8
+    pub fn read_ee_i8(&mut self, addr: u32) -> i8
     {
+        self.breakpoints.check_access(addr, 1, false);
         let word_addr = addr & !3;
-        let word_val = self.read_ee_u32(word_addr);
+        let word_val = if !Self::is_scratchpad(word_addr) && Self::is_ee_hw(word_addr & 0x1FFFFFFF) {
+            self.read_ee_hw_u32(word_addr & 0x1FFFFFFF)
+        } else {
+            self.peek_ee_u32(word_addr)
+        };
         
         (word_val >> ((addr & 3) * 8) & 0xFF) as i8
     }
@@ -57,9 +237,98 @@ impl Ps2
     /// Writes a 32 bit unsigned value to the EE memory. Slow but simple.
     pub fn write_ee_u32(&mut self, addr: u32, value: u32)
     {
+        self.breakpoints.check_access(addr, 4, true);
+        self.poke_ee_u32(addr, value);
+    }
+
+    /// Writes a 32 bit value to the EE memory without triggering watchpoints.
+    pub fn poke_ee_u32(&mut self, addr: u32, value: u32)
+    {
+        if Self::is_scratchpad(addr) {
+            self.scratchpad[(addr - SCRATCHPAD_START_ADDR) as usize/4] = value;
+            return;
+        }
         let phys_addr = (addr & 0x1FFFFFFF) as usize;
         if phys_addr < EE_RAM_SIZE {
             self.ee_ram[phys_addr/4] = value;
+        } else if Self::is_ee_hw(phys_addr as u32) {
+            self.write_ee_hw_u32(phys_addr as u32 & !3, value);
+        } else if Self::is_gs_privileged(phys_addr as u32) {
+            self.gs_write_u32(phys_addr as u32 & !3, value);
+        } else if Self::is_vu_mem(phys_addr as u32) {
+            self.vu_mem_write(phys_addr as u32, value);
         }
     }
+
+    pub fn write_ee_u8(&mut self, addr: u32, value: u8)
+    {
+        self.breakpoints.check_access(addr, 1, true);
+        self.poke_ee_u8(addr, value);
+    }
+
+    pub fn poke_ee_u8(&mut self, addr: u32, value: u8)
+    {
+        let phys_addr = (addr & 0x1FFFFFFF) as usize;
+        if Self::is_scratchpad(addr) {
+            let shift = (addr & 3) * 8;
+            let word = &mut self.scratchpad[(addr - SCRATCHPAD_START_ADDR) as usize/4];
+            *word = (*word & !(0xFF << shift)) | ((value as u32) << shift);
+        } else if phys_addr < EE_RAM_SIZE {
+            let shift = (phys_addr & 3) * 8;
+            let word = &mut self.ee_ram[phys_addr/4];
+            *word = (*word & !(0xFF << shift)) | ((value as u32) << shift);
+        } else if Self::is_ee_hw(phys_addr as u32) {
+            // the registers only take whole words, so merge the byte into what's there
+            let word_addr = phys_addr as u32 & !3;
+            let shift = (phys_addr & 3) * 8;
+            let word = self.peek_ee_hw_u32(word_addr);
+            self.write_ee_hw_u32(word_addr, (word & !(0xFF << shift)) | ((value as u32) << shift));
+        } else if Self::is_gs_privileged(phys_addr as u32) {
+            let word_addr = phys_addr as u32 & !3;
+            let shift = (phys_addr & 3) * 8;
+            let word = self.gs_read_u32(word_addr);
+            self.gs_write_u32(word_addr, (word & !(0xFF << shift)) | ((value as u32) << shift));
+        }
+    }
+
+    /// Reads a 32 bit unsigned value from the IOP memory.
+    pub fn read_iop_u32(&self, addr: u32) -> u32
+    {
+        let phys_addr = addr & 0x1FFFFFFF;
+        if (phys_addr as usize) < IOP_RAM_SIZE * 4 {
+            // the 2Mb of RAM is mirrored through the first 8Mb
+            return self.iop_ram[(phys_addr as usize & (IOP_RAM_SIZE - 1)) / 4];
+        }
+        self.read_rom_u32(phys_addr).unwrap_or(0xDEAD_BEEF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rom_mapping() {
+        let mut ps2 = Ps2::new(&[0x1234_5678, 0x9ABC_DEF0]);
+        assert_eq!(0x9ABC_DEF0, ps2.read_ee_u32(0xBFC0_0004));
+        assert_eq!(0x9ABC_DEF0, ps2.read_iop_u32(0xBFC0_0004));
+
+        // absent and short images read as zero rather than faulting
+        assert_eq!(0, ps2.read_ee_u32(0xBFC0_1000));
+        assert_eq!(0, ps2.read_ee_u32(0x1E00_0000));
+
+        ps2.set_rom1(&[1, 2]);
+        ps2.set_erom(&[3]);
+        ps2.set_rom2(&[4]);
+        assert_eq!(2, ps2.read_ee_u32(0x1E00_0004));
+        assert_eq!(3, ps2.read_ee_u32(0x1E04_0000));
+        assert_eq!(4, ps2.read_iop_u32(0x1E40_0000));
+        assert_eq!(0xDEAD_BEEF, ps2.read_ee_u32(0x1E80_0000));
+
+        // the scratchpad is separate from whatever its address would translate to
+        ps2.write_ee_u32(0x7000_3FFC, 0x55);
+        ps2.write_ee_u8(0x7000_3FFD, 0x66);
+        assert_eq!(0x6655, ps2.read_ee_u32(0x7000_3FFC));
+        assert_eq!(0, ps2.peek_ee_u32(0x1000_3FFC));
+    }
 }
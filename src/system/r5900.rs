@@ -1,19 +1,12 @@
-use super::ps2::Ps2;
+use std::sync::atomic::AtomicBool;
 
-// allows us to quickly turn off tracing
-macro_rules! trace {
-    ($fmt:expr) => (print!($fmt));
-    ($fmt:expr, $($arg:tt)*) => (print!($fmt, $($arg)*));
-}
+use std::io;
 
-// trace to a minimum width. Always expects parameters.
-macro_rules! tracew {
-    ($width:expr, $fmt:expr, $($arg:tt)*) => {
-        let str = format!($fmt, $($arg)*);
-        let width = $width;
-        print!("{:width$}", str);
-    };
-}
+use super::ps2::Ps2;
+use super::savestate::{Savestate, StateReader, StateWriter};
+
+/// Runtime switch for the instruction trace, so that the debuggers can keep their output readable.
+pub static TRACE_ENABLED: AtomicBool = AtomicBool::new(true);
 
 // trace the op-code disassembly portion into a minimum width area.
 macro_rules! trace_opdis {
@@ -41,15 +34,93 @@ pub struct R5900State {
 }
 
 const COP0_PRID: usize = 0x0f;
+pub const COP0_STATUS: usize = 12;
+pub const COP0_CAUSE: usize = 13;
+pub const COP0_EPC: usize = 14;
+pub const COP0_ERROREPC: usize = 30;
+
+const STATUS_IE: u32 = 1 << 0;
+const STATUS_EXL: u32 = 1 << 1;
+const STATUS_ERL: u32 = 1 << 2;
+const STATUS_KSU: u32 = 3 << 3;
+const STATUS_IM: u32 = 0x8C00;
+const STATUS_EIE: u32 = 1 << 16;
+const STATUS_EDI: u32 = 1 << 17;
+const STATUS_BEV: u32 = 1 << 22;
+
+const CAUSE_EXCCODE: u32 = 0x7C;
+const CAUSE_BD: u32 = 1 << 31;
+
+/// Coprocessors usable, BEV and ERL, as the CPU comes out of reset.
+const STATUS_RESET: u32 = 0x7040_0004;
+
+/* the VPU-STAT bit BC2F/BC2T test */
+const VPU_STAT_VU1_RUNNING: u32 = 1 << 8;
+
+/// Level 1 exception codes for Cause.ExcCode.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Exception {
+    Interrupt = 0,
+    AddressLoad = 4,
+    AddressStore = 5,
+    Syscall = 8,
+    Break = 9,
+    ReservedInstruction = 10,
+    CoprocessorUnusable = 11,
+    Overflow = 12,
+    Trap = 13,
+}
 
 impl R5900State {
     pub fn new() -> R5900State {
         let mut it = R5900State { pc: 0xBFC0_0000, branch_address: 0, delay_slot_addr: 0, gpr_regs: [[0;4]; 32], fpr_regs: [0.0; 32], cop0_regs: [0; 32], lo: 0, hi: 0 };
         it.cop0_regs[COP0_PRID] = 0x00002e20;
+        it.cop0_regs[COP0_STATUS] = STATUS_RESET;
         return it;
     }
 }
 
+impl Savestate for R5900State {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.pc);
+        w.write_u32(self.branch_address);
+        w.write_u32(self.delay_slot_addr);
+        for reg in &self.gpr_regs {
+            for word in reg {
+                w.write_u32(*word);
+            }
+        }
+        for reg in &self.fpr_regs {
+            w.write_f32(*reg);
+        }
+        for reg in &self.cop0_regs {
+            w.write_u32(*reg);
+        }
+        w.write_u32(self.lo);
+        w.write_u32(self.hi);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.pc = r.read_u32()?;
+        self.branch_address = r.read_u32()?;
+        self.delay_slot_addr = r.read_u32()?;
+        for reg in self.gpr_regs.iter_mut() {
+            for word in reg.iter_mut() {
+                *word = r.read_u32()?;
+            }
+        }
+        for reg in self.fpr_regs.iter_mut() {
+            *reg = r.read_f32()?;
+        }
+        for reg in self.cop0_regs.iter_mut() {
+            *reg = r.read_u32()?;
+        }
+        self.lo = r.read_u32()?;
+        self.hi = r.read_u32()?;
+        Ok(())
+    }
+}
+
 /*
     The R5900 processor is not encapsulated from the system, instead the state is part of the
     Ps2 system and the whole system state is passed in.
@@ -65,23 +136,70 @@ pub struct R5900 {}
 
 impl R5900 {
     pub fn step(sys: &mut Ps2) {
-        let instruction = sys.read_ee_u32(sys.r5900.pc);
+        if Self::interrupt_pending(sys) {
+            Self::take_exception(sys, Exception::Interrupt);
+            return;
+        }
+        let instruction = sys.peek_ee_u32(sys.r5900.pc);
         let op_code: usize = ((instruction >> 26) & 0x3f).try_into().unwrap();
+        if !sys.symbols.is_empty() {
+            tracew!(32, "{}", sys.symbols.format_addr(sys.r5900.pc).unwrap_or_default());
+        }
         trace!(
             "{:#010X}:  {:#010X}    ",
             sys.r5900.pc, instruction
         );
         trace!("{:#04X} ", op_code);
-        let in_branch_delay = sys.r5900.delay_slot_addr == sys.r5900.pc;
+        let pc = sys.r5900.pc;
+        let in_branch_delay = sys.r5900.delay_slot_addr == pc;
         Self::OPCODE_HANDLERS[op_code](sys, instruction);
         trace!("\n");
-        if in_branch_delay {
+        // an instruction that stalls leaves pc alone, and runs again next step
+        if in_branch_delay && sys.r5900.pc != pc {
             trace!("Branching\n");
             sys.r5900.pc = sys.r5900.branch_address;
             sys.r5900.delay_slot_addr = 0;
         }
     }
 
+    /// An interrupt is taken when a Cause.IP line is enabled in Status.IM, interrupts are on (IE and
+    /// EIE) and we aren't already handling an exception or error (EXL and ERL).
+    fn interrupt_pending(sys: &Ps2) -> bool {
+        let status = sys.r5900.cop0_regs[COP0_STATUS];
+        let cause = sys.r5900.cop0_regs[COP0_CAUSE];
+        status & (STATUS_IE | STATUS_EIE) == (STATUS_IE | STATUS_EIE)
+            && status & (STATUS_EXL | STATUS_ERL) == 0
+            && cause & status & STATUS_IM != 0
+    }
+
+    /*
+        Level 1 exceptions. EPC gets the address of the instruction to restart, which is the branch if
+        we're in a delay slot (and Cause.BD says so). Interrupts have their own vector, everything else
+        goes to the common one, and both move to the ROM while Status.BEV is set.
+    */
+    pub fn take_exception(sys: &mut Ps2, exception: Exception) {
+        let cpu = &mut sys.r5900;
+        let in_branch_delay = cpu.delay_slot_addr == cpu.pc;
+        trace!("Exception {:?} at {:#010X}\n", exception, cpu.pc);
+
+        let mut cause = (cpu.cop0_regs[COP0_CAUSE] & !(CAUSE_EXCCODE | CAUSE_BD)) | ((exception as u32) << 2);
+        if cpu.cop0_regs[COP0_STATUS] & STATUS_EXL == 0 {
+            if in_branch_delay {
+                cpu.cop0_regs[COP0_EPC] = cpu.pc.wrapping_sub(4);
+                cause |= CAUSE_BD;
+            } else {
+                cpu.cop0_regs[COP0_EPC] = cpu.pc;
+            }
+        }
+        cpu.cop0_regs[COP0_CAUSE] = cause;
+        cpu.cop0_regs[COP0_STATUS] |= STATUS_EXL;
+        cpu.delay_slot_addr = 0;
+
+        let base = if cpu.cop0_regs[COP0_STATUS] & STATUS_BEV != 0 { 0xBFC0_0200 } else { 0x8000_0000 };
+        let offset = if exception == Exception::Interrupt { 0x200 } else { 0x180 };
+        cpu.pc = base + offset;
+    }
+
     fn op_special(sys: &mut Ps2, instruction: u32) {
         let function_no: usize = (instruction & 0x3f).try_into().unwrap();
         Self::SPECIAL_HANDLERS[function_no](sys, instruction);
@@ -103,6 +221,9 @@ impl R5900 {
         let jump_addr = (instr_index*4) | ((sys.r5900.pc + 4) & 0xf000_0000);
 
         trace!("JAL {:#10X}", jump_addr);
+        if let Some(name) = sys.symbols.format_addr(jump_addr) {
+            trace!(" <{}>", name);
+        }
         Self::set_gpr_unsigned(sys, 31, sys.r5900.pc + 8);
         Self::schedule_jump(sys, jump_addr);
         sys.r5900.pc += 4;
@@ -287,23 +408,140 @@ impl R5900 {
                 sys.r5900.pc += 4;
             }
             4 => {
-                trace!("MT0");
+                trace!("MTC0 {}, {}", MIPS_GPR_NAMES[rt], COP0_REGNAMES[rd]);
+                let value = sys.r5900.gpr_regs[rt][0];
+                if rd == COP0_CAUSE {
+                    // only the software interrupt bits can be written
+                    let cause = &mut sys.r5900.cop0_regs[COP0_CAUSE];
+                    *cause = (*cause & !0x300) | (value & 0x300);
+                } else {
+                    sys.r5900.cop0_regs[rd] = value;
+                }
                 sys.r5900.pc += 4;
             }
             8 => {
                 trace!("BC0");
             }
             0x10 => {
-                trace!("C0");
-                sys.r5900.pc += 4;
+                Self::op_c0(sys, function_no);
             }
             _ => (),
         }
     }
 
+    fn op_c0(sys: &mut Ps2, function_no: u32) {
+        let status = sys.r5900.cop0_regs[COP0_STATUS];
+        match function_no {
+            0x18 => {
+                // ERET has no delay slot
+                trace!("ERET");
+                if status & STATUS_ERL != 0 {
+                    sys.r5900.pc = sys.r5900.cop0_regs[COP0_ERROREPC];
+                    sys.r5900.cop0_regs[COP0_STATUS] &= !STATUS_ERL;
+                } else {
+                    sys.r5900.pc = sys.r5900.cop0_regs[COP0_EPC];
+                    sys.r5900.cop0_regs[COP0_STATUS] &= !STATUS_EXL;
+                }
+                return;
+            }
+            0x38 | 0x39 => {
+                // EI and DI only work in kernel mode, at exception level or if Status.EDI allows it
+                let allowed = status & (STATUS_EDI | STATUS_EXL | STATUS_ERL) != 0 || status & STATUS_KSU == 0;
+                if function_no == 0x38 {
+                    trace!("EI");
+                    if allowed {
+                        sys.r5900.cop0_regs[COP0_STATUS] |= STATUS_EIE;
+                    }
+                } else {
+                    trace!("DI");
+                    if allowed {
+                        sys.r5900.cop0_regs[COP0_STATUS] &= !STATUS_EIE;
+                    }
+                }
+            }
+            _ => {
+                trace!("C0 {:#04X}", function_no);
+            }
+        }
+        sys.r5900.pc += 4;
+    }
+
     fn op_cop1(sys: &mut Ps2, instruction: u32) {}
 
-    fn op_cop2(sys: &mut Ps2, instruction: u32) {}
+    fn op_cop2(sys: &mut Ps2, instruction: u32) {
+        let rs = (instruction >> 21) & 0x1f;
+        let rt = ((instruction >> 16) & 0x1f) as usize;
+        let rd = ((instruction >> 11) & 0x1f) as usize;
+        // with the interlock bit, the moves wait for a VU0 microprogram to end or pass an M bit
+        let interlock = if instruction & 1 != 0 { ".I" } else { "" };
+        if (1..=6).contains(&rs) && instruction & 1 != 0 && sys.vu0_interlocked() {
+            trace!("COP2 - waiting for VU0");
+            return;
+        }
+        // and VU0 can't take macro instructions, or start another program, until it's done
+        if rs >= 0x10 && sys.vu0.micro.running {
+            trace!("COP2 - VU0 busy");
+            return;
+        }
+        match rs {
+            1 => {
+                trace!("QMFC2{} {}, vf{}", interlock, MIPS_GPR_NAMES[rt], rd);
+                if rt != 0 {
+                    sys.r5900.gpr_regs[rt] = sys.vu0.vf[rd];
+                }
+            }
+            2 => {
+                trace!("CFC2{} {}, vi{}", interlock, MIPS_GPR_NAMES[rt], rd);
+                if rt != 0 {
+                    let value = sys.vu0_control_read(rd);
+                    Self::write_sign_extended_32_bit_reg(sys, rt, value);
+                }
+            }
+            5 => {
+                trace!("QMTC2{} {}, vf{}", interlock, MIPS_GPR_NAMES[rt], rd);
+                let value = sys.r5900.gpr_regs[rt];
+                sys.vu0.set_vf(rd, 0xF, value);
+            }
+            6 => {
+                trace!("CTC2{} {}, vi{}", interlock, MIPS_GPR_NAMES[rt], rd);
+                let value = sys.r5900.gpr_regs[rt][0];
+                sys.vu0_control_write(rd, value);
+            }
+            8 => {
+                // BC2F, BC2T, BC2FL and BC2TL test whether VU1 is running
+                let offset = (instruction & 0xFFFF) as i16;
+                let running = sys.vpu_stat() & VPU_STAT_VU1_RUNNING != 0;
+                let taken = running == (rt & 1 != 0);
+                trace_opdis!("BC2{}{} {:#06X}", if rt & 1 != 0 { "T" } else { "F" }, if rt & 2 != 0 { "L" } else { "" }, offset);
+                if taken {
+                    Self::schedule_branch(sys, offset);
+                } else if rt & 2 != 0 {
+                    trace!("-> not taken, skip delay slot");
+                    sys.r5900.pc += 4;
+                }
+            }
+            0x10..=0x1F => match instruction & 0x3F {
+                0x38 => {
+                    let addr = ((instruction >> 6) & 0x7FFF) * 8;
+                    trace!("VCALLMS {:#06X}", addr);
+                    sys.vu0_start(addr);
+                }
+                0x39 => {
+                    trace!("VCALLMSR");
+                    let addr = sys.vu0.cmsar * 8;
+                    sys.vu0_start(addr);
+                }
+                _ => {
+                    trace!("COP2 {:#010X}", instruction);
+                    sys.vu0.macro_instruction(instruction);
+                }
+            },
+            _ => {
+                trace!("COP2 - unknown rs {:#04X}", rs);
+            }
+        }
+        sys.r5900.pc += 4;
+    }
 
     fn op_illegal(sys: &mut Ps2, instruction: u32) {}
 
@@ -453,7 +691,23 @@ impl R5900 {
 
     fn op_pref(sys: &mut Ps2, instruction: u32) {}
 
-    fn op_ldc2(sys: &mut Ps2, instruction: u32) {}
+    fn op_ldc2(sys: &mut Ps2, instruction: u32) {
+        let base = ((instruction >> 21) & 0x1f) as usize;
+        let ft = ((instruction >> 16) & 0x1f) as usize;
+        let offset = (instruction & 0xFFFF) as i16;
+
+        trace_opdis!("LQC2 vf{}, {:#06X}({})", ft, offset, MIPS_GPR_NAMES[base]);
+
+        // the low four bits of the address are ignored
+        let addr = (sys.r5900.gpr_regs[base][0] as i32 + i32::from(offset)) as u32 & !0xF;
+        let mut value = [0; 4];
+        for (i, word) in value.iter_mut().enumerate() {
+            *word = sys.read_ee_u32(addr + i as u32 * 4);
+        }
+        sys.vu0.set_vf(ft, 0xF, value);
+
+        sys.r5900.pc += 4;
+    }
 
     fn op_ld(sys: &mut Ps2, instruction: u32) {}
 
@@ -470,7 +724,21 @@ impl R5900 {
         sys.r5900.pc += 4;
     }
 
-    fn op_sdc2(sys: &mut Ps2, instruction: u32) {}
+    fn op_sdc2(sys: &mut Ps2, instruction: u32) {
+        let base = ((instruction >> 21) & 0x1f) as usize;
+        let ft = ((instruction >> 16) & 0x1f) as usize;
+        let offset = (instruction & 0xFFFF) as i16;
+
+        trace_opdis!("SQC2 vf{}, {:#06X}({})", ft, offset, MIPS_GPR_NAMES[base]);
+
+        let addr = (sys.r5900.gpr_regs[base][0] as i32 + i32::from(offset)) as u32 & !0xF;
+        let value = sys.vu0.vf[ft];
+        for (i, word) in value.iter().enumerate() {
+            sys.write_ee_u32(addr + i as u32 * 4, *word);
+        }
+
+        sys.r5900.pc += 4;
+    }
 
     fn op_sd(sys: &mut Ps2, instruction: u32) {
         let base = ((instruction >> 21) & 0x1f) as usize;
@@ -1000,9 +1268,50 @@ mod tests {
         assert_eq!(0xC000_0004, ps2.r5900.gpr_regs[31][0]);
         assert_eq!(0xC000_0000 | 40, ps2.r5900.branch_address);
     }
+
+    #[test]
+    fn test_cop2_moves() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.r5900.gpr_regs[8] = [1, 2, 3, 4];
+        // QMTC2 t0, vf1 then QMFC2 t1, vf1
+        R5900::op_cop2(&mut ps2, (0x12 << 26) | (5 << 21) | (8 << 16) | (1 << 11));
+        R5900::op_cop2(&mut ps2, (0x12 << 26) | (1 << 21) | (9 << 16) | (1 << 11));
+        assert_eq!([1, 2, 3, 4], ps2.vu0.vf[1]);
+        assert_eq!([1, 2, 3, 4], ps2.r5900.gpr_regs[9]);
+
+        // CTC2 t0, vi2 then CFC2 t2, vi2, sign extended
+        ps2.r5900.gpr_regs[8][0] = 0x8000;
+        R5900::op_cop2(&mut ps2, (0x12 << 26) | (6 << 21) | (8 << 16) | (2 << 11));
+        R5900::op_cop2(&mut ps2, (0x12 << 26) | (2 << 21) | (10 << 16) | (2 << 11));
+        assert_eq!([0x8000, 0], [ps2.r5900.gpr_regs[10][0], ps2.r5900.gpr_regs[10][1]]);
+        assert_eq!(0xBFC0_0010, ps2.r5900.pc);
+    }
+
+    #[test]
+    fn test_lqc2_sqc2() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.vu0.vf[3] = [5, 6, 7, 8];
+        ps2.r5900.gpr_regs[8][0] = 0x1000;
+        // SQC2 vf3, 0x24(t0) stores at 0x1020, then LQC2 vf4, 0x20(t0)
+        R5900::op_sdc2(&mut ps2, (0x3E << 26) | (8 << 21) | (3 << 16) | 0x24);
+        assert_eq!(7, ps2.read_ee_u32(0x1028));
+        R5900::op_ldc2(&mut ps2, (0x36 << 26) | (8 << 21) | (4 << 16) | 0x20);
+        assert_eq!([5, 6, 7, 8], ps2.vu0.vf[4]);
+    }
+
+    #[test]
+    fn test_bc2() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        // VU1 isn't running, so BC2F is taken and BC2TL skips its delay slot
+        R5900::op_cop2(&mut ps2, (0x12 << 26) | (8 << 21) | 4);
+        assert_eq!((0xBFC0_0004, 0xBFC0_0014), (ps2.r5900.pc, ps2.r5900.branch_address));
+        ps2.r5900.branch_address = 0;
+        R5900::op_cop2(&mut ps2, (0x12 << 26) | (8 << 21) | (3 << 16) | 4);
+        assert_eq!((0xBFC0_000C, 0), (ps2.r5900.pc, ps2.r5900.branch_address));
+    }
 }
 
-const COP0_REGNAMES: [&str; 32] = 
+pub const COP0_REGNAMES: [&str; 32] = 
 [
 	"Index",
 	"Random",
@@ -1038,7 +1347,7 @@ const COP0_REGNAMES: [&str; 32] =
 	"RESERVED"
 ];
 
-const MIPS_GPR_NAMES: [&str; 32] = 
+pub const MIPS_GPR_NAMES: [&str; 32] = 
 [
     "Zero", "AT", "V0", "V1", "A0", "A1", "A2", "A3",
     "T0", "T1", "T2", "T3", "T4", "T5", "T6", "T7",
@@ -1046,7 +1355,7 @@ const MIPS_GPR_NAMES: [&str; 32] =
     "T8", "T9", "K0", "K1", "GP", "SP", "FP", "RA"
 ];
 
-const MIPS_FPR_NAMES: [&str; 32] = 
+pub const MIPS_FPR_NAMES: [&str; 32] = 
 [
     "F0", "F1", "F2", "F3", "F4", "F5", "F6", "F7",
     "F8", "F9", "F10", "F11", "F12", "F13", "F14", "F15",
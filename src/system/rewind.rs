@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+use std::io;
+
+use super::ps2::Ps2;
+use super::savestate::{compress, decompress, Savestate, StateReader, StateWriter, STATE_VERSION};
+
+/*
+    Rewind buffer of periodic snapshots, used by the debugger to step backwards.
+
+    A snapshot is the save state payload of the whole machine. EE RAM makes up nearly all of it and
+    very little of it changes from one snapshot to the next, so most snapshots are deltas holding only
+    the 4KB pages that differ from the snapshot before. Every keyframe_interval snapshots a complete
+    compressed payload is stored instead, so restoring never has to replay too long a chain of deltas.
+
+    When the snapshots go over the memory budget the oldest keyframe and its deltas are dropped
+    together. The uncompressed copy of the latest payload kept to diff against isn't counted.
+
+    Stepping back restores the newest snapshot at or before the target and runs forward to it, which
+    relies on execution being deterministic (see movie.rs).
+*/
+
+const PAGE_SIZE: usize = 0x1000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RewindConfig {
+    /* frames between snapshots */
+    pub interval: u64,
+
+    /* bytes of compressed snapshot data to keep */
+    pub budget: usize,
+
+    /* snapshots per keyframe */
+    pub keyframe_interval: usize
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        RewindConfig { interval: 10, budget: 256 << 20, keyframe_interval: 30 }
+    }
+}
+
+enum SnapshotData {
+    Keyframe(Vec<u8>),
+
+    /* (page index, compressed page) for every page that changed */
+    Delta(Vec<(u32, Vec<u8>)>)
+}
+
+struct Snapshot {
+    cycles: u64,
+    payload_len: usize,
+    data: SnapshotData
+}
+
+impl Snapshot {
+    fn size(&self) -> usize {
+        match &self.data {
+            SnapshotData::Keyframe(data) => data.len(),
+            SnapshotData::Delta(pages) => pages.iter().map(|(_, page)| page.len() + 8).sum(),
+        }
+    }
+
+    fn is_keyframe(&self) -> bool {
+        matches!(self.data, SnapshotData::Keyframe(_))
+    }
+}
+
+pub struct RewindBuffer {
+    pub config: RewindConfig,
+    snapshots: VecDeque<Snapshot>,
+    total_size: usize,
+
+    /* the payload of the newest snapshot, which the next delta is made against */
+    last_payload: Vec<u8>,
+    since_keyframe: usize
+}
+
+fn page_bytes(payload: &[u8], page: usize) -> &[u8] {
+    let start = (page * PAGE_SIZE).min(payload.len());
+    &payload[start..(start + PAGE_SIZE).min(payload.len())]
+}
+
+impl RewindBuffer {
+    pub fn new(config: RewindConfig) -> RewindBuffer {
+        RewindBuffer { config, snapshots: VecDeque::new(), total_size: 0, last_payload: Vec::new(), since_keyframe: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Bytes used by the snapshots.
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    /// The cycle count of the oldest snapshot, which is as far back as we can go.
+    pub fn oldest_cycles(&self) -> Option<u64> {
+        self.snapshots.front().map(|s| s.cycles)
+    }
+
+    /// Takes a snapshot if a frame boundary on the interval has been reached since the last one.
+    pub fn maybe_capture(&mut self, ps2: &Ps2) {
+        let due = match self.snapshots.back() {
+            Some(last) => ps2.frame_number() >= last.cycles / super::ps2::EE_CYCLES_PER_FRAME + self.config.interval.max(1),
+            None => true,
+        };
+        if due {
+            self.capture(ps2);
+        }
+    }
+
+    /// Takes a snapshot now.
+    pub fn capture(&mut self, ps2: &Ps2) {
+        let mut w = StateWriter::new();
+        ps2.save_state(&mut w);
+        let payload = w.into_bytes();
+
+        let data = if self.snapshots.is_empty() || self.since_keyframe + 1 >= self.config.keyframe_interval {
+            self.since_keyframe = 0;
+            SnapshotData::Keyframe(compress(&payload))
+        } else {
+            self.since_keyframe += 1;
+            let changed = (0..payload.len().div_ceil(PAGE_SIZE))
+                .filter(|&page| page_bytes(&payload, page) != page_bytes(&self.last_payload, page))
+                .map(|page| (page as u32, compress(page_bytes(&payload, page))))
+                .collect();
+            SnapshotData::Delta(changed)
+        };
+        let snapshot = Snapshot { cycles: ps2.cycles, payload_len: payload.len(), data };
+        self.total_size += snapshot.size();
+        self.snapshots.push_back(snapshot);
+        self.last_payload = payload;
+        self.evict();
+    }
+
+    /// Drops the oldest keyframe and its deltas while over budget, always keeping the newest group.
+    fn evict(&mut self) {
+        while self.total_size > self.config.budget && self.snapshots.iter().skip(1).any(|s| s.is_keyframe()) {
+            loop {
+                let oldest = self.snapshots.pop_front().unwrap();
+                self.total_size -= oldest.size();
+                if self.snapshots.front().is_none_or(|s| s.is_keyframe()) {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Rebuilds the payload of a snapshot from its keyframe and the deltas after it.
+    fn payload(&self, index: usize) -> io::Result<Vec<u8>> {
+        let key = (0..=index).rev().find(|&i| self.snapshots[i].is_keyframe()).expect("rewind buffer starts with a keyframe");
+        let mut payload = Vec::new();
+        for snapshot in self.snapshots.range(key..=index) {
+            match &snapshot.data {
+                SnapshotData::Keyframe(data) => payload = decompress(data, snapshot.payload_len)?,
+                SnapshotData::Delta(pages) => {
+                    payload.resize(snapshot.payload_len, 0);
+                    for (page, data) in pages {
+                        let start = *page as usize * PAGE_SIZE;
+                        let len = PAGE_SIZE.min(snapshot.payload_len - start);
+                        payload[start..start + len].copy_from_slice(&decompress(data, len)?);
+                    }
+                }
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Restores the newest snapshot taken at or before the given cycle count, dropping any after it.
+    /// Returns the cycle count of the snapshot.
+    pub fn restore(&mut self, ps2: &mut Ps2, cycles: u64) -> io::Result<u64> {
+        let index = self.snapshots.iter().rposition(|s| s.cycles <= cycles)
+            .ok_or_else(|| io::Error::other("not that far back in the rewind buffer"))?;
+        let payload = self.payload(index)?;
+        ps2.load_state(&mut StateReader::new(&payload, STATE_VERSION))?;
+
+        while self.snapshots.len() > index + 1 {
+            let dropped = self.snapshots.pop_back().unwrap();
+            self.total_size -= dropped.size();
+        }
+        let key = self.snapshots.iter().rposition(|s| s.is_keyframe()).unwrap();
+        self.since_keyframe = index - key;
+        self.last_payload = payload;
+        Ok(self.snapshots[index].cycles)
+    }
+
+    /// Winds the machine back the given number of instructions.
+    pub fn step_back(&mut self, ps2: &mut Ps2, count: u64) -> io::Result<()> {
+        let target = ps2.cycles.checked_sub(count).ok_or_else(|| io::Error::other("can't step back past power on"))?;
+        self.restore(ps2, target)?;
+        while ps2.cycles < target {
+            ps2.step();
+        }
+        // the watchpoints may have fired on the way, but that was history being repeated
+        ps2.breakpoints.take_hit();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::r5900::TRACE_ENABLED;
+    use std::sync::atomic::Ordering;
+
+    /* counts up in $t0 and stores it to 0x1000 each time round */
+    fn counting_ps2() -> Box<Ps2> {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        let program = [
+            0x2508_0001,  // addiu t0, t0, 1
+            0xAC08_1000,  // sw t0, 0x1000(zero)
+            0x1000_FFFD,  // beq zero, zero, -3
+            0x0000_0000,  // nop
+        ];
+        for (i, word) in program.iter().enumerate() {
+            ps2.poke_ee_u32(0x0010_0000 + i as u32 * 4, *word);
+        }
+        ps2.r5900.pc = 0x0010_0000;
+        ps2
+    }
+
+    #[test]
+    fn test_step_back() {
+        TRACE_ENABLED.store(false, Ordering::Relaxed);
+        let mut ps2 = counting_ps2();
+        let mut rewind = RewindBuffer::new(RewindConfig { interval: 1, budget: usize::MAX, keyframe_interval: 2 });
+        for _ in 0..3 {
+            rewind.capture(&ps2);
+            for _ in 0..40 {
+                ps2.step();
+            }
+        }
+        assert_eq!(3, rewind.len());
+        assert_eq!(30, ps2.r5900.gpr_regs[8][0]);
+
+        rewind.step_back(&mut ps2, 4).unwrap();
+        assert_eq!(116, ps2.cycles);
+        assert_eq!(29, ps2.r5900.gpr_regs[8][0]);
+        assert_eq!(29, ps2.peek_ee_u32(0x1000));
+        assert_eq!(3, rewind.len());
+
+        // back into the delta snapshot, which drops the newest one
+        rewind.step_back(&mut ps2, 70).unwrap();
+        assert_eq!(46, ps2.cycles);
+        assert_eq!(12, ps2.peek_ee_u32(0x1000));
+        assert_eq!(2, rewind.len());
+        assert!(rewind.step_back(&mut ps2, 100).is_err());
+    }
+
+    #[test]
+    fn test_budget() {
+        TRACE_ENABLED.store(false, Ordering::Relaxed);
+        let mut ps2 = counting_ps2();
+        let mut rewind = RewindBuffer::new(RewindConfig { interval: 1, budget: 1, keyframe_interval: 2 });
+        for _ in 0..5 {
+            rewind.capture(&ps2);
+            ps2.step();
+        }
+        // the older groups are dropped and only the newest keyframe is left
+        assert_eq!(1, rewind.len());
+        assert_eq!(Some(4), rewind.oldest_cycles());
+    }
+}
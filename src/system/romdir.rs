@@ -0,0 +1,131 @@
+use std::io;
+
+/*
+    The BIOS image is a simple filesystem. Somewhere near the start of the ROM there is a table of
+    16 byte entries (the ROMDIR) describing each file. The entries are in the same order as the files
+    themselves, and each file starts on a 16 byte boundary following the previous one. The first file
+    is RESET which begins at offset 0 and the table is terminated by an entry with an empty name.
+
+    Each entry is laid out as:
+        0x00  name (10 bytes, nul padded)
+        0x0A  size of this file's data in the EXTINFO file (u16)
+        0x0C  size of the file (u32)
+*/
+
+const ROMDIR_ENTRY_SIZE: usize = 16;
+const ROMDIR_NAME_LEN: usize = 10;
+
+pub struct RomDirEntry {
+    pub name: String,
+
+    /* Byte offset of the file from the start of the ROM */
+    pub offset: usize,
+
+    pub size: usize,
+
+    pub ext_info_size: usize
+}
+
+pub struct RomDir {
+    pub entries: Vec<RomDirEntry>,
+
+    /* the ROM as bytes, for extracting from */
+    data: Vec<u8>
+}
+
+/// Converts a ROM held as words back into bytes.
+pub fn rom_bytes(rom: &[u32]) -> Vec<u8> {
+    rom.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+impl RomDir {
+    /// Parses the ROMDIR table of the given ROM image.
+    pub fn parse(rom: &[u32]) -> io::Result<RomDir> {
+        let data = rom_bytes(rom);
+        let table_start = Self::find_table(&data).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "no ROMDIR table found in ROM")
+        })?;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        let mut pos = table_start;
+        while pos + ROMDIR_ENTRY_SIZE <= data.len() && data[pos] != 0 {
+            let name_bytes = &data[pos..pos + ROMDIR_NAME_LEN];
+            let name_len = name_bytes.iter().position(|&b| b == 0).unwrap_or(ROMDIR_NAME_LEN);
+            let name = String::from_utf8_lossy(&name_bytes[..name_len]).into_owned();
+            let ext_info_size = u16::from_le_bytes([data[pos + 10], data[pos + 11]]) as usize;
+            let size = u32::from_le_bytes([data[pos + 12], data[pos + 13], data[pos + 14], data[pos + 15]]) as usize;
+
+            if offset + size > data.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("ROMDIR entry {} extends past the end of the ROM", name)));
+            }
+            entries.push(RomDirEntry { name, offset, size, ext_info_size });
+
+            offset = (offset + size + 15) & !15;
+            pos += ROMDIR_ENTRY_SIZE;
+        }
+        Ok(RomDir { entries, data })
+    }
+
+    /// Looks for the RESET entry which starts the table. The table is always 16 byte aligned.
+    fn find_table(data: &[u8]) -> Option<usize> {
+        (0..data.len().saturating_sub(ROMDIR_ENTRY_SIZE))
+            .step_by(ROMDIR_ENTRY_SIZE)
+            .find(|&pos| &data[pos..pos + 6] == b"RESET\0")
+    }
+
+    pub fn find(&self, name: &str) -> Option<&RomDirEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    /// Returns the contents of the named module.
+    pub fn extract(&self, name: &str) -> Option<Vec<u8>> {
+        let entry = self.find(name)?;
+        Some(self.data[entry.offset..entry.offset + entry.size].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_entry(name: &str, ext_info_size: u16, size: u32) -> Vec<u8> {
+        let mut entry = vec![0u8; ROMDIR_ENTRY_SIZE];
+        entry[..name.len()].copy_from_slice(name.as_bytes());
+        entry[10..12].copy_from_slice(&ext_info_size.to_le_bytes());
+        entry[12..16].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    fn make_rom() -> Vec<u32> {
+        // RESET is 0x20 bytes, ROMDIR follows it with 4 entries (3 + terminator), then a 5 byte module
+        let mut data = vec![0u8; 0x20];
+        data.extend(make_entry("RESET", 0, 0x20));
+        data.extend(make_entry("ROMDIR", 0, 0x40));
+        data.extend(make_entry("IOPBOOT", 4, 5));
+        data.extend(vec![0u8; ROMDIR_ENTRY_SIZE]);
+        data.extend(b"hello");
+        data.resize(0x100, 0);
+        data.chunks(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect()
+    }
+
+    #[test]
+    fn test_parse_romdir() {
+        let rom = make_rom();
+        let romdir = RomDir::parse(&rom).unwrap();
+        assert_eq!(3, romdir.entries.len());
+        assert_eq!("ROMDIR", romdir.entries[1].name);
+        assert_eq!(0x20, romdir.entries[1].offset);
+        assert_eq!(0x60, romdir.entries[2].offset);
+        assert_eq!(4, romdir.entries[2].ext_info_size);
+        assert_eq!(b"hello".to_vec(), romdir.extract("IOPBOOT").unwrap());
+        assert!(romdir.extract("EELOAD").is_none());
+    }
+
+    #[test]
+    fn test_parse_no_romdir() {
+        let rom = vec![0; 64];
+        assert!(RomDir::parse(&rom).is_err());
+    }
+}
@@ -0,0 +1,546 @@
+use std::io;
+
+use super::dmac::Dmac;
+use super::ee_hw::EeHw;
+use super::gif::Gif;
+use super::gs::Gs;
+use super::input::Inputs;
+use super::intc::Intc;
+use super::ps2::Ps2;
+use super::scheduler::Scheduler;
+use super::timers::Timers;
+use super::vu::Vu;
+
+/*
+    Save states.
+
+    A state file is a small header followed by the (usually compressed) payload:
+        0x00  magic "RSX2STAT"
+        0x08  version (u32)
+        0x0C  flags (u32), bit 0 set if the payload is compressed
+        0x10  uncompressed payload length (u32)
+        0x14  FNV-1a hash of the uncompressed payload (u32)
+        0x18  FNV-1a hash of the BIOS the state was made with (u32)
+        0x1C  payload
+
+    The payload is each part of the machine written in a fixed order by its Savestate implementation.
+    When something is added to the machine, bump STATE_VERSION and only read the new fields when
+    reader.version() says they are present, so that older states keep loading with defaults.
+*/
+
+const MAGIC: &[u8; 8] = b"RSX2STAT";
+const HEADER_SIZE: usize = 0x1C;
+
+/// The version written by this build.
+///     1   RAM and the EE core
+///     2   cycle counter and host inputs
+///     3   EE hardware registers
+///     4   INTC
+///     5   scheduler and timers
+///     6   scratchpad and DMAC
+///     7   GS
+///     8   GS vertex queue
+///     9   GS CLUT
+///     10  GS transfers
+///     11  GIF
+///     12  VU0
+///     13  VU micro mode and VU1
+pub const STATE_VERSION: u32 = 13;
+
+/// The oldest version this build can still load.
+const MIN_STATE_VERSION: u32 = 1;
+
+const FLAG_COMPRESSED: u32 = 1;
+
+/// Upper bound on a decompressed payload, well above a full machine state.
+const MAX_DECOMPRESSED_LEN: usize = 256 * 1024 * 1024;
+
+pub trait Savestate {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()>;
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { buf: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+
+    /// Writes a length prefixed block of bytes.
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.write_u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Writes a length prefixed block of words.
+    pub fn write_u32_slice(&mut self, data: &[u32]) {
+        self.write_u32(data.len() as u32);
+        self.buf.reserve(data.len() * 4);
+        for word in data {
+            self.buf.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    version: u32
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8], version: u32) -> StateReader<'a> {
+        StateReader { data, pos: 0, version }
+    }
+
+    /// The version of the state being read.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or_else(|| invalid("save state is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> io::Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        let b = self.take(8)?;
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    pub fn read_f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    pub fn read_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// Reads a block of words into an existing buffer, which must be the same size.
+    pub fn read_u32_slice_into(&mut self, out: &mut [u32]) -> io::Result<()> {
+        let len = self.read_u32()? as usize;
+        if len != out.len() {
+            return Err(invalid("save state block has the wrong size"));
+        }
+        let bytes = self.take(len * 4)?;
+        for (word, b) in out.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        }
+        Ok(())
+    }
+}
+
+/// 32 bit FNV-1a, good enough to catch corruption and mismatched BIOS images.
+pub fn fnv1a32(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C_9DC5u32, |hash, b| (hash ^ *b as u32).wrapping_mul(0x0100_0193))
+}
+
+pub fn fnv1a64(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF2_9CE4_8422_2325u64, |hash, b| (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01B3))
+}
+
+/*
+    Run length compression. Most of the machine state is long runs of zeros (unused RAM) so a simple
+    scheme does well. Each chunk starts with a control byte:
+        0x00-0x7F   copy the next (n + 1) bytes literally
+        0x80-0xFF   repeat the next byte (n - 0x80 + 3) times
+*/
+const MIN_RUN: usize = 3;
+const MAX_RUN: usize = 0x7F + MIN_RUN;
+const MAX_LITERAL: usize = 0x80;
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 4);
+    let mut literal_start = 0;
+    let mut i = 0;
+    let flush_literals = |out: &mut Vec<u8>, literals: &[u8]| {
+        for chunk in literals.chunks(MAX_LITERAL) {
+            out.push((chunk.len() - 1) as u8);
+            out.extend_from_slice(chunk);
+        }
+    };
+    while i < data.len() {
+        let byte = data[i];
+        let mut run = 1;
+        while i + run < data.len() && run < MAX_RUN && data[i + run] == byte {
+            run += 1;
+        }
+        if run >= MIN_RUN {
+            flush_literals(&mut out, &data[literal_start..i]);
+            out.push((0x80 + run - MIN_RUN) as u8);
+            out.push(byte);
+            i += run;
+            literal_start = i;
+        } else {
+            i += 1;
+        }
+    }
+    flush_literals(&mut out, &data[literal_start..]);
+    out
+}
+
+pub fn decompress(data: &[u8], expected_len: usize) -> io::Result<Vec<u8>> {
+    if expected_len > MAX_DECOMPRESSED_LEN {
+        return Err(invalid("compressed data claims an implausible length"));
+    }
+    let mut out = Vec::with_capacity(expected_len);
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i] as usize;
+        i += 1;
+        if control < 0x80 {
+            let literals = data.get(i..i + control + 1).ok_or_else(|| invalid("compressed data is truncated"))?;
+            out.extend_from_slice(literals);
+            i += control + 1;
+        } else {
+            let byte = *data.get(i).ok_or_else(|| invalid("compressed data is truncated"))?;
+            out.resize(out.len() + control - 0x80 + MIN_RUN, byte);
+            i += 1;
+        }
+        if out.len() > expected_len {
+            return Err(invalid("compressed data is longer than expected"));
+        }
+    }
+    if out.len() != expected_len {
+        return Err(invalid("compressed data is shorter than expected"));
+    }
+    Ok(out)
+}
+
+impl Savestate for Ps2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32_slice(&self.ee_ram);
+        w.write_u32_slice(&self.iop_ram);
+        self.r5900.save_state(w);
+        w.write_u64(self.cycles);
+        self.inputs.save_state(w);
+        self.ee_hw.save_state(w);
+        self.intc.save_state(w);
+        self.scheduler.save_state(w);
+        self.timers.save_state(w);
+        w.write_u32_slice(&self.scratchpad);
+        self.dmac.save_state(w);
+        self.gs.save_state(w);
+        self.gif.save_state(w);
+        self.vu0.save_state(w);
+        self.vu1.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_u32_slice_into(&mut self.ee_ram)?;
+        r.read_u32_slice_into(&mut self.iop_ram)?;
+        self.r5900.load_state(r)?;
+        if r.version() >= 2 {
+            self.cycles = r.read_u64()?;
+            self.inputs.load_state(r)?;
+        } else {
+            self.cycles = 0;
+            self.inputs = Inputs::new();
+        }
+        if r.version() >= 3 {
+            self.ee_hw.load_state(r)?;
+        } else {
+            self.ee_hw = EeHw::new();
+        }
+        if r.version() >= 4 {
+            self.intc.load_state(r)?;
+        } else {
+            self.intc = Intc::new();
+        }
+        if r.version() >= 5 {
+            self.scheduler.load_state(r)?;
+            self.timers.load_state(r)?;
+        } else {
+            self.scheduler = Scheduler::new();
+            self.timers = Timers::new();
+            self.schedule_video_events();
+        }
+        if r.version() >= 6 {
+            r.read_u32_slice_into(&mut self.scratchpad)?;
+            self.dmac.load_state(r)?;
+        } else {
+            self.scratchpad.fill(0);
+            self.dmac = Dmac::new();
+        }
+        if r.version() >= 7 {
+            self.gs.load_state(r)?;
+        } else {
+            self.gs = Gs::new();
+        }
+        if r.version() >= 11 {
+            self.gif.load_state(r)?;
+        } else {
+            self.gif = Gif::new();
+        }
+        if r.version() >= 12 {
+            self.vu0.load_state(r)?;
+        } else {
+            self.vu0 = Vu::vu0();
+        }
+        if r.version() >= 13 {
+            self.vu1.load_state(r)?;
+        } else {
+            self.vu1 = Vu::vu1();
+        }
+        Ok(())
+    }
+}
+
+impl Ps2 {
+    pub fn bios_hash(&self) -> u32 {
+        let bytes: Vec<u8> = self.rom.iter().flat_map(|w| w.to_le_bytes()).collect();
+        fnv1a32(&bytes)
+    }
+
+    /// Hash of the complete machine state, used to spot replays going out of sync.
+    pub fn state_hash(&self) -> u64 {
+        let mut w = StateWriter::new();
+        self.save_state(&mut w);
+        fnv1a64(&w.into_bytes())
+    }
+
+    /// Serializes the whole machine into a compressed state file image.
+    pub fn save_state_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        self.save_state(&mut w);
+        let payload = w.into_bytes();
+        let compressed = compress(&payload);
+
+        let mut out = Vec::with_capacity(HEADER_SIZE + compressed.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        out.extend_from_slice(&FLAG_COMPRESSED.to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&fnv1a32(&payload).to_le_bytes());
+        out.extend_from_slice(&self.bios_hash().to_le_bytes());
+        out.extend_from_slice(&compressed);
+        out
+    }
+
+    /// Restores the machine from a state file image. The machine is left as it was if the state can't be loaded.
+    pub fn load_state_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() < HEADER_SIZE || &data[0..8] != MAGIC {
+            return Err(invalid("not a save state"));
+        }
+        let header_word = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let version = header_word(0x08);
+        let flags = header_word(0x0C);
+        let payload_len = header_word(0x10) as usize;
+        let payload_hash = header_word(0x14);
+        let bios_hash = header_word(0x18);
+
+        if version > STATE_VERSION {
+            return Err(invalid(&format!("save state version {} is newer than this build supports ({})", version, STATE_VERSION)));
+        }
+        if version < MIN_STATE_VERSION {
+            return Err(invalid(&format!("save state version {} is too old, the oldest supported is {}", version, MIN_STATE_VERSION)));
+        }
+        if bios_hash != self.bios_hash() {
+            return Err(invalid("save state was made with a different BIOS"));
+        }
+
+        let payload = if flags & FLAG_COMPRESSED != 0 {
+            decompress(&data[HEADER_SIZE..], payload_len)?
+        } else {
+            data[HEADER_SIZE..].to_vec()
+        };
+        if fnv1a32(&payload) != payload_hash {
+            return Err(invalid("save state is corrupt"));
+        }
+
+        // a payload can still go wrong partway through, so keep what we had to go back to
+        let mut w = StateWriter::new();
+        self.save_state(&mut w);
+        let previous = w.into_bytes();
+
+        let mut r = StateReader::new(&payload, version);
+        let result = self.load_state(&mut r).and_then(|_| {
+            if r.is_at_end() { Ok(()) } else { Err(invalid("save state has unexpected trailing data")) }
+        });
+        if result.is_err() {
+            self.load_state(&mut StateReader::new(&previous, STATE_VERSION)).expect("a state we just saved loads back");
+        }
+        result
+    }
+
+    pub fn save_state_file(&self, path: &str) -> io::Result<()> {
+        std::fs::write(path, self.save_state_bytes())
+    }
+
+    pub fn load_state_file(&mut self, path: &str) -> io::Result<()> {
+        let data = std::fs::read(path)?;
+        self.load_state_bytes(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let mut data = vec![0u8; 1000];
+        data.extend((0..=255).cycle().take(700));
+        data.extend([7, 7, 1, 7, 7, 7]);
+        let compressed = compress(&data);
+        assert!(compressed.len() < data.len());
+        assert_eq!(data, decompress(&compressed, data.len()).unwrap());
+        assert!(decompress(&compressed, data.len() + 1).is_err());
+        assert!(decompress(&compressed, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut ps2 = Ps2::new(&[1, 2, 3, 4]);
+        ps2.write_ee_u32(0x0010_0000, 0x1234_5678);
+        ps2.iop_ram[0x100] = 0xCAFE;
+        ps2.r5900.pc = 0x0010_0008;
+        ps2.r5900.gpr_regs[5] = [1, 2, 3, 4];
+        ps2.r5900.fpr_regs[3] = 2.5;
+        ps2.r5900.cop0_regs[12] = 0x7000_0001;
+        ps2.r5900.hi = 0x11;
+        ps2.r5900.lo = 0x22;
+        let state = ps2.save_state_bytes();
+
+        let mut restored = Ps2::new(&[1, 2, 3, 4]);
+        restored.load_state_bytes(&state).unwrap();
+        assert_eq!(0x1234_5678, restored.read_ee_u32(0x0010_0000));
+        assert_eq!(0xCAFE, restored.iop_ram[0x100]);
+        assert_eq!(0x0010_0008, restored.r5900.pc);
+        assert_eq!([1, 2, 3, 4], restored.r5900.gpr_regs[5]);
+        assert_eq!(2.5, restored.r5900.fpr_regs[3]);
+        assert_eq!(0x7000_0001, restored.r5900.cop0_regs[12]);
+        assert_eq!((0x11, 0x22), (restored.r5900.hi, restored.r5900.lo));
+        assert_eq!(state, restored.save_state_bytes());
+    }
+
+    #[test]
+    fn test_old_version_resets() {
+        let fresh = Ps2::new(&[1, 2, 3, 4]);
+        let mut w = StateWriter::new();
+        w.write_u32_slice(&fresh.ee_ram);
+        w.write_u32_slice(&fresh.iop_ram);
+        fresh.r5900.save_state(&mut w);
+        let payload = w.into_bytes();
+
+        let mut used = Ps2::new(&[1, 2, 3, 4]);
+        used.cycles = 1234;
+        used.write_ee_u32(crate::system::intc::I_MASK, 0x4);
+        used.write_ee_u32(0x1000_F430, 0x1234);  // MCH_RICM
+        used.load_state(&mut StateReader::new(&payload, 1)).unwrap();
+
+        let mut reference = Ps2::new(&[1, 2, 3, 4]);
+        reference.load_state(&mut StateReader::new(&payload, 1)).unwrap();
+        assert_eq!(reference.state_hash(), used.state_hash());
+    }
+
+    #[test]
+    fn test_state_checks() {
+        let ps2 = Ps2::new(&[1, 2, 3, 4]);
+        let mut state = ps2.save_state_bytes();
+
+        let mut other_bios = Ps2::new(&[5, 6, 7, 8]);
+        assert!(other_bios.load_state_bytes(&state).is_err());
+
+        let mut restored = Ps2::new(&[1, 2, 3, 4]);
+        state[0x08] = (STATE_VERSION + 1) as u8;
+        assert!(restored.load_state_bytes(&state).is_err());
+        state[0x08] = STATE_VERSION as u8;
+
+        let last = state.len() - 1;
+        state[last] ^= 0xFF;
+        assert!(restored.load_state_bytes(&state).is_err());
+    }
+
+    #[test]
+    fn test_failed_load_keeps_machine() {
+        let mut other = Ps2::new(&[1, 2, 3, 4]);
+        other.write_ee_u32(0x0010_0000, 0x1234_5678);
+        other.cycles = 99;
+        let mut w = StateWriter::new();
+        other.save_state(&mut w);
+        let mut payload = w.into_bytes();
+        // cut short after the RAM, so the load fails once it has already replaced some of the machine
+        payload.truncate(payload.len() - 100);
+
+        let mut state = MAGIC.to_vec();
+        state.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        state.extend_from_slice(&0u32.to_le_bytes());
+        state.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        state.extend_from_slice(&fnv1a32(&payload).to_le_bytes());
+        state.extend_from_slice(&other.bios_hash().to_le_bytes());
+        state.extend_from_slice(&payload);
+
+        let mut ps2 = Ps2::new(&[1, 2, 3, 4]);
+        ps2.r5900.pc = 0x0010_0008;
+        let before = ps2.state_hash();
+        assert!(ps2.load_state_bytes(&state).is_err());
+        assert_eq!(before, ps2.state_hash());
+        assert_eq!(0, ps2.read_ee_u32(0x0010_0000));
+    }
+}
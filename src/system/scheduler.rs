@@ -0,0 +1,226 @@
+use std::io;
+
+use super::intc::Interrupt;
+use super::ps2::{Ps2, EE_CYCLES_PER_FRAME};
+use super::savestate::{Savestate, StateReader, StateWriter};
+
+/*
+    Things that happen at a known point in the future are scheduled against the EE cycle counter, so
+    Ps2::step only has to compare one number each instruction rather than poll every device.
+
+    The video timing lives here too since it is the heartbeat everything else hangs off. A frame is
+    SCANLINES_PER_FRAME lines, each starting with a horizontal blank, and the last few lines of the
+    frame are the vertical blank.
+*/
+
+pub const SCANLINES_PER_FRAME: u64 = 262;
+pub const CYCLES_PER_SCANLINE: u64 = EE_CYCLES_PER_FRAME / SCANLINES_PER_FRAME;
+
+/// About 17% of an NTSC line is horizontal blank.
+pub const HBLANK_CYCLES: u64 = CYCLES_PER_SCANLINE * 17 / 100;
+
+pub const VBLANK_START_LINE: u64 = 240;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    HBlankStart,
+    HBlankEnd,
+    VBlankStart,
+    VBlankEnd,
+
+    /* a timer reaches its compare value or overflows */
+    Timer(usize),
+
+    /* a started DMA channel gets to run */
+    Dma(usize),
+}
+
+impl Event {
+    fn to_code(self) -> u8 {
+        match self {
+            Event::HBlankStart => 0,
+            Event::HBlankEnd => 1,
+            Event::VBlankStart => 2,
+            Event::VBlankEnd => 3,
+            Event::Timer(n) => 0x10 + n as u8,
+            Event::Dma(n) => 0x20 + n as u8,
+        }
+    }
+
+    fn from_code(code: u8) -> io::Result<Event> {
+        match code {
+            0 => Ok(Event::HBlankStart),
+            1 => Ok(Event::HBlankEnd),
+            2 => Ok(Event::VBlankStart),
+            3 => Ok(Event::VBlankEnd),
+            0x10..=0x13 => Ok(Event::Timer((code - 0x10) as usize)),
+            0x20..=0x29 => Ok(Event::Dma((code - 0x20) as usize)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown scheduler event")),
+        }
+    }
+}
+
+/// Pending events. Each event is scheduled at most once.
+pub struct Scheduler {
+    events: Vec<(u64, Event)>,
+
+    /* cycle of the earliest event, so the check in step is cheap */
+    next: u64
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler { events: Vec::new(), next: u64::MAX }
+    }
+
+    pub fn next_cycle(&self) -> u64 {
+        self.next
+    }
+
+    fn update_next(&mut self) {
+        self.next = self.events.iter().map(|(cycle, _)| *cycle).min().unwrap_or(u64::MAX);
+    }
+
+    /// Schedules an event, replacing it if it was already pending.
+    pub fn schedule(&mut self, cycle: u64, event: Event) {
+        self.events.retain(|(_, e)| *e != event);
+        self.events.push((cycle, event));
+        self.update_next();
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.events.retain(|(_, e)| *e != event);
+        self.update_next();
+    }
+
+    /// Removes and returns the earliest event due by the given cycle.
+    pub fn pop_due(&mut self, now: u64) -> Option<(u64, Event)> {
+        if self.next > now {
+            return None;
+        }
+        let index = (0..self.events.len()).min_by_key(|&i| self.events[i].0)?;
+        let due = self.events.remove(index);
+        self.update_next();
+        Some(due)
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The cycle at which a line of the frame containing the given cycle starts.
+fn line_start(cycle: u64, line: u64) -> u64 {
+    cycle / EE_CYCLES_PER_FRAME * EE_CYCLES_PER_FRAME + line * CYCLES_PER_SCANLINE
+}
+
+impl Ps2 {
+    /// Runs the events that have come due.
+    pub fn run_events(&mut self) {
+        while let Some((cycle, event)) = self.scheduler.pop_due(self.cycles) {
+            match event {
+                Event::HBlankStart => self.hblank_start(cycle),
+                Event::HBlankEnd => self.timers_gate(false, false, cycle),
+                Event::VBlankStart => self.vblank_start(cycle),
+                Event::VBlankEnd => {
+                    self.raise_interrupt(Interrupt::VBlankEnd);
+                    self.timers_gate(true, false, cycle);
+                }
+                Event::Timer(n) => self.timer_event(n, cycle),
+                Event::Dma(n) => self.dma_event(n, cycle),
+            }
+        }
+    }
+
+    /// Sets up the video timing from the current cycle, after power on or loading an old state.
+    pub fn schedule_video_events(&mut self) {
+        let line = self.cycles % EE_CYCLES_PER_FRAME / CYCLES_PER_SCANLINE;
+        let next_line = if line + 1 < SCANLINES_PER_FRAME {
+            line_start(self.cycles, line + 1)
+        } else {
+            line_start(self.cycles, 0) + EE_CYCLES_PER_FRAME
+        };
+        // a line starting right now still gets its blank
+        let this_line = line_start(self.cycles, line);
+        self.scheduler.schedule(if this_line == self.cycles { this_line } else { next_line }, Event::HBlankStart);
+        if line < VBLANK_START_LINE {
+            self.scheduler.schedule(line_start(self.cycles, VBLANK_START_LINE), Event::VBlankStart);
+        } else {
+            self.scheduler.schedule(line_start(self.cycles, 0) + EE_CYCLES_PER_FRAME, Event::VBlankEnd);
+        }
+    }
+
+    fn hblank_start(&mut self, cycle: u64) {
+        let line = cycle % EE_CYCLES_PER_FRAME / CYCLES_PER_SCANLINE;
+        let next = if line + 1 < SCANLINES_PER_FRAME {
+            line_start(cycle, line + 1)
+        } else {
+            line_start(cycle, 0) + EE_CYCLES_PER_FRAME
+        };
+        self.scheduler.schedule(next, Event::HBlankStart);
+        self.scheduler.schedule(cycle + HBLANK_CYCLES, Event::HBlankEnd);
+        self.timers_gate(false, true, cycle);
+        self.timers_hblank();
+        self.gs_hsync();
+    }
+
+    fn vblank_start(&mut self, cycle: u64) {
+        self.scheduler.schedule(line_start(cycle, VBLANK_START_LINE) + EE_CYCLES_PER_FRAME, Event::VBlankStart);
+        self.scheduler.schedule(line_start(cycle, 0) + EE_CYCLES_PER_FRAME, Event::VBlankEnd);
+        self.raise_interrupt(Interrupt::VBlankStart);
+        self.timers_gate(true, true, cycle);
+        self.gs_vsync();
+    }
+}
+
+impl Savestate for Scheduler {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.events.len() as u32);
+        for (cycle, event) in &self.events {
+            w.write_u64(*cycle);
+            w.write_u8(event.to_code());
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.events.clear();
+        for _ in 0..r.read_u32()? {
+            let cycle = r.read_u64()?;
+            self.events.push((cycle, Event::from_code(r.read_u8()?)?));
+        }
+        self.update_next();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scheduler_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(100, Event::Timer(1));
+        scheduler.schedule(50, Event::HBlankStart);
+        scheduler.schedule(70, Event::Timer(1));
+        assert_eq!(50, scheduler.next_cycle());
+        assert_eq!(None, scheduler.pop_due(49));
+        assert_eq!(Some((50, Event::HBlankStart)), scheduler.pop_due(80));
+        assert_eq!(Some((70, Event::Timer(1))), scheduler.pop_due(80));
+        assert_eq!(None, scheduler.pop_due(80));
+        assert_eq!(u64::MAX, scheduler.next_cycle());
+    }
+
+    #[test]
+    fn test_vblank_interrupts() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.cycles = line_start(0, VBLANK_START_LINE);
+        ps2.run_events();
+        assert_eq!(1 << Interrupt::VBlankStart as u32, ps2.intc.stat);
+        ps2.cycles = EE_CYCLES_PER_FRAME;
+        ps2.run_events();
+        assert_eq!(3 << Interrupt::VBlankStart as u32, ps2.intc.stat);
+    }
+}
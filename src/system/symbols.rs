@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use super::elf::{read_string, read_u16, read_u32, Elf};
+
+const SHT_SYMTAB: u32 = 2;
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+
+const SYMBOL_ENTRY_SIZE: usize = 16;
+
+pub struct Symbol {
+    pub name: String,
+
+    /* Size in bytes, zero if unknown */
+    pub size: u32,
+
+    /* only functions name the addresses inside them, anything else just its own address */
+    pub function: bool
+}
+
+/// An address to name index used to make traces and disassembly readable.
+pub struct SymbolTable {
+    symbols: BTreeMap<u32, Symbol>,
+
+    /* values that aren't addresses in the program, like _gp, which can still be looked up by name */
+    absolute: BTreeMap<String, u32>
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable { symbols: BTreeMap::new(), absolute: BTreeMap::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Adds a function, which names the addresses inside it if its size is known.
+    pub fn add(&mut self, addr: u32, name: &str, size: u32) {
+        self.symbols.insert(addr, Symbol { name: name.to_string(), size, function: true });
+    }
+
+    /// Adds the function and object symbols from the ELF .symtab section.
+    pub fn load_elf(&mut self, elf: &Elf) -> io::Result<()> {
+        let symtab = match elf.section_headers.iter().find(|h| h.sh_type == SHT_SYMTAB) {
+            Some(symtab) => symtab,
+            None => return Ok(()),
+        };
+        let strtab = elf.section_headers.get(symtab.link as usize).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "symbol table has no string table")
+        })?;
+
+        let count = symtab.size as usize / SYMBOL_ENTRY_SIZE;
+        for i in 0..count {
+            let base = symtab.offset as usize + i * SYMBOL_ENTRY_SIZE;
+            let name_offset = read_u32(&elf.data, base)? as usize;
+            let value = read_u32(&elf.data, base + 4)?;
+            let size = read_u32(&elf.data, base + 8)?;
+            let info = *elf.data.get(base + 12).unwrap_or(&0);
+            let section = read_u16(&elf.data, base + 14)?;
+
+            let sym_type = info & 0xf;
+            if section == SHN_UNDEF || matches!(sym_type, STT_SECTION | STT_FILE) || !matches!(sym_type, STT_NOTYPE | STT_OBJECT | STT_FUNC) {
+                continue;
+            }
+            let name = read_string(&elf.data, strtab.offset as usize + name_offset);
+            if name.is_empty() {
+                continue;
+            }
+            if section == SHN_ABS {
+                self.absolute.insert(name, value);
+                continue;
+            }
+            // prefer a sized symbol over an unsized label at the same address
+            if self.symbols.get(&value).is_some_and(|s| s.size != 0 && size == 0) {
+                continue;
+            }
+            self.symbols.insert(value, Symbol { name, size, function: sym_type == STT_FUNC });
+        }
+        Ok(())
+    }
+
+    /// Adds symbols from a text map file. Each line is "address name" or "address size name" with hex numbers.
+    /// Blank lines and lines starting with # are ignored.
+    pub fn load_map(&mut self, text: &str) -> io::Result<()> {
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let bad_line = || io::Error::new(io::ErrorKind::InvalidData, format!("bad map file line {}: {}", line_no + 1, line));
+            let (addr, size, name) = match fields.as_slice() {
+                [addr, name] => (parse_hex(addr), Some(0), name),
+                [addr, size, name] => (parse_hex(addr), parse_hex(size), name),
+                _ => return Err(bad_line()),
+            };
+            self.add(addr.ok_or_else(bad_line)?, name, size.ok_or_else(bad_line)?);
+        }
+        Ok(())
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|(_, sym)| sym.name == name).map(|(addr, _)| *addr).or_else(|| self.absolute.get(name).copied())
+    }
+
+    /// Finds the symbol at the address, or the sized function containing it, returning its name and
+    /// the offset into it.
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let (start, sym) = self.symbols.range(..=addr).next_back()?;
+        let offset = addr - start;
+        if offset != 0 && (!sym.function || offset >= sym.size) {
+            return None;
+        }
+        Some((&sym.name, offset))
+    }
+
+    /// Formats the address as name+offset, or None if no symbol covers it.
+    pub fn format_addr(&self, addr: u32) -> Option<String> {
+        match self.lookup(addr)? {
+            (name, 0) => Some(name.to_string()),
+            (name, offset) => Some(format!("{}+{:#X}", name, offset)),
+        }
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u32::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut symbols = SymbolTable::new();
+        symbols.load_map("# test map\n00100000 main\n0x00100100 0x10 helper\n").unwrap();
+
+        // without a size, main only names its own address
+        assert_eq!(Some(String::from("main")), symbols.format_addr(0x0010_0000));
+        assert_eq!(None, symbols.format_addr(0x0010_0008));
+        assert_eq!(Some(String::from("helper+0xC")), symbols.format_addr(0x0010_010C));
+        assert_eq!(None, symbols.format_addr(0x0010_0110));
+        assert_eq!(None, symbols.format_addr(0x000F_FFFC));
+        assert_eq!(Some(0x0010_0100), symbols.find_by_name("helper"));
+        assert!(symbols.load_map("nonsense").is_err());
+    }
+
+    /// An ELF with no segments, just .symtab, .strtab and .shstrtab after the null section.
+    fn make_elf(symbols: &[(&str, u32, u32, u8, u16)]) -> Vec<u8> {
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; SYMBOL_ENTRY_SIZE];
+        for &(name, value, size, info, section) in symbols {
+            symtab.extend((strtab.len() as u32).to_le_bytes());
+            symtab.extend(value.to_le_bytes());
+            symtab.extend(size.to_le_bytes());
+            symtab.extend([info, 0]);
+            symtab.extend(section.to_le_bytes());
+            strtab.extend(name.bytes().chain([0]));
+        }
+        let shstrtab = b"\0.symtab\0.strtab\0.shstrtab\0".to_vec();
+
+        let mut data = vec![0u8; 0x34];
+        data[0..4].copy_from_slice(b"\x7FELF");
+        data[4] = 1;
+        data[5] = 1;
+        data[0x12..0x14].copy_from_slice(&8u16.to_le_bytes());
+        // name, type, offset, size and link for each section header
+        let mut sections = vec![[0u32; 5]];
+        for (name, sh_type, contents, link) in [(1, SHT_SYMTAB, &symtab, 2), (9, 3, &strtab, 0), (17, 3, &shstrtab, 0)] {
+            sections.push([name, sh_type, data.len() as u32, contents.len() as u32, link]);
+            data.extend(contents);
+        }
+        let shoff = data.len() as u32;
+        for [name, sh_type, offset, size, link] in sections {
+            let mut header = vec![0u8; 40];
+            header[0..4].copy_from_slice(&name.to_le_bytes());
+            header[4..8].copy_from_slice(&sh_type.to_le_bytes());
+            header[16..20].copy_from_slice(&offset.to_le_bytes());
+            header[20..24].copy_from_slice(&size.to_le_bytes());
+            header[24..28].copy_from_slice(&link.to_le_bytes());
+            data.extend(header);
+        }
+        data[0x20..0x24].copy_from_slice(&shoff.to_le_bytes());
+        data[0x2E..0x30].copy_from_slice(&40u16.to_le_bytes());
+        data[0x30..0x32].copy_from_slice(&4u16.to_le_bytes());
+        data[0x32..0x34].copy_from_slice(&3u16.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_load_elf_symtab() {
+        let elf = Elf::parse(make_elf(&[
+            ("main", 0x0010_0000, 0x20, STT_FUNC, 1),
+            ("table", 0x0020_0000, 0x10, STT_OBJECT, 2),
+            ("_end", 0x0030_0000, 0, STT_NOTYPE, 2),
+            ("_gp", 0x0010_8000, 0, STT_NOTYPE, SHN_ABS),
+            (".text", 0x0010_0000, 0, STT_SECTION, 1),
+            ("crt0.s", 0, 0, STT_FILE, SHN_ABS),
+            ("printf", 0, 0, STT_FUNC, SHN_UNDEF),
+        ])).unwrap();
+        let mut symbols = SymbolTable::new();
+        symbols.load_elf(&elf).unwrap();
+
+        assert_eq!(Some(String::from("main+0x1C")), symbols.format_addr(0x0010_001C));
+        assert_eq!(None, symbols.format_addr(0x0010_0020));
+        // objects and labels only name their own address, so the heap after _end isn't called _end
+        assert_eq!(Some(String::from("table")), symbols.format_addr(0x0020_0000));
+        assert_eq!(None, symbols.format_addr(0x0020_0004));
+        assert_eq!(Some(String::from("_end")), symbols.format_addr(0x0030_0000));
+        assert_eq!(None, symbols.format_addr(0x0030_1000));
+        // _gp is a value rather than a place
+        assert_eq!(None, symbols.format_addr(0x0010_8000));
+        assert_eq!(Some(0x0010_8000), symbols.find_by_name("_gp"));
+        assert_eq!(None, symbols.find_by_name("crt0.s"));
+        assert_eq!(None, symbols.format_addr(0));
+    }
+}
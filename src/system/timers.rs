@@ -0,0 +1,379 @@
+use std::io;
+
+use super::intc::Interrupt;
+use super::ps2::Ps2;
+use super::savestate::{Savestate, StateReader, StateWriter};
+use super::scheduler::Event;
+
+/*
+    The four EE timers. Each has a 16 bit COUNT, a MODE, a COMP value and (T0 and T1 only) HOLD.
+
+    MODE:
+        0-1   CLKS  clock, bus clock (EE / 2), bus / 16, bus / 256 or HBlank
+        2     GATE  gate enable
+        3     GATS  gate on HBlank (0) or VBlank (1)
+        4-5   GATM  0 count only outside the blank, 1 reset at blank start, 2 reset at blank end, 3 both
+        6     ZRET  reset the count when it reaches COMP
+        7     CUE   count enable
+        8     CMPE  interrupt on reaching COMP
+        9     OVFE  interrupt on overflow
+        10    EQUF  reached COMP, write 1 to clear
+        11    OVFF  overflowed, write 1 to clear
+
+    Rather than ticking every step, COUNT is brought up to date from the cycle counter whenever it is
+    looked at, and an event is scheduled for the next time it reaches COMP or wraps so the flags and
+    interrupts happen on time. Timers clocked by HBlank are ticked by the HBlank event instead.
+*/
+
+pub const TIMER_BASES: [u32; 4] = [0x1000_0000, 0x1000_0800, 0x1000_1000, 0x1000_1800];
+
+const TIMER_INTERRUPTS: [Interrupt; 4] = [Interrupt::Timer0, Interrupt::Timer1, Interrupt::Timer2, Interrupt::Timer3];
+
+const MODE_CLKS: u32 = 3;
+const MODE_GATE: u32 = 1 << 2;
+const MODE_GATS: u32 = 1 << 3;
+const MODE_GATM_SHIFT: u32 = 4;
+const MODE_ZRET: u32 = 1 << 6;
+const MODE_CUE: u32 = 1 << 7;
+const MODE_CMPE: u32 = 1 << 8;
+const MODE_OVFE: u32 = 1 << 9;
+const MODE_EQUF: u32 = 1 << 10;
+const MODE_OVFF: u32 = 1 << 11;
+const MODE_FLAGS: u32 = MODE_EQUF | MODE_OVFF;
+
+const CLKS_HBLANK: u32 = 3;
+
+pub struct Timer {
+    pub count: u32,
+    pub mode: u32,
+    pub comp: u32,
+    pub hold: u32,
+
+    /* the cycle COUNT was last brought up to date at, less any part of a tick not yet counted */
+    last_sync: u64,
+
+    /* stopped by the gate in mode 0 */
+    gated: bool
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer { count: 0, mode: 0, comp: 0, hold: 0, last_sync: 0, gated: false }
+    }
+
+    /// EE cycles per tick, or None when clocked by HBlank.
+    fn cycles_per_tick(&self) -> Option<u64> {
+        match self.mode & MODE_CLKS {
+            0 => Some(2),
+            1 => Some(32),
+            2 => Some(512),
+            _ => None,
+        }
+    }
+
+    fn is_counting(&self) -> bool {
+        self.mode & MODE_CUE != 0 && !self.gated
+    }
+
+    fn gate_mode(&self) -> u32 {
+        (self.mode >> MODE_GATM_SHIFT) & 3
+    }
+
+    /// Ticks until COUNT next reaches COMP or wraps.
+    fn ticks_to_event(&self) -> u64 {
+        if self.count < self.comp {
+            (self.comp - self.count) as u64
+        } else {
+            0x1_0000 - self.count as u64
+        }
+    }
+
+    /// Counts some ticks, never more than ticks_to_event. Returns the flags that became set.
+    fn advance(&mut self, ticks: u64) -> u32 {
+        let mut flags = 0;
+        let mut count = self.count as u64 + ticks;
+        if self.count < self.comp && count >= self.comp as u64 {
+            flags |= MODE_EQUF;
+            if self.mode & MODE_ZRET != 0 {
+                count -= self.comp as u64;
+            }
+        }
+        if count > 0xFFFF {
+            flags |= MODE_OVFF;
+            count &= 0xFFFF;
+        }
+        self.count = count as u32;
+        flags
+    }
+
+    /// Brings COUNT up to date with the cycle counter.
+    fn sync(&mut self, now: u64) -> u32 {
+        let rate = match self.cycles_per_tick() {
+            Some(rate) if self.is_counting() => rate,
+            _ => {
+                self.last_sync = now;
+                return 0;
+            }
+        };
+        let ticks = ((now - self.last_sync) / rate).min(self.ticks_to_event());
+        self.last_sync += ticks * rate;
+        self.advance(ticks)
+    }
+
+    /// When the next compare or overflow will happen, if the timer counts cycles.
+    fn next_event_cycle(&self) -> Option<u64> {
+        let rate = self.cycles_per_tick().filter(|_| self.is_counting())?;
+        Some(self.last_sync + self.ticks_to_event() * rate)
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Timers {
+    pub timers: [Timer; 4],
+
+    /* the blank signals the gates watch */
+    hblank: bool,
+    vblank: bool
+}
+
+impl Timers {
+    pub fn new() -> Timers {
+        Timers { timers: [Timer::new(), Timer::new(), Timer::new(), Timer::new()], hblank: false, vblank: false }
+    }
+
+    /// Register values without bringing COUNT up to date, for the debuggers.
+    pub fn peek(&self, addr: u32) -> u32 {
+        let (n, reg) = timer_register(addr);
+        let timer = &self.timers[n];
+        match reg {
+            0x00 => timer.count,
+            0x10 => timer.mode,
+            0x20 => timer.comp,
+            0x30 => timer.hold,
+            _ => 0,
+        }
+    }
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The timer number and register offset of an address in the timer block.
+fn timer_register(addr: u32) -> (usize, u32) {
+    (((addr >> 11) & 3) as usize, addr & 0x7FF)
+}
+
+impl Ps2 {
+    /// Brings a timer up to date, raising its interrupts for anything that happened. Events pass their
+    /// own cycle as they can run late when catching up.
+    fn timer_sync(&mut self, n: usize, now: u64) {
+        let flags = self.timers.timers[n].sync(now);
+        self.timer_flags(n, flags);
+    }
+
+    /// Sets newly raised flags, interrupting if enabled and the flag wasn't already set.
+    fn timer_flags(&mut self, n: usize, flags: u32) {
+        let timer = &mut self.timers.timers[n];
+        let new_flags = flags & !timer.mode;
+        timer.mode |= flags;
+        let enabled = (if timer.mode & MODE_CMPE != 0 { MODE_EQUF } else { 0 })
+                    | (if timer.mode & MODE_OVFE != 0 { MODE_OVFF } else { 0 });
+        if new_flags & enabled != 0 {
+            self.raise_interrupt(TIMER_INTERRUPTS[n]);
+        }
+    }
+
+    fn timer_reschedule(&mut self, n: usize) {
+        match self.timers.timers[n].next_event_cycle() {
+            Some(cycle) => self.scheduler.schedule(cycle, Event::Timer(n)),
+            None => self.scheduler.cancel(Event::Timer(n)),
+        }
+    }
+
+    pub fn timer_event(&mut self, n: usize, cycle: u64) {
+        self.timer_sync(n, cycle);
+        self.timer_reschedule(n);
+    }
+
+    /// Ticks the timers clocked by HBlank.
+    pub fn timers_hblank(&mut self) {
+        for n in 0..4 {
+            let timer = &mut self.timers.timers[n];
+            if timer.mode & MODE_CLKS == CLKS_HBLANK && timer.is_counting() {
+                let flags = timer.advance(1);
+                self.timer_flags(n, flags);
+            }
+        }
+    }
+
+    /// A blank signal changed. Gated timers watching it stop, start or reset.
+    pub fn timers_gate(&mut self, vblank: bool, active: bool, cycle: u64) {
+        if vblank {
+            self.timers.vblank = active;
+        } else {
+            self.timers.hblank = active;
+        }
+        for n in 0..4 {
+            let timer = &self.timers.timers[n];
+            let gats_vblank = timer.mode & MODE_GATS != 0;
+            // counting HBlanks while gated by HBlank doesn't mean anything
+            if timer.mode & MODE_GATE == 0 || gats_vblank != vblank || (!vblank && timer.mode & MODE_CLKS == CLKS_HBLANK) {
+                continue;
+            }
+            self.timer_sync(n, cycle);
+            let timer = &mut self.timers.timers[n];
+            match timer.gate_mode() {
+                0 => timer.gated = active,
+                1 if active => timer.count = 0,
+                2 if !active => timer.count = 0,
+                3 => timer.count = 0,
+                _ => (),
+            }
+            self.timer_reschedule(n);
+        }
+    }
+
+    pub fn timer_read(&mut self, addr: u32) -> u32 {
+        let (n, reg) = timer_register(addr);
+        match reg {
+            0x00 => {
+                self.timer_sync(n, self.cycles);
+                self.timers.timers[n].count
+            }
+            0x10 | 0x20 => self.timers.peek(addr),
+            0x30 if n < 2 => self.timers.peek(addr),
+            _ => self.ee_hw.unimplemented_read(addr),
+        }
+    }
+
+    pub fn timer_write(&mut self, addr: u32, value: u32) {
+        let (n, reg) = timer_register(addr);
+        if reg == 0x30 && n < 2 {
+            self.timers.timers[n].hold = value & 0xFFFF;
+            return;
+        }
+        if !matches!(reg, 0x00 | 0x10 | 0x20) {
+            return self.ee_hw.unimplemented_write(addr, value);
+        }
+        self.timer_sync(n, self.cycles);
+        let (hblank, vblank) = (self.timers.hblank, self.timers.vblank);
+        let timer = &mut self.timers.timers[n];
+        match reg {
+            0x00 => timer.count = value & 0xFFFF,
+            0x10 => {
+                let flags = timer.mode & MODE_FLAGS & !(value & MODE_FLAGS);
+                timer.mode = (value & 0x3FF) | flags;
+                let signal = if timer.mode & MODE_GATS != 0 { vblank } else { hblank };
+                timer.gated = timer.mode & MODE_GATE != 0 && timer.gate_mode() == 0 && signal;
+            }
+            _ => timer.comp = value & 0xFFFF,
+        }
+        // start counting whole ticks from now
+        timer.last_sync = self.cycles;
+        self.timer_reschedule(n);
+    }
+}
+
+impl Savestate for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.count);
+        w.write_u32(self.mode);
+        w.write_u32(self.comp);
+        w.write_u32(self.hold);
+        w.write_u64(self.last_sync);
+        w.write_bool(self.gated);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.count = r.read_u32()?;
+        self.mode = r.read_u32()?;
+        self.comp = r.read_u32()?;
+        self.hold = r.read_u32()?;
+        self.last_sync = r.read_u64()?;
+        self.gated = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Timers {
+    fn save_state(&self, w: &mut StateWriter) {
+        for timer in &self.timers {
+            timer.save_state(w);
+        }
+        w.write_bool(self.hblank);
+        w.write_bool(self.vblank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        for timer in self.timers.iter_mut() {
+            timer.load_state(r)?;
+        }
+        self.hblank = r.read_bool()?;
+        self.vblank = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::scheduler::{CYCLES_PER_SCANLINE, HBLANK_CYCLES};
+
+    fn run_until(ps2: &mut Ps2, cycles: u64) {
+        ps2.cycles = cycles;
+        ps2.run_events();
+    }
+
+    #[test]
+    fn test_compare_interrupt() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.write_ee_u32(0xB000_F010, 1 << Interrupt::Timer1 as u32);
+        ps2.write_ee_u32(0xB000_0820, 100);
+        ps2.write_ee_u32(0xB000_0810, MODE_CUE | MODE_CMPE | MODE_ZRET);
+
+        // the bus clock is half the EE clock
+        run_until(&mut ps2, 199);
+        assert_eq!(99, ps2.read_ee_u32(0xB000_0800));
+        assert_eq!(0, ps2.intc.stat);
+        run_until(&mut ps2, 200);
+        assert_eq!(1 << Interrupt::Timer1 as u32, ps2.intc.stat);
+        assert_eq!(0, ps2.read_ee_u32(0xB000_0800));
+        assert_ne!(0, ps2.read_ee_u32(0xB000_0810) & MODE_EQUF);
+
+        // the flag is cleared by writing 1 back to it
+        ps2.write_ee_u32(0xB000_0810, ps2.timers.timers[1].mode);
+        assert_eq!(0, ps2.read_ee_u32(0xB000_0810) & MODE_EQUF);
+    }
+
+    #[test]
+    fn test_overflow_and_prescale() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        ps2.write_ee_u32(0xB000_0000, 0xFFF0);
+        ps2.write_ee_u32(0xB000_0010, MODE_CUE | MODE_OVFE | 2);
+        run_until(&mut ps2, 16 * 512 - 1);
+        assert_eq!(0xFFFF, ps2.read_ee_u32(0xB000_0000));
+        run_until(&mut ps2, 16 * 512);
+        assert_eq!(0, ps2.read_ee_u32(0xB000_0000));
+        assert_eq!(1 << Interrupt::Timer0 as u32, ps2.intc.stat);
+    }
+
+    #[test]
+    fn test_hblank_clock_and_gate() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        // T2 counts HBlanks, T3 counts the bus clock only outside HBlank
+        ps2.write_ee_u32(0xB000_1010, MODE_CUE | CLKS_HBLANK);
+        ps2.write_ee_u32(0xB000_1810, MODE_CUE | MODE_GATE);
+        run_until(&mut ps2, CYCLES_PER_SCANLINE * 3);
+        assert_eq!(4, ps2.read_ee_u32(0xB000_1000));
+        // the odd cycle left at the end of each line is lost when the gate closes
+        assert_eq!((3 * ((CYCLES_PER_SCANLINE - HBLANK_CYCLES) / 2)) as u32, ps2.read_ee_u32(0xB000_1800));
+    }
+}
@@ -0,0 +1,198 @@
+use super::*;
+
+/*
+    Lower instructions that macro mode shares with micro mode: integer arithmetic, moves between the
+    register files, data memory through the integer registers, the divider and the random number
+    generator. They use the upper instruction layout, with it, is and id for integer registers where
+    ft, fs and fd would be, and fsf/ftf in the dest bits for the instructions taking one field.
+
+    Macro mode writes Q as soon as DIV, SQRT or RSQRT runs, so WAITQ has nothing to wait for there.
+    Micro mode has its own versions of the divider and ILWR, which take time.
+*/
+
+impl Vu {
+    /// IADD, ISUB, IADDI, IAND and IOR.
+    pub fn integer(&mut self, instruction: u32) {
+        let (it, is, id) = (ft(instruction), fs(instruction), fd(instruction));
+        let (s, t) = (self.vi[is], self.vi[it]);
+        match instruction & 0x3F {
+            0x30 => self.set_vi(id, s.wrapping_add(t)),
+            0x31 => self.set_vi(id, s.wrapping_sub(t)),
+            0x32 => {
+                // a 5 bit signed immediate where id would be
+                let imm = ((instruction >> 6) as i16) << 11 >> 11;
+                self.set_vi(it, s.wrapping_add(imm as u16));
+            }
+            0x34 => self.set_vi(id, s & t),
+            0x35 => self.set_vi(id, s | t),
+            _ => {}
+        }
+    }
+
+    /// The lower instructions in the 0x3C-0x3F group that both modes have.
+    pub fn lower_special(&mut self, instruction: u32) {
+        let (dest, ft, fs) = (dest(instruction), ft(instruction), fs(instruction));
+        let fs_field = self.vf[fs][fsf(instruction)];
+        match special_opcode(instruction) {
+            0x30 => self.set_vf(ft, dest, self.vf[fs]),
+            0x31 => {
+                let [x, y, z, w] = self.vf[fs];
+                self.set_vf(ft, dest, [y, z, w, x]);
+            }
+            0x34 => {
+                self.set_vf(ft, dest, self.read_data(self.vi[fs]));
+                self.set_vi(fs, self.vi[fs].wrapping_add(1));
+            }
+            0x35 => {
+                self.write_data(self.vi[ft], dest, self.vf[fs]);
+                self.set_vi(ft, self.vi[ft].wrapping_add(1));
+            }
+            0x36 => {
+                self.set_vi(fs, self.vi[fs].wrapping_sub(1));
+                self.set_vf(ft, dest, self.read_data(self.vi[fs]));
+            }
+            0x37 => {
+                self.set_vi(ft, self.vi[ft].wrapping_sub(1));
+                self.write_data(self.vi[ft], dest, self.vf[fs]);
+            }
+            opcode @ 0x38..=0x3A => {
+                let (q, flags) = self.divide(opcode, instruction);
+                self.q = q;
+                self.set_divide_flags(flags);
+            }
+            0x3C => self.set_vi(ft, fs_field as u16),
+            0x3D => self.set_vf(ft, dest, [self.vi[fs] as i16 as u32; 4]),
+            0x3E => {
+                let value = self.read_data(self.vi[fs]);
+                if let Some(field) = (X..=W).find(|&field| writes(dest, field)) {
+                    self.set_vi(ft, value[field] as u16);
+                }
+            }
+            0x3F => self.write_data(self.vi[fs], dest, [self.vi[ft] as u32; 4]),
+            0x40 => {
+                self.advance_r();
+                self.set_vf(ft, dest, [self.r; 4]);
+            }
+            0x41 => self.set_vf(ft, dest, [self.r; 4]),
+            0x42 => self.r = 0x3F80_0000 | (fs_field & 0x7F_FFFF),
+            0x43 => self.r = 0x3F80_0000 | ((self.r ^ fs_field) & 0x7F_FFFF),
+            // WAITQ, and the micro mode only instructions
+            _ => {}
+        }
+    }
+
+    /// DIV, SQRT or RSQRT, giving the new Q and its I and D flags.
+    pub fn divide(&self, opcode: u32, instruction: u32) -> (u32, u32) {
+        let fs_bits = self.vf[fs(instruction)][fsf(instruction)];
+        let ft_bits = self.vf[ft(instruction)][ftf(instruction)];
+        let (fs, ft) = (to_float(fs_bits), to_float(ft_bits));
+        let mut flags = 0;
+        let q = match opcode {
+            // a zero divisor gives the largest value with the sign the answer would have had
+            0x38 if ft == 0.0 => {
+                flags = if fs == 0.0 { STATUS_I } else { STATUS_D };
+                ((fs_bits ^ ft_bits) & 0x8000_0000) | MAX_FLOAT
+            }
+            0x38 => from_float(fs / ft).0,
+            0x39 => {
+                if ft < 0.0 {
+                    flags = STATUS_I;
+                }
+                from_float(ft.abs().sqrt()).0
+            }
+            _ if ft == 0.0 => {
+                flags = if fs == 0.0 { STATUS_I } else { STATUS_D };
+                (fs_bits & 0x8000_0000) | MAX_FLOAT
+            }
+            _ => {
+                if ft < 0.0 {
+                    flags = STATUS_I;
+                }
+                from_float(fs / ft.abs().sqrt()).0
+            }
+        };
+        (q, flags)
+    }
+
+    /// Steps the random number generator, a 23 bit shift register kept as a float between 1 and 2.
+    fn advance_r(&mut self) {
+        let feedback = ((self.r >> 4) ^ (self.r >> 22)) & 1;
+        self.r = 0x3F80_0000 | (((self.r << 1) | feedback) & 0x7F_FFFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{cop2, vector};
+    use super::*;
+
+    fn special(opcode: u32, dest: u32, ft: usize, fs: usize) -> u32 {
+        cop2(0x3C | (opcode & 3), dest, ft, fs, (opcode >> 2) as usize)
+    }
+
+    #[test]
+    fn test_integer_and_memory() {
+        let mut vu = Vu::vu0();
+        // IADDI vi1, vi0, -3 then IADD vi2, vi1, vi1
+        vu.macro_instruction(cop2(0x32, 0, 1, 0, 0x1D));
+        vu.macro_instruction(cop2(0x30, 0, 1, 1, 2));
+        assert_eq!((0xFFFD, 0xFFFA), (vu.vi[1], vu.vi[2]));
+
+        // SQI.xw vf1, (vi3++) then LQD vf2, (--vi3), and vi0 stays put
+        vu.vf[1] = [1, 2, 3, 4];
+        vu.vi[3] = 0x10;
+        vu.macro_instruction(special(0x35, 0x9, 3, 1));
+        assert_eq!([1, 0, 0, 4], vu.read_data(0x10));
+        vu.macro_instruction(special(0x36, 0xF, 2, 3));
+        assert_eq!(([1, 0, 0, 4], 0x10), (vu.vf[2], vu.vi[3]));
+        vu.macro_instruction(special(0x34, 0xF, 4, 0));
+        assert_eq!(0, vu.vi[0]);
+
+        // ISWR.y then ILWR.y, and MFIR / MTIR
+        vu.macro_instruction(special(0x3F, 0x4, 2, 3));
+        assert_eq!([1, 0xFFFA, 0, 4], vu.read_data(0x10));
+        vu.macro_instruction(special(0x3E, 0x4, 5, 3));
+        assert_eq!(0xFFFA, vu.vi[5]);
+        vu.macro_instruction(special(0x3D, 0x8, 6, 5));
+        assert_eq!(0xFFFF_FFFA, vu.vf[6][X]);
+        vu.macro_instruction(special(0x3C, 0x3, 7, 1));
+        assert_eq!(4, vu.vi[7]);
+    }
+
+    #[test]
+    fn test_divider() {
+        let mut vu = Vu::vu0();
+        vu.vf[1] = vector([1.0, -4.0, 0.0, 9.0]);
+        // DIV Q, vf1x, vf1y: fsf in bits 21-22, ftf in 23-24
+        let divide = |fsf: u32, ftf: u32, opcode: u32| special(opcode, ftf << 2 | fsf, 1, 1);
+        vu.macro_instruction(divide(0, 1, 0x38));
+        assert_eq!((-0.25f32).to_bits(), vu.q);
+        assert_eq!(0, vu.status & (STATUS_I | STATUS_D));
+
+        vu.macro_instruction(divide(0, 2, 0x38));
+        assert_eq!(MAX_FLOAT, vu.q);
+        assert_eq!(STATUS_D | STATUS_D << 6, vu.status);
+
+        // SQRT of a negative is taken of its magnitude, RSQRT likewise
+        vu.macro_instruction(divide(0, 1, 0x39));
+        assert_eq!((2.0f32.to_bits(), STATUS_I), (vu.q, vu.status & 0x3F));
+        vu.macro_instruction(divide(3, 3, 0x3A));
+        assert_eq!((3.0f32.to_bits(), 0), (vu.q, vu.status & 0x3F));
+        assert_eq!((STATUS_I | STATUS_D) << 6, vu.status & 0xFC0);
+    }
+
+    #[test]
+    fn test_random() {
+        let mut vu = Vu::vu0();
+        vu.vf[1] = [0x1234_5678; 4];
+        // RINIT R, vf1x then RNEXT.x vf2 and RGET.y vf2
+        vu.macro_instruction(special(0x42, 0, 0, 1));
+        assert_eq!(0x3FB4_5678, vu.r);
+        vu.macro_instruction(special(0x40, 0x8, 2, 0));
+        vu.macro_instruction(special(0x41, 0x4, 2, 0));
+        assert_eq!(0x3FE8_ACF1, vu.vf[2][X]);
+        assert_eq!(vu.vf[2][X], vu.vf[2][Y]);
+        vu.macro_instruction(special(0x43, 0, 0, 1));
+        assert_eq!(0x3F80_0000 | (0x68_ACF1 ^ 0x34_5678), vu.r);
+    }
+}
@@ -0,0 +1,662 @@
+use super::*;
+
+/*
+    Micro mode, where a VU runs a program from its own micro memory. Each instruction is 64 bits, a
+    lower instruction in the low word and an upper one in the high word, and both halves issue
+    together. They read their registers before either writes, and when both write the same vf
+    register the upper one wins. Five bits at the top of the upper word are flags:
+        31 I  the lower word is an immediate for I rather than an instruction
+        30 E  the program ends after the next instruction
+        29 M  lets interlocked COP2 moves on the EE through before VU0 ends
+        28 D  stops with a debug break, when FBRST.DE is set for this VU
+        27 T  the same, with FBRST.TE
+
+    Lower instructions have their opcode in bits 25-31. Opcode 0x40 is laid out like the upper ones
+    and holds the integer arithmetic and the 0x3C-0x3F group of macro mode, along with the EFU and
+    XGKICK. The rest take an 11 bit immediate (15 and 12 bit for a few, 24 for the clip flags) with
+    it and is in the ft and fs fields. Branches are relative to the instruction after, in
+    instructions, and have a delay slot.
+
+    Results from the FMACs are written straight away, and their stalls aren't counted. What is kept
+    is the latency that programs can see:
+        - The flag instructions see the MAC, status and clip flags from FLAG_LATENCY cycles ago.
+        - Q is written DIV_CYCLES (RSQRT_CYCLES) after DIV, SQRT or RSQRT issues, and P some time
+          after an EFU instruction depending on the function. WAITQ and WAITP stall until they are,
+          as does starting another calculation on a busy unit.
+        - The integer register an ILW or ILWR loads isn't there for the instruction after it.
+*/
+
+pub const I_BIT: u32 = 1 << 31;
+pub const E_BIT: u32 = 1 << 30;
+pub const M_BIT: u32 = 1 << 29;
+pub const D_BIT: u32 = 1 << 28;
+pub const T_BIT: u32 = 1 << 27;
+
+/* FBRST has these for each VU, VU1's 8 bits up */
+pub const FBRST_FORCE_BREAK: u32 = 1;
+pub const FBRST_RESET: u32 = 1 << 1;
+pub const FBRST_DE: u32 = 1 << 2;
+pub const FBRST_TE: u32 = 1 << 3;
+
+/* VPU-STAT likewise, for why a program stopped and what is busy */
+pub const STAT_RUNNING: u32 = 1;
+pub const STAT_D_STOP: u32 = 1 << 1;
+pub const STAT_T_STOP: u32 = 1 << 2;
+pub const STAT_FORCE_BREAK: u32 = 1 << 3;
+pub const STAT_XGKICK: u32 = 1 << 4;
+pub const STAT_DIV: u32 = 1 << 5;
+pub const STAT_EFU: u32 = 1 << 6;
+
+const FLAG_LATENCY: usize = 4;
+const DIV_CYCLES: u32 = 7;
+const RSQRT_CYCLES: u32 = 13;
+
+/// A result on its way to Q or P.
+#[derive(Clone, Copy, Debug)]
+struct Pending {
+    value: u32,
+    flags: u32,
+    cycles: u32
+}
+
+#[derive(Default)]
+pub struct Micro {
+    pub running: bool,
+
+    /* byte address of the next instruction in micro memory */
+    pub pc: u32,
+
+    branch: Option<u32>,
+    ending: bool,
+
+    /* an M bit has gone by since the program started */
+    pub released: bool,
+
+    /* an integer register waiting for its load */
+    load: Option<(usize, u16)>,
+
+    div: Option<Pending>,
+    efu: Option<Pending>,
+
+    /* MAC, status and clip as each of the last few cycles left them, newest first */
+    flags: [[u32; 3]; FLAG_LATENCY],
+
+    /* FBRST's debug enables, and the VPU-STAT bits for why the last program stopped */
+    pub debug: u32,
+    pub stopped: u32,
+
+    /* the next qword XGKICK sends to the GIF */
+    pub kick: Option<u16>,
+
+    /* what the upper instruction writes, held back until the lower one has read its registers */
+    upper_write: Option<(usize, u32, [u32; 4])>
+}
+
+impl Vu {
+    /// Starts a microprogram at a byte address in micro memory.
+    pub fn start(&mut self, addr: u32) {
+        let flags = [self.mac, self.status, self.clip];
+        let micro = &mut self.micro;
+        micro.running = true;
+        micro.pc = addr & (self.code.len() as u32 * 4 - 1);
+        micro.branch = None;
+        micro.ending = false;
+        micro.released = false;
+        micro.stopped = 0;
+        micro.flags = [flags; FLAG_LATENCY];
+        self.tpc = micro.pc / 8;
+    }
+
+    /// Stops a running program from outside, for FBRST.
+    pub fn force_break(&mut self) {
+        if self.micro.running {
+            self.stop();
+            self.micro.stopped = STAT_FORCE_BREAK;
+        }
+    }
+
+    /// Ends the program, letting everything still in flight land.
+    fn stop(&mut self) {
+        self.micro.running = false;
+        self.micro.branch = None;
+        self.micro.ending = false;
+        if let Some((reg, value)) = self.micro.load.take() {
+            self.set_vi(reg, value);
+        }
+        if let Some(div) = self.micro.div.take() {
+            self.q = div.value;
+            self.set_divide_flags(div.flags);
+        }
+        if let Some(efu) = self.micro.efu.take() {
+            self.p = efu.value;
+        }
+        self.tpc = self.micro.pc / 8;
+    }
+
+    /// This VU's half of VPU-STAT.
+    pub fn stat(&self) -> u32 {
+        let micro = &self.micro;
+        let mut stat = micro.stopped;
+        if micro.running {
+            stat |= STAT_RUNNING;
+        }
+        if micro.kick.is_some() {
+            stat |= STAT_XGKICK;
+        }
+        if micro.div.is_some() {
+            stat |= STAT_DIV;
+        }
+        if micro.efu.is_some() {
+            stat |= STAT_EFU;
+        }
+        stat
+    }
+
+    /// Runs a cycle of the program, returning true if a D or T bit stopped it.
+    pub fn step(&mut self) -> bool {
+        let index = (self.micro.pc as usize / 4) & (self.code.len() - 1);
+        let (lower, upper) = (self.code[index], self.code[index + 1]);
+        if upper & I_BIT == 0 && self.stalls(lower) {
+            self.tick();
+            return false;
+        }
+
+        let (branch, ending, load) = (self.micro.branch.take(), self.micro.ending, self.micro.load.take());
+        self.upper(upper);
+        if upper & I_BIT != 0 {
+            self.i = lower;
+        } else {
+            self.lower(lower);
+        }
+        if let Some((reg, dest, value)) = self.micro.upper_write.take() {
+            self.set_vf(reg, dest, value);
+        }
+        if let Some((reg, value)) = load {
+            self.set_vi(reg, value);
+        }
+
+        let pc = branch.unwrap_or(self.micro.pc + 8);
+        self.micro.pc = pc & (self.code.len() as u32 * 4 - 1);
+        self.tpc = self.micro.pc / 8;
+        if upper & E_BIT != 0 {
+            self.micro.ending = true;
+        }
+        if upper & M_BIT != 0 {
+            self.micro.released = true;
+        }
+        self.tick();
+
+        let debug_stop = if upper & D_BIT != 0 && self.micro.debug & FBRST_DE != 0 {
+            STAT_D_STOP
+        } else if upper & T_BIT != 0 && self.micro.debug & FBRST_TE != 0 {
+            STAT_T_STOP
+        } else {
+            0
+        };
+        if ending {
+            self.stop();
+        } else if debug_stop != 0 {
+            self.stop();
+            self.micro.stopped = debug_stop;
+            return true;
+        }
+        false
+    }
+
+    /// Writes the vf register of an upper instruction, which waits for the lower one in micro mode.
+    pub fn set_upper_vf(&mut self, reg: usize, dest: u32, value: [u32; 4]) {
+        if self.micro.running {
+            self.micro.upper_write = Some((reg, dest, value));
+        } else {
+            self.set_vf(reg, dest, value);
+        }
+    }
+
+    /// A cycle goes by for the pipelines.
+    fn tick(&mut self) {
+        if let Some(div) = &mut self.micro.div {
+            div.cycles -= 1;
+            if div.cycles == 0 {
+                let div = *div;
+                self.micro.div = None;
+                self.q = div.value;
+                self.set_divide_flags(div.flags);
+            }
+        }
+        if let Some(efu) = &mut self.micro.efu {
+            efu.cycles -= 1;
+            if efu.cycles == 0 {
+                self.p = efu.value;
+                self.micro.efu = None;
+            }
+        }
+        self.micro.flags.rotate_right(1);
+        self.micro.flags[0] = [self.mac, self.status, self.clip];
+    }
+
+    /// Whether a lower instruction has to wait for a unit that is still busy.
+    fn stalls(&self, lower: u32) -> bool {
+        if lower >> 25 != 0x40 || lower & 0x3C != 0x3C {
+            return false;
+        }
+        match special_opcode(lower) {
+            0x38..=0x3B => self.micro.div.is_some(),
+            0x6C => self.micro.kick.is_some(),
+            0x70..=0x7E => self.is_vu1 && self.micro.efu.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Loads an integer register from the first field of a dest mask, after the next instruction.
+    fn load_vi(&mut self, reg: usize, dest: u32, value: [u32; 4]) {
+        if let Some(field) = (X..=W).find(|&field| writes(dest, field)) {
+            if reg != 0 {
+                self.micro.load = Some((reg, value[field] as u16));
+            }
+        }
+    }
+
+    fn lower(&mut self, lower: u32) {
+        let (dest, it, is) = (dest(lower), ft(lower), fs(lower));
+        let (s, t) = (self.vi[is], self.vi[it]);
+        let imm11 = ((lower as i32) << 21 >> 21) as u16;
+        let imm12 = ((lower >> 10) & 0x800) | (lower & 0x7FF);
+        let imm15 = (((lower >> 10) & 0x7800) | (lower & 0x7FF)) as u16;
+        let imm24 = lower & 0xFF_FFFF;
+        let [mac, status, clip] = self.micro.flags[FLAG_LATENCY - 1];
+        let target = self.micro.pc.wrapping_add(8).wrapping_add((imm11 as i16 as u32).wrapping_mul(8));
+        let link = ((self.micro.pc + 16) / 8) as u16;
+        let taken = match lower >> 25 {
+            0x00 => {
+                self.set_vf(it, dest, self.read_data(s.wrapping_add(imm11)));
+                false
+            }
+            0x01 => {
+                self.write_data(t.wrapping_add(imm11), dest, self.vf[is]);
+                false
+            }
+            0x04 => {
+                self.load_vi(it, dest, self.read_data(s.wrapping_add(imm11)));
+                false
+            }
+            0x05 => {
+                self.write_data(s.wrapping_add(imm11), dest, [t as u32; 4]);
+                false
+            }
+            0x08 => {
+                self.set_vi(it, s.wrapping_add(imm15));
+                false
+            }
+            0x09 => {
+                self.set_vi(it, s.wrapping_sub(imm15));
+                false
+            }
+            // FCEQ, FCSET, FCAND, FCOR
+            0x10 => {
+                self.set_vi(1, (clip & 0xFF_FFFF == imm24) as u16);
+                false
+            }
+            0x11 => {
+                self.clip = imm24;
+                false
+            }
+            0x12 => {
+                self.set_vi(1, (clip & imm24 != 0) as u16);
+                false
+            }
+            0x13 => {
+                self.set_vi(1, ((clip | imm24) & 0xFF_FFFF == 0xFF_FFFF) as u16);
+                false
+            }
+            // FSEQ, FSSET, FSAND, FSOR
+            0x14 => {
+                self.set_vi(it, (status & 0xFFF == imm12) as u16);
+                false
+            }
+            0x15 => {
+                self.status = (self.status & 0x3F) | (imm12 & 0xFC0);
+                false
+            }
+            0x16 => {
+                self.set_vi(it, (status & imm12) as u16);
+                false
+            }
+            0x17 => {
+                self.set_vi(it, ((status | imm12) & 0xFFF) as u16);
+                false
+            }
+            // FMEQ, FMAND, FMOR, FCGET
+            0x18 => {
+                self.set_vi(it, (mac as u16 == s) as u16);
+                false
+            }
+            0x1A => {
+                self.set_vi(it, mac as u16 & s);
+                false
+            }
+            0x1B => {
+                self.set_vi(it, mac as u16 | s);
+                false
+            }
+            0x1C => {
+                self.set_vi(it, (clip & 0xFFF) as u16);
+                false
+            }
+            0x20 => true,
+            0x21 => {
+                self.set_vi(it, link);
+                true
+            }
+            0x24 | 0x25 => {
+                if lower >> 25 == 0x25 {
+                    self.set_vi(it, link);
+                }
+                self.micro.branch = Some(s as u32 * 8);
+                false
+            }
+            0x28 => t == s,
+            0x29 => t != s,
+            0x2C => (s as i16) < 0,
+            0x2D => (s as i16) > 0,
+            0x2E => (s as i16) <= 0,
+            0x2F => (s as i16) >= 0,
+            0x40 => {
+                self.lower_group(lower);
+                false
+            }
+            _ => false,
+        };
+        if taken {
+            self.micro.branch = Some(target);
+        }
+    }
+
+    /// Lower opcode 0x40, the instructions shaped like upper ones.
+    fn lower_group(&mut self, lower: u32) {
+        if lower & 0x3C != 0x3C {
+            return self.integer(lower);
+        }
+        let (dest, ft, fs) = (dest(lower), ft(lower), fs(lower));
+        match special_opcode(lower) {
+            opcode @ 0x38..=0x3A => {
+                let (value, flags) = self.divide(opcode, lower);
+                let cycles = if opcode == 0x3A { RSQRT_CYCLES } else { DIV_CYCLES };
+                self.micro.div = Some(Pending { value, flags, cycles });
+            }
+            0x3E => self.load_vi(ft, dest, self.read_data(self.vi[fs])),
+            0x64 => self.set_vf(ft, dest, [self.p; 4]),
+            // XTOP and XITOP read VIF1 registers, which aren't there yet
+            0x68 | 0x69 => self.set_vi(ft, 0),
+            0x6C if self.is_vu1 => self.micro.kick = Some(self.vi[fs]),
+            opcode @ 0x70..=0x7E if self.is_vu1 => self.efu(opcode, lower),
+            _ => self.lower_special(lower),
+        }
+    }
+
+    /// Starts an elementary function on its way to P.
+    fn efu(&mut self, opcode: u32, lower: u32) {
+        let v = self.vf[fs(lower)].map(to_float);
+        let f = to_float(self.vf[fs(lower)][fsf(lower)]);
+        let squares = v[X] * v[X] + v[Y] * v[Y] + v[Z] * v[Z];
+        let (value, cycles) = match opcode {
+            0x70 => (squares, 11),
+            0x71 => (1.0 / squares, 18),
+            0x72 => (squares.sqrt(), 18),
+            0x73 => (1.0 / squares.sqrt(), 24),
+            0x74 => ((v[Y] / v[X]).atan(), 54),
+            0x75 => ((v[Z] / v[X]).atan(), 54),
+            0x76 => (v[X] + v[Y] + v[Z] + v[W], 12),
+            0x78 => (f.abs().sqrt(), 12),
+            0x79 => (1.0 / f.abs().sqrt(), 18),
+            0x7A => (1.0 / f, 12),
+            0x7C => (f.sin(), 29),
+            0x7D => (f.atan(), 54),
+            0x7E => ((-f).exp(), 44),
+            // WAITP, which only had to stall
+            _ => return,
+        };
+        self.micro.efu = Some(Pending { value: from_float(value).0, flags: 0, cycles });
+    }
+}
+
+impl Savestate for Pending {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.value);
+        w.write_u32(self.flags);
+        w.write_u32(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.value = r.read_u32()?;
+        self.flags = r.read_u32()?;
+        self.cycles = r.read_u32()?;
+        Ok(())
+    }
+}
+
+fn save_pending(w: &mut StateWriter, pending: &Option<Pending>) {
+    w.write_bool(pending.is_some());
+    if let Some(pending) = pending {
+        pending.save_state(w);
+    }
+}
+
+fn load_pending(r: &mut StateReader) -> io::Result<Option<Pending>> {
+    if !r.read_bool()? {
+        return Ok(None);
+    }
+    let mut pending = Pending { value: 0, flags: 0, cycles: 0 };
+    pending.load_state(r)?;
+    Ok(Some(pending))
+}
+
+impl Savestate for Micro {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.running);
+        w.write_u32(self.pc);
+        w.write_bool(self.branch.is_some());
+        w.write_u32(self.branch.unwrap_or(0));
+        w.write_bool(self.ending);
+        w.write_bool(self.released);
+        w.write_bool(self.load.is_some());
+        let (reg, value) = self.load.unwrap_or((0, 0));
+        w.write_u32(reg as u32);
+        w.write_u16(value);
+        save_pending(w, &self.div);
+        save_pending(w, &self.efu);
+        for flags in &self.flags {
+            w.write_u32_slice(flags);
+        }
+        w.write_u32(self.debug);
+        w.write_u32(self.stopped);
+        w.write_bool(self.kick.is_some());
+        w.write_u16(self.kick.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.running = r.read_bool()?;
+        self.pc = r.read_u32()?;
+        let has_branch = r.read_bool()?;
+        let branch = r.read_u32()?;
+        self.branch = has_branch.then_some(branch);
+        self.ending = r.read_bool()?;
+        self.released = r.read_bool()?;
+        let has_load = r.read_bool()?;
+        let (reg, value) = (r.read_u32()? as usize & 0xF, r.read_u16()?);
+        self.load = has_load.then_some((reg, value));
+        self.div = load_pending(r)?;
+        self.efu = load_pending(r)?;
+        for flags in self.flags.iter_mut() {
+            r.read_u32_slice_into(flags)?;
+        }
+        self.debug = r.read_u32()?;
+        self.stopped = r.read_u32()?;
+        let has_kick = r.read_bool()?;
+        let kick = r.read_u16()?;
+        self.kick = has_kick.then_some(kick);
+        self.upper_write = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{cop2, vector};
+    use super::*;
+
+    const NOP: u32 = 0x2FF;
+    const LOWER_NOP: u32 = 0x8000_033C;
+
+    /// An upper instruction, which is the macro mode one without COP2 and CO.
+    fn upper(opcode: u32, dest: u32, ft: usize, fs: usize, fd: usize) -> u32 {
+        cop2(opcode, dest, ft, fs, fd) & 0x01FF_FFFF
+    }
+
+    /// A lower instruction shaped like an upper one, under opcode 0x40.
+    fn lower_group(opcode: u32, dest: u32, ft: usize, fs: usize) -> u32 {
+        0x40 << 25 | upper(0x3C | (opcode & 3), dest, ft, fs, (opcode >> 2) as usize)
+    }
+
+    /// IADD and the like, under opcode 0x40 too.
+    fn integer(opcode: u32, it: usize, is: usize, id: usize) -> u32 {
+        0x40 << 25 | upper(opcode, 0, it, is, id)
+    }
+
+    /// A lower instruction with an 11 bit immediate.
+    fn lower(opcode: u32, dest: u32, it: usize, is: usize, imm: i32) -> u32 {
+        opcode << 25 | dest << 21 | (it as u32) << 16 | (is as u32) << 11 | (imm as u32 & 0x7FF)
+    }
+
+    fn load(vu: &mut Vu, program: &[(u32, u32)]) {
+        for (i, &(upper, lower)) in program.iter().enumerate() {
+            vu.code[i * 2] = lower;
+            vu.code[i * 2 + 1] = upper;
+        }
+    }
+
+    fn run(vu: &mut Vu) -> usize {
+        vu.start(0);
+        let mut cycles = 0;
+        while vu.micro.running {
+            vu.step();
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_end_and_branches() {
+        let mut vu = Vu::vu0();
+        load(&mut vu, &[
+            // IADDIU vi1, vi0, 5, then B over the next, whose delay slot still runs
+            (NOP, lower(0x08, 0, 1, 0, 5)),
+            (NOP, lower(0x20, 0, 0, 0, 2)),
+            (NOP, lower(0x08, 0, 2, 0, 1)),
+            (NOP, lower(0x08, 0, 3, 0, 1)),
+            // BAL vi15 to the end, and IBNE that isn't taken in its delay slot
+            (NOP, lower(0x21, 0, 15, 0, 2)),
+            (NOP, lower(0x29, 0, 0, 0, 5)),
+            (NOP, lower(0x08, 0, 4, 0, 1)),
+            (E_BIT | NOP, LOWER_NOP),
+            (NOP, lower(0x08, 0, 5, 0, 1)),
+            (NOP, lower(0x08, 0, 6, 0, 1)),
+        ]);
+        assert_eq!(7, run(&mut vu));
+        assert_eq!([5, 1, 0, 0, 1, 0, 6], [vu.vi[1], vu.vi[2], vu.vi[3], vu.vi[4], vu.vi[5], vu.vi[6], vu.vi[15]]);
+        assert_eq!(9, vu.tpc);
+    }
+
+    #[test]
+    fn test_pair_and_i_bit() {
+        let mut vu = Vu::vu0();
+        vu.vf[1] = vector([1.0, 2.0, 3.0, 4.0]);
+        load(&mut vu, &[
+            // ADD vf1, vf1, vf1 beside MOVE vf2, vf1, which sees the old vf1
+            (upper(0x28, 0xF, 1, 1, 1), lower_group(0x30, 0xF, 2, 1)),
+            // loading I, then MULi with it, and an upper write beating a lower one
+            (I_BIT | NOP, 0x4040_0000),
+            (E_BIT | upper(0x1E, 0xF, 0, 2, 3), lower_group(0x30, 0xF, 3, 1)),
+            (NOP, LOWER_NOP),
+        ]);
+        run(&mut vu);
+        assert_eq!(vector([2.0, 4.0, 6.0, 8.0]), vu.vf[1]);
+        assert_eq!(vector([1.0, 2.0, 3.0, 4.0]), vu.vf[2]);
+        assert_eq!(vector([3.0, 6.0, 9.0, 12.0]), vu.vf[3]);
+    }
+
+    #[test]
+    fn test_flag_latency() {
+        let mut vu = Vu::vu0();
+        // SUB.x vf1, vf0, vf0 gives zero, which FMAND sees four cycles later
+        let fmand = |it| lower(0x1A, 0, it, 15, 0);
+        vu.vi[15] = 0xFFFF;
+        load(&mut vu, &[
+            (upper(0x2C, 0x8, 0, 0, 1), fmand(1)),
+            (NOP, fmand(2)),
+            (NOP, fmand(3)),
+            (NOP, fmand(4)),
+            (E_BIT | NOP, fmand(5)),
+            (NOP, LOWER_NOP),
+        ]);
+        vu.mac = 0x10;
+        run(&mut vu);
+        assert_eq!([0x10, 0x10, 0x10, 0x10, 0x8], [vu.vi[1], vu.vi[2], vu.vi[3], vu.vi[4], vu.vi[5]]);
+    }
+
+    #[test]
+    fn test_divider_and_load_delay() {
+        let mut vu = Vu::vu0();
+        vu.vf[1] = vector([1.0, 4.0, 0.0, 0.0]);
+        vu.data[0x10 * 4] = 7;
+        vu.q = 0x3F80_0000;
+        load(&mut vu, &[
+            // DIV Q, vf1x, vf1y then ADDq.x vf2 straight away, and again after WAITQ
+            (NOP, lower_group(0x38, 1 << 2, 1, 1)),
+            (upper(0x20, 0x8, 0, 0, 2), LOWER_NOP),
+            (NOP, lower_group(0x3B, 0, 0, 0)),
+            (upper(0x20, 0x8, 0, 0, 3), LOWER_NOP),
+            // ILW.x vi1, 0x10(vi0) and IADD vi2, vi1, vi0 behind it sees the old vi1
+            (NOP, lower(0x04, 0x8, 1, 0, 0x10)),
+            (NOP, integer(0x30, 0, 1, 2)),
+            (E_BIT | NOP, integer(0x30, 0, 1, 3)),
+            (NOP, LOWER_NOP),
+        ]);
+        vu.vi[1] = 9;
+        let cycles = run(&mut vu);
+        assert_eq!(1.0f32.to_bits(), vu.vf[2][X]);
+        assert_eq!(0.25f32.to_bits(), vu.vf[3][X]);
+        assert_eq!((9, 7), (vu.vi[2], vu.vi[3]));
+        // the DIV takes 7 cycles, and WAITQ sits out the 5 left after it and ADDq
+        assert_eq!(8 + 5, cycles);
+    }
+
+    #[test]
+    fn test_efu() {
+        let mut vu = Vu::vu1();
+        vu.vf[1] = vector([3.0, 4.0, 0.0, 0.0]);
+        load(&mut vu, &[
+            // ELENG P, vf1 then WAITP and MFP.x vf2
+            (NOP, lower_group(0x72, 0, 0, 1)),
+            (NOP, lower_group(0x7B, 0, 0, 0)),
+            (E_BIT | NOP, lower_group(0x64, 0x8, 2, 0)),
+            (NOP, LOWER_NOP),
+        ]);
+        let cycles = run(&mut vu);
+        assert_eq!(5.0f32.to_bits(), vu.vf[2][X]);
+        assert_eq!(18 + 3, cycles);
+    }
+
+    #[test]
+    fn test_debug_stop() {
+        let mut vu = Vu::vu1();
+        load(&mut vu, &[(NOP, LOWER_NOP), (D_BIT | NOP, LOWER_NOP), (E_BIT | NOP, LOWER_NOP)]);
+        vu.micro.debug = FBRST_TE;
+        assert_eq!(4, run(&mut vu));
+
+        vu.micro.debug = FBRST_DE;
+        vu.start(0);
+        assert!(!vu.step());
+        assert!(vu.step());
+        assert_eq!((false, STAT_D_STOP, 2), (vu.micro.running, vu.stat(), vu.tpc));
+    }
+}
@@ -0,0 +1,522 @@
+use std::io;
+
+use super::gif::PATH1;
+use super::intc::Interrupt;
+use super::ps2::Ps2;
+use super::savestate::{Savestate, StateReader, StateWriter};
+use micro::{Micro, FBRST_DE, FBRST_FORCE_BREAK, FBRST_RESET, FBRST_TE};
+
+pub mod lower;
+pub mod micro;
+pub mod upper;
+
+/*
+    The vector units. Each has 32 128 bit floating point registers (vf, four fields x y z w), 16 16 bit
+    integer registers (vi), the accumulator, and the special registers: Q from the divider, P from the
+    elementary function unit (VU1 only), I for immediates, R for random numbers, and the status, MAC
+    and clipping flags.
+
+    vf0 always reads (0, 0, 0, 1) and vi0 always reads 0.
+
+    The floats aren't IEEE. There are no denormals, infinities or NaNs: a zero exponent is zero, the
+    top exponent is just a big number, and results too big or too small for the format come out as
+    the largest value or zero, with the overflow or underflow flag set.
+
+    Both run microprograms from their own micro memory alongside the EE, a cycle each for each EE
+    cycle. VU0 has 4KB of micro memory and 4KB of data memory, VU1 16KB of each, along with the EFU
+    for P and XGKICK, which sends GIF packets from its data memory down PATH1. The EE sees all four
+    memories at 0x1100_0000.
+
+    VU0 can also be driven a single instruction at a time by the EE through COP2, which is macro
+    mode. Those instructions are the upper and lower instructions of micro mode with a different
+    wrapper, so both modes share the code here. Macro instructions wait while VU0 is running a
+    program, as do the COP2 moves with the interlock bit unless the program has passed an M bit.
+
+    The MAC flags have a bit for each field in each of four groups, x at the top of each:
+        0-3 zero, 4-7 sign, 8-11 underflow, 12-15 overflow
+    Status:
+        0 Z, 1 S, 2 U, 3 O (any MAC bit in the group), 4 I (invalid), 5 D (divide by zero),
+        6-11 the same again, but sticky
+    The clipping flags keep the results of the last four CLIPs, newest in the low 6 bits.
+*/
+
+pub const VU0_CODE_SIZE: usize = 0x1000;
+pub const VU0_DATA_SIZE: usize = 0x1000;
+pub const VU1_CODE_SIZE: usize = 0x4000;
+pub const VU1_DATA_SIZE: usize = 0x4000;
+
+/* where the EE sees the micro and data memories, VU0's each mirrored over 16KB */
+pub const VU_MEM_START: u32 = 0x1100_0000;
+pub const VU_MEM_SIZE: usize = 0x1_0000;
+
+/* CFC2 and CTC2 number the control registers after the integer ones */
+pub const REG_STATUS: usize = 16;
+pub const REG_MAC: usize = 17;
+pub const REG_CLIP: usize = 18;
+pub const REG_R: usize = 20;
+pub const REG_I: usize = 21;
+pub const REG_Q: usize = 22;
+pub const REG_TPC: usize = 26;
+pub const REG_CMSAR0: usize = 27;
+pub const REG_FBRST: usize = 28;
+pub const REG_VPU_STAT: usize = 29;
+pub const REG_CMSAR1: usize = 31;
+
+pub const STATUS_I: u32 = 1 << 4;
+pub const STATUS_D: u32 = 1 << 5;
+const STATUS_STICKY_SHIFT: u32 = 6;
+
+/* FBRST and VPU-STAT have VU1's bits this far above VU0's */
+const VU1_SHIFT: u32 = 8;
+
+pub const X: usize = 0;
+pub const Y: usize = 1;
+pub const Z: usize = 2;
+pub const W: usize = 3;
+
+const VF0: [u32; 4] = [0, 0, 0, 0x3F80_0000];
+
+/// The largest magnitude a VU float can hold.
+pub const MAX_FLOAT: u32 = 0x7FFF_FFFF;
+
+/// A VU float as a host one.
+pub fn to_float(bits: u32) -> f32 {
+    match bits & 0x7F80_0000 {
+        0 => f32::from_bits(bits & 0x8000_0000),
+        // the largest f32 is as close as we can get
+        0x7F80_0000 => f32::from_bits((bits & 0x8000_0000) | 0x7F7F_FFFF),
+        _ => f32::from_bits(bits),
+    }
+}
+
+/// A result back as a VU float, with whether it overflowed and whether it underflowed.
+pub fn from_float(value: f32) -> (u32, bool, bool) {
+    let bits = value.to_bits();
+    let sign = bits & 0x8000_0000;
+    match bits & 0x7F80_0000 {
+        0x7F80_0000 => (sign | MAX_FLOAT, true, false),
+        0 if bits & 0x7F_FFFF != 0 => (sign, false, true),
+        _ => (bits, false, false),
+    }
+}
+
+/* instruction fields, the same for macro and micro mode */
+
+pub fn dest(instruction: u32) -> u32 {
+    (instruction >> 21) & 0xF
+}
+
+/// Whether a field is in a dest mask, which has x at the top.
+pub fn writes(dest: u32, field: usize) -> bool {
+    dest & (8 >> field) != 0
+}
+
+pub fn ft(instruction: u32) -> usize {
+    ((instruction >> 16) & 0x1F) as usize
+}
+
+pub fn fs(instruction: u32) -> usize {
+    ((instruction >> 11) & 0x1F) as usize
+}
+
+pub fn fd(instruction: u32) -> usize {
+    ((instruction >> 6) & 0x1F) as usize
+}
+
+/// The field of fs and ft used by the instructions that take a single one.
+pub fn fsf(instruction: u32) -> usize {
+    ((instruction >> 21) & 3) as usize
+}
+
+pub fn ftf(instruction: u32) -> usize {
+    ((instruction >> 23) & 3) as usize
+}
+
+/// The opcode of an instruction in the 0x3C-0x3F group, which continues in bits 6-10.
+pub fn special_opcode(instruction: u32) -> u32 {
+    ((instruction >> 6) & 0x1F) << 2 | (instruction & 3)
+}
+
+pub struct Vu {
+    pub vf: [[u32; 4]; 32],
+    pub vi: [u16; 16],
+    pub acc: [u32; 4],
+    pub q: u32,
+    pub p: u32,
+    pub i: u32,
+    pub r: u32,
+    pub status: u32,
+    pub mac: u32,
+    pub clip: u32,
+
+    /* where the last microprogram was started or stopped, and where CALLMSR starts the next */
+    pub tpc: u32,
+    pub cmsar: u32,
+
+    pub code: Vec<u32>,
+    pub data: Vec<u32>,
+
+    /* VU1 has the EFU and XGKICK */
+    pub is_vu1: bool,
+
+    pub micro: Micro
+}
+
+impl Vu {
+    fn new(code_size: usize, data_size: usize, is_vu1: bool) -> Vu {
+        let mut vf = [[0; 4]; 32];
+        vf[0] = VF0;
+        Vu {
+            vf, vi: [0; 16], acc: [0; 4], q: 0, p: 0, i: 0, r: 0x3F80_0000, status: 0, mac: 0, clip: 0, tpc: 0, cmsar: 0,
+            code: vec![0; code_size / 4], data: vec![0; data_size / 4], is_vu1, micro: Micro::default()
+        }
+    }
+
+    pub fn vu0() -> Vu {
+        Vu::new(VU0_CODE_SIZE, VU0_DATA_SIZE, false)
+    }
+
+    pub fn vu1() -> Vu {
+        Vu::new(VU1_CODE_SIZE, VU1_DATA_SIZE, true)
+    }
+
+    /// FBRST's reset, which clears the registers and stops any program but leaves the memories.
+    pub fn reset(&mut self) {
+        let code = std::mem::take(&mut self.code);
+        let data = std::mem::take(&mut self.data);
+        *self = Vu { code, data, ..Vu::new(0, 0, self.is_vu1) };
+    }
+
+    /// Writes the fields of a vf register picked by a dest mask.
+    pub fn set_vf(&mut self, reg: usize, dest: u32, value: [u32; 4]) {
+        if reg == 0 {
+            return;
+        }
+        for (field, word) in value.iter().enumerate() {
+            if writes(dest, field) {
+                self.vf[reg][field] = *word;
+            }
+        }
+    }
+
+    pub fn set_vi(&mut self, reg: usize, value: u16) {
+        if reg != 0 {
+            self.vi[reg] = value;
+        }
+    }
+
+    /// Sets the MAC flags from the results of an instruction, and the status flags from them.
+    pub fn set_mac(&mut self, dest: u32, results: &[(u32, bool, bool); 4]) {
+        let mut mac = 0;
+        for (field, &(bits, overflow, underflow)) in results.iter().enumerate() {
+            if !writes(dest, field) {
+                continue;
+            }
+            let bit = 3 - field;
+            if bits & 0x7FFF_FFFF == 0 {
+                mac |= 1 << bit;
+            }
+            if bits & 0x8000_0000 != 0 {
+                mac |= 0x10 << bit;
+            }
+            if underflow {
+                mac |= 0x100 << bit;
+            }
+            if overflow {
+                mac |= 0x1000 << bit;
+            }
+        }
+        self.mac = mac;
+        let flags = (0..4).filter(|group| mac & (0xF << (group * 4)) != 0).fold(0, |flags, group| flags | 1 << group);
+        self.status = (self.status & !0xF) | flags | flags << STATUS_STICKY_SHIFT;
+    }
+
+    /// Sets the divider's invalid and divide by zero flags, which are left alone by everything else.
+    pub fn set_divide_flags(&mut self, flags: u32) {
+        self.status = (self.status & !(STATUS_I | STATUS_D)) | flags | flags << STATUS_STICKY_SHIFT;
+    }
+
+    /// Address of a qword in data memory, which wraps.
+    pub fn data_index(&self, addr: u16) -> usize {
+        (addr as usize * 4) & (self.data.len() - 1)
+    }
+
+    pub fn read_data(&self, addr: u16) -> [u32; 4] {
+        let index = self.data_index(addr);
+        let mut value = [0; 4];
+        value.copy_from_slice(&self.data[index..index + 4]);
+        value
+    }
+
+    pub fn write_data(&mut self, addr: u16, dest: u32, value: [u32; 4]) {
+        let index = self.data_index(addr);
+        for (field, word) in value.iter().enumerate() {
+            if writes(dest, field) {
+                self.data[index + field] = *word;
+            }
+        }
+    }
+
+    /// Runs a COP2 instruction with the CO bit set, other than the CALLMS pair.
+    pub fn macro_instruction(&mut self, instruction: u32) {
+        let opcode = instruction & 0x3F;
+        if opcode >= 0x3C {
+            if special_opcode(instruction) < 0x30 {
+                self.upper(instruction);
+            } else {
+                self.lower_special(instruction);
+            }
+        } else if opcode < 0x30 {
+            self.upper(instruction);
+        } else {
+            self.integer(instruction);
+        }
+    }
+}
+
+impl Ps2 {
+    /// VPU-STAT, how both VUs are getting on.
+    pub fn vpu_stat(&self) -> u32 {
+        self.vu0.stat() | self.vu1.stat() << VU1_SHIFT
+    }
+
+    /// Runs both VUs for a cycle, and XGKICK for a qword.
+    pub fn vu_step(&mut self) {
+        if self.vu0.micro.running && self.vu0.step() {
+            self.raise_interrupt(Interrupt::Vu0);
+        }
+        if self.vu1.micro.running && self.vu1.step() {
+            self.raise_interrupt(Interrupt::Vu1);
+        }
+        if let Some(addr) = self.vu1.micro.kick {
+            // the GIF reads VU1 data memory itself, so the program carries on meanwhile
+            if self.gif_path_write(PATH1, self.vu1.read_data(addr)) {
+                self.vu1.micro.kick = self.gif_path_in_packet(PATH1).then_some(addr.wrapping_add(1));
+            }
+        }
+    }
+
+    /// Whether an interlocked COP2 move has to wait for VU0.
+    pub fn vu0_interlocked(&self) -> bool {
+        self.vu0.micro.running && !self.vu0.micro.released
+    }
+
+    /// Reads a VU0 register for CFC2.
+    pub fn vu0_control_read(&self, reg: usize) -> u32 {
+        let vu = &self.vu0;
+        match reg {
+            0..=15 => vu.vi[reg] as u32,
+            REG_STATUS => vu.status,
+            REG_MAC => vu.mac,
+            REG_CLIP => vu.clip,
+            REG_R => vu.r,
+            REG_I => vu.i,
+            REG_Q => vu.q,
+            REG_TPC => vu.tpc,
+            REG_CMSAR0 => vu.cmsar,
+            REG_VPU_STAT => self.vpu_stat(),
+            _ => 0,
+        }
+    }
+
+    /// Writes a VU0 register for CTC2.
+    pub fn vu0_control_write(&mut self, reg: usize, value: u32) {
+        let vu = &mut self.vu0;
+        match reg {
+            0..=15 => vu.set_vi(reg, value as u16),
+            // only the sticky flags can be written, the rest follow the instructions
+            REG_STATUS => vu.status = (vu.status & 0x3F) | (value & 0xFC0),
+            REG_CLIP => vu.clip = value & 0xFF_FFFF,
+            REG_R => vu.r = 0x3F80_0000 | (value & 0x7F_FFFF),
+            REG_I => vu.i = value,
+            REG_Q => vu.q = value,
+            REG_CMSAR0 => vu.cmsar = value & 0xFFFF,
+            REG_FBRST => {
+                for (vu, bits) in [(&mut self.vu0, value), (&mut self.vu1, value >> VU1_SHIFT)] {
+                    if bits & FBRST_FORCE_BREAK != 0 {
+                        vu.force_break();
+                    }
+                    if bits & FBRST_RESET != 0 {
+                        vu.reset();
+                    }
+                    vu.micro.debug = bits & (FBRST_DE | FBRST_TE);
+                }
+            }
+            // CMSAR1 starts VU1, unless it's already running
+            REG_CMSAR1 if !self.vu1.micro.running => self.vu1.start((value & 0xFFFF) * 8),
+            _ => {}
+        }
+    }
+
+    /// Starts a VU0 microprogram, from CALLMS or CALLMSR.
+    pub fn vu0_start(&mut self, addr: u32) {
+        self.vu0.start(addr);
+    }
+
+    pub fn is_vu_mem(phys_addr: u32) -> bool {
+        phys_addr >= VU_MEM_START && ((phys_addr - VU_MEM_START) as usize) < VU_MEM_SIZE
+    }
+
+    /// Which of VU0 code, VU0 data, VU1 code and VU1 data an address is in, and the word offset.
+    fn vu_mem_region(phys_addr: u32) -> (u32, usize) {
+        let offset = phys_addr - VU_MEM_START;
+        (offset >> 14, (offset as usize & 0x3FFF) / 4)
+    }
+
+    pub fn vu_mem_read(&self, phys_addr: u32) -> u32 {
+        let (region, index) = Self::vu_mem_region(phys_addr);
+        let mem = match region {
+            0 => &self.vu0.code,
+            1 => &self.vu0.data,
+            2 => &self.vu1.code,
+            _ => &self.vu1.data,
+        };
+        mem[index & (mem.len() - 1)]
+    }
+
+    pub fn vu_mem_write(&mut self, phys_addr: u32, value: u32) {
+        let (region, index) = Self::vu_mem_region(phys_addr);
+        let mem = match region {
+            0 => &mut self.vu0.code,
+            1 => &mut self.vu0.data,
+            2 => &mut self.vu1.code,
+            _ => &mut self.vu1.data,
+        };
+        let len = mem.len();
+        mem[index & (len - 1)] = value;
+    }
+}
+
+impl Savestate for Vu {
+    fn save_state(&self, w: &mut StateWriter) {
+        for reg in &self.vf {
+            w.write_u32_slice(reg);
+        }
+        for reg in &self.vi {
+            w.write_u16(*reg);
+        }
+        w.write_u32_slice(&self.acc);
+        w.write_u32(self.q);
+        w.write_u32(self.i);
+        w.write_u32(self.r);
+        w.write_u32(self.status);
+        w.write_u32(self.mac);
+        w.write_u32(self.clip);
+        w.write_u32(self.tpc);
+        w.write_u32(self.cmsar);
+        w.write_u32_slice(&self.data);
+        w.write_u32(self.p);
+        w.write_u32_slice(&self.code);
+        self.micro.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        for reg in self.vf.iter_mut() {
+            r.read_u32_slice_into(reg)?;
+        }
+        for reg in self.vi.iter_mut() {
+            *reg = r.read_u16()?;
+        }
+        r.read_u32_slice_into(&mut self.acc)?;
+        self.q = r.read_u32()?;
+        self.i = r.read_u32()?;
+        self.r = r.read_u32()?;
+        self.status = r.read_u32()?;
+        self.mac = r.read_u32()?;
+        self.clip = r.read_u32()?;
+        self.tpc = r.read_u32()?;
+        self.cmsar = r.read_u32()?;
+        r.read_u32_slice_into(&mut self.data)?;
+        if r.version() >= 13 {
+            self.p = r.read_u32()?;
+            r.read_u32_slice_into(&mut self.code)?;
+            self.micro.load_state(r)?;
+        } else {
+            self.p = 0;
+            self.code.fill(0);
+            self.micro = Micro::default();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::gs::PRIM;
+    use super::*;
+
+    /// A macro mode instruction: COP2 with CO, then the fields.
+    pub fn cop2(opcode: u32, dest: u32, ft: usize, fs: usize, fd: usize) -> u32 {
+        0x12 << 26 | 1 << 25 | dest << 21 | (ft as u32) << 16 | (fs as u32) << 11 | (fd as u32) << 6 | opcode
+    }
+
+    pub fn vector(values: [f32; 4]) -> [u32; 4] {
+        values.map(f32::to_bits)
+    }
+
+    #[test]
+    fn test_floats() {
+        // denormals are zero and the top exponent is a number
+        assert_eq!(0.0, to_float(0x0000_0001));
+        assert_eq!(f32::MAX, to_float(0x7F80_0000));
+        assert_eq!((0x8000_0000 | MAX_FLOAT, true, false), from_float(f32::NEG_INFINITY));
+        assert_eq!((0, false, true), from_float(f32::MIN_POSITIVE / 2.0));
+        assert_eq!((0x3F80_0000, false, false), from_float(1.0));
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut vu = Vu::vu0();
+        vu.vf[1] = vector([1.0, -2.0, 3.0, f32::MAX]);
+        vu.vf[2] = vector([-1.0, 1.0, 1.0, f32::MAX]);
+        // ADD.xyw vf3, vf1, vf2
+        vu.macro_instruction(cop2(0x28, 0xD, 2, 1, 3));
+        assert_eq!([0, (-1.0f32).to_bits(), 0, MAX_FLOAT], vu.vf[3]);
+        assert_eq!(0x8 | 0x40 | 0x1000, vu.mac);
+        assert_eq!(0b1011 | 0b1011 << 6, vu.status);
+
+        // the next result replaces the flags but not the sticky ones
+        vu.macro_instruction(cop2(0x28, 0x8, 0, 0, 3));
+        assert_eq!((0x8, 0b0001 | 0b1011 << 6), (vu.mac, vu.status));
+    }
+
+    #[test]
+    fn test_vcallms_interlock() {
+        // VCALLMS 0, then QMFC2.I t0, vf1, which waits for the program to end
+        let mut ps2 = Ps2::new(&[cop2(0x38, 0, 0, 0, 0), 0x12 << 26 | 1 << 21 | 8 << 16 | 1 << 11 | 1]);
+        // ADDw.x vf1, vf0, vf0 and three NOPs, the last with E
+        let program = [(0x0100_0043, 0x8000_033C), (0x2FF, 0x8000_033C), (micro::E_BIT | 0x2FF, 0x8000_033C), (0x2FF, 0x8000_033C)];
+        for (i, (upper, lower)) in program.into_iter().enumerate() {
+            ps2.write_ee_u32(VU_MEM_START + i as u32 * 8, lower);
+            ps2.write_ee_u32(VU_MEM_START + i as u32 * 8 + 4, upper);
+        }
+        for _ in 0..3 {
+            ps2.step();
+        }
+        assert_eq!((0xBFC0_0004, micro::STAT_RUNNING), (ps2.r5900.pc, ps2.vpu_stat()));
+        ps2.step();
+        assert_eq!((0xBFC0_0004, 0), (ps2.r5900.pc, ps2.vpu_stat()));
+        ps2.step();
+        assert_eq!((0xBFC0_0008, 1.0f32.to_bits()), (ps2.r5900.pc, ps2.r5900.gpr_regs[8][0]));
+        assert_eq!(4, ps2.vu0_control_read(REG_TPC));
+    }
+
+    #[test]
+    fn test_xgkick() {
+        let mut ps2 = Ps2::new(&[0; 4]);
+        // XGKICK vi0 with E, then a NOP, and a packet setting PRIM in VU1 data memory
+        let words = [0x8000_06FC, micro::E_BIT | 0x2FF, 0x8000_033C, 0x2FF];
+        let packet = [0x8001, 0x1000_0000, 0xE, 0, 5, 0, PRIM as u32, 0];
+        for (i, word) in words.into_iter().enumerate() {
+            ps2.write_ee_u32(VU_MEM_START + 0x8000 + i as u32 * 4, word);
+        }
+        for (i, word) in packet.into_iter().enumerate() {
+            ps2.write_ee_u32(VU_MEM_START + 0xC000 + i as u32 * 4, word);
+        }
+        ps2.vu0_control_write(REG_CMSAR1, 0);
+        ps2.vu_step();
+        assert_eq!((micro::STAT_RUNNING | micro::STAT_XGKICK) << VU1_SHIFT, ps2.vpu_stat());
+        ps2.vu_step();
+        assert_eq!((0, 5), (ps2.vpu_stat(), ps2.gs.regs[PRIM as usize]));
+    }
+}
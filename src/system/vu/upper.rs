@@ -0,0 +1,271 @@
+use super::*;
+
+/*
+    Upper instructions, the floating point half of each VU instruction pair. The encoding is the same
+    in micro mode and in COP2 macro mode:
+        0-5 opcode, 6-10 fd, 11-15 fs, 16-20 ft, 21-24 dest
+    Opcodes 0x3C-0x3F continue in bits 6-10 for the instructions without fd, which mostly write ACC.
+    The broadcast forms (ADDx and so on) use the field of ft in bits 0-1 for every field of fs.
+
+    Only the add, subtract and multiply family set the MAC and status flags. MAX and MINI pick one of
+    their operands as they are, and the conversions and ABS never overflow.
+*/
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    MAdd,
+    MSub,
+    Max,
+    Mini
+}
+
+/// Where the second operand comes from.
+#[derive(Clone, Copy)]
+enum Operand {
+    Vector,
+    Broadcast(usize),
+    Q,
+    I
+}
+
+/// Fraction bits for FTOI0/4/12/15 and ITOF0/4/12/15.
+const FIXED_POINT_BITS: [i32; 4] = [0, 4, 12, 15];
+
+impl Vu {
+    /// Runs an upper instruction.
+    pub fn upper(&mut self, instruction: u32) {
+        let opcode = instruction & 0x3F;
+        if opcode >= 0x3C {
+            return self.upper_special(instruction);
+        }
+        let bc = Operand::Broadcast((instruction & 3) as usize);
+        let (op, operand) = match opcode {
+            0x00..=0x03 => (Op::Add, bc),
+            0x04..=0x07 => (Op::Sub, bc),
+            0x08..=0x0B => (Op::MAdd, bc),
+            0x0C..=0x0F => (Op::MSub, bc),
+            0x10..=0x13 => (Op::Max, bc),
+            0x14..=0x17 => (Op::Mini, bc),
+            0x18..=0x1B => (Op::Mul, bc),
+            0x1C => (Op::Mul, Operand::Q),
+            0x1D => (Op::Max, Operand::I),
+            0x1E => (Op::Mul, Operand::I),
+            0x1F => (Op::Mini, Operand::I),
+            0x20 => (Op::Add, Operand::Q),
+            0x21 => (Op::MAdd, Operand::Q),
+            0x22 => (Op::Add, Operand::I),
+            0x23 => (Op::MAdd, Operand::I),
+            0x24 => (Op::Sub, Operand::Q),
+            0x25 => (Op::MSub, Operand::Q),
+            0x26 => (Op::Sub, Operand::I),
+            0x27 => (Op::MSub, Operand::I),
+            0x28 => (Op::Add, Operand::Vector),
+            0x29 => (Op::MAdd, Operand::Vector),
+            0x2A => (Op::Mul, Operand::Vector),
+            0x2B => (Op::Max, Operand::Vector),
+            0x2C => (Op::Sub, Operand::Vector),
+            0x2D => (Op::MSub, Operand::Vector),
+            0x2E => return self.outer_product(instruction, false),
+            0x2F => (Op::Mini, Operand::Vector),
+            // the integer instructions, which only macro mode puts here
+            _ => return,
+        };
+        self.arithmetic(instruction, op, operand, false);
+    }
+
+    fn upper_special(&mut self, instruction: u32) {
+        let opcode = special_opcode(instruction);
+        let bc = Operand::Broadcast((instruction & 3) as usize);
+        let (op, operand) = match opcode {
+            0x00..=0x03 => (Op::Add, bc),
+            0x04..=0x07 => (Op::Sub, bc),
+            0x08..=0x0B => (Op::MAdd, bc),
+            0x0C..=0x0F => (Op::MSub, bc),
+            0x10..=0x13 => return self.itof(instruction, FIXED_POINT_BITS[(opcode & 3) as usize]),
+            0x14..=0x17 => return self.ftoi(instruction, FIXED_POINT_BITS[(opcode & 3) as usize]),
+            0x18..=0x1B => (Op::Mul, bc),
+            0x1C => (Op::Mul, Operand::Q),
+            0x1D => return self.abs(instruction),
+            0x1E => (Op::Mul, Operand::I),
+            0x1F => return self.clip(instruction),
+            0x20 => (Op::Add, Operand::Q),
+            0x21 => (Op::MAdd, Operand::Q),
+            0x22 => (Op::Add, Operand::I),
+            0x23 => (Op::MAdd, Operand::I),
+            0x24 => (Op::Sub, Operand::Q),
+            0x25 => (Op::MSub, Operand::Q),
+            0x26 => (Op::Sub, Operand::I),
+            0x27 => (Op::MSub, Operand::I),
+            0x28 => (Op::Add, Operand::Vector),
+            0x29 => (Op::MAdd, Operand::Vector),
+            0x2A => (Op::Mul, Operand::Vector),
+            0x2C => (Op::Sub, Operand::Vector),
+            0x2D => (Op::MSub, Operand::Vector),
+            0x2E => return self.outer_product(instruction, true),
+            // NOP, and the lower instructions macro mode puts here
+            _ => return,
+        };
+        self.arithmetic(instruction, op, operand, true);
+    }
+
+    fn arithmetic(&mut self, instruction: u32, op: Op, operand: Operand, to_acc: bool) {
+        let dest = dest(instruction);
+        let (fs, ft) = (self.vf[fs(instruction)], self.vf[ft(instruction)]);
+        let mut results = [(0, false, false); 4];
+        for (field, result) in results.iter_mut().enumerate() {
+            if !writes(dest, field) {
+                continue;
+            }
+            let b_bits = match operand {
+                Operand::Vector => ft[field],
+                Operand::Broadcast(bc) => ft[bc],
+                Operand::Q => self.q,
+                Operand::I => self.i,
+            };
+            let (a, b, acc) = (to_float(fs[field]), to_float(b_bits), to_float(self.acc[field]));
+            *result = match op {
+                Op::Add => from_float(a + b),
+                Op::Sub => from_float(a - b),
+                Op::Mul => from_float(a * b),
+                Op::MAdd => from_float(acc + a * b),
+                Op::MSub => from_float(acc - a * b),
+                Op::Max => (if a >= b { fs[field] } else { b_bits }, false, false),
+                Op::Mini => (if a < b { fs[field] } else { b_bits }, false, false),
+            };
+        }
+        let value = results.map(|(bits, _, _)| bits);
+        if to_acc {
+            for (field, bits) in value.iter().enumerate() {
+                if writes(dest, field) {
+                    self.acc[field] = *bits;
+                }
+            }
+        } else {
+            self.set_upper_vf(fd(instruction), dest, value);
+        }
+        if op != Op::Max && op != Op::Mini {
+            self.set_mac(dest, &results);
+        }
+    }
+
+    /// OPMULA and OPMSUB, the two halves of a cross product.
+    fn outer_product(&mut self, instruction: u32, to_acc: bool) {
+        let dest = dest(instruction);
+        let (fs, ft) = (self.vf[fs(instruction)].map(to_float), self.vf[ft(instruction)].map(to_float));
+        let products = [fs[Y] * ft[Z], fs[Z] * ft[X], fs[X] * ft[Y], 0.0];
+        let mut results = [(0, false, false); 4];
+        for field in X..=Z {
+            results[field] = if to_acc {
+                from_float(products[field])
+            } else {
+                from_float(to_float(self.acc[field]) - products[field])
+            };
+        }
+        let value = results.map(|(bits, _, _)| bits);
+        if to_acc {
+            for (field, bits) in value.iter().enumerate().take(3) {
+                if writes(dest, field) {
+                    self.acc[field] = *bits;
+                }
+            }
+        } else {
+            self.set_upper_vf(fd(instruction), dest, value);
+        }
+        self.set_mac(dest, &results);
+    }
+
+    fn itof(&mut self, instruction: u32, fraction_bits: i32) {
+        let value = self.vf[fs(instruction)].map(|bits| (bits as i32 as f32 / (1 << fraction_bits) as f32).to_bits());
+        self.set_upper_vf(ft(instruction), dest(instruction), value);
+    }
+
+    fn ftoi(&mut self, instruction: u32, fraction_bits: i32) {
+        // casting saturates, which is what the VU does too
+        let value = self.vf[fs(instruction)].map(|bits| (to_float(bits) * (1 << fraction_bits) as f32) as i32 as u32);
+        self.set_upper_vf(ft(instruction), dest(instruction), value);
+    }
+
+    fn abs(&mut self, instruction: u32) {
+        let value = self.vf[fs(instruction)].map(|bits| bits & 0x7FFF_FFFF);
+        self.set_upper_vf(ft(instruction), dest(instruction), value);
+    }
+
+    /// Judges fs.xyz against ft.w, pushing the six results onto the clipping flags.
+    fn clip(&mut self, instruction: u32) {
+        let fs = self.vf[fs(instruction)].map(to_float);
+        let w = to_float(self.vf[ft(instruction)][W]).abs();
+        let mut flags = 0;
+        for (field, &value) in fs.iter().enumerate().take(3) {
+            if value > w {
+                flags |= 1 << (field * 2);
+            }
+            if value < -w {
+                flags |= 2 << (field * 2);
+            }
+        }
+        self.clip = ((self.clip << 6) | flags) & 0xFF_FFFF;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{cop2, vector};
+    use super::*;
+
+    /// The 0x3C-0x3F group puts the rest of the opcode where fd would be.
+    fn special(opcode: u32, dest: u32, ft: usize, fs: usize) -> u32 {
+        cop2(0x3C | (opcode & 3), dest, ft, fs, (opcode >> 2) as usize)
+    }
+
+    #[test]
+    fn test_multiply_accumulate() {
+        let mut vu = Vu::vu0();
+        vu.vf[1] = vector([1.0, 2.0, 3.0, 4.0]);
+        vu.vf[2] = vector([0.0, 0.0, 0.0, 2.0]);
+        vu.i = 10.0f32.to_bits();
+        // MULAw ACC, vf1, vf2w then MADDi vf3, vf1, I
+        vu.macro_instruction(special(0x1B, 0xF, 2, 1));
+        assert_eq!(vector([2.0, 4.0, 6.0, 8.0]), vu.acc);
+        vu.macro_instruction(cop2(0x23, 0xE, 0, 1, 3));
+        assert_eq!(vector([12.0, 24.0, 36.0, 0.0]), vu.vf[3]);
+
+        // MAXw picks whole operands, MINIi too
+        vu.macro_instruction(cop2(0x13, 0xF, 2, 1, 4));
+        assert_eq!(vector([2.0, 2.0, 3.0, 4.0]), vu.vf[4]);
+        vu.macro_instruction(cop2(0x1F, 0xF, 0, 3, 5));
+        assert_eq!(vector([10.0, 10.0, 10.0, 0.0]), vu.vf[5]);
+    }
+
+    #[test]
+    fn test_cross_product() {
+        let mut vu = Vu::vu0();
+        vu.vf[1] = vector([1.0, 0.0, 0.0, 0.0]);
+        vu.vf[2] = vector([0.0, 1.0, 0.0, 0.0]);
+        // OPMULA.xyz ACC, vf1, vf2 then OPMSUB.xyz vf3, vf2, vf1
+        vu.macro_instruction(special(0x2E, 0xE, 2, 1));
+        vu.macro_instruction(cop2(0x2E, 0xE, 1, 2, 3));
+        assert_eq!(vector([0.0, 0.0, 1.0, 0.0]).map(|bits| bits & 0x7FFF_FFFF), vu.vf[3].map(|bits| bits & 0x7FFF_FFFF));
+    }
+
+    #[test]
+    fn test_conversions_and_clip() {
+        let mut vu = Vu::vu0();
+        vu.vf[1] = vector([1.5, -2.25, 3e10, -0.0]);
+        // FTOI4, then back with ITOF4
+        vu.macro_instruction(special(0x15, 0xF, 2, 1));
+        assert_eq!([24, -36i32 as u32, i32::MAX as u32, 0], vu.vf[2]);
+        vu.macro_instruction(special(0x11, 0xC, 3, 2));
+        assert_eq!(vector([1.5, -2.25, 0.0, 0.0]), vu.vf[3]);
+
+        // ABS, then CLIP against w = 2
+        vu.macro_instruction(special(0x1D, 0xF, 4, 1));
+        assert_eq!(0x4010_0000, vu.vf[4][Y]);
+        vu.vf[5] = vector([0.0, 0.0, 0.0, -2.0]);
+        vu.clip = 0x3F;
+        vu.macro_instruction(special(0x1F, 0xE, 5, 1));
+        assert_eq!(0x3F << 6 | 0b01_10_00, vu.clip);
+    }
+}